@@ -0,0 +1,52 @@
+//! Measures building a Merkle Tree of 10 million leaves, sequentially and in parallel.
+//!
+//! Run with `cargo bench --bench merkle_build`. Hashing the levels is split across every
+//! available core, while writing the nodes stays on one thread, so the speedup is below
+//! the number of cores. The leaves are only indexed on the first lookup, so neither
+//! build pays for it. On a single core both builds should take about the same time.
+
+use std::thread;
+use std::time::Instant;
+
+use training_llms::merkle::{Hasher, MerkleTree, Sha3Hasher};
+
+const LEAVES: usize = 10_000_000;
+
+fn leaves() -> Vec<[u8; 32]> {
+    (0..LEAVES)
+        .map(|i| Sha3Hasher::hash_leaf(&i.to_le_bytes()))
+        .collect()
+}
+
+fn main() {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+
+    // Only one tree is kept at a time, a tree of 10M leaves takes more than 1 GB
+    let hashes = leaves();
+    let start = Instant::now();
+    let tree: MerkleTree = MerkleTree::new_from_hashes(hashes).unwrap();
+    let sequential = start.elapsed();
+    let sequential_root = *tree.root();
+    drop(tree);
+
+    let hashes = leaves();
+    let start = Instant::now();
+    let tree: MerkleTree = MerkleTree::new_from_hashes_parallel(hashes, threads).unwrap();
+    let parallel = start.elapsed();
+    assert_eq!(
+        *tree.root(),
+        sequential_root,
+        "parallel build changed the root"
+    );
+    drop(tree);
+
+    println!("{:>10} {:>10} {:>14}", "leaves", "threads", "build (ms)");
+    for (threads, elapsed) in [(1, sequential), (threads, parallel)] {
+        println!(
+            "{:>10} {:>10} {:>14.1}",
+            LEAVES,
+            threads,
+            elapsed.as_secs_f64() * 1000.0
+        );
+    }
+}
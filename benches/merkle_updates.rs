@@ -0,0 +1,58 @@
+//! Measures appends and in-place updates on Merkle Trees of growing size.
+//!
+//! Run with `cargo bench --bench merkle_updates`. Rebuilding the tree is linear in the
+//! number of leaves, while appends and updates only touch the path to the root, so
+//! their cost should grow by a constant amount each time the tree doubles in size.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use training_llms::merkle::{Hasher, MerkleTree, Sha3Hasher};
+
+const OPERATIONS: usize = 10_000;
+
+fn leaf(i: usize) -> [u8; 32] {
+    Sha3Hasher::hash_leaf(&i.to_le_bytes())
+}
+
+fn per_operation(elapsed: Duration, operations: usize) -> f64 {
+    elapsed.as_nanos() as f64 / operations as f64 / 1000.0
+}
+
+fn main() {
+    println!(
+        "{:>10} {:>10} {:>14} {:>14} {:>14}",
+        "leaves", "depth", "build (ms)", "append (us)", "update (us)"
+    );
+
+    for size in [1_000, 10_000, 100_000, 1_000_000] {
+        let leaves: Vec<_> = (0..size).map(leaf).collect();
+
+        let start = Instant::now();
+        let mut tree: MerkleTree = MerkleTree::new_from_hashes(leaves).unwrap();
+        let build = start.elapsed();
+
+        let start = Instant::now();
+        for i in 0..OPERATIONS {
+            tree.append_hash(leaf(size + i)).unwrap();
+        }
+        let append = start.elapsed();
+
+        let start = Instant::now();
+        for i in 0..OPERATIONS {
+            let index = (i * 7_919) % size;
+            tree.update_hash(index, leaf(2 * size + i)).unwrap();
+        }
+        let update = start.elapsed();
+        black_box(tree.root());
+
+        println!(
+            "{:>10} {:>10} {:>14.1} {:>14.2} {:>14.2}",
+            size,
+            (size as f64).log2().ceil(),
+            build.as_secs_f64() * 1000.0,
+            per_operation(append, OPERATIONS),
+            per_operation(update, OPERATIONS)
+        );
+    }
+}
@@ -0,0 +1,113 @@
+//! Runs a node of a regtest chain, to propagate blocks between local processes:
+//!
+//! ```text
+//! cargo run --bin blockchain_node -- 127.0.0.1:8001 --mine 5
+//! cargo run --bin blockchain_node -- 127.0.0.1:8002 --connect 127.0.0.1:8001
+//! cargo run --bin blockchain_node -- 127.0.0.1:8003 --connect 127.0.0.1:8002
+//! ```
+//!
+//! Every node starts from the same genesis block, and a node with `--mine` produces a
+//! block every given number of seconds. Each node prints its tip when it changes.
+
+use std::env;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use training_llms::blockchain::network::unix_time;
+use training_llms::blockchain::{proof_of_work, Block, ChainParams, Node, Transaction};
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: blockchain_node <listen address> [--connect <address>]... [--mine <seconds>]"
+    );
+    process::exit(2)
+}
+
+/// The genesis block every node shares: its proof of work is found from nonce 0, so
+/// every process mines the same one.
+fn genesis() -> Block {
+    let params = ChainParams::regtest();
+    let mut genesis = Block::new(
+        [0; 32],
+        1_700_000_000,
+        params.pow_limit,
+        vec![Transaction::coinbase(0, vec![])],
+    );
+    assert!(proof_of_work(&mut genesis.header));
+    genesis
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let listen = args.next().unwrap_or_else(|| usage());
+    let mut peers = vec![];
+    let mut mine_every = None;
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match flag.as_str() {
+            "--connect" => peers.push(value),
+            "--mine" => mine_every = Some(value.parse::<u64>().unwrap_or_else(|_| usage())),
+            _ => usage(),
+        }
+    }
+
+    let node = Node::start(genesis(), ChainParams::regtest(), &listen).unwrap_or_else(|error| {
+        eprintln!("can't listen on {}: {}", listen, error);
+        process::exit(1)
+    });
+    println!("listening on {}", node.local_addr());
+    for peer in peers.iter() {
+        if let Err(error) = node.connect(peer) {
+            eprintln!("can't connect to {}: {}", peer, error);
+        }
+    }
+
+    let mut last_tip = None;
+    let mut next_block = unix_time() as u64;
+    loop {
+        if let Some(every) = mine_every {
+            if unix_time() as u64 >= next_block {
+                let mut block = node.with_tree(|tree| {
+                    let chain = tree.chain();
+                    // Tags the coinbase with the port, so two miners don't make the same block
+                    let mut coinbase = Transaction::coinbase(chain.height() + 1, vec![]);
+                    coinbase
+                        .data
+                        .extend_from_slice(&node.local_addr().port().to_le_bytes());
+                    let timestamp = unix_time().max(chain.median_time_past() + 1);
+                    Block::new(
+                        chain.tip().hash(),
+                        timestamp,
+                        chain.next_bits(),
+                        vec![coinbase],
+                    )
+                });
+                if proof_of_work(&mut block.header) {
+                    let _ = node.broadcast_block(block);
+                }
+                next_block += every;
+            }
+        }
+
+        let (height, tip) =
+            node.with_tree(|tree| (tree.chain().height(), tree.chain().tip().hash()));
+        if last_tip != Some(tip) {
+            let hex: String = tip
+                .iter()
+                .rev()
+                .take(8)
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            println!(
+                "height {} tip {}… peers {} {:?}",
+                height,
+                hex,
+                node.peer_count(),
+                node.stats()
+            );
+            last_tip = Some(tip);
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,98 @@
+//! Runs a node of a DHT over UDP, to share key-value pairs between local processes:
+//!
+//! ```text
+//! cargo run --bin dht_node -- 127.0.0.1:9001
+//! cargo run --bin dht_node -- 127.0.0.1:9002 --bootstrap 127.0.0.1:9001 --put lobby=eu-west
+//! cargo run --bin dht_node -- 127.0.0.1:9003 --bootstrap 127.0.0.1:9001 --get lobby
+//! ```
+//!
+//! The id of a node is the hash of its listen address. A node bootstraps off the seed
+//! node given with `--bootstrap`, publishes the pairs given with `--put`, prints the
+//! values of the keys given with `--get`, then keeps serving the other nodes and prints
+//! the size of its routing table and storage when they change.
+
+use std::env;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use training_llms::dht::{DhtConfig, NodeId, UdpNode};
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: dht_node <listen address> [--bootstrap <address>] [--put <key>=<value>]... \
+         [--get <key>]..."
+    );
+    process::exit(2)
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let listen = args.next().unwrap_or_else(|| usage());
+    let mut seed = None;
+    let mut puts = vec![];
+    let mut gets = vec![];
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match flag.as_str() {
+            "--bootstrap" => seed = Some(value),
+            "--put" => {
+                let (key, value) = value.split_once('=').unwrap_or_else(|| usage());
+                puts.push((key.to_string(), value.to_string()));
+            }
+            "--get" => gets.push(value),
+            _ => usage(),
+        }
+    }
+
+    let id = NodeId::from_key(listen.as_bytes());
+    let rng_seed = u64::from_le_bytes(id.as_bytes()[..8].try_into().expect("8 bytes"));
+    let node =
+        UdpNode::start(&listen, id, DhtConfig::default(), rng_seed).unwrap_or_else(|error| {
+            eprintln!("can't listen on {}: {}", listen, error);
+            process::exit(1)
+        });
+    println!("node {} listening on {}", id, node.local_addr());
+
+    if let Some(seed) = seed {
+        match node.bootstrap(&seed) {
+            Ok(lookup) => println!(
+                "bootstrapped off {}, {} closest nodes",
+                seed,
+                lookup.closest().len()
+            ),
+            Err(error) => {
+                eprintln!("can't bootstrap off {}: {}", seed, error);
+                process::exit(1)
+            }
+        }
+    }
+    for (key, value) in puts {
+        match node.put(key.as_bytes(), value.into_bytes()) {
+            Ok(lookup) => println!("put {} on {} nodes", key, lookup.closest().len()),
+            Err(error) => eprintln!("can't put {}: {}", key, error),
+        }
+    }
+    for key in gets {
+        match node.get(key.as_bytes()) {
+            Ok(Some(value)) => println!("{} = {}", key, String::from_utf8_lossy(&value)),
+            Ok(None) => println!("{} not found", key),
+            Err(error) => eprintln!("can't get {}: {}", key, error),
+        }
+    }
+
+    let mut last = None;
+    loop {
+        let sizes = node.with_node(|node| (node.table().len(), node.storage().len()));
+        if last != Some(sizes) {
+            println!(
+                "{} contacts, {} values, {:?}",
+                sizes.0,
+                sizes.1,
+                node.stats()
+            );
+            last = Some(sizes);
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,288 @@
+use super::chain_error::ChainError;
+use super::transaction::Transaction;
+use super::{double_sha256, Hash};
+use crate::bytes::{take, take_u32};
+use crate::merkle::{MerkleTree, Sha256Hasher};
+
+/// Version of the blocks this chain creates.
+pub const BLOCK_VERSION: u32 = 1;
+
+/// The header of a block, which is all that is hashed: the transactions are committed
+/// to through the Merkle root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub version: u32,
+    /// Hash of the header of the block this one builds on.
+    pub prev_block: Hash,
+    /// Root of the Merkle Tree over the ids of the transactions of the block.
+    pub merkle_root: Hash,
+    /// Seconds since the Unix epoch, as claimed by the producer of the block.
+    pub timestamp: u32,
+    /// The target the hash of the header must be below, in Bitcoin's compact encoding.
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    /// Number of bytes of a serialized header.
+    pub const SIZE: usize = 80;
+
+    /// Serializes the header as Bitcoin does: the version, the two hashes, then the
+    /// timestamp, target and nonce, with every integer in little-endian order.
+    pub fn to_bytes(&self) -> [u8; BlockHeader::SIZE] {
+        let mut bytes = [0; BlockHeader::SIZE];
+        bytes[..4].copy_from_slice(&self.version.to_le_bytes());
+        bytes[4..36].copy_from_slice(&self.prev_block);
+        bytes[36..68].copy_from_slice(&self.merkle_root);
+        bytes[68..72].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[72..76].copy_from_slice(&self.bits.to_le_bytes());
+        bytes[76..].copy_from_slice(&self.nonce.to_le_bytes());
+        bytes
+    }
+
+    /// Deserializes a header written by `to_bytes`.
+    ///
+    /// # Returns
+    /// `ChainError::MalformedHeader` if `bytes` isn't exactly 80 bytes long.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ChainError> {
+        let bytes: &[u8; BlockHeader::SIZE] = bytes
+            .try_into()
+            .map_err(|_| ChainError::MalformedHeader(bytes.len()))?;
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        let hash_at = |offset: usize| {
+            let mut hash = [0; 32];
+            hash.copy_from_slice(&bytes[offset..offset + 32]);
+            hash
+        };
+
+        Ok(BlockHeader {
+            version: u32_at(0),
+            prev_block: hash_at(4),
+            merkle_root: hash_at(36),
+            timestamp: u32_at(68),
+            bits: u32_at(72),
+            nonce: u32_at(76),
+        })
+    }
+
+    /// Returns the hash of the header, which identifies the block: the double SHA-256
+    /// of its serialized form.
+    pub fn hash(&self) -> Hash {
+        double_sha256(&self.to_bytes())
+    }
+}
+
+/// A block: a header, the transactions it commits to, and the seal of its producer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+    /// The proof that the block was produced by someone entitled to, as encoded by its
+    /// `BlockProducer`. It isn't hashed, as it signs the hash: proof of work needs none,
+    /// its proof being in the header.
+    pub seal: Vec<u8>,
+}
+
+impl Block {
+    /// Creates a block of `transactions` building on the block with hash `prev_block`,
+    /// with the Merkle root filled in, a nonce of 0 and no seal.
+    ///
+    /// # Parameters
+    /// - `prev_block`: The hash of the header of the parent block
+    /// - `timestamp`: Seconds since the Unix epoch
+    /// - `bits`: The target of the block, in compact encoding
+    /// - `transactions`: The transactions of the block, in order
+    pub fn new(
+        prev_block: Hash,
+        timestamp: u32,
+        bits: u32,
+        transactions: Vec<Transaction>,
+    ) -> Self {
+        Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_block,
+                merkle_root: merkle_root(&transactions),
+                timestamp,
+                bits,
+                nonce: 0,
+            },
+            transactions,
+            seal: vec![],
+        }
+    }
+
+    /// Returns the hash of the header of the block.
+    pub fn hash(&self) -> Hash {
+        self.header.hash()
+    }
+
+    /// Serializes the block: its header, the number of transactions as a little-endian
+    /// `u32`, each transaction as `Transaction::to_bytes` does, then the length of the
+    /// seal as a little-endian `u32` and the seal.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header.to_bytes().to_vec();
+        bytes.extend_from_slice(&(self.transactions.len() as u32).to_le_bytes());
+        for transaction in self.transactions.iter() {
+            bytes.extend_from_slice(&transaction.to_bytes());
+        }
+        bytes.extend_from_slice(&(self.seal.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.seal);
+        bytes
+    }
+
+    /// Deserializes a block written by `to_bytes`. The block isn't checked otherwise.
+    ///
+    /// # Returns
+    /// `ChainError::MalformedHeader` if `bytes` is too short for a header, or
+    /// `ChainError::MalformedBlock` if the transactions or the seal are truncated, or the
+    /// seal is followed by trailing bytes.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, ChainError> {
+        let header =
+            take(&mut bytes, BlockHeader::SIZE).ok_or(ChainError::MalformedHeader(bytes.len()))?;
+        let header = BlockHeader::from_bytes(header)?;
+
+        let truncated = || ChainError::MalformedBlock("truncated transactions".to_string());
+        let count = take_u32(&mut bytes).ok_or_else(truncated)?;
+        let mut transactions = vec![];
+        for _ in 0..count {
+            transactions.push(Transaction::read(&mut bytes).ok_or_else(truncated)?);
+        }
+        let seal = take_u32(&mut bytes)
+            .and_then(|len| take(&mut bytes, len as usize))
+            .ok_or_else(|| ChainError::MalformedBlock("truncated seal".to_string()))?
+            .to_vec();
+        if !bytes.is_empty() {
+            return Err(ChainError::MalformedBlock(format!(
+                "{} trailing bytes after the seal",
+                bytes.len()
+            )));
+        }
+        Ok(Block {
+            header,
+            transactions,
+            seal,
+        })
+    }
+
+    /// Returns the Merkle root of the transactions the block actually carries, which
+    /// must equal the one in its header.
+    pub fn compute_merkle_root(&self) -> Hash {
+        merkle_root(&self.transactions)
+    }
+}
+
+/// Returns the root of the Merkle Tree over the ids of `transactions`, or all zeros if
+/// there are none.
+///
+/// Unlike Bitcoin, which duplicates the last id of a level with an odd number of nodes,
+/// the tree promotes it, so two lists of transactions can't share a root (CVE-2012-2459).
+pub fn merkle_root(transactions: &[Transaction]) -> Hash {
+    let ids = transactions.iter().map(Transaction::id).collect();
+    match MerkleTree::<Sha256Hasher>::new_from_hashes(ids) {
+        Ok(tree) => *tree.root(),
+        Err(_) => [0; 32],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::merkle_hash::{from_hex, to_hex};
+
+    fn reversed(hex: &str) -> Hash {
+        let mut hash = from_hex(hex).unwrap();
+        hash.reverse();
+        hash
+    }
+
+    /// Test that the Bitcoin genesis header hashes to the Bitcoin genesis block hash
+    #[test]
+    fn bitcoin_genesis_header() {
+        // Bitcoin shows hashes with their bytes reversed
+        let header = BlockHeader {
+            version: 1,
+            prev_block: [0; 32],
+            merkle_root: reversed(
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            ),
+            timestamp: 1231006505,
+            bits: 0x1d00ffff,
+            nonce: 2083236893,
+        };
+
+        let mut hash = header.hash();
+        hash.reverse();
+        assert_eq!(
+            to_hex(&hash),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+    }
+
+    /// Test that a header survives a round trip through its serialized form
+    #[test]
+    fn header_round_trip() {
+        let header = Block::new([7; 32], 1_700_000_000, 0x207fffff, vec![]).header;
+        let header = BlockHeader {
+            nonce: 42,
+            ..header
+        };
+
+        let bytes = header.to_bytes();
+        assert_eq!(BlockHeader::from_bytes(&bytes), Ok(header));
+        assert_eq!(
+            BlockHeader::from_bytes(&bytes[..79]),
+            Err(ChainError::MalformedHeader(79))
+        );
+    }
+
+    /// Test that a block survives a round trip through its serialized form
+    #[test]
+    fn block_round_trip() {
+        let transactions = vec![
+            Transaction::coinbase(1, vec![]),
+            Transaction::coinbase(2, vec![]),
+        ];
+        let mut block = Block::new([7; 32], 1_700_000_000, 0x207fffff, transactions);
+        block.seal = vec![1, 2, 3];
+
+        let bytes = block.to_bytes();
+        assert_eq!(Block::from_bytes(&bytes), Ok(block));
+        assert_eq!(
+            Block::from_bytes(&bytes[..50]),
+            Err(ChainError::MalformedHeader(50))
+        );
+        assert!(matches!(
+            Block::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ChainError::MalformedBlock(_))
+        ));
+        assert!(matches!(
+            Block::from_bytes(&[bytes.as_slice(), &[0]].concat()),
+            Err(ChainError::MalformedBlock(_))
+        ));
+    }
+
+    /// Test that the Merkle root commits to the transactions and their order
+    #[test]
+    fn merkle_root_commits_to_transactions() {
+        let a = Transaction::coinbase(1, vec![]);
+        let b = Transaction::coinbase(2, vec![]);
+
+        assert_eq!(merkle_root(&[]), [0; 32]);
+        assert_eq!(merkle_root(std::slice::from_ref(&a)), a.id());
+        assert_ne!(
+            merkle_root(&[a.clone(), b.clone()]),
+            merkle_root(&[b.clone(), a.clone()])
+        );
+
+        let block = Block::new([0; 32], 0, 0x207fffff, vec![a, b]);
+        assert_eq!(block.compute_merkle_root(), block.header.merkle_root);
+    }
+}
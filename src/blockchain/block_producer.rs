@@ -0,0 +1,199 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::block::Block;
+use super::chain_error::ChainError;
+use super::miner::{Miner, MiningOutcome};
+use super::proof_of_work::check_proof_of_work;
+
+/// A way of deciding who may produce the next block, and of proving that they did.
+///
+/// A producer seals a block built on the tip, and anyone can check the seal. Proof of
+/// work seals a block by finding a nonce that makes its hash meet its target, so the
+/// seal is in the header. Proof of stake seals it with the signature of the validator
+/// whose turn it is, which is carried in the `seal` bytes of the block. A `Chain` and
+/// a `BlockTree` check every block with the producer they follow.
+pub trait BlockProducer {
+    /// The proof that a block was produced by someone entitled to, on top of its header.
+    type Seal: Clone + fmt::Debug + PartialEq;
+
+    /// Name of the scheme, used in reports.
+    const NAME: &'static str;
+
+    /// Whether a block weighs the work its target requires, the target being retargeted
+    /// to keep the pace of the chain. Otherwise every block has the limit of the chain
+    /// params as its target and weighs the same, so that packing blocks closer together
+    /// doesn't make a branch heavier.
+    const WEIGHS_WORK: bool;
+
+    /// Seals `block`, and stores the encoded seal in its `seal` bytes, so that `verify`
+    /// and `verify_block` accept it.
+    ///
+    /// # Returns
+    /// The seal, or `None` if this producer can't produce the block: no nonce works, or
+    /// it isn't the turn of this validator.
+    fn seal(&self, block: &mut Block) -> Option<Self::Seal>;
+
+    /// Checks that `block` was sealed with `seal` by someone entitled to produce it. The
+    /// rest of the block, such as its parent and transactions, isn't checked.
+    fn verify(&self, block: &Block, seal: &Self::Seal) -> Result<(), ChainError>;
+
+    /// Checks the rules between `block` and `parent`, the block it builds on, such as the
+    /// order of their slots. There are none by default.
+    fn verify_parent(&self, _block: &Block, _parent: &Block) -> Result<(), ChainError> {
+        Ok(())
+    }
+
+    /// Serializes `seal`, as it is carried in a block.
+    fn seal_to_bytes(seal: &Self::Seal) -> Vec<u8>;
+
+    /// Deserializes a seal written by `seal_to_bytes`, or returns `None` if `bytes`
+    /// isn't one.
+    fn seal_from_bytes(bytes: &[u8]) -> Option<Self::Seal>;
+
+    /// Checks that `block` was sealed by someone entitled to produce it, with the seal
+    /// it carries.
+    ///
+    /// # Returns
+    /// `ChainError::MalformedBlock` if the seal bytes of the block aren't a seal, or
+    /// the error of `verify`.
+    fn verify_block(&self, block: &Block) -> Result<(), ChainError> {
+        let seal = Self::seal_from_bytes(&block.seal)
+            .ok_or_else(|| ChainError::MalformedBlock("malformed seal".to_string()))?;
+        self.verify(block, &seal)
+    }
+}
+
+/// Produces blocks by proof of work, mining them with a `Miner`.
+///
+/// # Methods
+/// - `new`: Creates a producer mining with the given miner.
+/// - `observer`: Creates a producer that only verifies seals.
+/// - `cancel`: Stops searching, such as when a competing block arrives.
+/// - `resume`: Lets searches run again.
+#[derive(Debug, Clone)]
+pub struct ProofOfWork {
+    /// Mines the blocks to seal, or `None` for a producer that only verifies seals.
+    miner: Option<Miner>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl ProofOfWork {
+    /// Creates a producer mining with `miner`.
+    pub fn new(miner: Miner) -> Self {
+        ProofOfWork {
+            miner: Some(miner),
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Creates a producer that never seals, and only verifies seals.
+    pub fn observer() -> Self {
+        ProofOfWork {
+            miner: None,
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Stops the current search, and the next ones until `resume` is called.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    /// Lets searches run again after `cancel`.
+    pub fn resume(&self) {
+        self.cancel.store(false, Ordering::Relaxed);
+    }
+}
+
+impl BlockProducer for ProofOfWork {
+    type Seal = ();
+
+    const NAME: &'static str = "proof of work";
+
+    const WEIGHS_WORK: bool = true;
+
+    /// Mines the header of `block`, rolling its timestamp when the nonces run out. An
+    /// observer never seals.
+    fn seal(&self, block: &mut Block) -> Option<()> {
+        match self.miner?.mine(block.header, &self.cancel).outcome {
+            MiningOutcome::Found(header) => {
+                block.header = header;
+                Some(())
+            }
+            MiningOutcome::Cancelled | MiningOutcome::Exhausted | MiningOutcome::InvalidTarget => {
+                None
+            }
+        }
+    }
+
+    /// Checks that the hash of `block` meets its target. That the target is the one the
+    /// chain requires is up to the chain.
+    fn verify(&self, block: &Block, _seal: &()) -> Result<(), ChainError> {
+        if !check_proof_of_work(&block.header) {
+            return Err(ChainError::HashAboveTarget(block.hash()));
+        }
+        Ok(())
+    }
+
+    /// The proof is in the header, so the seal is empty.
+    fn seal_to_bytes(_seal: &()) -> Vec<u8> {
+        vec![]
+    }
+
+    fn seal_from_bytes(bytes: &[u8]) -> Option<()> {
+        bytes.is_empty().then_some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::transaction::Transaction;
+
+    /// Seals a block of each height with `producer`, and checks each seal.
+    fn produce<P: BlockProducer>(producer: &P, count: usize) -> usize {
+        let mut produced = 0;
+        for height in 1..=count {
+            let coinbase = Transaction::coinbase(height, vec![]);
+            let mut block = Block::new([0; 32], 1_700_000_000, 0x2000ffff, vec![coinbase]);
+            if let Some(seal) = producer.seal(&mut block) {
+                assert_eq!(producer.verify(&block, &seal), Ok(()));
+                assert_eq!(producer.verify_block(&block), Ok(()));
+                assert_eq!(P::seal_from_bytes(&P::seal_to_bytes(&seal)), Some(seal));
+                produced += 1;
+            }
+        }
+        produced
+    }
+
+    /// Test that proof-of-work seals are checked, and searches can be cancelled
+    #[test]
+    fn proof_of_work_seals() {
+        let producer = ProofOfWork::new(Miner::new(2));
+        assert_eq!(ProofOfWork::NAME, "proof of work");
+        assert_eq!(produce(&producer, 3), 3);
+
+        let mut block = Block::new([0; 32], 1_700_000_000, 0x1d00ffff, vec![]);
+        assert!(matches!(
+            producer.verify(&block, &()),
+            Err(ChainError::HashAboveTarget(_))
+        ));
+        block.seal = vec![0];
+        assert!(matches!(
+            producer.verify_block(&block),
+            Err(ChainError::MalformedBlock(_))
+        ));
+        producer.cancel();
+        assert_eq!(producer.seal(&mut block), None);
+        producer.resume();
+        assert_eq!(produce(&producer, 1), 1);
+
+        let observer = ProofOfWork::observer();
+        assert_eq!(produce(&observer, 3), 0);
+        let mut mined = Block::new([0; 32], 1_700_000_000, 0x207fffff, vec![]);
+        producer.seal(&mut mined).unwrap();
+        assert_eq!(observer.verify_block(&mined), Ok(()));
+    }
+}
@@ -0,0 +1,900 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::block::Block;
+use super::block_producer::{BlockProducer, ProofOfWork};
+use super::chain::{check_block, Chain};
+use super::chain_error::ChainError;
+use super::proof_of_work::ChainParams;
+use super::target::Target;
+use super::Hash;
+
+/// Maximum number of blocks waiting for their parent. Once there are as many, the one
+/// that arrived first is dropped to make room for a new one.
+pub const MAX_ORPHANS: usize = 100;
+
+/// A block stored in the tree, with where it stands from the genesis block.
+#[derive(Debug, Clone)]
+struct Node {
+    block: Block,
+    height: usize,
+    /// The work of the block and all its ancestors.
+    chain_work: u128,
+    /// The number of blocks stored before this one, so that the first one seen wins
+    /// ties of work.
+    sequence: usize,
+    /// The stored blocks building on this one.
+    children: Vec<Hash>,
+}
+
+/// A block whose parent is unknown.
+#[derive(Debug, Clone)]
+struct Orphan {
+    block: Block,
+    /// The order of arrival of the orphan.
+    sequence: u64,
+}
+
+/// How the active chain changed when blocks were inserted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Reorg {
+    /// The blocks removed from the tip, from the old tip down to the fork.
+    pub disconnected: Vec<Hash>,
+    /// The blocks appended after the fork, from the fork up to the new tip.
+    pub connected: Vec<Hash>,
+}
+
+impl Reorg {
+    /// Returns `true` if the active chain didn't change.
+    pub fn is_empty(&self) -> bool {
+        self.disconnected.is_empty() && self.connected.is_empty()
+    }
+
+    /// Appends `next`, a change of the active chain that followed this one.
+    fn merge(&mut self, next: Reorg) {
+        for hash in next.disconnected {
+            if self.connected.last() == Some(&hash) {
+                self.connected.pop();
+            } else {
+                self.disconnected.push(hash);
+            }
+        }
+        self.connected.extend(next.connected);
+    }
+}
+
+/// What happened to a block given to `BlockTree::insert`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Insertion {
+    /// The parent of the block is unknown, so it waits in the orphan pool until the
+    /// parent arrives.
+    Orphan,
+    /// The block was stored, along with the orphans that were waiting for it, in
+    /// `stored`, and the active chain changed as `reorg` says.
+    Stored { stored: Vec<Hash>, reorg: Reorg },
+}
+
+/// Every valid block known from a genesis block, forming a tree whose active chain is
+/// the branch with the most cumulative work. For a producer that doesn't weigh work,
+/// such as proof of stake, every block weighs 1, so the work of a branch is its length.
+///
+/// A block is checked on its own when it is inserted: its target, seal and Merkle root,
+/// the seal by the `BlockProducer` of the tree, proof of work by default. The rest of
+/// the checks, such as its transactions, depend on the state after its parent, and are
+/// run when the block is appended to the active chain. When a branch gets more work
+/// than the active chain, the active chain is reorganized onto it: blocks are popped
+/// down to the fork, undoing their transactions, and those of the branch are appended.
+/// If one of them is invalid, it is marked so, along with the blocks building on it,
+/// and the next branch with the most work is tried, until one has no invalid block or
+/// none has more work than the active chain. Branches with equal work don't replace the
+/// active chain, so the first one seen wins. A failed reorganization puts the blocks it
+/// popped back as they were, without checking them again.
+///
+/// Once a block is final, as `Finality` decides for proof of stake, no branch forking
+/// below it replaces the active chain, whatever its work: the first block of such a
+/// branch is marked invalid along with its descendants.
+///
+/// Blocks whose parent is unknown wait in a pool of at most `MAX_ORPHANS` blocks, from
+/// which the oldest are dropped, so that peers can't fill the memory with blocks that
+/// build on nothing.
+///
+/// # Methods
+/// - `new`: Creates a tree of proof of work from its genesis block.
+/// - `with_producer`: Creates a tree of another producer from its genesis block.
+/// - `insert`: Stores a block, or keeps it as an orphan, and reorganizes if needed.
+/// - `finalize`: Makes a block of the active chain final.
+/// - `finalized`: Returns the last final block.
+/// - `chain`: Returns the active chain.
+/// - `get`: Returns a stored block.
+/// - `contains`: Returns `true` if a block is stored or kept as an orphan.
+/// - `height`: Returns the height of a stored block.
+/// - `chain_work`: Returns the cumulative work of a stored block.
+/// - `locator`: Returns hashes of the active chain for a peer to find the fork.
+/// - `hashes_after`: Returns the hashes of the active chain after the fork with a peer.
+#[derive(Debug, Clone)]
+pub struct BlockTree<P = ProofOfWork> {
+    active: Chain<P>,
+    nodes: HashMap<Hash, Node>,
+    /// The stored blocks no other block builds on, the tips of every branch.
+    tips: HashSet<Hash>,
+    /// Blocks whose parent is unknown, by their hash.
+    orphans: HashMap<Hash, Orphan>,
+    /// The hashes of the orphans, by order of arrival.
+    orphan_order: BTreeMap<u64, Hash>,
+    next_orphan: u64,
+    /// The hashes of the orphans waiting for each missing parent.
+    waiting: HashMap<Hash, Vec<Hash>>,
+    /// Blocks that failed the checks of the active chain, or conflict with the final
+    /// block. Blocks building on them are rejected.
+    invalid: HashSet<Hash>,
+    /// The last final block, which stays on the active chain.
+    finalized: Option<Hash>,
+}
+
+impl BlockTree {
+    /// Creates a tree of proof of work with only its genesis block, which is checked as
+    /// by `Chain::new`.
+    ///
+    /// # Parameters
+    /// - `genesis`: The root of the tree
+    /// - `params`: The rules every branch follows
+    pub fn new(genesis: Block, params: ChainParams) -> Result<Self, ChainError> {
+        Ok(BlockTree::from_chain(Chain::new(genesis, params)?))
+    }
+}
+
+impl<P: BlockProducer> BlockTree<P> {
+    /// Creates a tree whose blocks are sealed by `producer`'s scheme, with only its
+    /// genesis block, which is checked as by `Chain::with_producer`.
+    ///
+    /// # Parameters
+    /// - `genesis`: The root of the tree
+    /// - `params`: The rules every branch follows
+    /// - `producer`: Checks the seals of the blocks
+    pub fn with_producer(
+        genesis: Block,
+        params: ChainParams,
+        producer: P,
+    ) -> Result<Self, ChainError> {
+        Ok(BlockTree::from_chain(Chain::with_producer(
+            genesis, params, producer,
+        )?))
+    }
+
+    /// Creates a tree of the genesis block of `active`, its only block.
+    fn from_chain(active: Chain<P>) -> Self {
+        let genesis = active.genesis().clone();
+        let hash = genesis.hash();
+        let node = Node {
+            chain_work: work_of::<P>(&genesis),
+            block: genesis,
+            height: 0,
+            sequence: 0,
+            children: vec![],
+        };
+        BlockTree {
+            active,
+            nodes: HashMap::from([(hash, node)]),
+            tips: HashSet::from([hash]),
+            orphans: HashMap::new(),
+            orphan_order: BTreeMap::new(),
+            next_orphan: 0,
+            waiting: HashMap::new(),
+            invalid: HashSet::new(),
+            finalized: None,
+        }
+    }
+
+    /// Returns the active chain, the valid branch with the most cumulative work.
+    pub fn chain(&self) -> &Chain<P> {
+        &self.active
+    }
+
+    /// Returns the stored block with hash `hash`. Orphans aren't stored yet.
+    pub fn get(&self, hash: &Hash) -> Option<&Block> {
+        self.nodes.get(hash).map(|node| &node.block)
+    }
+
+    /// Returns `true` if the block with hash `hash` is stored or is an orphan, so that it
+    /// doesn't need to be inserted again.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.nodes.contains_key(hash) || self.orphans.contains_key(hash)
+    }
+
+    /// Returns the number of stored blocks, on every branch.
+    pub fn block_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns the number of blocks waiting for their parent.
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+
+    /// Returns the height of the stored block with hash `hash`, on whichever branch it
+    /// is.
+    pub fn height(&self, hash: &Hash) -> Option<usize> {
+        self.nodes.get(hash).map(|node| node.height)
+    }
+
+    /// Returns the cumulative work of the stored block with hash `hash`, from the
+    /// genesis block.
+    pub fn chain_work(&self, hash: &Hash) -> Option<u128> {
+        self.nodes.get(hash).map(|node| node.chain_work)
+    }
+
+    /// Returns `true` if the block with hash `hash` was found invalid.
+    pub fn is_invalid(&self, hash: &Hash) -> bool {
+        self.invalid.contains(hash)
+    }
+
+    /// Returns the hash of the last final block, if any.
+    pub fn finalized(&self) -> Option<Hash> {
+        self.finalized
+    }
+
+    /// Makes the block with hash `hash` final, so that the active chain never leaves it.
+    /// Finalizing an ancestor of the final block changes nothing.
+    ///
+    /// # Returns
+    /// `ChainError::UnknownBlock` if the block isn't stored,
+    /// `ChainError::ConflictsWithFinal` if it neither descends from the final block nor
+    /// is one of its ancestors, or `ChainError::InactiveBlock` if it descends from it
+    /// but isn't on the active chain.
+    pub fn finalize(&mut self, hash: &Hash) -> Result<(), ChainError> {
+        let height = self.height(hash).ok_or(ChainError::UnknownBlock(*hash))?;
+        if let Some(finalized) = self.finalized {
+            let final_height = self.nodes[&finalized].height;
+            if height <= final_height {
+                if self.ancestor(&finalized, height) != *hash {
+                    return Err(ChainError::ConflictsWithFinal(*hash));
+                }
+                return Ok(());
+            }
+            if self.ancestor(hash, final_height) != finalized {
+                return Err(ChainError::ConflictsWithFinal(*hash));
+            }
+        }
+        if !self.is_active(hash) {
+            return Err(ChainError::InactiveBlock(*hash));
+        }
+        self.finalized = Some(*hash);
+        Ok(())
+    }
+
+    /// Returns hashes of the active chain for a peer to find where its own chain forks
+    /// from it: the last ten blocks from the tip, then blocks ever further apart down to
+    /// the genesis block. In O(log n) time, with n = height of the tip.
+    pub fn locator(&self) -> Vec<Hash> {
+        let mut hashes = vec![];
+        let mut height = self.active.height();
+        let mut step = 1;
+        loop {
+            hashes.push(self.active.blocks()[height].hash());
+            if height == 0 {
+                return hashes;
+            }
+            if hashes.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+    }
+
+    /// Returns the hashes of the blocks of the active chain after the fork with a peer's
+    /// chain, found as the first hash of `locator` on the active chain, or the genesis
+    /// block if there is none.
+    ///
+    /// # Parameters
+    /// - `locator`: What `locator` returns on the peer
+    /// - `limit`: The maximum number of hashes to return
+    pub fn hashes_after(&self, locator: &[Hash], limit: usize) -> Vec<Hash> {
+        let fork = locator
+            .iter()
+            .find(|hash| self.nodes.contains_key(*hash) && self.is_active(hash))
+            .map_or(0, |hash| self.nodes[hash].height);
+        self.active.blocks()[fork + 1..]
+            .iter()
+            .take(limit)
+            .map(Block::hash)
+            .collect()
+    }
+
+    /// Inserts `block`, connects the orphans that were waiting for it, and reorganizes
+    /// the active chain if a branch now has more work. In O(b·(d + r·t)) time, with
+    /// b = number of branches with more work tried, d = depth of their forks, r = number
+    /// of blocks reorganized and t = number of inputs and outputs of their transactions.
+    /// An orphan dropped for room needs to be inserted again.
+    ///
+    /// # Parameters
+    /// - `block`: The block to insert
+    /// - `now`: The local clock, in seconds since the Unix epoch
+    ///
+    /// # Returns
+    /// What happened to the block, `ChainError::DuplicateBlock` if it is already known,
+    /// `ChainError::InvalidAncestor` if it builds on an invalid block, or the error of
+    /// its own checks. A block that fails once it is appended to the active chain is
+    /// still stored, and marked invalid.
+    pub fn insert(&mut self, block: Block, now: u32) -> Result<Insertion, ChainError> {
+        let hash = block.hash();
+        if self.contains(&hash) {
+            return Err(ChainError::DuplicateBlock(hash));
+        }
+        check_block(&block, self.active.params(), self.active.producer())?;
+
+        let parent = block.header.prev_block;
+        if self.invalid.contains(&parent) {
+            self.invalid.insert(hash);
+            return Err(ChainError::InvalidAncestor(parent));
+        }
+        if !self.nodes.contains_key(&parent) {
+            self.add_orphan(block);
+            return Ok(Insertion::Orphan);
+        }
+
+        let mut stored = vec![];
+        let mut pending = vec![block];
+        while let Some(block) = pending.pop() {
+            let hash = block.hash();
+            for child in self.waiting.remove(&hash).unwrap_or_default() {
+                pending.extend(self.remove_orphan(&child));
+            }
+            self.store(block);
+            stored.push(hash);
+        }
+
+        let reorg = self.activate_best(now);
+        Ok(Insertion::Stored { stored, reorg })
+    }
+
+    /// Stores `block`, whose parent is stored.
+    fn store(&mut self, block: Block) {
+        let hash = block.hash();
+        let parent_hash = block.header.prev_block;
+        let parent = self
+            .nodes
+            .get_mut(&parent_hash)
+            .expect("the parent is stored");
+        parent.children.push(hash);
+        let node = Node {
+            height: parent.height + 1,
+            chain_work: parent.chain_work.saturating_add(work_of::<P>(&block)),
+            sequence: self.nodes.len(),
+            children: vec![],
+            block,
+        };
+        self.nodes.insert(hash, node);
+        self.tips.remove(&parent_hash);
+        self.tips.insert(hash);
+    }
+
+    /// Adds `block`, whose parent is unknown, to the orphans, dropping the oldest one if
+    /// there are `MAX_ORPHANS` already.
+    fn add_orphan(&mut self, block: Block) {
+        if self.orphans.len() >= MAX_ORPHANS {
+            if let Some(oldest) = self.orphan_order.values().next().copied() {
+                self.remove_orphan(&oldest);
+            }
+        }
+        let hash = block.hash();
+        let sequence = self.next_orphan;
+        self.next_orphan += 1;
+        self.waiting
+            .entry(block.header.prev_block)
+            .or_default()
+            .push(hash);
+        self.orphan_order.insert(sequence, hash);
+        self.orphans.insert(hash, Orphan { block, sequence });
+    }
+
+    /// Removes the orphan with hash `hash`, and returns it.
+    fn remove_orphan(&mut self, hash: &Hash) -> Option<Block> {
+        let orphan = self.orphans.remove(hash)?;
+        self.orphan_order.remove(&orphan.sequence);
+        let parent = orphan.block.header.prev_block;
+        if let Some(siblings) = self.waiting.get_mut(&parent) {
+            siblings.retain(|sibling| sibling != hash);
+            if siblings.is_empty() {
+                self.waiting.remove(&parent);
+            }
+        }
+        Some(orphan.block)
+    }
+
+    /// Marks the stored block with hash `hash` invalid, along with every block building
+    /// on it.
+    fn mark_invalid(&mut self, hash: Hash) {
+        let mut pending = vec![hash];
+        while let Some(hash) = pending.pop() {
+            self.invalid.insert(hash);
+            pending.extend(self.nodes[&hash].children.iter().copied());
+        }
+    }
+
+    /// Returns the hash of the ancestor at `height` of the stored block with hash `hash`,
+    /// which is higher.
+    fn ancestor(&self, hash: &Hash, height: usize) -> Hash {
+        let mut hash = *hash;
+        while self.nodes[&hash].height > height {
+            hash = self.nodes[&hash].block.header.prev_block;
+        }
+        hash
+    }
+
+    /// Returns `true` if the stored block with hash `hash` is part of the active chain.
+    fn is_active(&self, hash: &Hash) -> bool {
+        let height = self.nodes[hash].height;
+        self.active
+            .block(height)
+            .is_some_and(|block| block.hash() == *hash)
+    }
+
+    /// Makes the tip of the valid branch with the most work the tip of the active chain,
+    /// trying the next branch whenever one turns out to have an invalid block.
+    fn activate_best(&mut self, now: u32) -> Reorg {
+        let mut reorg = Reorg::default();
+        loop {
+            let work = self.nodes[&self.active.tip().hash()].chain_work;
+            let best = self
+                .tips
+                .iter()
+                .map(|hash| &self.nodes[hash])
+                .filter(|node| node.chain_work > work && !self.invalid.contains(&node.block.hash()))
+                .max_by_key(|node| (node.chain_work, Reverse(node.sequence)))
+                .map(|node| node.block.hash());
+            let Some(best) = best else {
+                return reorg;
+            };
+            // Either the active chain gets more work, or `best` is marked invalid
+            reorg.merge(self.activate(best, now));
+        }
+    }
+
+    /// Makes `candidate` the tip if it has more work than the tip, its branch doesn't
+    /// fork below the final block, and every block from the fork to it is valid. If one
+    /// of them is invalid, it is marked so along with its descendants, and the active
+    /// chain ends at its parent or is put back as it was, whichever has more work. A
+    /// branch forking below the final block is marked invalid from its first block.
+    fn activate(&mut self, candidate: Hash, now: u32) -> Reorg {
+        let old_work = self.nodes[&self.active.tip().hash()].chain_work;
+        if self.nodes[&candidate].chain_work <= old_work {
+            return Reorg::default();
+        }
+
+        let mut branch = vec![];
+        let mut hash = candidate;
+        while !self.is_active(&hash) {
+            if self.invalid.contains(&hash) {
+                self.mark_invalid(hash);
+                return Reorg::default();
+            }
+            branch.push(hash);
+            hash = self.nodes[&hash].block.header.prev_block;
+        }
+        let fork_height = self.nodes[&hash].height;
+        let final_height = self.finalized.map_or(0, |hash| self.nodes[&hash].height);
+        if fork_height < final_height {
+            let first = *branch.last().expect("the candidate isn't active");
+            self.mark_invalid(first);
+            return Reorg::default();
+        }
+
+        let mut disconnected = vec![];
+        while self.active.height() > fork_height {
+            let popped = self
+                .active
+                .pop_with_changes()
+                .expect("the fork is above the genesis block");
+            disconnected.push(popped);
+        }
+
+        let mut connected = vec![];
+        for hash in branch.into_iter().rev() {
+            let block = self.nodes[&hash].block.clone();
+            if self.active.append(block, now).is_err() {
+                self.mark_invalid(hash);
+                break;
+            }
+            connected.push(hash);
+        }
+
+        if self.nodes[&self.active.tip().hash()].chain_work <= old_work {
+            while self.active.height() > fork_height {
+                self.active.pop();
+            }
+            for (block, changes) in disconnected.into_iter().rev() {
+                self.active.restore(block, changes);
+            }
+            return Reorg::default();
+        }
+
+        Reorg {
+            disconnected: disconnected.iter().map(|(block, _)| block.hash()).collect(),
+            connected,
+        }
+    }
+}
+
+/// Returns the weight of `block`, which passed `check_block`, in fork choice: the work
+/// its target requires, or 1 if `P` doesn't weigh work.
+fn work_of<P: BlockProducer>(block: &Block) -> u128 {
+    if !P::WEIGHS_WORK {
+        return 1;
+    }
+    Target::from_compact(block.header.bits).map_or(0, |target| target.work())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proof_of_work::proof_of_work;
+    use crate::blockchain::transaction::{OutPoint, Transaction, TxOutput};
+    use ed25519_dalek::SigningKey;
+
+    const BITS: u32 = 0x207fffff;
+    const START: u32 = 1_700_000_000;
+    const NOW: u32 = START + 100_000;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn owner(key: &SigningKey) -> [u8; 32] {
+        key.verifying_key().to_bytes()
+    }
+
+    fn reward() -> u64 {
+        ChainParams::regtest().subsidy(0)
+    }
+
+    /// Mines a block on `parent` at `height`, whose coinbase pays the reward to `miner`.
+    fn child(
+        parent: &Block,
+        height: usize,
+        miner: &SigningKey,
+        mut payments: Vec<Transaction>,
+    ) -> Block {
+        let coinbase = Transaction::coinbase(
+            height,
+            vec![TxOutput::new(reward(), &miner.verifying_key())],
+        );
+        payments.insert(0, coinbase);
+        let timestamp = START + height as u32 * 600;
+        let mut block = Block::new(parent.hash(), timestamp, BITS, payments);
+        assert!(proof_of_work(&mut block.header));
+        block
+    }
+
+    /// Mines `len` blocks on `parent`, at the height after `height`, paying `miner`.
+    fn branch(parent: &Block, height: usize, len: usize, miner: &SigningKey) -> Vec<Block> {
+        let mut blocks: Vec<Block> = vec![];
+        for i in 1..=len {
+            let parent = blocks.last().unwrap_or(parent);
+            blocks.push(child(parent, height + i, miner, vec![]));
+        }
+        blocks
+    }
+
+    /// Returns a tree whose genesis block pays the reward to the key of seed 1.
+    fn tree() -> BlockTree {
+        let coinbase =
+            Transaction::coinbase(0, vec![TxOutput::new(reward(), &key(1).verifying_key())]);
+        let mut genesis = Block::new([0; 32], START, BITS, vec![coinbase]);
+        assert!(proof_of_work(&mut genesis.header));
+        BlockTree::new(genesis, ChainParams::regtest()).unwrap()
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<Hash> {
+        blocks.iter().map(Block::hash).collect()
+    }
+
+    /// Test that the branch with the most work becomes active, the first one seen winning ties
+    #[test]
+    fn follows_most_work() {
+        let mut tree = tree();
+        let genesis = tree.chain().genesis().clone();
+        let (alice, bob) = (key(1), key(2));
+
+        let ours = branch(&genesis, 0, 2, &alice);
+        for block in ours.iter() {
+            tree.insert(block.clone(), NOW).unwrap();
+        }
+        let theirs = branch(&genesis, 0, 3, &bob);
+        for block in theirs[..2].iter() {
+            let insertion = tree.insert(block.clone(), NOW).unwrap();
+            assert_eq!(
+                insertion,
+                Insertion::Stored {
+                    stored: vec![block.hash()],
+                    reorg: Reorg::default()
+                }
+            );
+        }
+        assert_eq!(tree.chain().tip(), &ours[1]);
+        assert_eq!(tree.block_count(), 5);
+
+        let insertion = tree.insert(theirs[2].clone(), NOW).unwrap();
+        let mut disconnected = hashes(&ours);
+        disconnected.reverse();
+        assert_eq!(
+            insertion,
+            Insertion::Stored {
+                stored: vec![theirs[2].hash()],
+                reorg: Reorg {
+                    disconnected,
+                    connected: hashes(&theirs),
+                }
+            }
+        );
+        assert_eq!(tree.chain().blocks()[1..], theirs[..]);
+        assert_eq!(tree.chain().utxos().balance(&owner(&alice)), reward());
+        assert_eq!(tree.chain().utxos().balance(&owner(&bob)), 3 * reward());
+        assert_eq!(
+            tree.chain_work(&theirs[2].hash()),
+            Some(4 * tree.chain_work(&genesis.hash()).unwrap())
+        );
+
+        assert_eq!(
+            tree.insert(ours[0].clone(), NOW),
+            Err(ChainError::DuplicateBlock(ours[0].hash()))
+        );
+    }
+
+    /// Test that a reorganization undoes the payments of the branch it leaves
+    #[test]
+    fn reorganization_undoes_payments() {
+        let mut tree = tree();
+        let genesis = tree.chain().genesis().clone();
+        let (alice, bob, carol) = (key(1), key(2), key(3));
+
+        let funds = OutPoint::new(genesis.transactions[0].id(), 0);
+        let mut payment = Transaction::new(
+            vec![funds],
+            vec![TxOutput::new(reward(), &bob.verifying_key())],
+        );
+        payment.sign(&alice);
+        let paid = child(&genesis, 1, &carol, vec![payment.clone()]);
+        tree.insert(paid.clone(), NOW).unwrap();
+        assert_eq!(tree.chain().utxos().balance(&owner(&alice)), 0);
+        assert_eq!(tree.chain().utxos().balance(&owner(&bob)), reward());
+
+        for block in branch(&genesis, 0, 2, &carol) {
+            tree.insert(block, NOW).unwrap();
+        }
+        assert_eq!(tree.chain().height(), 2);
+        assert_eq!(tree.chain().utxos().balance(&owner(&alice)), reward());
+        assert_eq!(tree.chain().utxos().balance(&owner(&bob)), 0);
+        assert_eq!(tree.chain().utxos().balance(&owner(&carol)), 2 * reward());
+
+        // The payment can be mined again on the new branch
+        let tip = tree.chain().tip().clone();
+        tree.insert(child(&tip, 3, &carol, vec![payment]), NOW)
+            .unwrap();
+        assert_eq!(tree.chain().height(), 3);
+        assert_eq!(tree.chain().utxos().balance(&owner(&bob)), reward());
+    }
+
+    /// Test that an invalid branch with more work is rejected, and so are its descendants
+    #[test]
+    fn rejects_invalid_branch() {
+        let mut tree = tree();
+        let genesis = tree.chain().genesis().clone();
+        let (alice, bob) = (key(1), key(2));
+
+        let ours = branch(&genesis, 0, 1, &alice);
+        tree.insert(ours[0].clone(), NOW).unwrap();
+
+        let first = child(&genesis, 1, &bob, vec![]);
+        let theft = Transaction::new(
+            vec![OutPoint::new(genesis.transactions[0].id(), 0)],
+            vec![TxOutput::new(reward(), &bob.verifying_key())],
+        );
+        let invalid = child(&first, 2, &bob, vec![theft]);
+        tree.insert(first.clone(), NOW).unwrap();
+        let insertion = tree.insert(invalid.clone(), NOW).unwrap();
+        assert_eq!(
+            insertion,
+            Insertion::Stored {
+                stored: vec![invalid.hash()],
+                reorg: Reorg::default()
+            }
+        );
+        assert!(tree.is_invalid(&invalid.hash()));
+        assert_eq!(tree.chain().tip(), &ours[0]);
+        assert_eq!(tree.chain().utxos().balance(&owner(&alice)), 2 * reward());
+
+        let descendant = child(&invalid, 3, &bob, vec![]);
+        assert_eq!(
+            tree.insert(descendant.clone(), NOW),
+            Err(ChainError::InvalidAncestor(invalid.hash()))
+        );
+        assert!(tree.is_invalid(&descendant.hash()));
+
+        // A valid sibling of the invalid block still takes over
+        let second = child(&first, 2, &alice, vec![]);
+        tree.insert(second.clone(), NOW).unwrap();
+        assert_eq!(tree.chain().blocks()[1..], [first, second]);
+    }
+
+    /// Test that the next best branch takes over when the best one has an invalid block
+    #[test]
+    fn falls_back_to_next_best_branch() {
+        let mut tree = tree();
+        let genesis = tree.chain().genesis().clone();
+        let (alice, bob) = (key(1), key(2));
+
+        // Both branches build on a block that arrives last, so the tree only sees them
+        // once they are complete
+        let fork = child(&genesis, 1, &alice, vec![]);
+        let theft = Transaction::new(
+            vec![OutPoint::new(genesis.transactions[0].id(), 0)],
+            vec![TxOutput::new(reward(), &bob.verifying_key())],
+        );
+        let bad = child(&fork, 2, &bob, vec![theft]);
+        let mut best = vec![bad.clone()];
+        best.extend(branch(&bad, 2, 2, &bob));
+        let next = branch(&fork, 1, 2, &alice);
+        for block in best.iter().chain(next.iter()) {
+            assert_eq!(tree.insert(block.clone(), NOW), Ok(Insertion::Orphan));
+        }
+
+        let Insertion::Stored { stored, reorg } = tree.insert(fork.clone(), NOW).unwrap() else {
+            panic!("the fork is stored");
+        };
+        assert_eq!(stored.len(), 6);
+        let mut connected = vec![fork.hash()];
+        connected.extend(hashes(&next));
+        assert_eq!(
+            reorg,
+            Reorg {
+                disconnected: vec![],
+                connected
+            }
+        );
+        assert_eq!(tree.chain().tip(), &next[1]);
+        assert!(best.iter().all(|block| tree.is_invalid(&block.hash())));
+        assert!(!tree.is_invalid(&next[1].hash()));
+    }
+
+    /// Test that a failed reorganization puts the active chain back, even if its blocks
+    /// would now fail the rules on time
+    #[test]
+    fn restores_chain_after_failed_reorganization() {
+        let mut tree = tree();
+        let genesis = tree.chain().genesis().clone();
+        let (alice, bob) = (key(1), key(2));
+        let ours = branch(&genesis, 0, 15, &alice);
+        for block in ours.iter() {
+            tree.insert(block.clone(), NOW).unwrap();
+        }
+
+        let theft = Transaction::new(
+            vec![OutPoint::new(genesis.transactions[0].id(), 0)],
+            vec![TxOutput::new(reward(), &bob.verifying_key())],
+        );
+        let bad = child(&genesis, 1, &bob, vec![theft]);
+        for block in branch(&bad, 1, 15, &bob) {
+            tree.insert(block, NOW).unwrap();
+        }
+        // With a clock this far behind, the last blocks of ours are too far ahead of it
+        let insertion = tree.insert(bad.clone(), START).unwrap();
+        let Insertion::Stored { reorg, .. } = insertion else {
+            panic!("the bad block is stored");
+        };
+        assert!(reorg.is_empty());
+        assert!(tree.is_invalid(&bad.hash()));
+        assert_eq!(tree.chain().blocks()[1..], ours[..]);
+        assert_eq!(tree.chain().utxos().balance(&owner(&alice)), 16 * reward());
+    }
+
+    /// Test that the orphan pool keeps the newest `MAX_ORPHANS` orphans
+    #[test]
+    fn bounds_orphans() {
+        let mut tree = tree();
+        let genesis = tree.chain().genesis().clone();
+        let blocks = branch(&genesis, 0, MAX_ORPHANS + 2, &key(2));
+        for block in blocks[1..].iter() {
+            assert_eq!(tree.insert(block.clone(), NOW), Ok(Insertion::Orphan));
+        }
+        assert_eq!(tree.orphan_count(), MAX_ORPHANS);
+        assert!(!tree.contains(&blocks[1].hash()));
+        assert!(tree.contains(&blocks[2].hash()));
+
+        // The dropped orphan is missing until it is inserted again
+        tree.insert(blocks[0].clone(), NOW).unwrap();
+        assert_eq!(tree.chain().height(), 1);
+        let insertion = tree.insert(blocks[1].clone(), NOW).unwrap();
+        let Insertion::Stored { stored, .. } = insertion else {
+            panic!("the parent is stored");
+        };
+        assert_eq!(stored.len(), MAX_ORPHANS + 1);
+        assert_eq!(tree.orphan_count(), 0);
+        assert_eq!(tree.chain().tip(), &blocks[MAX_ORPHANS + 1]);
+    }
+
+    /// Test that a block failing its own checks isn't stored
+    #[test]
+    fn rejects_unmined_block() {
+        let mut tree = tree();
+        let genesis = tree.chain().genesis().clone();
+        let mut block = child(&genesis, 1, &key(1), vec![]);
+        while check_block(&block, tree.chain().params(), tree.chain().producer()).is_ok() {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        assert_eq!(
+            tree.insert(block.clone(), NOW),
+            Err(ChainError::HashAboveTarget(block.hash()))
+        );
+        assert!(!tree.contains(&block.hash()));
+    }
+
+    /// Test that blocks arriving before their parent are connected once it arrives
+    #[test]
+    fn connects_orphans() {
+        let mut tree = tree();
+        let genesis = tree.chain().genesis().clone();
+        let blocks = branch(&genesis, 0, 5, &key(2));
+
+        for block in blocks[1..].iter().rev() {
+            assert_eq!(tree.insert(block.clone(), NOW), Ok(Insertion::Orphan));
+            assert!(tree.contains(&block.hash()));
+            assert_eq!(tree.get(&block.hash()), None);
+        }
+        assert_eq!(tree.orphan_count(), 4);
+        assert_eq!(tree.chain().height(), 0);
+        assert_eq!(
+            tree.insert(blocks[2].clone(), NOW),
+            Err(ChainError::DuplicateBlock(blocks[2].hash()))
+        );
+
+        let insertion = tree.insert(blocks[0].clone(), NOW).unwrap();
+        assert_eq!(
+            insertion,
+            Insertion::Stored {
+                stored: hashes(&blocks),
+                reorg: Reorg {
+                    disconnected: vec![],
+                    connected: hashes(&blocks),
+                }
+            }
+        );
+        assert_eq!(tree.orphan_count(), 0);
+        assert_eq!(tree.chain().blocks()[1..], blocks[..]);
+    }
+
+    /// Test that a locator lets a peer on another branch find the blocks it misses
+    #[test]
+    fn locates_fork() {
+        let mut tree = tree();
+        let genesis = tree.chain().genesis().clone();
+        let ours = branch(&genesis, 0, 40, &key(1));
+        for block in ours.iter() {
+            tree.insert(block.clone(), NOW).unwrap();
+        }
+
+        let locator = tree.locator();
+        let heights: Vec<usize> = locator
+            .iter()
+            .map(|hash| {
+                ours.iter()
+                    .position(|block| block.hash() == *hash)
+                    .map_or(0, |i| i + 1)
+            })
+            .collect();
+        assert_eq!(
+            heights,
+            [40, 39, 38, 37, 36, 35, 34, 33, 32, 31, 29, 25, 17, 1, 0]
+        );
+
+        let mut peer = BlockTree::new(genesis.clone(), ChainParams::regtest()).unwrap();
+        for block in ours[..20].iter() {
+            peer.insert(block.clone(), NOW).unwrap();
+        }
+        for block in branch(&ours[19], 20, 3, &key(2)) {
+            peer.insert(block, NOW).unwrap();
+        }
+        assert_eq!(tree.hashes_after(&peer.locator(), 5), hashes(&ours[20..25]));
+        assert_eq!(tree.hashes_after(&[[1; 32]], 2), hashes(&ours[..2]));
+        assert!(tree.hashes_after(&locator, 500).is_empty());
+    }
+}
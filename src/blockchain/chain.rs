@@ -0,0 +1,605 @@
+use super::block::Block;
+use super::block_producer::{BlockProducer, ProofOfWork};
+use super::chain_error::ChainError;
+use super::proof_of_work::{retarget, ChainParams};
+use super::target::Target;
+use super::utxo_set::{UtxoChanges, UtxoSet};
+
+/// Number of previous blocks whose median timestamp a new block must be later than.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far, in seconds, the timestamp of a block may be ahead of the local clock.
+pub const MAX_FUTURE_DRIFT: u32 = 2 * 60 * 60;
+
+/// A chain of blocks from a genesis block, which only grows by blocks that extend its
+/// tip validly.
+///
+/// Who may produce each block is checked by the `BlockProducer` of the chain, proof of
+/// work by default. The target of the header is checked whatever the producer: unless
+/// the producer weighs blocks by their work, it is the limit of the params for every
+/// block, and isn't retargeted.
+///
+/// Time is passed in explicitly as seconds since the Unix epoch, as in the
+/// [`consensus`](crate::consensus) module, so tests don't depend on the system clock.
+///
+/// # Methods
+/// - `new`: Creates a chain of proof of work from its genesis block.
+/// - `with_producer`: Creates a chain of another producer from its genesis block.
+/// - `tip`: Returns the last block of the chain.
+/// - `next_bits`: Returns the target the next block must have.
+/// - `validate`: Checks that a block can extend the chain, without appending it.
+/// - `append`: Appends a block to the chain once it is validated.
+/// - `pop`: Removes the tip, undoing its transactions.
+/// - `utxos`: Returns the unspent outputs after the tip.
+#[derive(Debug, Clone)]
+pub struct Chain<P = ProofOfWork> {
+    params: ChainParams,
+    /// Checks the seal of every block.
+    producer: P,
+    blocks: Vec<Block>,
+    utxos: UtxoSet,
+    /// What each block after the genesis block changed in `utxos`, to undo it in `pop`.
+    undo: Vec<UtxoChanges>,
+}
+
+impl Chain {
+    /// Creates a chain of proof of work from its genesis block. The genesis block is
+    /// trusted to have no parent and a sensible timestamp, but must still have a valid
+    /// proof of work, a Merkle root matching its transactions and a coinbase no larger
+    /// than the reward.
+    ///
+    /// # Parameters
+    /// - `genesis`: The first block of the chain
+    /// - `params`: The rules the chain follows
+    ///
+    /// # Returns
+    /// The chain, `ChainError::InvalidParams` if `params` don't pass
+    /// `ChainParams::validate`, `ChainError::WrongTarget` if the genesis block's target is easier than
+    /// the limit, `ChainError::HashAboveTarget` if it wasn't mined,
+    /// `ChainError::WrongMerkleRoot` if its header doesn't commit to its transactions,
+    /// or `ChainError::InvalidTransaction` if its transactions are invalid.
+    pub fn new(genesis: Block, params: ChainParams) -> Result<Self, ChainError> {
+        Chain::with_producer(genesis, params, ProofOfWork::observer())
+    }
+}
+
+impl<P: BlockProducer> Chain<P> {
+    /// Creates a chain whose blocks are sealed by `producer`'s scheme, from its genesis
+    /// block, which is checked as by `new` except that it must have a valid seal
+    /// instead of a proof of work.
+    ///
+    /// # Parameters
+    /// - `genesis`: The first block of the chain
+    /// - `params`: The rules the chain follows
+    /// - `producer`: Checks the seals of the blocks
+    ///
+    /// # Returns
+    /// The chain, or the errors of `new`, with the error of `BlockProducer::verify_block`
+    /// instead of `ChainError::HashAboveTarget`.
+    pub fn with_producer(
+        genesis: Block,
+        params: ChainParams,
+        producer: P,
+    ) -> Result<Self, ChainError> {
+        params.validate()?;
+        check_block(&genesis, &params, &producer)?;
+        let mut utxos = UtxoSet::new();
+        utxos.apply_block(&genesis.transactions, params.subsidy(0))?;
+
+        Ok(Chain {
+            params,
+            producer,
+            blocks: vec![genesis],
+            utxos,
+            undo: vec![],
+        })
+    }
+
+    /// Returns the rules the chain follows.
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// Returns the producer that checks the seals of the blocks.
+    pub fn producer(&self) -> &P {
+        &self.producer
+    }
+
+    /// Returns the outputs that are unspent once every block of the chain is applied.
+    pub fn utxos(&self) -> &UtxoSet {
+        &self.utxos
+    }
+
+    /// Returns the first block of the chain.
+    pub fn genesis(&self) -> &Block {
+        &self.blocks[0]
+    }
+
+    /// Returns the last block of the chain.
+    pub fn tip(&self) -> &Block {
+        &self.blocks[self.blocks.len() - 1]
+    }
+
+    /// Returns the height of the tip, the genesis block being at height 0.
+    pub fn height(&self) -> usize {
+        self.blocks.len() - 1
+    }
+
+    /// Returns the block at `height`, if the chain is that long.
+    pub fn block(&self, height: usize) -> Option<&Block> {
+        self.blocks.get(height)
+    }
+
+    /// Returns every block of the chain, from the genesis block to the tip.
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    /// Returns the median timestamp of the last `MEDIAN_TIME_SPAN` blocks, which the
+    /// next block must be later than. Using the median rather than the tip's timestamp
+    /// stops one producer with a wrong clock from dragging the chain's time around.
+    pub fn median_time_past(&self) -> u32 {
+        let start = self.blocks.len().saturating_sub(MEDIAN_TIME_SPAN);
+        let mut timestamps: Vec<u32> = self.blocks[start..]
+            .iter()
+            .map(|block| block.header.timestamp)
+            .collect();
+        timestamps.sort_unstable();
+        timestamps[timestamps.len() / 2]
+    }
+
+    /// Returns the target the next block must have, in compact encoding.
+    ///
+    /// The target is the tip's, except every `retarget_interval` blocks where it is
+    /// adjusted by how long the last interval took. As in Bitcoin, the interval is
+    /// measured from its first block to the tip, so it spans one block less than it
+    /// should. Producers that don't weigh work always use the limit of the params.
+    pub fn next_bits(&self) -> u32 {
+        if !P::WEIGHS_WORK {
+            return self.params.pow_limit;
+        }
+        let height = self.blocks.len();
+        let tip = &self.tip().header;
+        if !self.params.is_retarget_height(height) {
+            return tip.bits;
+        }
+
+        let first = &self.blocks[height - self.params.retarget_interval as usize].header;
+        retarget(
+            tip.bits,
+            tip.timestamp.saturating_sub(first.timestamp),
+            &self.params,
+        )
+    }
+
+    /// Checks that `block` can extend the chain, without appending it. In O(t) time,
+    /// with t = number of inputs and outputs of the block's transactions.
+    ///
+    /// # Parameters
+    /// - `block`: The block to check
+    /// - `now`: The local clock, in seconds since the Unix epoch
+    ///
+    /// # Returns
+    /// `ChainError::WrongParent` if the block doesn't build on the tip,
+    /// `ChainError::TimestampTooOld` or `ChainError::TimestampTooNew` if its timestamp
+    /// is out of bounds, `ChainError::WrongTarget` if it doesn't have the required
+    /// target, the error of `BlockProducer::verify_block` if its seal is invalid, such
+    /// as `ChainError::HashAboveTarget` if its hash doesn't meet its target, the error
+    /// of `BlockProducer::verify_parent` if it doesn't follow the tip as the producer
+    /// requires,
+    /// `ChainError::WrongMerkleRoot` if its header doesn't commit to its transactions,
+    /// or `ChainError::InvalidTransaction` if one of them can't be applied.
+    pub fn validate(&self, block: &Block, now: u32) -> Result<(), ChainError> {
+        self.check(block, now).map(|_| ())
+    }
+
+    /// Appends `block` to the chain and applies its transactions if `validate` accepts
+    /// it. Nothing changes otherwise.
+    ///
+    /// # Parameters
+    /// - `block`: The block to append
+    /// - `now`: The local clock, in seconds since the Unix epoch
+    pub fn append(&mut self, block: Block, now: u32) -> Result<(), ChainError> {
+        let changes = self.check(&block, now)?;
+        self.utxos.apply(&changes);
+        self.undo.push(changes);
+        self.blocks.push(block);
+        Ok(())
+    }
+
+    /// Removes the tip and undoes its transactions, so that its parent is the tip again.
+    ///
+    /// # Returns
+    /// The removed block, or `None` if the tip is the genesis block, which can't be
+    /// removed.
+    pub fn pop(&mut self) -> Option<Block> {
+        self.pop_with_changes().map(|(block, _)| block)
+    }
+
+    /// Removes the tip as `pop` does, and returns it with the changes it made to the
+    /// unspent outputs, for `restore` to append it again.
+    pub(super) fn pop_with_changes(&mut self) -> Option<(Block, UtxoChanges)> {
+        let changes = self.undo.pop()?;
+        self.utxos.revert(&changes);
+        let block = self.blocks.pop().expect("every undo has its block");
+        Some((block, changes))
+    }
+
+    /// Appends `block`, which `pop_with_changes` removed from the tip along with
+    /// `changes`, without checking it again: it was valid there, while rules such as
+    /// the bounds of its timestamp may no longer hold at a later time.
+    pub(super) fn restore(&mut self, block: Block, changes: UtxoChanges) {
+        debug_assert_eq!(block.header.prev_block, self.tip().hash());
+        self.utxos.apply(&changes);
+        self.undo.push(changes);
+        self.blocks.push(block);
+    }
+
+    /// Runs the checks of `validate`, and returns the changes the block makes to the
+    /// unspent outputs.
+    fn check(&self, block: &Block, now: u32) -> Result<UtxoChanges, ChainError> {
+        let header = &block.header;
+
+        let tip = self.tip().hash();
+        if header.prev_block != tip {
+            return Err(ChainError::WrongParent {
+                expected: tip,
+                found: header.prev_block,
+            });
+        }
+
+        let median = self.median_time_past();
+        if header.timestamp <= median {
+            return Err(ChainError::TimestampTooOld {
+                timestamp: header.timestamp,
+                median,
+            });
+        }
+        let limit = now.saturating_add(MAX_FUTURE_DRIFT);
+        if header.timestamp > limit {
+            return Err(ChainError::TimestampTooNew {
+                timestamp: header.timestamp,
+                limit,
+            });
+        }
+
+        let bits = self.next_bits();
+        if header.bits != bits {
+            return Err(ChainError::WrongTarget {
+                expected: bits,
+                found: header.bits,
+            });
+        }
+        self.producer.verify_block(block)?;
+        self.producer.verify_parent(block, self.tip())?;
+        check_merkle_root(block)?;
+
+        let height = self.blocks.len();
+        self.utxos
+            .validate_block(&block.transactions, self.params.subsidy(height))
+    }
+}
+
+/// Runs the checks that don't depend on the rest of the chain: the target of `block` is
+/// valid and no easier than the limit of `params`, `producer` accepts its seal, and its
+/// header commits to its transactions.
+pub(super) fn check_block<P: BlockProducer>(
+    block: &Block,
+    params: &ChainParams,
+    producer: &P,
+) -> Result<(), ChainError> {
+    let limit = Target::from_compact(params.pow_limit);
+    if Target::from_compact(block.header.bits).is_none_or(|target| Some(target) > limit) {
+        return Err(ChainError::WrongTarget {
+            expected: params.pow_limit,
+            found: block.header.bits,
+        });
+    }
+    producer.verify_block(block)?;
+    check_merkle_root(block)
+}
+
+fn check_merkle_root(block: &Block) -> Result<(), ChainError> {
+    let expected = block.compute_merkle_root();
+    if block.header.merkle_root != expected {
+        return Err(ChainError::WrongMerkleRoot {
+            expected,
+            found: block.header.merkle_root,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proof_of_work::proof_of_work;
+    use crate::blockchain::transaction::{OutPoint, Transaction, TxOutput};
+    use crate::blockchain::transaction_error::TransactionError;
+    use ed25519_dalek::SigningKey;
+
+    const BITS: u32 = 0x207fffff;
+    const START: u32 = 1_700_000_000;
+
+    fn mined(mut block: Block) -> Block {
+        assert!(proof_of_work(&mut block.header));
+        block
+    }
+
+    fn genesis(bits: u32) -> Block {
+        mined(Block::new(
+            [0; 32],
+            START,
+            bits,
+            vec![Transaction::coinbase(0, vec![])],
+        ))
+    }
+
+    fn chain() -> Chain {
+        Chain::new(genesis(BITS), ChainParams::regtest()).unwrap()
+    }
+
+    fn block_with(chain: &Chain, timestamp: u32, transactions: Vec<Transaction>) -> Block {
+        mined(Block::new(
+            chain.tip().hash(),
+            timestamp,
+            chain.next_bits(),
+            transactions,
+        ))
+    }
+
+    fn next(chain: &Chain, timestamp: u32) -> Block {
+        let coinbase = Transaction::coinbase(chain.height() + 1, vec![]);
+        block_with(chain, timestamp, vec![coinbase])
+    }
+
+    /// Test that blocks building on the tip are appended in order
+    #[test]
+    fn appends_valid_blocks() {
+        let mut chain = chain();
+        for i in 1..=20 {
+            let block = next(&chain, START + i * 600);
+            chain.append(block, START + i * 600).unwrap();
+        }
+
+        assert_eq!(chain.height(), 20);
+        for height in 1..=20 {
+            assert_eq!(
+                chain.block(height).unwrap().header.prev_block,
+                chain.block(height - 1).unwrap().hash()
+            );
+        }
+        assert_eq!(chain.median_time_past(), START + 15 * 600);
+    }
+
+    /// Test that a block that doesn't build on the tip is rejected
+    #[test]
+    fn rejects_wrong_parent() {
+        let mut chain = chain();
+        let first = next(&chain, START + 600);
+        chain.append(first.clone(), START + 600).unwrap();
+
+        let sibling = mined(Block::new(
+            first.header.prev_block,
+            START + 700,
+            BITS,
+            vec![],
+        ));
+        assert_eq!(
+            chain.append(sibling, START + 700),
+            Err(ChainError::WrongParent {
+                expected: first.hash(),
+                found: chain.genesis().hash()
+            })
+        );
+        assert_eq!(chain.height(), 1);
+    }
+
+    /// Test that timestamps must be after the median time past and not too far ahead
+    #[test]
+    fn rejects_out_of_bounds_timestamps() {
+        let mut chain = chain();
+        for i in 1..=4 {
+            chain
+                .append(next(&chain, START + i * 600), START + i * 600)
+                .unwrap();
+        }
+        // The median of 5 blocks is the third one
+        let median = START + 2 * 600;
+        assert_eq!(chain.median_time_past(), median);
+
+        assert_eq!(
+            chain.validate(&next(&chain, median), median),
+            Err(ChainError::TimestampTooOld {
+                timestamp: median,
+                median
+            })
+        );
+        // Earlier than the tip, but still after the median
+        assert!(chain.validate(&next(&chain, median + 1), median).is_ok());
+
+        let now = START + 3_000;
+        let limit = now + MAX_FUTURE_DRIFT;
+        assert!(chain.validate(&next(&chain, limit), now).is_ok());
+        assert_eq!(
+            chain.validate(&next(&chain, limit + 1), now),
+            Err(ChainError::TimestampTooNew {
+                timestamp: limit + 1,
+                limit
+            })
+        );
+    }
+
+    /// Test that a block with another target or a wrong Merkle root is rejected
+    #[test]
+    fn rejects_wrong_target_and_merkle_root() {
+        let chain = chain();
+        let now = START + 600;
+
+        let mut block = next(&chain, now);
+        block.header.bits = 0x2000ffff;
+        assert!(proof_of_work(&mut block.header));
+        assert_eq!(
+            chain.validate(&block, now),
+            Err(ChainError::WrongTarget {
+                expected: BITS,
+                found: 0x2000ffff
+            })
+        );
+
+        let mut block = next(&chain, now);
+        block.transactions.push(Transaction::coinbase(99, vec![]));
+        assert!(matches!(
+            chain.validate(&block, now),
+            Err(ChainError::WrongMerkleRoot { .. })
+        ));
+
+        let mut wrong_root = genesis(BITS);
+        wrong_root.header.merkle_root = [1; 32];
+        let wrong_root = mined(wrong_root);
+        assert!(Chain::new(wrong_root, ChainParams::regtest()).is_err());
+
+        assert!(Chain::new(genesis(0x2100ffff), ChainParams::regtest()).is_err());
+    }
+
+    /// Test that a block whose hash doesn't meet its target is rejected
+    #[test]
+    fn rejects_unmined_block() {
+        let chain = chain();
+        let target = Target::from_compact(BITS).unwrap();
+
+        let mut block = next(&chain, START + 600);
+        while target.is_met_by(&block.hash()) {
+            block.header.nonce += 1;
+        }
+        assert_eq!(
+            chain.validate(&block, START + 600),
+            Err(ChainError::HashAboveTarget(block.hash()))
+        );
+    }
+
+    /// Test that the target follows the pace of the blocks every retarget interval
+    #[test]
+    fn retargets_every_interval() {
+        let params = ChainParams {
+            pow_limit: BITS,
+            retarget_interval: 10,
+            ..ChainParams::regtest()
+        };
+        let mut chain = Chain::new(genesis(0x2000ffff), params).unwrap();
+        let mut time = START;
+
+        // Blocks twice as fast as they should be: the target halves
+        for _ in 1..10 {
+            assert_eq!(chain.next_bits(), 0x2000ffff);
+            time += 300;
+            chain.append(next(&chain, time), time).unwrap();
+        }
+        let harder = chain.next_bits();
+        assert_eq!(harder, retarget(0x2000ffff, 9 * 300, &params));
+        let ratio = Target::from_compact(harder).unwrap().work() as f64
+            / Target::from_compact(0x2000ffff).unwrap().work() as f64;
+        assert!((ratio - 6000.0 / 2700.0).abs() < 0.01, "{}", ratio);
+
+        let mut stale = Block::new(chain.tip().hash(), time + 300, 0x2000ffff, vec![]);
+        assert!(proof_of_work(&mut stale.header));
+        assert!(matches!(
+            chain.validate(&stale, time + 300),
+            Err(ChainError::WrongTarget { .. })
+        ));
+
+        // Blocks far too slow: the target grows back, at most 4 times per interval
+        for _ in 0..10 {
+            time += 60 * 60;
+            chain.append(next(&chain, time), time).unwrap();
+        }
+        assert_eq!(chain.height(), 19);
+        assert_eq!(chain.next_bits(), retarget(harder, 9 * 3600, &params));
+        for _ in 0..10 {
+            time += 24 * 60 * 60;
+            chain.append(next(&chain, time), time).unwrap();
+        }
+        let slowest = retarget(chain.block(20).unwrap().header.bits, 4 * 6000, &params);
+        assert_eq!(chain.next_bits(), slowest);
+        assert_eq!(retarget(BITS, u32::MAX, &params), BITS);
+    }
+
+    /// Test that appending a block applies its payments, an invalid one changes nothing,
+    /// and popping blocks undoes them
+    #[test]
+    fn applies_transactions() {
+        let (alice, bob) = (
+            SigningKey::from_bytes(&[1; 32]),
+            SigningKey::from_bytes(&[2; 32]),
+        );
+        let reward = ChainParams::regtest().subsidy(1);
+        let mut chain = chain();
+
+        let coinbase =
+            Transaction::coinbase(1, vec![TxOutput::new(reward, &alice.verifying_key())]);
+        let funds = OutPoint::new(coinbase.id(), 0);
+        chain
+            .append(block_with(&chain, START + 600, vec![coinbase]), START + 600)
+            .unwrap();
+        assert_eq!(
+            chain.utxos().balance(&alice.verifying_key().to_bytes()),
+            reward
+        );
+
+        let mut payment = Transaction::new(
+            vec![funds],
+            vec![TxOutput::new(reward - 10, &bob.verifying_key())],
+        );
+        payment.sign(&alice);
+        let mut replay = Transaction::new(
+            vec![funds],
+            vec![TxOutput::new(reward, &alice.verifying_key())],
+        );
+        replay.sign(&alice);
+
+        let coinbase =
+            Transaction::coinbase(2, vec![TxOutput::new(reward + 10, &bob.verifying_key())]);
+        let double_spend = block_with(
+            &chain,
+            START + 1200,
+            vec![coinbase.clone(), payment.clone(), replay],
+        );
+        assert_eq!(
+            chain.append(double_spend, START + 1200),
+            Err(ChainError::InvalidTransaction {
+                index: 2,
+                error: TransactionError::DoubleSpend(funds)
+            })
+        );
+        assert_eq!(chain.height(), 1);
+        assert_eq!(
+            chain.utxos().balance(&alice.verifying_key().to_bytes()),
+            reward
+        );
+
+        chain
+            .append(
+                block_with(&chain, START + 1200, vec![coinbase, payment]),
+                START + 1200,
+            )
+            .unwrap();
+        assert_eq!(chain.utxos().balance(&alice.verifying_key().to_bytes()), 0);
+        assert_eq!(
+            chain.utxos().balance(&bob.verifying_key().to_bytes()),
+            2 * reward
+        );
+
+        let before_payment = chain.block(1).unwrap().clone();
+        assert!(chain.pop().is_some());
+        assert_eq!(chain.tip(), &before_payment);
+        assert_eq!(
+            chain.utxos().balance(&alice.verifying_key().to_bytes()),
+            reward
+        );
+        assert_eq!(chain.utxos().balance(&bob.verifying_key().to_bytes()), 0);
+        assert!(chain.pop().is_some());
+        assert!(chain.utxos().is_empty());
+        assert_eq!(chain.pop(), None);
+        assert_eq!(chain.height(), 0);
+    }
+}
@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::fmt;
+
+use super::transaction::PublicKey;
+use super::transaction_error::TransactionError;
+use super::Hash;
+use crate::merkle::merkle_hash::to_hex;
+
+/// Reasons a block is rejected by the chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainError {
+    /// A serialized header doesn't have the 80 bytes of a header.
+    MalformedHeader(usize),
+    /// A serialized block or transaction is truncated or has trailing bytes.
+    MalformedBlock(String),
+    /// The block doesn't build on the tip of the chain, whose hash is `expected`.
+    WrongParent { expected: Hash, found: Hash },
+    /// The Merkle root of the header doesn't commit to the transactions of the block.
+    WrongMerkleRoot { expected: Hash, found: Hash },
+    /// The timestamp isn't later than `median`, the median of the previous blocks'
+    /// timestamps.
+    TimestampTooOld { timestamp: u32, median: u32 },
+    /// The timestamp is later than `limit`, too far in the future of the local clock.
+    TimestampTooNew { timestamp: u32, limit: u32 },
+    /// The header doesn't have the target the chain requires at its height.
+    WrongTarget { expected: u32, found: u32 },
+    /// The hash of the header, given here, doesn't meet its target.
+    HashAboveTarget(Hash),
+    /// The transaction at `index` in the block can't be applied.
+    InvalidTransaction {
+        index: usize,
+        error: TransactionError,
+    },
+    /// The block, whose hash is given, is already known.
+    DuplicateBlock(Hash),
+    /// The block builds on the given block, which was found invalid.
+    InvalidAncestor(Hash),
+    /// The seal of a proof-of-stake block claims another slot than its timestamp's.
+    WrongSlot { expected: u64, found: u64 },
+    /// The block isn't in a later slot than its parent.
+    StaleSlot { slot: u64, parent_slot: u64 },
+    /// The block was produced by another validator than the leader of its slot.
+    WrongProducer {
+        slot: u64,
+        expected: PublicKey,
+        found: PublicKey,
+    },
+    /// The signature of the producer of the block, or of an attestation, is invalid.
+    InvalidSignature,
+    /// An attestation is signed by a key that isn't a validator.
+    UnknownValidator(PublicKey),
+    /// The validator already attested another block at the same height.
+    ConflictingAttestation(PublicKey),
+    /// The block, whose hash is given, isn't stored.
+    UnknownBlock(Hash),
+    /// An attestation claims its block is at `found`, while it is at `expected`.
+    WrongHeight { expected: u64, found: u64 },
+    /// The block, whose hash is given, neither descends from the final block nor is one
+    /// of its ancestors.
+    ConflictsWithFinal(Hash),
+    /// The block, whose hash is given, isn't on the active chain.
+    InactiveBlock(Hash),
+    /// The rules of the chain can't be followed, for the given reason.
+    InvalidParams(String),
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::MalformedHeader(len) => {
+                write!(f, "a header has 80 bytes, not {}", len)
+            }
+            ChainError::MalformedBlock(reason) => write!(f, "malformed block: {}", reason),
+            ChainError::WrongParent { expected, found } => write!(
+                f,
+                "block builds on {} instead of the tip {}",
+                to_hex(found),
+                to_hex(expected)
+            ),
+            ChainError::WrongMerkleRoot { expected, found } => write!(
+                f,
+                "Merkle root {} doesn't match the transactions, expected {}",
+                to_hex(found),
+                to_hex(expected)
+            ),
+            ChainError::TimestampTooOld { timestamp, median } => write!(
+                f,
+                "timestamp {} isn't later than the median time past {}",
+                timestamp, median
+            ),
+            ChainError::TimestampTooNew { timestamp, limit } => write!(
+                f,
+                "timestamp {} is later than the limit {}",
+                timestamp, limit
+            ),
+            ChainError::WrongTarget { expected, found } => write!(
+                f,
+                "target {:#010x} instead of the required {:#010x}",
+                found, expected
+            ),
+            ChainError::HashAboveTarget(hash) => {
+                write!(f, "hash {} doesn't meet the target", to_hex(hash))
+            }
+            ChainError::InvalidTransaction { index, error } => {
+                write!(f, "transaction {}: {}", index, error)
+            }
+            ChainError::DuplicateBlock(hash) => {
+                write!(f, "block {} is already known", to_hex(hash))
+            }
+            ChainError::InvalidAncestor(hash) => {
+                write!(f, "block builds on the invalid block {}", to_hex(hash))
+            }
+            ChainError::WrongSlot { expected, found } => write!(
+                f,
+                "seal claims slot {} but the timestamp is in slot {}",
+                found, expected
+            ),
+            ChainError::StaleSlot { slot, parent_slot } => write!(
+                f,
+                "block is in slot {} but its parent is already in slot {}",
+                slot, parent_slot
+            ),
+            ChainError::WrongProducer {
+                slot,
+                expected,
+                found,
+            } => write!(
+                f,
+                "slot {} belongs to {}, not {}",
+                slot,
+                to_hex(expected),
+                to_hex(found)
+            ),
+            ChainError::InvalidSignature => write!(f, "invalid signature"),
+            ChainError::UnknownValidator(key) => write!(f, "{} isn't a validator", to_hex(key)),
+            ChainError::ConflictingAttestation(key) => write!(
+                f,
+                "{} already attested another block at this height",
+                to_hex(key)
+            ),
+            ChainError::UnknownBlock(hash) => {
+                write!(f, "block {} isn't stored", to_hex(hash))
+            }
+            ChainError::WrongHeight { expected, found } => write!(
+                f,
+                "attestation claims height {} but the block is at height {}",
+                found, expected
+            ),
+            ChainError::ConflictsWithFinal(hash) => {
+                write!(f, "block {} conflicts with the final block", to_hex(hash))
+            }
+            ChainError::InactiveBlock(hash) => {
+                write!(f, "block {} isn't on the active chain", to_hex(hash))
+            }
+            ChainError::InvalidParams(reason) => write!(f, "invalid chain params: {}", reason),
+        }
+    }
+}
+
+impl Error for ChainError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ChainError::InvalidTransaction { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,793 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use ed25519_dalek::VerifyingKey;
+
+use super::block::Block;
+use super::block_producer::BlockProducer;
+use super::block_tree::{BlockTree, Reorg};
+use super::chain::Chain;
+use super::mempool_error::MempoolError;
+use super::transaction::{OutPoint, Transaction, TxOutput};
+use super::transaction_error::TransactionError;
+use super::utxo_set::{check_spend, UtxoSet};
+use super::Hash;
+
+/// Default limit of the serialized size of the transactions of a mempool, in bytes.
+pub const DEFAULT_MAX_SIZE: usize = 5_000_000;
+
+/// A transaction waiting in the mempool.
+#[derive(Debug, Clone)]
+struct Entry {
+    transaction: Transaction,
+    fee: u64,
+    /// Size of the serialized transaction, in bytes.
+    size: usize,
+    /// Order of arrival: a transaction always arrives after those whose outputs it
+    /// spends, so sorting by it sorts parents first.
+    sequence: u64,
+    /// The transactions of the mempool whose outputs this one spends.
+    parents: HashSet<Hash>,
+    /// The transactions of the mempool spending outputs of this one.
+    children: HashSet<Hash>,
+    /// The total fee and size of this transaction and its ancestors in the mempool,
+    /// which only change when it is removed, as its ancestors are removed with it.
+    ancestors: Package,
+}
+
+/// The total fee and size of a set of transactions, compared by fee rate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Package {
+    fee: u64,
+    size: usize,
+}
+
+impl Package {
+    /// Counts `entry` in the package, saturating instead of overflowing.
+    fn add(&mut self, entry: &Entry) {
+        self.fee = self.fee.saturating_add(entry.fee);
+        self.size = self.size.saturating_add(entry.size);
+    }
+
+    /// Stops counting `entry`, which the package counts, in the package.
+    fn remove(&mut self, entry: &Entry) {
+        self.fee = self.fee.saturating_sub(entry.fee);
+        self.size = self.size.saturating_sub(entry.size);
+    }
+
+    /// Returns `true` if the package pays a higher fee per byte than `other`.
+    fn pays_more_than(&self, other: &Package) -> bool {
+        self.fee as u128 * other.size as u128 > other.fee as u128 * self.size as u128
+    }
+}
+
+/// A transaction waiting to be selected for a block template, ordered by the fee rate
+/// of its package, then first arrived first.
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    txid: Hash,
+    sequence: u64,
+    /// The transaction and its ancestors not in the template yet.
+    package: Package,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let rate = self.package.fee as u128 * other.package.size as u128;
+        let other_rate = other.package.fee as u128 * self.package.size as u128;
+        rate.cmp(&other_rate)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+/// Transactions selected for a block, without its coinbase.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockTemplate {
+    /// The transactions, each after those whose outputs it spends.
+    pub transactions: Vec<Transaction>,
+    /// The sum of their fees, which the coinbase may claim on top of the subsidy.
+    pub fees: u64,
+    /// The sum of their serialized sizes, in bytes.
+    pub size: usize,
+}
+
+impl BlockTemplate {
+    /// Returns the block of the transactions building on the tip of `chain`, with a
+    /// coinbase paying the subsidy and the fees to `miner`. The block still has to be
+    /// sealed.
+    pub fn into_block<P: BlockProducer>(
+        self,
+        chain: &Chain<P>,
+        miner: &VerifyingKey,
+        timestamp: u32,
+    ) -> Block {
+        let height = chain.height() + 1;
+        let reward = chain.params().subsidy(height) + self.fees;
+        let coinbase = Transaction::coinbase(height, vec![TxOutput::new(reward, miner)]);
+        let mut transactions = vec![coinbase];
+        transactions.extend(self.transactions);
+        Block::new(
+            chain.tip().hash(),
+            timestamp,
+            chain.next_bits(),
+            transactions,
+        )
+    }
+}
+
+/// The transactions waiting to be included in a block, which spend outputs of the
+/// chain or of each other.
+///
+/// A transaction is accepted if it is valid on top of the unspent outputs of the chain
+/// and the outputs of the transactions already waiting. One spending the same output as
+/// waiting transactions replaces them and their descendants only if it pays more fees
+/// in total, at a higher fee rate. Once the mempool exceeds its size limit, the
+/// transactions whose descendants pay the lowest fee rate are evicted with them.
+///
+/// Blocks are filled by fee rate, counting a transaction together with its ancestors
+/// that aren't in the block yet, so a child paying a high fee pulls in a parent paying
+/// a low one.
+///
+/// # Methods
+/// - `insert`: Validates a transaction and adds it.
+/// - `block_template`: Selects the transactions paying the most for a block.
+/// - `update`: Follows a change of the active chain of a `BlockTree`.
+#[derive(Debug, Clone)]
+pub struct Mempool {
+    entries: HashMap<Hash, Entry>,
+    /// The transaction of the mempool spending each out point.
+    spenders: HashMap<OutPoint, Hash>,
+    max_size: usize,
+    size: usize,
+    next_sequence: u64,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new(DEFAULT_MAX_SIZE)
+    }
+}
+
+impl Mempool {
+    /// Creates an empty mempool holding at most `max_size` bytes of transactions.
+    pub fn new(max_size: usize) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            spenders: HashMap::new(),
+            max_size,
+            size: 0,
+            next_sequence: 0,
+        }
+    }
+
+    /// Returns the number of transactions.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if there are no transactions.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the serialized size of the transactions, in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns `true` if the transaction with id `txid` is waiting.
+    pub fn contains(&self, txid: &Hash) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    /// Returns the waiting transaction with id `txid`.
+    pub fn get(&self, txid: &Hash) -> Option<&Transaction> {
+        self.entries.get(txid).map(|entry| &entry.transaction)
+    }
+
+    /// Returns the fee of the waiting transaction with id `txid`.
+    pub fn fee(&self, txid: &Hash) -> Option<u64> {
+        self.entries.get(txid).map(|entry| entry.fee)
+    }
+
+    /// Validates `transaction` on top of `utxos` and the waiting transactions, and adds
+    /// it, replacing the transactions it conflicts with and evicting the lowest paying
+    /// ones if the mempool is full. Nothing changes if it is rejected. In O(n·d) time,
+    /// with n = number of waiting transactions and d = number of their descendants, when
+    /// some must be evicted, and O(i + c) otherwise, with i = number of inputs and
+    /// c = number of replaced transactions.
+    ///
+    /// # Parameters
+    /// - `transaction`: The transaction to add
+    /// - `utxos`: The unspent outputs after the tip of the chain
+    ///
+    /// # Returns
+    /// The ids of the transactions it replaced or evicted, `MempoolError::AlreadyKnown`
+    /// if it is already waiting, `MempoolError::InvalidTransaction` if it is invalid,
+    /// `MempoolError::Conflict` if it doesn't pay enough to replace the transactions it
+    /// conflicts with, or `MempoolError::FeeTooLow` if it pays too little to fit.
+    pub fn insert(
+        &mut self,
+        transaction: Transaction,
+        utxos: &UtxoSet,
+    ) -> Result<Vec<Hash>, MempoolError> {
+        let txid = transaction.id();
+        if self.entries.contains_key(&txid) {
+            return Err(MempoolError::AlreadyKnown(txid));
+        }
+
+        let conflicts = transaction
+            .inputs
+            .iter()
+            .filter_map(|input| self.spenders.get(&input.previous).copied());
+        let mut removed = self.descendants(conflicts);
+
+        let fee = check_spend(&transaction, |out_point| {
+            let missing = TransactionError::MissingOutput(*out_point);
+            match self.entries.get(&out_point.txid) {
+                // Replacing a transaction takes its outputs away
+                Some(_) if removed.contains(&out_point.txid) => Err(missing),
+                Some(parent) => parent
+                    .transaction
+                    .outputs
+                    .get(out_point.index as usize)
+                    .copied()
+                    .ok_or(missing),
+                None => utxos.get(out_point).copied().ok_or(missing),
+            }
+        })?;
+        let entry = Package {
+            fee,
+            size: transaction.to_bytes().len(),
+        };
+
+        let replaced = self.package(&removed);
+        if !removed.is_empty() && (fee <= replaced.fee || !entry.pays_more_than(&replaced)) {
+            return Err(MempoolError::Conflict {
+                fee,
+                replaced_fee: replaced.fee,
+            });
+        }
+
+        // Evicts before adding, so that a transaction that doesn't fit changes nothing
+        let parents: HashSet<Hash> = transaction
+            .inputs
+            .iter()
+            .map(|input| input.previous.txid)
+            .filter(|txid| self.entries.contains_key(txid))
+            .collect();
+        let mut size = self.size - replaced.size + entry.size;
+        while size > self.max_size {
+            let Some(lowest) = self.lowest_package(&removed) else {
+                return Err(MempoolError::FeeTooLow);
+            };
+            let package = self.package(&lowest);
+            if !entry.pays_more_than(&package) || lowest.iter().any(|txid| parents.contains(txid)) {
+                return Err(MempoolError::FeeTooLow);
+            }
+            size -= package.size;
+            removed.extend(lowest);
+        }
+
+        let mut removed: Vec<Hash> = removed.into_iter().collect();
+        removed.sort_unstable_by_key(|txid| self.entries[txid].sequence);
+        self.remove(&removed);
+        self.add(txid, transaction, entry, parents);
+        Ok(removed)
+    }
+
+    /// Selects the transactions paying the highest fee rate, up to `max_size` bytes.
+    ///
+    /// Each round adds the transaction whose ancestors not in the template yet pay,
+    /// together with it, the highest fee rate and still fit, along with those ancestors.
+    /// The transactions wait in a heap by the fee rate of these packages, which shrink
+    /// as ancestors are added. One that doesn't fit is dropped until its package
+    /// shrinks, as the template only grows. In O(n·(d + log n)) time, with n = number
+    /// of waiting transactions and d = number of their descendants.
+    pub fn block_template(&self, max_size: usize) -> BlockTemplate {
+        let mut packages: HashMap<Hash, Package> = self
+            .entries
+            .iter()
+            .map(|(txid, entry)| (*txid, entry.ancestors))
+            .collect();
+        let mut candidates: BinaryHeap<Candidate> = packages
+            .iter()
+            .map(|(txid, package)| self.candidate(*txid, *package))
+            .collect();
+
+        let mut included = HashSet::new();
+        let mut template = BlockTemplate::default();
+        while let Some(candidate) = candidates.pop() {
+            // Skips the candidates included or pushed again since with a smaller package
+            if packages.get(&candidate.txid) != Some(&candidate.package)
+                || template.size + candidate.package.size > max_size
+            {
+                continue;
+            }
+
+            let mut ancestors: Vec<Hash> = self
+                .ancestors(&candidate.txid, &included)
+                .into_iter()
+                .collect();
+            ancestors.sort_unstable_by_key(|txid| self.entries[txid].sequence);
+            let mut modified = HashSet::new();
+            for txid in ancestors {
+                let entry = &self.entries[&txid];
+                template.transactions.push(entry.transaction.clone());
+                included.insert(txid);
+                packages.remove(&txid);
+                for descendant in self.descendants([txid]) {
+                    if let Some(package) = packages.get_mut(&descendant) {
+                        package.remove(entry);
+                        modified.insert(descendant);
+                    }
+                }
+            }
+            for txid in modified {
+                if let Some(package) = packages.get(&txid) {
+                    candidates.push(self.candidate(txid, *package));
+                }
+            }
+            template.fees = template.fees.saturating_add(candidate.package.fee);
+            template.size += candidate.package.size;
+        }
+        template
+    }
+
+    /// Follows `reorg`, a change of the active chain of `tree`: the transactions of the
+    /// disconnected blocks wait again, and the transactions that the new blocks include
+    /// or conflict with are dropped, along with their descendants.
+    ///
+    /// Every waiting transaction is validated again, in O(n·i) time, with n = number of
+    /// transactions of the mempool and of the disconnected blocks and i = number of
+    /// their inputs.
+    pub fn update<P: BlockProducer>(&mut self, tree: &BlockTree<P>, reorg: &Reorg) {
+        if reorg.is_empty() {
+            return;
+        }
+
+        // Disconnected blocks come first, as the transactions waiting may spend theirs
+        let mut transactions: Vec<Transaction> = reorg
+            .disconnected
+            .iter()
+            .rev()
+            .filter_map(|hash| tree.get(hash))
+            .flat_map(|block| block.transactions.iter().skip(1).cloned())
+            .collect();
+        let mut entries: Vec<Entry> = self.entries.drain().map(|(_, entry)| entry).collect();
+        entries.sort_unstable_by_key(|entry| entry.sequence);
+        transactions.extend(entries.into_iter().map(|entry| entry.transaction));
+        self.spenders.clear();
+        self.size = 0;
+
+        for transaction in transactions {
+            let _ = self.insert(transaction, tree.chain().utxos());
+        }
+    }
+
+    /// Returns the candidate for a block template of the transaction `txid`, with
+    /// `package` its ancestors not in the template yet.
+    fn candidate(&self, txid: Hash, package: Package) -> Candidate {
+        Candidate {
+            txid,
+            sequence: self.entries[&txid].sequence,
+            package,
+        }
+    }
+
+    /// Adds the transaction `txid`, which is valid and fits.
+    fn add(
+        &mut self,
+        txid: Hash,
+        transaction: Transaction,
+        package: Package,
+        parents: HashSet<Hash>,
+    ) {
+        for input in transaction.inputs.iter() {
+            self.spenders.insert(input.previous, txid);
+        }
+        for parent in parents.iter() {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.insert(txid);
+            }
+        }
+        let entry = Entry {
+            transaction,
+            fee: package.fee,
+            size: package.size,
+            sequence: self.next_sequence,
+            parents,
+            children: HashSet::new(),
+            ancestors: package,
+        };
+        self.next_sequence += 1;
+        self.size += entry.size;
+        self.entries.insert(txid, entry);
+        let ancestors = self.package(&self.ancestors(&txid, &HashSet::new()));
+        if let Some(entry) = self.entries.get_mut(&txid) {
+            entry.ancestors = ancestors;
+        }
+    }
+
+    /// Removes the transactions `txids`, which include all their descendants.
+    fn remove(&mut self, txids: &[Hash]) {
+        for txid in txids.iter() {
+            let Some(entry) = self.entries.remove(txid) else {
+                continue;
+            };
+            self.size -= entry.size;
+            for input in entry.transaction.inputs.iter() {
+                self.spenders.remove(&input.previous);
+            }
+            for parent in entry.parents.iter() {
+                if let Some(parent) = self.entries.get_mut(parent) {
+                    parent.children.remove(txid);
+                }
+            }
+        }
+    }
+
+    /// Returns `roots` and every waiting transaction spending their outputs, directly or
+    /// not.
+    fn descendants(&self, roots: impl IntoIterator<Item = Hash>) -> HashSet<Hash> {
+        let mut descendants = HashSet::new();
+        let mut pending: Vec<Hash> = roots.into_iter().collect();
+        while let Some(txid) = pending.pop() {
+            if descendants.insert(txid) {
+                pending.extend(self.entries[&txid].children.iter().copied());
+            }
+        }
+        descendants
+    }
+
+    /// Returns `txid` and the transactions whose outputs it spends, directly or not,
+    /// except those of `excluded`.
+    fn ancestors(&self, txid: &Hash, excluded: &HashSet<Hash>) -> HashSet<Hash> {
+        let mut ancestors = HashSet::new();
+        let mut pending = vec![*txid];
+        while let Some(txid) = pending.pop() {
+            if !excluded.contains(&txid) && ancestors.insert(txid) {
+                pending.extend(self.entries[&txid].parents.iter().copied());
+            }
+        }
+        ancestors
+    }
+
+    /// Returns the total fee and size of the transactions `txids`.
+    fn package<'a>(&self, txids: impl IntoIterator<Item = &'a Hash>) -> Package {
+        let mut package = Package::default();
+        for txid in txids {
+            package.add(&self.entries[txid]);
+        }
+        package
+    }
+
+    /// Returns the transaction whose descendants pay, together with it, the lowest fee
+    /// rate, and those descendants, ignoring the transactions of `excluded`.
+    fn lowest_package(&self, excluded: &HashSet<Hash>) -> Option<HashSet<Hash>> {
+        self.entries
+            .keys()
+            .filter(|txid| !excluded.contains(*txid))
+            .map(|txid| {
+                let mut descendants = self.descendants([*txid]);
+                descendants.retain(|txid| !excluded.contains(txid));
+                let package = self.package(&descendants);
+                (descendants, package)
+            })
+            .reduce(|lowest, candidate| {
+                if lowest.1.pays_more_than(&candidate.1) {
+                    candidate
+                } else {
+                    lowest
+                }
+            })
+            .map(|(descendants, _)| descendants)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block_tree::Insertion;
+    use crate::blockchain::proof_of_work::{proof_of_work, ChainParams};
+    use ed25519_dalek::SigningKey;
+
+    const START: u32 = 1_700_000_000;
+    const NOW: u32 = START + 100_000;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    /// Returns a payment by `from` spending `previous` and paying `values` to `to`.
+    fn pay(
+        from: &SigningKey,
+        previous: Vec<OutPoint>,
+        to: &SigningKey,
+        values: &[u64],
+    ) -> Transaction {
+        let outputs = values
+            .iter()
+            .map(|value| TxOutput::new(*value, &to.verifying_key()))
+            .collect();
+        let mut transaction = Transaction::new(previous, outputs);
+        transaction.sign(from);
+        transaction
+    }
+
+    /// Returns a set where alice owns ten outputs of 1000.
+    fn funded() -> (UtxoSet, Vec<OutPoint>) {
+        let mut utxos = UtxoSet::new();
+        let coinbase =
+            Transaction::coinbase(0, vec![TxOutput::new(1000, &key(1).verifying_key()); 10]);
+        utxos
+            .apply_block(std::slice::from_ref(&coinbase), 10_000)
+            .unwrap();
+        let funds = (0..10)
+            .map(|index| OutPoint::new(coinbase.id(), index))
+            .collect();
+        (utxos, funds)
+    }
+
+    fn ids(transactions: &[Transaction]) -> Vec<Hash> {
+        transactions.iter().map(Transaction::id).collect()
+    }
+
+    /// Test that transactions may spend outputs of waiting ones, but only valid ones are accepted
+    #[test]
+    fn accepts_valid_chains() {
+        let (alice, bob) = (key(1), key(2));
+        let (utxos, funds) = funded();
+        let mut mempool = Mempool::default();
+
+        let parent = pay(&alice, vec![funds[0]], &bob, &[900]);
+        let child = pay(&bob, vec![OutPoint::new(parent.id(), 0)], &alice, &[850]);
+        assert_eq!(mempool.insert(parent.clone(), &utxos), Ok(vec![]));
+        assert_eq!(mempool.insert(child.clone(), &utxos), Ok(vec![]));
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool.fee(&child.id()), Some(50));
+        assert_eq!(
+            mempool.size(),
+            parent.to_bytes().len() + child.to_bytes().len()
+        );
+
+        assert_eq!(
+            mempool.insert(parent.clone(), &utxos),
+            Err(MempoolError::AlreadyKnown(parent.id()))
+        );
+        let theft = pay(&bob, vec![funds[1]], &bob, &[1000]);
+        assert_eq!(
+            mempool.insert(theft, &utxos),
+            Err(MempoolError::InvalidTransaction(
+                TransactionError::InvalidSignature(0)
+            ))
+        );
+        let missing = OutPoint::new(parent.id(), 1);
+        assert_eq!(
+            mempool.insert(pay(&bob, vec![missing], &bob, &[1]), &utxos),
+            Err(MempoolError::InvalidTransaction(
+                TransactionError::MissingOutput(missing)
+            ))
+        );
+        assert_eq!(
+            mempool.insert(Transaction::coinbase(1, vec![]), &utxos),
+            Err(MempoolError::InvalidTransaction(
+                TransactionError::MisplacedCoinbase
+            ))
+        );
+        assert_eq!(mempool.len(), 2);
+    }
+
+    /// Test that a conflicting transaction only replaces others if it pays more
+    #[test]
+    fn replaces_conflicts_paying_more() {
+        let (alice, bob, carol) = (key(1), key(2), key(3));
+        let (utxos, funds) = funded();
+        let mut mempool = Mempool::default();
+
+        let parent = pay(&alice, vec![funds[0]], &bob, &[900]);
+        let child = pay(&bob, vec![OutPoint::new(parent.id(), 0)], &bob, &[800]);
+        mempool.insert(parent.clone(), &utxos).unwrap();
+        mempool.insert(child.clone(), &utxos).unwrap();
+
+        // The replacement must pay more than both, which pay 200 in total
+        let cheap = pay(&alice, vec![funds[0]], &carol, &[850]);
+        assert_eq!(
+            mempool.insert(cheap, &utxos),
+            Err(MempoolError::Conflict {
+                fee: 150,
+                replaced_fee: 200
+            })
+        );
+        assert_eq!(mempool.len(), 2);
+
+        let replacement = pay(&alice, vec![funds[0]], &carol, &[700]);
+        assert_eq!(
+            mempool.insert(replacement.clone(), &utxos),
+            Ok(vec![parent.id(), child.id()])
+        );
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&replacement.id()));
+        assert_eq!(mempool.size(), replacement.to_bytes().len());
+
+        // The outputs of the replaced transaction are gone
+        assert!(mempool.insert(child, &utxos).is_err());
+    }
+
+    /// Test that a full mempool evicts the lowest fee rates, with their descendants
+    #[test]
+    fn evicts_lowest_fee_rates() {
+        let (alice, bob) = (key(1), key(2));
+        let (utxos, funds) = funded();
+        let size = pay(&alice, vec![funds[0]], &bob, &[1]).to_bytes().len();
+        let mut mempool = Mempool::new(3 * size);
+
+        let low = pay(&alice, vec![funds[0]], &bob, &[990]);
+        let low_child = pay(&bob, vec![OutPoint::new(low.id(), 0)], &bob, &[970]);
+        let high = pay(&alice, vec![funds[1]], &bob, &[900]);
+        for transaction in [&low, &low_child, &high] {
+            mempool.insert(transaction.clone(), &utxos).unwrap();
+        }
+
+        // Paying less than any package changes nothing
+        let lowest = pay(&alice, vec![funds[2]], &bob, &[995]);
+        assert_eq!(mempool.insert(lowest, &utxos), Err(MempoolError::FeeTooLow));
+        assert_eq!(mempool.len(), 3);
+
+        let medium = pay(&alice, vec![funds[3]], &bob, &[950]);
+        assert_eq!(
+            mempool.insert(medium.clone(), &utxos),
+            Ok(vec![low.id(), low_child.id()])
+        );
+        let mut waiting: Vec<Hash> = mempool.entries.keys().copied().collect();
+        waiting.sort_unstable();
+        let mut expected = ids(&[high, medium]);
+        expected.sort_unstable();
+        assert_eq!(waiting, expected);
+    }
+
+    /// Test that templates are ordered by fee rate, counting the ancestors of a transaction
+    #[test]
+    fn template_orders_by_package_fee_rate() {
+        let (alice, bob) = (key(1), key(2));
+        let (utxos, funds) = funded();
+        let mut mempool = Mempool::default();
+
+        let medium = pay(&alice, vec![funds[0]], &bob, &[950]);
+        let poor_parent = pay(&alice, vec![funds[1]], &bob, &[999]);
+        let rich_child = pay(&bob, vec![OutPoint::new(poor_parent.id(), 0)], &bob, &[800]);
+        let low = pay(&alice, vec![funds[2]], &bob, &[990]);
+        for transaction in [&medium, &poor_parent, &rich_child, &low] {
+            mempool.insert(transaction.clone(), &utxos).unwrap();
+        }
+
+        let template = mempool.block_template(usize::MAX);
+        assert_eq!(
+            ids(&template.transactions),
+            ids(&[
+                poor_parent.clone(),
+                rich_child.clone(),
+                medium.clone(),
+                low.clone()
+            ])
+        );
+        assert_eq!(template.fees, 200 + 50 + 10);
+        assert_eq!(template.size, mempool.size());
+
+        // Without room for both, the package loses to a single transaction that fits
+        let size = medium.to_bytes().len();
+        let template = mempool.block_template(size + size / 2);
+        assert_eq!(ids(&template.transactions), ids(&[medium]));
+        assert_eq!(mempool.block_template(0), BlockTemplate::default());
+    }
+
+    /// Test that the package of a transaction shrinks once its ancestors are in the template
+    #[test]
+    fn template_updates_packages_of_descendants() {
+        let (alice, bob) = (key(1), key(2));
+        let (utxos, funds) = funded();
+        let mut mempool = Mempool::default();
+
+        let parent = pay(&alice, vec![funds[0]], &bob, &[500, 499]);
+        let rich_child = pay(&bob, vec![OutPoint::new(parent.id(), 0)], &bob, &[200]);
+        let sibling = pay(&bob, vec![OutPoint::new(parent.id(), 1)], &bob, &[349]);
+        let other = pay(&alice, vec![funds[1]], &bob, &[880]);
+        for transaction in [&parent, &rich_child, &sibling, &other] {
+            mempool.insert(transaction.clone(), &utxos).unwrap();
+        }
+
+        // With its parent paying almost nothing, the sibling pays less than the other
+        // transaction until the parent is in
+        let template = mempool.block_template(usize::MAX);
+        assert_eq!(
+            ids(&template.transactions),
+            ids(&[parent, rich_child, sibling, other])
+        );
+        assert_eq!(template.fees, 1 + 300 + 150 + 120);
+        assert_eq!(template.size, mempool.size());
+    }
+
+    /// Test that the mempool drops mined transactions and takes back those of disconnected blocks
+    #[test]
+    fn follows_reorganizations() {
+        let (alice, bob, miner) = (key(1), key(2), key(3));
+        let params = ChainParams::regtest();
+        let block_on = |parent: &Block, height: usize| {
+            let reward = TxOutput::new(params.subsidy(height), &miner.verifying_key());
+            let coinbase = Transaction::coinbase(height, vec![reward]);
+            let timestamp = START + height as u32 * 600;
+            let mut block = Block::new(parent.hash(), timestamp, params.pow_limit, vec![coinbase]);
+            assert!(proof_of_work(&mut block.header));
+            block
+        };
+
+        let funding = TxOutput::new(1000, &alice.verifying_key());
+        let mut genesis = Block::new(
+            [0; 32],
+            START,
+            params.pow_limit,
+            vec![Transaction::coinbase(0, vec![funding; 2])],
+        );
+        assert!(proof_of_work(&mut genesis.header));
+        let funds: Vec<OutPoint> = (0..2)
+            .map(|index| OutPoint::new(genesis.transactions[0].id(), index))
+            .collect();
+        let mut tree = BlockTree::new(genesis.clone(), params).unwrap();
+        let mut mempool = Mempool::default();
+
+        let first = pay(&alice, vec![funds[0]], &bob, &[900]);
+        let second = pay(&alice, vec![funds[1]], &bob, &[900]);
+        let child = pay(&bob, vec![OutPoint::new(second.id(), 0)], &bob, &[800]);
+        for transaction in [&first, &second, &child] {
+            mempool
+                .insert(transaction.clone(), tree.chain().utxos())
+                .unwrap();
+        }
+
+        // A block mines `second` and a conflict of `first`
+        let conflict = pay(&alice, vec![funds[0]], &alice, &[990]);
+        let template = BlockTemplate {
+            transactions: vec![conflict.clone(), second.clone()],
+            fees: 110,
+            size: 0,
+        };
+        let mut mined = template.into_block(tree.chain(), &miner.verifying_key(), START + 600);
+        assert!(proof_of_work(&mut mined.header));
+        let Ok(Insertion::Stored { reorg, .. }) = tree.insert(mined.clone(), NOW) else {
+            panic!("the block is valid");
+        };
+        mempool.update(&tree, &reorg);
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&child.id()));
+
+        // A longer branch without it gives its transactions back
+        let side = block_on(&genesis, 1);
+        tree.insert(side.clone(), NOW).unwrap();
+        let Ok(Insertion::Stored { reorg, .. }) = tree.insert(block_on(&side, 2), NOW) else {
+            panic!("the block is valid");
+        };
+        assert_eq!(reorg.disconnected, vec![mined.hash()]);
+        mempool.update(&tree, &reorg);
+
+        let mut waiting: Vec<Hash> = mempool.entries.keys().copied().collect();
+        waiting.sort_unstable();
+        let mut expected = ids(&[conflict, second, child]);
+        expected.sort_unstable();
+        assert_eq!(waiting, expected);
+        assert_eq!(mempool.block_template(usize::MAX).fees, 10 + 100 + 100);
+    }
+}
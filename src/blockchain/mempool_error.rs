@@ -0,0 +1,58 @@
+use std::error::Error;
+use std::fmt;
+
+use super::transaction_error::TransactionError;
+use super::Hash;
+use crate::merkle::merkle_hash::to_hex;
+
+/// Reasons a transaction isn't accepted into the mempool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    /// The transaction, whose id is given, is already in the mempool.
+    AlreadyKnown(Hash),
+    /// The transaction can't be applied on top of the chain and the mempool.
+    InvalidTransaction(TransactionError),
+    /// The transaction spends outputs that transactions of the mempool already spend,
+    /// and doesn't pay enough to replace them: more fees in total, at a higher fee
+    /// rate.
+    Conflict { fee: u64, replaced_fee: u64 },
+    /// The mempool is full of transactions paying a higher fee rate.
+    FeeTooLow,
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MempoolError::AlreadyKnown(txid) => {
+                write!(f, "transaction {} is already in the mempool", to_hex(txid))
+            }
+            MempoolError::InvalidTransaction(error) => write!(f, "invalid transaction: {}", error),
+            MempoolError::Conflict { fee, replaced_fee } => write!(
+                f,
+                "fee {} doesn't pay for replacing transactions with fees of {}",
+                fee, replaced_fee
+            ),
+            MempoolError::FeeTooLow => {
+                write!(
+                    f,
+                    "the mempool is full of transactions with higher fee rates"
+                )
+            }
+        }
+    }
+}
+
+impl Error for MempoolError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MempoolError::InvalidTransaction(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<TransactionError> for MempoolError {
+    fn from(error: TransactionError) -> Self {
+        MempoolError::InvalidTransaction(error)
+    }
+}
@@ -0,0 +1,202 @@
+use std::io::{self, Read, Write};
+
+use super::block::Block;
+use super::Hash;
+use crate::bytes::{invalid, take, take_array, take_u32};
+
+/// Largest message a peer may send, in bytes, so a length prefix can't make a node
+/// allocate without bound.
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
+const VERSION: u8 = 0;
+const INVENTORY: u8 = 1;
+const GET_DATA: u8 = 2;
+const BLOCK: u8 = 3;
+const GET_BLOCKS: u8 = 4;
+
+/// The messages nodes exchange to propagate blocks.
+///
+/// A message is framed on the wire by its length, as a little-endian `u32`, followed by
+/// a tag byte and its fields, in the same little-endian layout as blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Sent first on every connection: the hash of the genesis block the sender
+    /// follows, which must be the receiver's, and the height of its tip.
+    Version { genesis: Hash, height: u64 },
+    /// Announces blocks the sender has stored.
+    Inventory(Vec<Hash>),
+    /// Requests the blocks with these hashes.
+    GetData(Vec<Hash>),
+    /// A block that was requested.
+    Block(Block),
+    /// Requests the hashes of the blocks after the fork with the sender's active chain,
+    /// which this locator describes, as returned by `BlockTree::locator`.
+    GetBlocks(Vec<Hash>),
+}
+
+impl Message {
+    /// Serializes the message, without its length prefix.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let hashes = |tag: u8, hashes: &[Hash]| {
+            let mut bytes = Vec::with_capacity(5 + hashes.len() * 32);
+            bytes.push(tag);
+            bytes.extend_from_slice(&(hashes.len() as u32).to_le_bytes());
+            for hash in hashes.iter() {
+                bytes.extend_from_slice(hash);
+            }
+            bytes
+        };
+
+        match self {
+            Message::Version { genesis, height } => {
+                let mut bytes = vec![VERSION];
+                bytes.extend_from_slice(genesis);
+                bytes.extend_from_slice(&height.to_le_bytes());
+                bytes
+            }
+            Message::Inventory(inventory) => hashes(INVENTORY, inventory),
+            Message::GetData(wanted) => hashes(GET_DATA, wanted),
+            Message::Block(block) => [vec![BLOCK], block.to_bytes()].concat(),
+            Message::GetBlocks(locator) => hashes(GET_BLOCKS, locator),
+        }
+    }
+
+    /// Deserializes a message written by `to_bytes`.
+    ///
+    /// # Returns
+    /// An error of kind `InvalidData` if the tag is unknown, or the message is truncated
+    /// or has trailing bytes.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let (&tag, mut bytes) = bytes
+            .split_first()
+            .ok_or_else(|| invalid("empty message".to_string()))?;
+        if tag == BLOCK {
+            return Block::from_bytes(bytes)
+                .map(Message::Block)
+                .map_err(|error| invalid(error.to_string()));
+        }
+
+        let bytes = &mut bytes;
+        let message = match tag {
+            VERSION => take_array(bytes)
+                .zip(take_array(bytes))
+                .map(|(genesis, height)| Message::Version {
+                    genesis,
+                    height: u64::from_le_bytes(height),
+                }),
+            INVENTORY => take_hashes(bytes).map(Message::Inventory),
+            GET_DATA => take_hashes(bytes).map(Message::GetData),
+            GET_BLOCKS => take_hashes(bytes).map(Message::GetBlocks),
+            tag => return Err(invalid(format!("unknown message tag {}", tag))),
+        };
+        let message = message.ok_or_else(|| invalid("truncated message".to_string()))?;
+        if !bytes.is_empty() {
+            return Err(invalid(format!(
+                "{} trailing bytes after the message",
+                bytes.len()
+            )));
+        }
+        Ok(message)
+    }
+
+    /// Writes the message to `writer`, prefixed by its length.
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let bytes = self.to_bytes();
+        writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        writer.write_all(&bytes)?;
+        writer.flush()
+    }
+
+    /// Reads a message written by `write_to` from `reader`, blocking until it is
+    /// complete.
+    ///
+    /// # Returns
+    /// The message, an error of kind `InvalidData` if it is larger than
+    /// `MAX_MESSAGE_SIZE` or malformed, or the error of `reader`.
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(invalid(format!(
+                "message of {} bytes is larger than {}",
+                len, MAX_MESSAGE_SIZE
+            )));
+        }
+        let mut bytes = vec![0; len];
+        reader.read_exact(&mut bytes)?;
+        Message::from_bytes(&bytes)
+    }
+}
+
+/// Removes a list of hashes, prefixed by their number, from the front of `bytes`.
+fn take_hashes(bytes: &mut &[u8]) -> Option<Vec<Hash>> {
+    let count = take_u32(bytes)? as usize;
+    let hashes = take(bytes, count.checked_mul(32)?)?;
+    Some(
+        hashes
+            .chunks_exact(32)
+            .map(|hash| hash.try_into().expect("chunks of 32 bytes"))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::transaction::Transaction;
+
+    /// Test that every kind of message survives a round trip through a stream
+    #[test]
+    fn round_trip() {
+        let block = Block::new(
+            [3; 32],
+            1_700_000_000,
+            0x207fffff,
+            vec![Transaction::coinbase(7, vec![])],
+        );
+        let messages = [
+            Message::Version {
+                genesis: [1; 32],
+                height: 42,
+            },
+            Message::Inventory(vec![[2; 32], [3; 32]]),
+            Message::GetData(vec![]),
+            Message::Block(block),
+            Message::GetBlocks(vec![[4; 32]]),
+        ];
+
+        let mut stream = vec![];
+        for message in messages.iter() {
+            message.write_to(&mut stream).unwrap();
+        }
+        let mut reader = stream.as_slice();
+        for message in messages.iter() {
+            assert_eq!(&Message::read_from(&mut reader).unwrap(), message);
+        }
+        assert_eq!(
+            Message::read_from(&mut reader).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    /// Test that malformed and oversized messages are rejected
+    #[test]
+    fn rejects_malformed() {
+        let bytes = Message::Inventory(vec![[2; 32]]).to_bytes();
+        for bytes in [
+            &bytes[..bytes.len() - 1],
+            &[bytes.as_slice(), &[0]].concat(),
+            &[9][..],
+            &[][..],
+            &[BLOCK, 0, 0][..],
+        ] {
+            let error = Message::from_bytes(bytes).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let oversized = (MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes();
+        let error = Message::read_from(&mut oversized.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,282 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crossbeam::thread;
+
+use super::block::BlockHeader;
+use super::target::Target;
+
+/// Number of hashes a worker computes between two checks of whether it should stop.
+const CHECK_INTERVAL: u64 = 1024;
+
+/// How a search for a header meeting its target ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MiningOutcome {
+    /// A header meeting its target was found.
+    Found(BlockHeader),
+    /// The search was cancelled, usually because a competing block arrived first.
+    Cancelled,
+    /// Every nonce was tried and rolling no longer changes the header, such as once the
+    /// timestamp reaches `u32::MAX`.
+    Exhausted,
+    /// The header's bits don't encode a valid target, so there was nothing to search.
+    InvalidTarget,
+}
+
+/// The outcome of a search along with the work it took.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MiningReport {
+    pub outcome: MiningOutcome,
+    /// Number of headers hashed, over every thread.
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningReport {
+    /// Returns the number of hashes per second over the whole search.
+    pub fn hash_rate(&self) -> f64 {
+        self.hashes as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+/// Searches for a nonce that makes a header meet its target on several threads.
+///
+/// Each round, the nonce space is cut into one contiguous range per thread. When no
+/// nonce of the space works, the header is rolled, by default by moving its timestamp
+/// one second ahead, and a new round starts.
+///
+/// # Methods
+/// - `new`: Creates a miner with the given number of threads.
+/// - `nonces_per_round`: Limits the nonces tried before the header is rolled.
+/// - `mine`: Searches for a header, rolling its timestamp.
+/// - `mine_with`: Searches for a header, rolling it with the given function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Miner {
+    threads: usize,
+    nonces_per_round: u64,
+}
+
+impl Miner {
+    /// Creates a miner that hashes on `threads` threads, at least one, and tries every
+    /// `u32` nonce before rolling the header.
+    pub fn new(threads: usize) -> Self {
+        Miner {
+            threads: threads.max(1),
+            nonces_per_round: 1 << 32,
+        }
+    }
+
+    /// Limits the nonces tried before the header is rolled to `0..nonces`, at least one
+    /// and at most every `u32`.
+    pub fn nonces_per_round(self, nonces: u64) -> Self {
+        Miner {
+            nonces_per_round: nonces.clamp(1, 1 << 32),
+            ..self
+        }
+    }
+
+    /// Searches for a header meeting its target, moving the timestamp one second ahead
+    /// each time the nonces run out.
+    ///
+    /// # Parameters
+    /// - `header`: The header to mine, whose nonce is ignored
+    /// - `cancel`: Set to `true`, from any thread, to stop the search
+    pub fn mine(&self, header: BlockHeader, cancel: &AtomicBool) -> MiningReport {
+        self.mine_with(header, cancel, |header, _| {
+            header.timestamp = header.timestamp.saturating_add(1)
+        })
+    }
+
+    /// Searches for a header meeting its target, calling `roll` each time the nonces run
+    /// out, for instance to change an extra nonce in the first transaction and
+    /// recompute the Merkle root.
+    ///
+    /// # Parameters
+    /// - `header`: The header to mine, whose nonce is ignored
+    /// - `cancel`: Set to `true`, from any thread, to stop the search
+    /// - `roll`: Changes the header before a new round, given the number of the round
+    ///   starting from 1. The search is exhausted once it leaves the header unchanged.
+    pub fn mine_with(
+        &self,
+        mut header: BlockHeader,
+        cancel: &AtomicBool,
+        mut roll: impl FnMut(&mut BlockHeader, u64),
+    ) -> MiningReport {
+        let start = Instant::now();
+        let hashes = AtomicU64::new(0);
+        let report = |outcome| MiningReport {
+            outcome,
+            hashes: hashes.load(Ordering::Relaxed),
+            elapsed: start.elapsed(),
+        };
+
+        let Some(target) = Target::from_compact(header.bits) else {
+            return report(MiningOutcome::InvalidTarget);
+        };
+
+        for round in 0.. {
+            if round > 0 {
+                let previous = header;
+                roll(&mut header, round);
+                if header == previous {
+                    return report(MiningOutcome::Exhausted);
+                }
+            }
+            if let Some(found) = self.search(&header, &target, cancel, &hashes) {
+                return report(MiningOutcome::Found(found));
+            }
+            if cancel.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+        report(MiningOutcome::Cancelled)
+    }
+
+    /// Tries every nonce of a round, and returns the first header found to meet `target`.
+    fn search(
+        &self,
+        header: &BlockHeader,
+        target: &Target,
+        cancel: &AtomicBool,
+        hashes: &AtomicU64,
+    ) -> Option<BlockHeader> {
+        let found = Mutex::new(None);
+        let done = AtomicBool::new(false);
+        let chunk = self.nonces_per_round.div_ceil(self.threads as u64);
+
+        thread::scope(|scope| {
+            for worker in 0..self.threads as u64 {
+                let nonces = worker * chunk..((worker + 1) * chunk).min(self.nonces_per_round);
+                let (found, done) = (&found, &done);
+
+                scope.spawn(move |_| {
+                    let mut candidate = *header;
+                    let mut count = 0;
+                    for nonce in nonces {
+                        if count % CHECK_INTERVAL == 0
+                            && (done.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed))
+                        {
+                            break;
+                        }
+                        candidate.nonce = nonce as u32;
+                        count += 1;
+                        if target.is_met_by(&candidate.hash()) {
+                            done.store(true, Ordering::Relaxed);
+                            found.lock().unwrap().get_or_insert(candidate);
+                            break;
+                        }
+                    }
+                    hashes.fetch_add(count, Ordering::Relaxed);
+                });
+            }
+        })
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+
+        found.into_inner().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::Block;
+    use crate::blockchain::proof_of_work::check_proof_of_work;
+    use std::sync::Arc;
+
+    const TIMESTAMP: u32 = 1_700_000_000;
+
+    fn header(bits: u32) -> BlockHeader {
+        Block::new([3; 32], TIMESTAMP, bits, vec![]).header
+    }
+
+    /// Test that every thread count finds a valid header and reports its hashes
+    #[test]
+    fn finds_valid_header() {
+        for threads in [1, 2, 4] {
+            let report = Miner::new(threads).mine(header(0x2000ffff), &AtomicBool::new(false));
+            let MiningOutcome::Found(found) = report.outcome else {
+                panic!("{:?} with {} threads", report.outcome, threads);
+            };
+            assert!(check_proof_of_work(&found));
+            assert_eq!(found.timestamp, TIMESTAMP);
+            assert!(report.hashes >= 1);
+            assert!(report.hash_rate() > 0.0);
+        }
+    }
+
+    /// Test that the timestamp moves ahead once every nonce of a round was tried
+    #[test]
+    fn rolls_timestamp_when_nonces_run_out() {
+        let miner = Miner::new(3).nonces_per_round(8);
+        let report = miner.mine(header(0x2000ffff), &AtomicBool::new(false));
+
+        let MiningOutcome::Found(found) = report.outcome else {
+            panic!("{:?}", report.outcome);
+        };
+        assert!(check_proof_of_work(&found));
+        assert!(found.nonce < 8);
+        assert!(found.timestamp > TIMESTAMP);
+        assert!(report.hashes > 8);
+    }
+
+    /// Test that a custom roll, such as an extra nonce, is called with increasing rounds
+    #[test]
+    fn rolls_extra_nonce() {
+        let mut rounds = vec![];
+        let report = Miner::new(2).nonces_per_round(4).mine_with(
+            header(0x2000ffff),
+            &AtomicBool::new(false),
+            |header, round| {
+                rounds.push(round);
+                header.merkle_root[..8].copy_from_slice(&round.to_le_bytes());
+            },
+        );
+
+        let MiningOutcome::Found(found) = report.outcome else {
+            panic!("{:?}", report.outcome);
+        };
+        assert!(check_proof_of_work(&found));
+        assert_eq!(found.timestamp, TIMESTAMP);
+        assert_eq!(rounds, (1..=rounds.len() as u64).collect::<Vec<_>>());
+    }
+
+    /// Test that a search for an impossible target stops once cancelled
+    #[test]
+    fn stops_when_cancelled() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let competing_block = {
+            let cancel = Arc::clone(&cancel);
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                cancel.store(true, Ordering::Relaxed);
+            })
+        };
+
+        let report = Miner::new(2).mine(header(0x03000001), &cancel);
+        competing_block.join().unwrap();
+        assert_eq!(report.outcome, MiningOutcome::Cancelled);
+        assert!(report.hashes > 0);
+
+        let report = Miner::new(1).mine(header(0x04923456), &AtomicBool::new(false));
+        assert_eq!(report.outcome, MiningOutcome::InvalidTarget);
+        assert_eq!(report.hashes, 0);
+    }
+
+    /// Test that a search stops once rolling no longer changes the header
+    #[test]
+    fn stops_when_exhausted() {
+        let miner = Miner::new(2).nonces_per_round(16);
+        let last = BlockHeader {
+            timestamp: u32::MAX - 2,
+            ..header(0x03000001)
+        };
+        let report = miner.mine(last, &AtomicBool::new(false));
+        assert_eq!(report.outcome, MiningOutcome::Exhausted);
+        assert_eq!(report.hashes, 3 * 16);
+
+        let report = miner.mine_with(header(0x03000001), &AtomicBool::new(false), |_, _| {});
+        assert_eq!(report.outcome, MiningOutcome::Exhausted);
+        assert_eq!(report.hashes, 16);
+    }
+}
@@ -0,0 +1,78 @@
+//! A proof-of-work blockchain for the use case of `issues/consensus-algorithm.rs`: blocks
+//! of transactions chained by the hash of their header, a [`chain`] that only accepts
+//! blocks extending it validly, and a [`block_tree`] that follows the branch with the most
+//! work among competing ones. Nodes propagate blocks to each other over TCP through the
+//! [`network`] module.
+//!
+//! Who may produce a block is up to a [`block_producer`], which chains and trees check
+//! every block with: proof of work, or the validators of a [`proof_of_stake`] schedule,
+//! whose blocks become final once a supermajority of the stake attests them, and are
+//! then never reorganized away.
+//!
+//! Headers are serialized and hashed as in Bitcoin, so a header can be checked against
+//! known Bitcoin blocks. The Merkle root of a block is built with the [`crate::merkle`]
+//! tree over the transaction ids, and transactions move value between Ed25519 keys
+//! through a [`utxo_set`] of unspent outputs. Transactions wait in a [`mempool`] until
+//! they are selected for a block by fee rate.
+
+pub mod block;
+pub mod block_producer;
+pub mod block_tree;
+pub mod chain;
+pub mod chain_error;
+pub mod mempool;
+pub mod mempool_error;
+pub mod message;
+pub mod miner;
+pub mod network;
+pub mod proof_of_stake;
+pub mod proof_of_work;
+pub mod target;
+pub mod transaction;
+pub mod transaction_error;
+pub mod utxo_set;
+
+use sha2::{Digest, Sha256};
+
+pub use block::{Block, BlockHeader};
+pub use block_producer::{BlockProducer, ProofOfWork};
+pub use block_tree::{BlockTree, Insertion, Reorg};
+pub use chain::Chain;
+pub use chain_error::ChainError;
+pub use mempool::{BlockTemplate, Mempool};
+pub use mempool_error::MempoolError;
+pub use message::Message;
+pub use miner::{Miner, MiningOutcome, MiningReport};
+pub use network::{Node, NodeStats};
+pub use proof_of_stake::{
+    Attestation, Finality, LeaderSchedule, ProofOfStake, StakeSeal, Validator,
+};
+pub use proof_of_work::{proof_of_work, ChainParams};
+pub use target::Target;
+pub use transaction::{OutPoint, Transaction, TxInput, TxOutput};
+pub use transaction_error::TransactionError;
+pub use utxo_set::{UtxoChanges, UtxoSet};
+
+/// A 256-bit hash, of a block header or of a transaction.
+pub type Hash = [u8; 32];
+
+/// Returns SHA-256 applied twice to `data`, which is how Bitcoin hashes headers and
+/// transactions to rule out length-extension attacks.
+pub fn double_sha256(data: &[u8]) -> Hash {
+    Sha256::digest(Sha256::digest(data)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::merkle_hash::to_hex;
+
+    /// Test that the double hash matches a known value
+    #[test]
+    fn double_sha256_known_value() {
+        assert_eq!(
+            to_hex(&double_sha256(b"hello")),
+            "9595c9df90075148eb06860365df33584b75bff782a510c6cd4883a419833d50"
+        );
+    }
+}
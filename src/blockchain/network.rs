@@ -0,0 +1,717 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufReader, BufWriter};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::block::Block;
+use super::block_tree::{BlockTree, Insertion};
+use super::chain_error::ChainError;
+use super::message::Message;
+use super::proof_of_work::ChainParams;
+use super::Hash;
+
+/// Most block hashes sent in answer to `Message::GetBlocks`. A node downloading more
+/// asks again once it has received them.
+pub const MAX_INVENTORY: usize = 500;
+
+/// How long a peer has to send a requested block before it is requested from another
+/// peer that announced it.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often a node looks for requests that timed out.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Identifier of a connection to a peer, unique within a node.
+pub type PeerId = u64;
+
+/// Counters of what a node received, to check that blocks aren't flooded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NodeStats {
+    /// Blocks received from peers and stored.
+    pub blocks_received: usize,
+    /// Blocks received from peers that were already known.
+    pub duplicate_blocks: usize,
+    /// Blocks received from peers that were rejected.
+    pub invalid_blocks: usize,
+}
+
+/// A connection to a peer, as seen by the node.
+struct Peer {
+    /// Messages for the thread writing to the peer.
+    outbox: Sender<Message>,
+    /// The stream, kept to shut the connection down.
+    stream: TcpStream,
+    /// The height of the best block the peer is known to have: the one it claimed in
+    /// its version message at first, then those of the blocks it announced or sent.
+    height: u64,
+    /// Blocks the peer has, because it announced or sent them or was sent them.
+    known: HashSet<Hash>,
+    /// Whether the peer sent its version, which must be its first message.
+    handshaken: bool,
+}
+
+impl Peer {
+    /// Sends `message`. If the peer is gone, its reader thread removes it.
+    fn send(&self, message: Message) {
+        let _ = self.outbox.send(message);
+    }
+}
+
+/// A block requested from a peer.
+#[derive(Debug, Clone, Copy)]
+struct Request {
+    peer: PeerId,
+    /// When to request the block from another peer if it didn't arrive.
+    deadline: Instant,
+}
+
+/// What every thread of a node shares.
+struct State {
+    tree: BlockTree,
+    peers: HashMap<PeerId, Peer>,
+    next_peer: PeerId,
+    /// Blocks requested from a peer and not received yet, so each block is only
+    /// requested once however many peers announce it.
+    requested: HashMap<Hash, Request>,
+    stats: NodeStats,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    local_addr: SocketAddr,
+    shutdown: AtomicBool,
+}
+
+/// A node that propagates blocks to its peers over TCP, so blocks mined by any node
+/// reach every node connected to it, directly or not.
+///
+/// Blocks are gossiped rather than pushed: a node announces the hashes of the blocks it
+/// stores with `Message::Inventory`, and a peer requests those it doesn't know with
+/// `Message::GetData`. A node remembers which blocks each peer knows and which it has
+/// requested, so a block crosses each connection at most once and is downloaded once,
+/// however many peers announce it. A node that connects with a shorter chain, or
+/// receives a block whose parent it misses, sends `Message::GetBlocks` with a locator
+/// of its chain, and the peer answers with the hashes of up to `MAX_INVENTORY` blocks it
+/// misses: this is the initial block download. A block that doesn't arrive within
+/// `REQUEST_TIMEOUT` is requested from another peer that announced it, so that a peer
+/// announcing blocks it never sends can't stall the download.
+///
+/// The first message on a connection must be `Message::Version`, whose genesis block
+/// must be the node's: a peer sending anything else first is disconnected.
+///
+/// Every connection has a thread reading from it, which handles the messages, and a
+/// thread writing to it, fed by a channel so that no lock is held while writing. One
+/// more thread retries the requests that timed out.
+///
+/// # Methods
+/// - `start`: Starts a node listening for peers.
+/// - `connect`: Connects to a peer.
+/// - `broadcast_block`: Stores a block produced locally and announces it to the peers.
+/// - `with_tree`: Reads the blocks of the node.
+/// - `stats`: Returns what the node received.
+pub struct Node {
+    shared: Arc<Shared>,
+}
+
+impl Node {
+    /// Starts a node with only `genesis`, listening for peers on `addr`.
+    ///
+    /// # Parameters
+    /// - `genesis`: The genesis block, which peers must share
+    /// - `params`: The rules of the chain
+    /// - `addr`: The address to listen on, with port 0 for any free port
+    ///
+    /// # Returns
+    /// The node, an error of kind `InvalidInput` if the genesis block is invalid, or the
+    /// error of binding the listener.
+    pub fn start(
+        genesis: Block,
+        params: ChainParams,
+        addr: impl ToSocketAddrs,
+    ) -> io::Result<Node> {
+        let tree = BlockTree::new(genesis, params)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        let listener = TcpListener::bind(addr)?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                tree,
+                peers: HashMap::new(),
+                next_peer: 0,
+                requested: HashMap::new(),
+                stats: NodeStats::default(),
+            }),
+            local_addr: listener.local_addr()?,
+            shutdown: AtomicBool::new(false),
+        });
+
+        let retrying = Arc::clone(&shared);
+        thread::spawn(move || {
+            while !retrying.shutdown.load(Ordering::Relaxed) {
+                thread::sleep(RETRY_INTERVAL);
+                retrying.lock().retry_requests(Instant::now());
+            }
+        });
+
+        let accepting = Arc::clone(&shared);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accepting.shutdown.load(Ordering::Relaxed) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let _ = add_peer(&accepting, stream);
+                }
+            }
+        });
+        Ok(Node { shared })
+    }
+
+    /// Returns the address the node listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.local_addr
+    }
+
+    /// Connects to the node listening on `addr`. The nodes exchange their heights, and
+    /// the one behind starts downloading blocks.
+    ///
+    /// # Returns
+    /// The id of the peer, or the error of connecting.
+    pub fn connect(&self, addr: impl ToSocketAddrs) -> io::Result<PeerId> {
+        add_peer(&self.shared, TcpStream::connect(addr)?)
+    }
+
+    /// Returns the number of connected peers.
+    pub fn peer_count(&self) -> usize {
+        self.shared.lock().peers.len()
+    }
+
+    /// Calls `f` with the blocks of the node, such as to build a block on the tip.
+    /// Blocks received meanwhile wait for `f` to return.
+    pub fn with_tree<R>(&self, f: impl FnOnce(&BlockTree) -> R) -> R {
+        f(&self.shared.lock().tree)
+    }
+
+    /// Returns what the node received from its peers so far.
+    pub fn stats(&self) -> NodeStats {
+        self.shared.lock().stats
+    }
+
+    /// Stores `block`, produced by this node, and announces it and the orphans it
+    /// connected to every peer.
+    ///
+    /// # Returns
+    /// What happened to the block, or the error of `BlockTree::insert`.
+    pub fn broadcast_block(&self, block: Block) -> Result<Insertion, ChainError> {
+        let mut state = self.shared.lock();
+        let insertion = state.tree.insert(block, unix_time())?;
+        if let Insertion::Stored { stored, .. } = &insertion {
+            state.announce(stored, None);
+        }
+        Ok(insertion)
+    }
+}
+
+impl Drop for Node {
+    /// Stops listening and closes every connection, which ends the threads of the node.
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Relaxed);
+        for peer in self.shared.lock().peers.values() {
+            let _ = peer.stream.shutdown(Shutdown::Both);
+        }
+        // Wakes the listener up so it sees the flag
+        let _ = TcpStream::connect(self.shared.local_addr);
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl State {
+    /// Announces the blocks `hashes` to every peer that doesn't know them, except
+    /// `from`, which sent them.
+    fn announce(&mut self, hashes: &[Hash], from: Option<PeerId>) {
+        for (id, peer) in self.peers.iter_mut() {
+            if Some(*id) == from {
+                continue;
+            }
+            let unknown: Vec<Hash> = hashes
+                .iter()
+                .copied()
+                .filter(|hash| peer.known.insert(*hash))
+                .collect();
+            if !unknown.is_empty() {
+                peer.send(Message::Inventory(unknown));
+            }
+        }
+    }
+
+    /// Asks `id` for the blocks after the fork with its chain.
+    fn request_blocks(&self, id: PeerId) {
+        if let Some(peer) = self.peers.get(&id) {
+            peer.send(Message::GetBlocks(self.tree.locator()));
+        }
+    }
+
+    /// Handles `message`, received from the peer `id`.
+    ///
+    /// # Returns
+    /// An error of kind `InvalidData` if the peer must be disconnected: its first
+    /// message isn't its version, or it follows another genesis block.
+    fn handle(&mut self, id: PeerId, message: Message) -> io::Result<()> {
+        let Some(peer) = self.peers.get_mut(&id) else {
+            return Ok(());
+        };
+        if !peer.handshaken && !matches!(message, Message::Version { .. }) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer didn't send its version first",
+            ));
+        }
+        match message {
+            Message::Version { genesis, height } => {
+                if genesis != self.tree.chain().genesis().hash() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "peer follows another genesis block",
+                    ));
+                }
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    peer.handshaken = true;
+                    peer.height = height;
+                }
+                if height > self.tree.chain().height() as u64 {
+                    self.request_blocks(id);
+                }
+            }
+            Message::Inventory(hashes) => {
+                let Some(peer) = self.peers.get_mut(&id) else {
+                    return Ok(());
+                };
+                let deadline = Instant::now() + REQUEST_TIMEOUT;
+                let mut wanted = vec![];
+                for hash in hashes.iter() {
+                    peer.known.insert(*hash);
+                    if !self.tree.contains(hash) && !self.requested.contains_key(hash) {
+                        self.requested.insert(*hash, Request { peer: id, deadline });
+                        wanted.push(*hash);
+                    }
+                }
+                if !wanted.is_empty() {
+                    peer.send(Message::GetData(wanted));
+                }
+                self.update_heights(&hashes);
+            }
+            Message::GetData(hashes) => {
+                let Some(peer) = self.peers.get_mut(&id) else {
+                    return Ok(());
+                };
+                for hash in hashes {
+                    if let Some(block) = self.tree.get(&hash) {
+                        peer.known.insert(hash);
+                        peer.send(Message::Block(block.clone()));
+                    }
+                }
+            }
+            Message::GetBlocks(locator) => {
+                let hashes = self.tree.hashes_after(&locator, MAX_INVENTORY);
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    if !hashes.is_empty() {
+                        peer.known.extend(hashes.iter().copied());
+                        peer.send(Message::Inventory(hashes));
+                    }
+                }
+            }
+            Message::Block(block) => self.receive_block(id, block),
+        }
+        Ok(())
+    }
+
+    /// Stores `block`, received from the peer `id`, and relays the blocks it stored to
+    /// the other peers.
+    fn receive_block(&mut self, id: PeerId, block: Block) {
+        let hash = block.hash();
+        self.requested.remove(&hash);
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.known.insert(hash);
+        }
+
+        match self.tree.insert(block, unix_time()) {
+            Ok(Insertion::Stored { stored, .. }) => {
+                self.stats.blocks_received += 1;
+                self.update_heights(&stored);
+                self.announce(&stored, Some(id));
+            }
+            Ok(Insertion::Orphan) => {
+                self.stats.blocks_received += 1;
+                self.request_blocks(id);
+                return;
+            }
+            Err(ChainError::DuplicateBlock(_)) => {
+                self.stats.duplicate_blocks += 1;
+                return;
+            }
+            Err(_) => {
+                self.stats.invalid_blocks += 1;
+                return;
+            }
+        }
+
+        // The peer answered every request: ask for more if it is still ahead
+        let waiting = self.requested.values().any(|request| request.peer == id);
+        let ahead = self
+            .peers
+            .get(&id)
+            .is_some_and(|peer| peer.height > self.tree.chain().height() as u64);
+        if !waiting && ahead {
+            self.request_blocks(id);
+        }
+    }
+
+    /// Raises the height of the peers that have the stored blocks `hashes` to theirs.
+    fn update_heights(&mut self, hashes: &[Hash]) {
+        for hash in hashes.iter() {
+            let Some(height) = self.tree.height(hash) else {
+                continue;
+            };
+            for peer in self.peers.values_mut() {
+                if peer.known.contains(hash) {
+                    peer.height = peer.height.max(height as u64);
+                }
+            }
+        }
+    }
+
+    /// Requests the blocks whose request timed out at `now` from another peer that
+    /// announced them. A block no other peer announced is forgotten, so that the next
+    /// peer announcing it is asked for it.
+    fn retry_requests(&mut self, now: Instant) {
+        let mut expired: Vec<(Hash, PeerId)> = self
+            .requested
+            .iter()
+            .filter(|(_, request)| request.deadline <= now)
+            .map(|(hash, request)| (*hash, request.peer))
+            .collect();
+        expired.sort_unstable();
+
+        let mut wanted: HashMap<PeerId, Vec<Hash>> = HashMap::new();
+        for (hash, stalled) in expired {
+            let other = self
+                .peers
+                .iter()
+                .filter(|(id, peer)| **id != stalled && peer.known.contains(&hash))
+                .map(|(id, _)| *id)
+                .min();
+            let Some(other) = other else {
+                self.requested.remove(&hash);
+                continue;
+            };
+            let deadline = now + REQUEST_TIMEOUT;
+            self.requested.insert(
+                hash,
+                Request {
+                    peer: other,
+                    deadline,
+                },
+            );
+            wanted.entry(other).or_default().push(hash);
+        }
+        for (id, hashes) in wanted {
+            if let Some(peer) = self.peers.get(&id) {
+                peer.send(Message::GetData(hashes));
+            }
+        }
+    }
+
+    /// Forgets the peer `id`, and the requests it didn't answer so other peers are asked.
+    fn remove_peer(&mut self, id: PeerId) {
+        self.peers.remove(&id);
+        self.requested.retain(|_, request| request.peer != id);
+    }
+}
+
+/// Registers the connection `stream`, sends the version of the node on it and starts
+/// the threads reading and writing it.
+fn add_peer(shared: &Arc<Shared>, stream: TcpStream) -> io::Result<PeerId> {
+    stream.set_nodelay(true)?;
+    let reader = stream.try_clone()?;
+    let writer = stream.try_clone()?;
+    let (outbox, inbox) = mpsc::channel::<Message>();
+
+    let id = {
+        let mut state = shared.lock();
+        let id = state.next_peer;
+        state.next_peer += 1;
+        let version = Message::Version {
+            genesis: state.tree.chain().genesis().hash(),
+            height: state.tree.chain().height() as u64,
+        };
+        let peer = Peer {
+            outbox,
+            stream,
+            height: 0,
+            known: HashSet::new(),
+            handshaken: false,
+        };
+        peer.send(version);
+        state.peers.insert(id, peer);
+        id
+    };
+
+    thread::spawn(move || {
+        let mut writer = BufWriter::new(writer);
+        for message in inbox {
+            if message.write_to(&mut writer).is_err() {
+                break;
+            }
+        }
+    });
+
+    let shared = Arc::clone(shared);
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        while let Ok(message) = Message::read_from(&mut reader) {
+            if shared.lock().handle(id, message).is_err() {
+                break;
+            }
+        }
+        let _ = reader.get_ref().shutdown(Shutdown::Both);
+        shared.lock().remove_peer(id);
+    });
+    Ok(id)
+}
+
+/// Returns the seconds since the Unix epoch of the system clock.
+pub fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proof_of_work::proof_of_work;
+    use crate::blockchain::transaction::Transaction;
+    use std::time::{Duration, Instant};
+
+    const START: u32 = 1_700_000_000;
+
+    fn genesis() -> Block {
+        let mut genesis = Block::new(
+            [0; 32],
+            START,
+            ChainParams::regtest().pow_limit,
+            vec![Transaction::coinbase(0, vec![])],
+        );
+        assert!(proof_of_work(&mut genesis.header));
+        genesis
+    }
+
+    fn start() -> Node {
+        Node::start(genesis(), ChainParams::regtest(), "127.0.0.1:0").unwrap()
+    }
+
+    /// Mines `count` blocks on the tip of `node` and broadcasts them.
+    fn mine(node: &Node, count: usize) {
+        for _ in 0..count {
+            let mut block = node.with_tree(|tree| {
+                let chain = tree.chain();
+                let coinbase = Transaction::coinbase(chain.height() + 1, vec![]);
+                Block::new(
+                    chain.tip().hash(),
+                    chain.tip().header.timestamp + 600,
+                    chain.next_bits(),
+                    vec![coinbase],
+                )
+            });
+            assert!(proof_of_work(&mut block.header));
+            node.broadcast_block(block).unwrap();
+        }
+    }
+
+    fn height(node: &Node) -> usize {
+        node.with_tree(|tree| tree.chain().height())
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Test that blocks reach every node of a full mesh, each node downloading each block once
+    #[test]
+    fn gossips_blocks_once() {
+        let nodes: Vec<Node> = (0..4).map(|_| start()).collect();
+        for (i, node) in nodes.iter().enumerate() {
+            for peer in nodes[i + 1..].iter() {
+                node.connect(peer.local_addr()).unwrap();
+            }
+        }
+        wait_until(|| nodes.iter().all(|node| node.peer_count() == 3));
+
+        mine(&nodes[0], 10);
+        wait_until(|| nodes.iter().all(|node| height(node) == 10));
+
+        let tip = nodes[0].with_tree(|tree| tree.chain().tip().hash());
+        for node in nodes[1..].iter() {
+            assert_eq!(node.with_tree(|tree| tree.chain().tip().hash()), tip);
+            assert_eq!(
+                node.stats(),
+                NodeStats {
+                    blocks_received: 10,
+                    ..NodeStats::default()
+                }
+            );
+        }
+        assert_eq!(nodes[0].stats(), NodeStats::default());
+    }
+
+    /// Test that blocks are relayed to nodes that aren't connected to their producer
+    #[test]
+    fn relays_blocks() {
+        let nodes: Vec<Node> = (0..4).map(|_| start()).collect();
+        for pair in nodes.windows(2) {
+            pair[1].connect(pair[0].local_addr()).unwrap();
+        }
+        wait_until(|| nodes[1..3].iter().all(|node| node.peer_count() == 2));
+
+        mine(&nodes[0], 5);
+        wait_until(|| height(&nodes[3]) == 5);
+        mine(&nodes[3], 5);
+        wait_until(|| nodes.iter().all(|node| height(node) == 10));
+        // The ends received the blocks of the other end, the middle nodes all of them
+        let received: Vec<usize> = nodes
+            .iter()
+            .map(|node| node.stats().blocks_received)
+            .collect();
+        assert_eq!(received, [5, 10, 10, 5]);
+        assert!(nodes.iter().all(|node| node.stats().duplicate_blocks == 0));
+    }
+
+    /// Test that a new node downloads a chain longer than one inventory from its peers
+    #[test]
+    fn initial_block_download() {
+        let first = start();
+        mine(&first, 2 * MAX_INVENTORY + 100);
+        let second = start();
+        second.connect(first.local_addr()).unwrap();
+        wait_until(|| height(&second) == 2 * MAX_INVENTORY + 100);
+
+        // Downloading from two peers at once still gets each block once
+        let third = start();
+        third.connect(first.local_addr()).unwrap();
+        third.connect(second.local_addr()).unwrap();
+        wait_until(|| height(&third) == 2 * MAX_INVENTORY + 100);
+        for node in [&second, &third] {
+            assert_eq!(
+                node.stats(),
+                NodeStats {
+                    blocks_received: 2 * MAX_INVENTORY + 100,
+                    ..NodeStats::default()
+                }
+            );
+        }
+    }
+
+    /// Test that blocks announced by a peer that never sends them are downloaded from
+    /// another peer that announced them
+    #[test]
+    fn retries_stalled_requests() {
+        let honest = start();
+        mine(&honest, 5);
+        let hashes = honest.with_tree(|tree| tree.hashes_after(&[], MAX_INVENTORY));
+
+        // A peer that announces the blocks of the honest one, and ignores every request
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stalling = listener.local_addr().unwrap();
+        let (requested, wait_requested) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = BufWriter::new(stream);
+            let version = Message::Version {
+                genesis: genesis().hash(),
+                height: 5,
+            };
+            version.write_to(&mut writer).unwrap();
+            while let Ok(message) = Message::read_from(&mut reader) {
+                match message {
+                    Message::GetBlocks(_) => {
+                        Message::Inventory(hashes.clone())
+                            .write_to(&mut writer)
+                            .unwrap();
+                    }
+                    Message::GetData(_) => {
+                        let _ = requested.send(());
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        let node = start();
+        node.connect(stalling).unwrap();
+        wait_requested
+            .recv_timeout(Duration::from_secs(30))
+            .unwrap();
+        let started = Instant::now();
+        node.connect(honest.local_addr()).unwrap();
+        wait_until(|| height(&node) == 5);
+        assert!(started.elapsed() >= REQUEST_TIMEOUT / 2);
+        assert_eq!(node.stats().blocks_received, 5);
+    }
+
+    /// Test that a peer sending anything before its version is disconnected, and its
+    /// messages ignored
+    #[test]
+    fn requires_version_first() {
+        let node = start();
+        let mut block = Block::new(
+            genesis().hash(),
+            START + 600,
+            ChainParams::regtest().pow_limit,
+            vec![Transaction::coinbase(1, vec![])],
+        );
+        assert!(proof_of_work(&mut block.header));
+
+        let stream = TcpStream::connect(node.local_addr()).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = BufWriter::new(stream);
+        Message::Block(block).write_to(&mut writer).unwrap();
+        // The node sends its version, then closes the connection
+        assert!(matches!(
+            Message::read_from(&mut reader),
+            Ok(Message::Version { .. })
+        ));
+        assert!(Message::read_from(&mut reader).is_err());
+        wait_until(|| node.peer_count() == 0);
+        assert_eq!(height(&node), 0);
+        assert_eq!(node.stats(), NodeStats::default());
+    }
+
+    /// Test that nodes following another genesis block are disconnected
+    #[test]
+    fn rejects_other_genesis() {
+        let node = start();
+        let mut other = Block::new([0; 32], START + 1, ChainParams::regtest().pow_limit, vec![]);
+        other.transactions.push(Transaction::coinbase(0, vec![]));
+        other.header.merkle_root = other.compute_merkle_root();
+        assert!(proof_of_work(&mut other.header));
+        let stranger = Node::start(other, ChainParams::regtest(), "127.0.0.1:0").unwrap();
+
+        stranger.connect(node.local_addr()).unwrap();
+        wait_until(|| node.peer_count() == 0 && stranger.peer_count() == 0);
+    }
+}
@@ -0,0 +1,867 @@
+use std::collections::HashMap;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+use super::block::Block;
+use super::block_producer::BlockProducer;
+use super::block_tree::BlockTree;
+use super::chain_error::ChainError;
+use super::transaction::PublicKey;
+use super::{double_sha256, Hash};
+use crate::bytes::take_array;
+use crate::rng::SimRng;
+
+/// A key allowed to produce and attest blocks, and the stake that weighs its turns and
+/// its attestations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Validator {
+    pub key: PublicKey,
+    pub stake: u64,
+}
+
+impl Validator {
+    /// Creates a validator of `key` with `stake`.
+    pub fn new(key: &VerifyingKey, stake: u64) -> Self {
+        Validator {
+            key: key.to_bytes(),
+            stake,
+        }
+    }
+}
+
+/// Who produces the block of each slot, a fixed period of time from the genesis block.
+///
+/// The leader of a slot is drawn among the validators with a probability proportional
+/// to their stake. The draw is the value of a `SimRng` seeded with the seed of the
+/// schedule at the position of the slot, so every node computes the same schedule
+/// without exchanging anything, and the leader of any slot is found in O(log v) time,
+/// with v = number of validators. As the seed is known in advance, so is the schedule.
+///
+/// # Methods
+/// - `new`: Creates a schedule of validators.
+/// - `slot_at`: Returns the slot of a timestamp.
+/// - `slot_start`: Returns the first timestamp of a slot.
+/// - `leader`: Returns the validator that produces the block of a slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaderSchedule {
+    validators: Vec<Validator>,
+    /// The stake of the validators up to each one, included.
+    cumulative_stakes: Vec<u64>,
+    seed: u64,
+    genesis_time: u32,
+    slot_duration: u32,
+}
+
+impl LeaderSchedule {
+    /// Creates the schedule of `validators`. Validators without stake never lead.
+    ///
+    /// # Parameters
+    /// - `validators`: The validators, with distinct keys
+    /// - `seed`: The seed of the draws
+    /// - `genesis_time`: When slot 0 starts, in seconds since the Unix epoch
+    /// - `slot_duration`: How long a slot lasts, in seconds
+    ///
+    /// # Panics
+    /// If there is no stake at all, the total stake doesn't fit in a `u64`, or
+    /// `slot_duration` is 0.
+    pub fn new(
+        validators: Vec<Validator>,
+        seed: u64,
+        genesis_time: u32,
+        slot_duration: u32,
+    ) -> Self {
+        assert!(slot_duration > 0, "slots must last at least a second");
+        let mut total = 0u64;
+        let cumulative_stakes = validators
+            .iter()
+            .map(|validator| {
+                total = total
+                    .checked_add(validator.stake)
+                    .expect("total stake overflows");
+                total
+            })
+            .collect();
+        assert!(total > 0, "validators must have some stake");
+
+        LeaderSchedule {
+            validators,
+            cumulative_stakes,
+            seed,
+            genesis_time,
+            slot_duration,
+        }
+    }
+
+    /// Returns the validators.
+    pub fn validators(&self) -> &[Validator] {
+        &self.validators
+    }
+
+    /// Returns the stake of every validator together.
+    pub fn total_stake(&self) -> u64 {
+        self.cumulative_stakes[self.cumulative_stakes.len() - 1]
+    }
+
+    /// Returns the slot that `timestamp` falls in. Timestamps before the genesis block
+    /// are in slot 0.
+    pub fn slot_at(&self, timestamp: u32) -> u64 {
+        (timestamp.saturating_sub(self.genesis_time) / self.slot_duration) as u64
+    }
+
+    /// Returns the first timestamp of `slot`, or `None` if it doesn't fit in a `u32`.
+    pub fn slot_start(&self, slot: u64) -> Option<u32> {
+        let offset = slot.checked_mul(self.slot_duration as u64)?;
+        u32::try_from(offset).ok()?.checked_add(self.genesis_time)
+    }
+
+    /// Returns the validator that produces the block of `slot`.
+    pub fn leader(&self, slot: u64) -> &Validator {
+        let mut rng = SimRng::new(self.seed);
+        rng.advance(slot);
+        // Scales the draw to the total stake without the bias of a modulo
+        let draw = ((rng.next_u64() as u128 * self.total_stake() as u128) >> 64) as u64;
+        let index = self
+            .cumulative_stakes
+            .partition_point(|stake| *stake <= draw);
+        &self.validators[index]
+    }
+}
+
+/// The proof that a block was produced by the leader of its slot, carried in the `seal`
+/// bytes of the block as the slot, a little-endian `u64`, then the key and signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StakeSeal {
+    pub slot: u64,
+    pub producer: PublicKey,
+    /// Ed25519 signature of the hash of the block by the producer.
+    pub signature: [u8; 64],
+}
+
+/// Produces blocks by proof of stake: the leader of each slot signs the block whose
+/// timestamp is in it, and nobody else may produce it. Each block is in a later slot
+/// than its parent, and they all weigh the same in fork choice.
+///
+/// A slot without a block, because its leader is offline, is skipped, and the next
+/// leader builds on the last block. Blocks are final once attested by a supermajority
+/// of the stake, which `Finality` tracks.
+///
+/// # Methods
+/// - `validator`: Creates the producer of a validator, which seals in its slots.
+/// - `observer`: Creates a producer that only verifies seals.
+/// - `schedule`: Returns the schedule of leaders.
+#[derive(Debug, Clone)]
+pub struct ProofOfStake {
+    schedule: LeaderSchedule,
+    key: Option<SigningKey>,
+}
+
+impl ProofOfStake {
+    /// Creates the producer of the validator of `key`, which seals blocks in its slots.
+    pub fn validator(schedule: LeaderSchedule, key: SigningKey) -> Self {
+        ProofOfStake {
+            schedule,
+            key: Some(key),
+        }
+    }
+
+    /// Creates a producer that never seals, and only verifies seals.
+    pub fn observer(schedule: LeaderSchedule) -> Self {
+        ProofOfStake {
+            schedule,
+            key: None,
+        }
+    }
+
+    /// Returns the schedule of leaders.
+    pub fn schedule(&self) -> &LeaderSchedule {
+        &self.schedule
+    }
+}
+
+impl BlockProducer for ProofOfStake {
+    type Seal = StakeSeal;
+
+    const NAME: &'static str = "proof of stake";
+
+    /// Every block weighs the same, so fork choice follows the branch with the most
+    /// blocks, which is the one with the most slots filled.
+    const WEIGHS_WORK: bool = false;
+
+    /// Signs `block` if its timestamp is in a slot this validator leads.
+    fn seal(&self, block: &mut Block) -> Option<StakeSeal> {
+        let key = self.key.as_ref()?;
+        let slot = self.schedule.slot_at(block.header.timestamp);
+        let producer = key.verifying_key().to_bytes();
+        if self.schedule.leader(slot).key != producer {
+            return None;
+        }
+        let seal = StakeSeal {
+            slot,
+            producer,
+            signature: key.sign(&block.hash()).to_bytes(),
+        };
+        block.seal = ProofOfStake::seal_to_bytes(&seal);
+        Some(seal)
+    }
+
+    fn verify(&self, block: &Block, seal: &StakeSeal) -> Result<(), ChainError> {
+        let slot = self.schedule.slot_at(block.header.timestamp);
+        if seal.slot != slot {
+            return Err(ChainError::WrongSlot {
+                expected: slot,
+                found: seal.slot,
+            });
+        }
+        let leader = self.schedule.leader(slot).key;
+        if seal.producer != leader {
+            return Err(ChainError::WrongProducer {
+                slot,
+                expected: leader,
+                found: seal.producer,
+            });
+        }
+        if !verify_signature(&seal.producer, &block.hash(), &seal.signature) {
+            return Err(ChainError::InvalidSignature);
+        }
+        Ok(())
+    }
+
+    /// Checks that `block` is in a later slot than `parent`, so a leader produces at most
+    /// one block of a branch in its slot.
+    fn verify_parent(&self, block: &Block, parent: &Block) -> Result<(), ChainError> {
+        let slot = self.schedule.slot_at(block.header.timestamp);
+        let parent_slot = self.schedule.slot_at(parent.header.timestamp);
+        if slot <= parent_slot {
+            return Err(ChainError::StaleSlot { slot, parent_slot });
+        }
+        Ok(())
+    }
+
+    fn seal_to_bytes(seal: &StakeSeal) -> Vec<u8> {
+        [
+            seal.slot.to_le_bytes().as_slice(),
+            &seal.producer,
+            &seal.signature,
+        ]
+        .concat()
+    }
+
+    fn seal_from_bytes(mut bytes: &[u8]) -> Option<StakeSeal> {
+        let bytes = &mut bytes;
+        let seal = StakeSeal {
+            slot: u64::from_le_bytes(take_array(bytes)?),
+            producer: take_array(bytes)?,
+            signature: take_array(bytes)?,
+        };
+        bytes.is_empty().then_some(seal)
+    }
+}
+
+/// A validator's vote that a block is part of the chain at a height.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attestation {
+    pub block: Hash,
+    pub height: u64,
+    pub validator: PublicKey,
+    /// Ed25519 signature of the block and height by the validator.
+    pub signature: [u8; 64],
+}
+
+impl Attestation {
+    /// Creates the attestation of the validator of `key` that `block` is at `height`.
+    pub fn new(block: Hash, height: u64, key: &SigningKey) -> Self {
+        Attestation {
+            block,
+            height,
+            validator: key.verifying_key().to_bytes(),
+            signature: key.sign(&Attestation::message(&block, height)).to_bytes(),
+        }
+    }
+
+    /// Returns `true` if the attestation is signed by its validator.
+    pub fn verify(&self) -> bool {
+        let message = Attestation::message(&self.block, self.height);
+        verify_signature(&self.validator, &message, &self.signature)
+    }
+
+    /// Returns what an attestation signs: the double SHA-256 of the block hash followed
+    /// by the height as a little-endian `u64`.
+    fn message(block: &Hash, height: u64) -> Hash {
+        double_sha256(&[block.as_slice(), &height.to_le_bytes()].concat())
+    }
+}
+
+/// Finalizes blocks attested by validators holding more than two thirds of the stake.
+///
+/// Attestations are counted against a `BlockTree`: a block becomes final in the tree,
+/// which then refuses any branch forking below it, whatever its work. A new final block
+/// must descend from the previous one. Since a validator may only attest one block per
+/// height, two blocks at the same height can only both become final if more than a
+/// third of the stake attests both, which is rejected as conflicting.
+///
+/// # Methods
+/// - `new`: Tracks the attestations of the validators of a schedule.
+/// - `add`: Counts an attestation, finalizing its block on a supermajority.
+/// - `finalized`: Returns the last final block.
+#[derive(Debug, Clone)]
+pub struct Finality {
+    stakes: HashMap<PublicKey, u64>,
+    total_stake: u64,
+    /// The block each validator attested, at each height above the last final block.
+    votes: HashMap<u64, HashMap<PublicKey, Hash>>,
+    finalized: Option<(u64, Hash)>,
+}
+
+impl Finality {
+    /// Tracks the attestations of the validators of `schedule`, with no final block yet.
+    pub fn new(schedule: &LeaderSchedule) -> Self {
+        Finality {
+            stakes: schedule
+                .validators()
+                .iter()
+                .map(|validator| (validator.key, validator.stake))
+                .collect(),
+            total_stake: schedule.total_stake(),
+            votes: HashMap::new(),
+            finalized: None,
+        }
+    }
+
+    /// Returns the height and hash of the last final block, if any.
+    pub fn finalized(&self) -> Option<(u64, Hash)> {
+        self.finalized
+    }
+
+    /// Returns the stake of the validators that attested `block` at `height`.
+    pub fn attested_stake(&self, block: &Hash, height: u64) -> u64 {
+        self.votes.get(&height).map_or(0, |votes| {
+            votes
+                .iter()
+                .filter(|(_, attested)| *attested == block)
+                .map(|(validator, _)| self.stakes[validator])
+                .sum()
+        })
+    }
+
+    /// Counts `attestation`, and finalizes its block in `tree` once validators holding
+    /// more than two thirds of the stake attested it. Attestations at or below the last
+    /// final block are ignored.
+    ///
+    /// # Parameters
+    /// - `attestation`: The attestation to count
+    /// - `tree`: The blocks of the chain, where the attested block must be stored
+    ///
+    /// # Returns
+    /// `true` if the block of the attestation just became final,
+    /// `ChainError::UnknownValidator` if it isn't signed by a validator,
+    /// `ChainError::InvalidSignature` if the signature is invalid,
+    /// `ChainError::UnknownBlock` if the block isn't stored in `tree`, which the
+    /// attestation can be counted once it is, `ChainError::WrongHeight` if it is at
+    /// another height, `ChainError::ConflictingAttestation` if the validator attested
+    /// another block at the same height, or the error of `BlockTree::finalize` if the
+    /// block can't be final, such as when it doesn't descend from the last final block.
+    pub fn add<P: BlockProducer>(
+        &mut self,
+        attestation: &Attestation,
+        tree: &mut BlockTree<P>,
+    ) -> Result<bool, ChainError> {
+        if !self.stakes.contains_key(&attestation.validator) {
+            return Err(ChainError::UnknownValidator(attestation.validator));
+        }
+        if !attestation.verify() {
+            return Err(ChainError::InvalidSignature);
+        }
+        if self
+            .finalized
+            .is_some_and(|(height, _)| attestation.height <= height)
+        {
+            return Ok(false);
+        }
+        let height = tree
+            .height(&attestation.block)
+            .ok_or(ChainError::UnknownBlock(attestation.block))? as u64;
+        if height != attestation.height {
+            return Err(ChainError::WrongHeight {
+                expected: height,
+                found: attestation.height,
+            });
+        }
+
+        let votes = self.votes.entry(attestation.height).or_default();
+        match votes.get(&attestation.validator) {
+            Some(block) if *block != attestation.block => {
+                return Err(ChainError::ConflictingAttestation(attestation.validator));
+            }
+            Some(_) => return Ok(false),
+            None => votes.insert(attestation.validator, attestation.block),
+        };
+
+        let attested = self.attested_stake(&attestation.block, attestation.height);
+        if attested as u128 * 3 <= self.total_stake as u128 * 2 {
+            return Ok(false);
+        }
+        tree.finalize(&attestation.block)?;
+        self.finalized = Some((attestation.height, attestation.block));
+        self.votes.retain(|height, _| *height > attestation.height);
+        Ok(true)
+    }
+}
+
+/// Returns `true` if `signature` is the signature of `message` by `key`, checked
+/// strictly.
+fn verify_signature(key: &PublicKey, message: &[u8], signature: &[u8; 64]) -> bool {
+    VerifyingKey::from_bytes(key).is_ok_and(|key| {
+        key.verify_strict(message, &Signature::from_bytes(signature))
+            .is_ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block_tree::Insertion;
+    use crate::blockchain::proof_of_work::ChainParams;
+    use crate::blockchain::transaction::{Transaction, TxOutput};
+    use crate::blockchain::utxo_set::UtxoSet;
+
+    const GENESIS_TIME: u32 = 1_700_000_000;
+    const SLOT: u32 = 2;
+    const NOW: u32 = GENESIS_TIME + 100_000;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    /// Returns validators of seeds 1, 2, 3... with the given stakes.
+    fn schedule(stakes: &[u64], seed: u64) -> LeaderSchedule {
+        let validators = stakes
+            .iter()
+            .zip(1..)
+            .map(|(stake, seed)| Validator::new(&key(seed).verifying_key(), *stake))
+            .collect();
+        LeaderSchedule::new(validators, seed, GENESIS_TIME, SLOT)
+    }
+
+    /// Returns the block of `slot` building on `parent`, paying 50 to `producer`.
+    fn block_at(schedule: &LeaderSchedule, parent: Hash, slot: u64, producer: &PublicKey) -> Block {
+        let reward = TxOutput {
+            value: 50,
+            owner: *producer,
+        };
+        let coinbase = Transaction::coinbase(slot as usize, vec![reward]);
+        Block::new(
+            parent,
+            schedule.slot_start(slot).unwrap(),
+            ChainParams::regtest().pow_limit,
+            vec![coinbase],
+        )
+    }
+
+    /// Returns the block of `slot` building on `parent`, sealed by its leader, one of
+    /// the validators of seeds 1 to 4.
+    fn sealed(schedule: &LeaderSchedule, parent: Hash, slot: u64) -> Block {
+        let leader = schedule.leader(slot).key;
+        let key = (1..=4)
+            .map(key)
+            .find(|key| key.verifying_key().to_bytes() == leader)
+            .unwrap();
+        let mut block = block_at(schedule, parent, slot, &leader);
+        ProofOfStake::validator(schedule.clone(), key)
+            .seal(&mut block)
+            .unwrap();
+        block
+    }
+
+    /// Returns a tree following `schedule`, whose genesis block is the block of slot 0.
+    /// Its params retarget every 10 blocks, which proof of stake must ignore.
+    fn tree(schedule: &LeaderSchedule) -> BlockTree<ProofOfStake> {
+        let genesis = sealed(schedule, [0; 32], 0);
+        let observer = ProofOfStake::observer(schedule.clone());
+        let params = ChainParams {
+            retarget_interval: 10,
+            ..ChainParams::regtest()
+        };
+        BlockTree::with_producer(genesis, params, observer).unwrap()
+    }
+
+    /// Inserts the blocks of `slots` into `tree`, each building on the previous one and
+    /// the first one on `parent`, and returns them.
+    fn extend(
+        tree: &mut BlockTree<ProofOfStake>,
+        parent: Hash,
+        slots: impl IntoIterator<Item = u64>,
+    ) -> Vec<Block> {
+        let mut blocks: Vec<Block> = vec![];
+        for slot in slots {
+            let parent = blocks.last().map_or(parent, Block::hash);
+            let block = sealed(tree.chain().producer().schedule(), parent, slot);
+            tree.insert(block.clone(), NOW).unwrap();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    /// Test that the schedule is the same for a seed, and leads in proportion to stake
+    #[test]
+    fn schedule_follows_stake() {
+        let schedule = schedule(&[1, 2, 7, 0], 42);
+        let leaders: Vec<PublicKey> = (0..10_000).map(|slot| schedule.leader(slot).key).collect();
+
+        let again = self::schedule(&[1, 2, 7, 0], 42);
+        assert!((0..10_000).all(|slot| again.leader(slot).key == leaders[slot as usize]));
+        let other = self::schedule(&[1, 2, 7, 0], 43);
+        assert!((0..100).any(|slot| other.leader(slot).key != leaders[slot as usize]));
+
+        for (validator, expected) in schedule.validators().iter().zip([1000, 2000, 7000, 0]) {
+            let led = leaders.iter().filter(|key| **key == validator.key).count();
+            assert!(
+                led.abs_diff(expected) <= expected / 10,
+                "{} instead of {}",
+                led,
+                expected
+            );
+        }
+
+        assert_eq!(schedule.total_stake(), 10);
+        assert_eq!(schedule.slot_at(GENESIS_TIME - 5), 0);
+        assert_eq!(schedule.slot_at(GENESIS_TIME + 5), 2);
+        assert_eq!(schedule.slot_start(2), Some(GENESIS_TIME + 4));
+        assert_eq!(schedule.slot_start(u64::MAX), None);
+    }
+
+    /// Test that each slot is sealed by its leader only, and offline leaders are skipped
+    #[test]
+    fn validators_take_turns() {
+        let schedule = schedule(&[10, 20, 30, 40], 7);
+        let validators: Vec<ProofOfStake> = (1..=4)
+            .map(|seed| ProofOfStake::validator(schedule.clone(), key(seed)))
+            .collect();
+        let observer = ProofOfStake::observer(schedule.clone());
+        let offline = schedule.validators()[0].key;
+
+        let mut utxos = UtxoSet::new();
+        let mut tip = [0; 32];
+        let mut produced = 0;
+        for slot in 0..100 {
+            let leader = schedule.leader(slot).key;
+            let mut block = block_at(&schedule, tip, slot, &leader);
+            let seals: Vec<StakeSeal> = validators
+                .iter()
+                .filter(|validator| validator.schedule.leader(slot).key != offline)
+                .filter_map(|validator| validator.seal(&mut block))
+                .collect();
+            if leader == offline {
+                assert!(seals.is_empty());
+                continue;
+            }
+
+            assert_eq!(seals.len(), 1);
+            assert_eq!(seals[0].producer, leader);
+            assert_eq!(observer.verify(&block, &seals[0]), Ok(()));
+            assert_eq!(observer.seal(&mut block), None);
+            utxos.apply_block(&block.transactions, 50).unwrap();
+            tip = block.hash();
+            produced += 1;
+        }
+
+        assert!(produced > 70 && produced < 100);
+        let earned: u64 = schedule
+            .validators()
+            .iter()
+            .map(|validator| utxos.balance(&validator.key))
+            .sum();
+        assert_eq!(earned, 50 * produced);
+        assert_eq!(utxos.balance(&offline), 0);
+    }
+
+    /// Test that seals of the wrong validator, slot or block are rejected
+    #[test]
+    fn rejects_forged_seals() {
+        let schedule = schedule(&[1, 1], 3);
+        let observer = ProofOfStake::observer(schedule.clone());
+        let slot = (0..)
+            .find(|slot| schedule.leader(*slot).key == schedule.validators()[0].key)
+            .unwrap();
+        let (leader, other) = (key(1), key(2));
+        let mut block = block_at(&schedule, [0; 32], slot, &leader.verifying_key().to_bytes());
+        let seal = ProofOfStake::validator(schedule.clone(), leader)
+            .seal(&mut block)
+            .unwrap();
+
+        assert_eq!(
+            ProofOfStake::validator(schedule.clone(), other.clone()).seal(&mut block),
+            None
+        );
+        let forged = StakeSeal {
+            producer: other.verifying_key().to_bytes(),
+            signature: other.sign(&block.hash()).to_bytes(),
+            ..seal
+        };
+        assert_eq!(
+            observer.verify(&block, &forged),
+            Err(ChainError::WrongProducer {
+                slot,
+                expected: seal.producer,
+                found: forged.producer
+            })
+        );
+        let stolen = StakeSeal {
+            signature: forged.signature,
+            ..seal
+        };
+        assert_eq!(
+            observer.verify(&block, &stolen),
+            Err(ChainError::InvalidSignature)
+        );
+        assert_eq!(
+            observer.verify(
+                &block,
+                &StakeSeal {
+                    slot: slot + 1,
+                    ..seal
+                }
+            ),
+            Err(ChainError::WrongSlot {
+                expected: slot,
+                found: slot + 1
+            })
+        );
+
+        let mut tampered = block.clone();
+        tampered.header.nonce += 1;
+        assert_eq!(
+            observer.verify(&tampered, &seal),
+            Err(ChainError::InvalidSignature)
+        );
+        assert_eq!(observer.verify(&block, &seal), Ok(()));
+    }
+
+    /// Test that validators take turns extending a tree, whose blocks carry their seals
+    #[test]
+    fn grows_chain_of_stake() {
+        let schedule = schedule(&[10, 20, 30, 40], 7);
+        let mut tree = tree(&schedule);
+        let genesis = tree.chain().genesis().hash();
+        let blocks = extend(&mut tree, genesis, 1..=20);
+        assert_eq!(tree.chain().blocks()[1..], blocks[..]);
+
+        // Blocks far faster than the target spacing neither harden the target nor weigh
+        // more
+        let limit = ChainParams::regtest().pow_limit;
+        assert_eq!(tree.chain().next_bits(), limit);
+        assert!(blocks.iter().all(|block| block.header.bits == limit));
+        assert_eq!(tree.chain_work(&blocks[19].hash()), Some(21));
+
+        // Seals survive the wire
+        for block in blocks.iter() {
+            assert_eq!(Block::from_bytes(&block.to_bytes()).as_ref(), Ok(block));
+        }
+        let earned: u64 = schedule
+            .validators()
+            .iter()
+            .map(|validator| tree.chain().utxos().balance(&validator.key))
+            .sum();
+        assert_eq!(earned, 50 * 21);
+
+        let tip = tree.chain().tip().hash();
+        let mut unsealed = block_at(&schedule, tip, 21, &schedule.leader(21).key);
+        assert!(matches!(
+            tree.insert(unsealed.clone(), NOW),
+            Err(ChainError::MalformedBlock(_))
+        ));
+        let impostor = (1..=4)
+            .map(key)
+            .find(|key| key.verifying_key().to_bytes() != schedule.leader(21).key)
+            .unwrap();
+        let forged = StakeSeal {
+            slot: 21,
+            producer: impostor.verifying_key().to_bytes(),
+            signature: impostor.sign(&unsealed.hash()).to_bytes(),
+        };
+        unsealed.seal = ProofOfStake::seal_to_bytes(&forged);
+        assert!(matches!(
+            tree.insert(unsealed.clone(), NOW),
+            Err(ChainError::WrongProducer { .. })
+        ));
+        assert!(!tree.contains(&unsealed.hash()));
+        assert_eq!(tree.chain().height(), 20);
+    }
+
+    /// Test that a leader can't chain several blocks in its slot
+    #[test]
+    fn one_block_per_slot() {
+        let schedule = schedule(&[10, 20, 30, 40], 7);
+        let mut tree = tree(&schedule);
+        let genesis = tree.chain().genesis().hash();
+        let first = extend(&mut tree, genesis, [3])[0].clone();
+
+        let leader = schedule.leader(3).key;
+        let key = (1..=4)
+            .map(key)
+            .find(|key| key.verifying_key().to_bytes() == leader)
+            .unwrap();
+        let mut second = block_at(&schedule, first.hash(), 3, &leader);
+        second.header.timestamp += 1;
+        ProofOfStake::validator(schedule.clone(), key)
+            .seal(&mut second)
+            .unwrap();
+        assert_eq!(
+            tree.chain().validate(&second, NOW),
+            Err(ChainError::StaleSlot {
+                slot: 3,
+                parent_slot: 3
+            })
+        );
+        let insertion = tree.insert(second.clone(), NOW).unwrap();
+        let Insertion::Stored { reorg, .. } = insertion else {
+            panic!("the parent is stored");
+        };
+        assert!(reorg.is_empty());
+        assert!(tree.is_invalid(&second.hash()));
+        assert_eq!(tree.chain().tip(), &first);
+    }
+
+    /// Test that a block is final once more than two thirds of the stake attests it
+    #[test]
+    fn finalizes_with_supermajority() {
+        let schedule = schedule(&[10, 20, 30, 40], 7);
+        let mut tree = tree(&schedule);
+        let genesis = tree.chain().genesis().hash();
+        let blocks = extend(&mut tree, genesis, 1..=5);
+        let sibling = extend(&mut tree, blocks[3].hash(), [6])[0].hash();
+        let block = blocks[4].hash();
+        let mut finality = Finality::new(&schedule);
+
+        assert_eq!(
+            finality.add(&Attestation::new(block, 5, &key(4)), &mut tree),
+            Ok(false)
+        );
+        assert_eq!(
+            finality.add(&Attestation::new(block, 5, &key(4)), &mut tree),
+            Ok(false)
+        );
+        assert_eq!(
+            finality.add(&Attestation::new(block, 5, &key(2)), &mut tree),
+            Ok(false)
+        );
+        assert_eq!(
+            finality.add(&Attestation::new(sibling, 5, &key(2)), &mut tree),
+            Err(ChainError::ConflictingAttestation(
+                key(2).verifying_key().to_bytes()
+            ))
+        );
+        assert_eq!(
+            finality.add(&Attestation::new(sibling, 5, &key(3)), &mut tree),
+            Ok(false)
+        );
+        assert_eq!(finality.attested_stake(&block, 5), 60);
+        assert_eq!(finality.finalized(), None);
+        assert_eq!(tree.finalized(), None);
+
+        assert_eq!(
+            finality.add(&Attestation::new(block, 4, &key(1)), &mut tree),
+            Err(ChainError::WrongHeight {
+                expected: 5,
+                found: 4
+            })
+        );
+        assert_eq!(
+            finality.add(&Attestation::new([9; 32], 6, &key(1)), &mut tree),
+            Err(ChainError::UnknownBlock([9; 32]))
+        );
+        assert_eq!(
+            finality.add(&Attestation::new(block, 5, &key(1)), &mut tree),
+            Ok(true)
+        );
+        assert_eq!(finality.finalized(), Some((5, block)));
+        assert_eq!(tree.finalized(), Some(block));
+        assert_eq!(
+            finality.add(&Attestation::new(sibling, 4, &key(1)), &mut tree),
+            Ok(false)
+        );
+
+        let stranger = Attestation::new(block, 6, &key(9));
+        assert_eq!(
+            finality.add(&stranger, &mut tree),
+            Err(ChainError::UnknownValidator(stranger.validator))
+        );
+        let mut forged = Attestation::new(block, 6, &key(1));
+        forged.height = 7;
+        assert_eq!(
+            finality.add(&forged, &mut tree),
+            Err(ChainError::InvalidSignature)
+        );
+    }
+
+    /// Test that exactly two thirds of the stake isn't a supermajority
+    #[test]
+    fn two_thirds_is_not_enough() {
+        let schedule = schedule(&[1, 1, 1], 7);
+        let mut tree = tree(&schedule);
+        let genesis = tree.chain().genesis().hash();
+        let block = extend(&mut tree, genesis, [1])[0].hash();
+        let mut finality = Finality::new(&schedule);
+        assert_eq!(
+            finality.add(&Attestation::new(block, 1, &key(1)), &mut tree),
+            Ok(false)
+        );
+        assert_eq!(
+            finality.add(&Attestation::new(block, 1, &key(2)), &mut tree),
+            Ok(false)
+        );
+        assert_eq!(
+            finality.add(&Attestation::new(block, 1, &key(3)), &mut tree),
+            Ok(true)
+        );
+    }
+
+    /// Test that a branch with more work forking below the final block is refused, and
+    /// that a new final block must descend from the previous one
+    #[test]
+    fn keeps_final_blocks() {
+        let schedule = schedule(&[10, 20, 30, 40], 7);
+        let mut tree = tree(&schedule);
+        let genesis = tree.chain().genesis().hash();
+        let ours = extend(&mut tree, genesis, 1..=3);
+        let mut finality = Finality::new(&schedule);
+        for seed in 2..=4 {
+            finality
+                .add(&Attestation::new(ours[1].hash(), 2, &key(seed)), &mut tree)
+                .unwrap();
+        }
+        assert_eq!(tree.finalized(), Some(ours[1].hash()));
+
+        let theirs: Vec<Block> = (4..=7)
+            .scan(ours[0].hash(), |parent, slot| {
+                let block = sealed(&schedule, *parent, slot);
+                *parent = block.hash();
+                Some(block)
+            })
+            .collect();
+        for block in theirs[..3].iter() {
+            let insertion = tree.insert(block.clone(), NOW).unwrap();
+            let Insertion::Stored { reorg, .. } = insertion else {
+                panic!("the parent is stored");
+            };
+            assert!(reorg.is_empty());
+        }
+        assert!(tree.chain_work(&theirs[2].hash()) > tree.chain_work(&ours[2].hash()));
+        assert_eq!(tree.chain().blocks()[1..], ours[..]);
+        assert!(theirs[..3]
+            .iter()
+            .all(|block| tree.is_invalid(&block.hash())));
+        assert_eq!(
+            tree.insert(theirs[3].clone(), NOW),
+            Err(ChainError::InvalidAncestor(theirs[2].hash()))
+        );
+
+        assert_eq!(
+            tree.finalize(&theirs[1].hash()),
+            Err(ChainError::ConflictsWithFinal(theirs[1].hash()))
+        );
+        assert_eq!(tree.finalize(&ours[0].hash()), Ok(()));
+        assert_eq!(tree.finalized(), Some(ours[1].hash()));
+        assert_eq!(tree.finalize(&ours[2].hash()), Ok(()));
+        assert_eq!(tree.finalized(), Some(ours[2].hash()));
+    }
+}
@@ -0,0 +1,259 @@
+use super::block::BlockHeader;
+use super::chain_error::ChainError;
+use super::target::{Target, BITCOIN_POW_LIMIT};
+
+/// The consensus rules of a chain that depend on how fast blocks should come.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainParams {
+    /// The easiest target a block may have, in compact encoding.
+    pub pow_limit: u32,
+    /// Seconds the chain aims to have between two blocks.
+    pub target_spacing: u32,
+    /// Number of blocks between two target adjustments.
+    pub retarget_interval: u32,
+    /// The value the coinbase of the first blocks may create.
+    pub block_reward: u64,
+    /// Number of blocks after which the block reward halves.
+    pub halving_interval: usize,
+}
+
+impl ChainParams {
+    /// The rules of Bitcoin's main network: a block every 10 minutes, with the target
+    /// adjusted every 2016 blocks, about two weeks.
+    pub fn bitcoin() -> Self {
+        ChainParams {
+            pow_limit: BITCOIN_POW_LIMIT,
+            target_spacing: 600,
+            retarget_interval: 2016,
+            block_reward: 50 * 100_000_000,
+            halving_interval: 210_000,
+        }
+    }
+
+    /// Rules with an easy limit, where about one hash in two meets the target, so tests
+    /// can mine blocks instantly.
+    pub fn regtest() -> Self {
+        ChainParams {
+            pow_limit: 0x207fffff,
+            target_spacing: 600,
+            retarget_interval: 2016,
+            block_reward: 50 * 100_000_000,
+            halving_interval: 150,
+        }
+    }
+
+    /// Checks that the rules make sense: a valid limit, and a spacing and an interval
+    /// that aren't zero, which retargeting divides by.
+    ///
+    /// # Returns
+    /// An error of kind `InvalidParams` saying which rule is wrong.
+    pub fn validate(&self) -> Result<(), ChainError> {
+        if Target::from_compact(self.pow_limit).is_none() {
+            return Err(ChainError::InvalidParams(format!(
+                "invalid proof-of-work limit {:#010x}",
+                self.pow_limit
+            )));
+        }
+        if self.target_spacing == 0 || self.retarget_interval == 0 {
+            return Err(ChainError::InvalidParams(
+                "the target spacing and retarget interval must not be zero".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the time the chain aims to take for `retarget_interval` blocks, which
+    /// doesn't fit in a `u32` for large spacings and intervals.
+    pub fn target_timespan(&self) -> u64 {
+        self.target_spacing as u64 * self.retarget_interval as u64
+    }
+
+    /// Returns the value the coinbase of the block at `height` may create, on top of the
+    /// fees of its transactions.
+    pub fn subsidy(&self, height: usize) -> u64 {
+        match height / self.halving_interval.max(1) {
+            halvings if halvings < 64 => self.block_reward >> halvings,
+            _ => 0,
+        }
+    }
+
+    /// Returns `true` if the target of the block at `height` is adjusted rather than
+    /// copied from its parent.
+    pub fn is_retarget_height(&self, height: usize) -> bool {
+        height > 0 && height.is_multiple_of(self.retarget_interval as usize)
+    }
+}
+
+/// Returns `true` if the hash of `header` meets the target in its `bits`, which must be
+/// a valid target.
+pub fn check_proof_of_work(header: &BlockHeader) -> bool {
+    Target::from_compact(header.bits).is_some_and(|target| target.is_met_by(&header.hash()))
+}
+
+/// Searches for a nonce that makes the hash of `header` meet its target, starting at
+/// its current nonce. In O(2^256 / target) time on average.
+///
+/// # Returns
+/// `true` with `header.nonce` set to the nonce found, or `false` if the target is
+/// invalid or every remaining nonce was tried, in which case the header is unchanged.
+pub fn proof_of_work(header: &mut BlockHeader) -> bool {
+    let Some(target) = Target::from_compact(header.bits) else {
+        return false;
+    };
+
+    let mut candidate = *header;
+    loop {
+        if target.is_met_by(&candidate.hash()) {
+            *header = candidate;
+            return true;
+        }
+        match candidate.nonce.checked_add(1) {
+            Some(nonce) => candidate.nonce = nonce,
+            None => return false,
+        }
+    }
+}
+
+/// Returns the target of the first block of a new retarget interval, as in Bitcoin:
+/// the previous target scaled by how long the interval took compared to how long it
+/// should have taken. The factor is clamped to [1/4, 4] so the target can't swing
+/// wildly, and the result never gets easier than the limit.
+///
+/// # Parameters
+/// - `bits`: The target of the previous interval, in compact encoding
+/// - `actual_timespan`: Seconds between the first and last blocks of the interval
+/// - `params`: The rules of the chain
+///
+/// # Returns
+/// The new target, in compact encoding.
+pub fn retarget(bits: u32, actual_timespan: u32, params: &ChainParams) -> u32 {
+    // Params that `ChainParams::validate` rejects never divide by zero either
+    let expected = params.target_timespan().max(1);
+    let timespan = (actual_timespan as u64).clamp(expected / 4, expected.saturating_mul(4));
+
+    let limit = Target::from_compact(params.pow_limit).unwrap_or(Target::MAX);
+    let target = Target::from_compact(bits).unwrap_or(limit);
+    // Easy targets overflow once multiplied, dividing first only loses their low bits
+    let adjusted = match target.checked_mul(timespan) {
+        Some(product) => product.div_u64(expected),
+        None => target
+            .div_u64(expected)
+            .checked_mul(timespan)
+            .unwrap_or(limit),
+    };
+
+    adjusted.min(limit).to_compact()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::Block;
+    use crate::blockchain::chain::Chain;
+
+    /// Test the retargeting against the cases of Bitcoin Core's tests
+    #[test]
+    fn bitcoin_retargets() {
+        let params = ChainParams::bitcoin();
+        let cases = [
+            // Block 32256, the first adjustment of Bitcoin's main network
+            (1261130161, 1262152739, 0x1d00ffff, 0x1d00d86a),
+            // Blocks came too slowly, but the target can't get easier than the limit
+            (1231006505, 1233061996, 0x1d00ffff, 0x1d00ffff),
+            // Blocks came more than 4 times too fast
+            (1279008237, 1279297671, 0x1c05a3f4, 0x1c0168fd),
+            // Blocks came more than 4 times too slowly
+            (1263163443, 1269211443, 0x1c387f6f, 0x1d00e1fd),
+        ];
+        for (first, last, bits, expected) in cases {
+            assert_eq!(retarget(bits, last - first, &params), expected);
+        }
+    }
+
+    /// Test that a block is mined quickly at a low difficulty and fails once changed
+    #[test]
+    fn mines_low_difficulty_block() {
+        let mut header = Block::new([0; 32], 1_700_000_000, 0x2000ffff, vec![]).header;
+        assert!(proof_of_work(&mut header));
+        assert!(check_proof_of_work(&header));
+        // The hash starts with at least 8 zero bits once displayed
+        assert_eq!(header.hash()[31], 0);
+
+        let target = Target::from_compact(header.bits).unwrap();
+        let mut tampered = header;
+        tampered.timestamp += 1;
+        while target.is_met_by(&tampered.hash()) {
+            tampered.timestamp += 1;
+        }
+        assert!(!check_proof_of_work(&tampered));
+    }
+
+    /// Test that the block reward halves every interval until it is gone
+    #[test]
+    fn subsidy_halves() {
+        let params = ChainParams::bitcoin();
+        assert_eq!(params.subsidy(0), 5_000_000_000);
+        assert_eq!(params.subsidy(209_999), 5_000_000_000);
+        assert_eq!(params.subsidy(210_000), 2_500_000_000);
+        assert_eq!(params.subsidy(420_000 * 2), 312_500_000);
+        assert_eq!(params.subsidy(210_000 * 33), 0);
+        assert_eq!(params.subsidy(usize::MAX), 0);
+    }
+
+    /// Test that the search gives up when the nonces run out or the target is invalid
+    #[test]
+    fn gives_up() {
+        let mut header = Block::new([0; 32], 1_700_000_000, 0x03000001, vec![]).header;
+        header.nonce = u32::MAX - 1000;
+        let before = header;
+        assert!(!proof_of_work(&mut header));
+        assert_eq!(header, before);
+
+        header.bits = 0x04923456;
+        assert!(!proof_of_work(&mut header));
+        assert!(!check_proof_of_work(&header));
+    }
+
+    /// Test that params that would divide by zero are rejected, and that large ones
+    /// retarget without overflowing
+    #[test]
+    fn validates_params() {
+        assert_eq!(ChainParams::bitcoin().validate(), Ok(()));
+        let zero_spacing = ChainParams {
+            target_spacing: 0,
+            ..ChainParams::regtest()
+        };
+        let zero_interval = ChainParams {
+            retarget_interval: 0,
+            ..ChainParams::regtest()
+        };
+        let bad_limit = ChainParams {
+            pow_limit: 0x04923456,
+            ..ChainParams::regtest()
+        };
+        for params in [zero_spacing, zero_interval, bad_limit] {
+            assert!(matches!(
+                params.validate(),
+                Err(ChainError::InvalidParams(_))
+            ));
+            // Still no panic when called anyway
+            retarget(0x1d00ffff, 600, &params);
+        }
+        let genesis = Block::new([0; 32], 1_700_000_000, 0x207fffff, vec![]);
+        assert!(matches!(
+            Chain::new(genesis, zero_spacing),
+            Err(ChainError::InvalidParams(_))
+        ));
+
+        let huge = ChainParams {
+            target_spacing: u32::MAX,
+            retarget_interval: u32::MAX,
+            ..ChainParams::bitcoin()
+        };
+        assert_eq!(huge.target_timespan(), u32::MAX as u64 * u32::MAX as u64);
+        // Any timespan is far below the expected one, so the target gets 4 times harder,
+        // give or take the rounding of the quarter of the expected timespan
+        assert_eq!(retarget(0x1c100000, 1, &huge), 0x1c03ffff);
+        assert_eq!(retarget(0x1c100000, u32::MAX, &huge), 0x1c03ffff);
+    }
+}
@@ -0,0 +1,358 @@
+use std::cmp::Ordering;
+
+use super::Hash;
+
+/// The largest target, in compact encoding, that Bitcoin's main network allows: hashes
+/// must start with 32 zero bits.
+pub const BITCOIN_POW_LIMIT: u32 = 0x1d00ffff;
+
+/// A 256-bit unsigned integer that a block hash must not exceed.
+///
+/// Block hashes are read as little-endian integers, as in Bitcoin, so the zero bytes a
+/// valid hash starts with when displayed are at the end of the `Hash` array.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Target {
+    /// The 64-bit limbs of the integer, least significant first.
+    limbs: [u64; 4],
+}
+
+impl Target {
+    /// The target 0, which no hash meets.
+    pub const ZERO: Target = Target { limbs: [0; 4] };
+
+    /// The target 2^256 - 1, which every hash meets.
+    pub const MAX: Target = Target {
+        limbs: [u64::MAX; 4],
+    };
+
+    /// Creates a target from a small integer.
+    pub fn from_u64(value: u64) -> Self {
+        Target {
+            limbs: [value, 0, 0, 0],
+        }
+    }
+
+    /// Creates a target from its 32 bytes, most significant first.
+    pub fn from_be_bytes(bytes: [u8; 32]) -> Self {
+        let mut limbs = [0; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let start = 32 - 8 * (i + 1);
+            *limb = u64::from_be_bytes(bytes[start..start + 8].try_into().unwrap());
+        }
+        Target { limbs }
+    }
+
+    /// Returns the 32 bytes of the target, most significant first.
+    pub fn to_be_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for (i, limb) in self.limbs.iter().enumerate() {
+            let start = 32 - 8 * (i + 1);
+            bytes[start..start + 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Decodes a target from Bitcoin's compact encoding: a one-byte exponent `e`
+    /// followed by a three-byte mantissa `m`, for `m * 256^(e - 3)`.
+    ///
+    /// # Returns
+    /// `None` if the sign bit of the mantissa is set or the target doesn't fit in 256
+    /// bits, which Bitcoin treats as invalid.
+    pub fn from_compact(bits: u32) -> Option<Self> {
+        let exponent = bits >> 24;
+        let mantissa = bits & 0x007f_ffff;
+        if bits & 0x0080_0000 != 0 && mantissa != 0 {
+            return None;
+        }
+
+        if exponent <= 3 {
+            return Some(Target::from_u64((mantissa >> (8 * (3 - exponent))) as u64));
+        }
+        let target = Target::from_u64(mantissa as u64);
+        let shift = 8 * (exponent - 3);
+        if mantissa != 0 && target.bits() + shift > 256 {
+            return None;
+        }
+        Some(target.shl(shift))
+    }
+
+    /// Encodes the target in Bitcoin's compact encoding, keeping its three most
+    /// significant bytes. Decoding the result gives the target rounded down.
+    pub fn to_compact(&self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut mantissa = if size <= 3 {
+            (self.limbs[0] << (8 * (3 - size))) as u32
+        } else {
+            self.shr(8 * (size - 3)).limbs[0] as u32
+        };
+        // The top bit of the mantissa is a sign bit, move it to the exponent instead
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        mantissa | (size << 24)
+    }
+
+    /// Returns `true` if `hash`, read as a little-endian integer, is at most the target.
+    pub fn is_met_by(&self, hash: &Hash) -> bool {
+        let mut bytes = *hash;
+        bytes.reverse();
+        Target::from_be_bytes(bytes) <= *self
+    }
+
+    /// Returns the expected number of hashes needed to find one meeting the target,
+    /// 2^256 / (target + 1), saturated at `u128::MAX`. The chain with the most
+    /// cumulative work is the one that cost the most to produce.
+    pub fn work(&self) -> u128 {
+        if *self == Target::MAX {
+            return 1;
+        }
+        if *self == Target::ZERO {
+            return u128::MAX;
+        }
+        // 2^256 doesn't fit, but 2^256 / (t + 1) = (2^256 - t - 1) / (t + 1) + 1
+        let divisor = self.add_u64(1);
+        let work = self.not().div(&divisor).add_u64(1);
+        if work.limbs[2] != 0 || work.limbs[3] != 0 {
+            return u128::MAX;
+        }
+        ((work.limbs[1] as u128) << 64) | work.limbs[0] as u128
+    }
+
+    /// Returns the target multiplied by `factor`, or `None` if it doesn't fit in 256 bits.
+    pub fn checked_mul(&self, factor: u64) -> Option<Self> {
+        let mut limbs = [0; 4];
+        let mut carry = 0u128;
+        for (i, limb) in self.limbs.iter().enumerate() {
+            let product = *limb as u128 * factor as u128 + carry;
+            limbs[i] = product as u64;
+            carry = product >> 64;
+        }
+        (carry == 0).then_some(Target { limbs })
+    }
+
+    /// Returns the target divided by `divisor`, rounded down.
+    ///
+    /// # Panics
+    /// If `divisor` is 0.
+    pub fn div_u64(&self, divisor: u64) -> Self {
+        let mut limbs = [0; 4];
+        let mut remainder = 0u128;
+        for i in (0..4).rev() {
+            let dividend = (remainder << 64) | self.limbs[i] as u128;
+            limbs[i] = (dividend / divisor as u128) as u64;
+            remainder = dividend % divisor as u128;
+        }
+        Target { limbs }
+    }
+
+    /// Returns the number of significant bits.
+    fn bits(&self) -> u32 {
+        for i in (0..4).rev() {
+            if self.limbs[i] != 0 {
+                return 64 * i as u32 + 64 - self.limbs[i].leading_zeros();
+            }
+        }
+        0
+    }
+
+    fn shl(&self, shift: u32) -> Self {
+        let mut limbs = [0; 4];
+        let (words, bits) = ((shift / 64) as usize, shift % 64);
+        for i in (words..4).rev() {
+            limbs[i] = self.limbs[i - words] << bits;
+            if bits > 0 && i > words {
+                limbs[i] |= self.limbs[i - words - 1] >> (64 - bits);
+            }
+        }
+        Target { limbs }
+    }
+
+    fn shr(&self, shift: u32) -> Self {
+        let mut limbs = [0; 4];
+        let (words, bits) = ((shift / 64) as usize, shift % 64);
+        for (i, limb) in limbs
+            .iter_mut()
+            .enumerate()
+            .take(4usize.saturating_sub(words))
+        {
+            *limb = self.limbs[i + words] >> bits;
+            if bits > 0 && i + words + 1 < 4 {
+                *limb |= self.limbs[i + words + 1] << (64 - bits);
+            }
+        }
+        Target { limbs }
+    }
+
+    fn not(&self) -> Self {
+        Target {
+            limbs: self.limbs.map(|limb| !limb),
+        }
+    }
+
+    /// Adds a small integer, wrapping around at 2^256.
+    fn add_u64(&self, value: u64) -> Self {
+        let mut limbs = self.limbs;
+        let mut carry = value;
+        for limb in limbs.iter_mut() {
+            let (sum, overflow) = limb.overflowing_add(carry);
+            *limb = sum;
+            carry = overflow as u64;
+        }
+        Target { limbs }
+    }
+
+    fn sub(&self, other: &Self) -> Self {
+        let mut limbs = self.limbs;
+        let mut borrow = false;
+        for (limb, other) in limbs.iter_mut().zip(other.limbs) {
+            let (difference, first) = limb.overflowing_sub(other);
+            let (difference, second) = difference.overflowing_sub(borrow as u64);
+            *limb = difference;
+            borrow = first || second;
+        }
+        Target { limbs }
+    }
+
+    /// Long division, one bit at a time. Only used to compute the work of a target.
+    fn div(&self, divisor: &Self) -> Self {
+        let mut quotient = Target::ZERO;
+        let mut remainder = Target::ZERO;
+        for bit in (0..self.bits()).rev() {
+            remainder = remainder.shl(1);
+            remainder.limbs[0] |= (self.limbs[bit as usize / 64] >> (bit % 64)) & 1;
+            if remainder >= *divisor {
+                remainder = remainder.sub(divisor);
+                quotient.limbs[bit as usize / 64] |= 1 << (bit % 64);
+            }
+        }
+        quotient
+    }
+}
+
+impl PartialOrd for Target {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Target {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.limbs.iter().rev().cmp(other.limbs.iter().rev())
+    }
+}
+
+/// Returns how many times harder it is to meet the target `bits` than Bitcoin's easiest
+/// target, as Bitcoin reports difficulty. 0 if `bits` isn't a valid target.
+pub fn difficulty(bits: u32) -> f64 {
+    let to_f64 = |target: Target| {
+        target
+            .limbs
+            .iter()
+            .rev()
+            .fold(0.0, |value, limb| value * 2f64.powi(64) + *limb as f64)
+    };
+    match Target::from_compact(bits) {
+        Some(target) if target != Target::ZERO => {
+            to_f64(Target::from_compact(BITCOIN_POW_LIMIT).unwrap()) / to_f64(target)
+        }
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::merkle_hash::from_hex;
+
+    fn target(hex: &str) -> Target {
+        Target::from_be_bytes(from_hex(hex).unwrap())
+    }
+
+    /// Test the compact encoding against the values of Bitcoin Core's tests
+    #[test]
+    fn compact_encoding() {
+        let cases = [
+            (0x00000000, Some(Target::ZERO), 0x00000000),
+            (0x00123456, Some(Target::ZERO), 0x00000000),
+            (0x01003456, Some(Target::ZERO), 0x00000000),
+            (0x01123456, Some(Target::from_u64(0x12)), 0x01120000),
+            (0x02123456, Some(Target::from_u64(0x1234)), 0x02123400),
+            (0x03123456, Some(Target::from_u64(0x123456)), 0x03123456),
+            (0x04123456, Some(Target::from_u64(0x12345600)), 0x04123456),
+            (0x05009234, Some(Target::from_u64(0x92340000)), 0x05009234),
+            (0x04923456, None, 0),
+            (0xff123456, None, 0),
+        ];
+        for (bits, expected, reencoded) in cases {
+            let decoded = Target::from_compact(bits);
+            assert_eq!(decoded, expected, "{:#010x}", bits);
+            if let Some(decoded) = decoded {
+                assert_eq!(decoded.to_compact(), reencoded, "{:#010x}", bits);
+            }
+        }
+
+        let limit = Target::from_compact(BITCOIN_POW_LIMIT).unwrap();
+        assert_eq!(
+            limit,
+            target("00000000ffff0000000000000000000000000000000000000000000000000000")
+        );
+        assert_eq!(limit.to_compact(), BITCOIN_POW_LIMIT);
+
+        let regtest = target("7fffff0000000000000000000000000000000000000000000000000000000000");
+        assert_eq!(Target::from_compact(0x207fffff), Some(regtest));
+        assert_eq!(Target::from_compact(0x20800001), None);
+    }
+
+    /// Test that hashes are compared to the target as little-endian integers
+    #[test]
+    fn hash_is_compared_as_little_endian() {
+        let limit = Target::from_compact(BITCOIN_POW_LIMIT).unwrap();
+
+        let mut hash = [0; 32];
+        hash[26..28].copy_from_slice(&[0xff, 0xff]);
+        assert!(limit.is_met_by(&hash));
+
+        hash[0] = 0x01;
+        assert!(!limit.is_met_by(&hash));
+
+        hash = [0xff; 32];
+        hash[26..].fill(0);
+        hash[26] = 0xfe;
+        assert!(limit.is_met_by(&hash));
+
+        assert!(!limit.is_met_by(&[0xff; 32]));
+        assert!(Target::MAX.is_met_by(&[0xff; 32]));
+    }
+
+    /// Test the expected number of hashes for a few targets
+    #[test]
+    fn work() {
+        assert_eq!(Target::MAX.work(), 1);
+        assert_eq!(Target::from_compact(0x207fffff).unwrap().work(), 2);
+        // Bitcoin's genesis block: 2^32 / 0xffff... rounded down
+        assert_eq!(
+            Target::from_compact(BITCOIN_POW_LIMIT).unwrap().work(),
+            0x0000_0001_0001_0001
+        );
+        assert_eq!(Target::ZERO.work(), u128::MAX);
+
+        assert_eq!(difficulty(BITCOIN_POW_LIMIT), 1.0);
+        assert!((difficulty(0x1b0404cb) - 16307.420938523983).abs() < 1e-6);
+    }
+
+    /// Test the arithmetic the retargeting relies on
+    #[test]
+    fn multiply_and_divide() {
+        let limit = Target::from_compact(BITCOIN_POW_LIMIT).unwrap();
+        assert_eq!(limit.checked_mul(4).unwrap().div_u64(4), limit);
+        assert_eq!(Target::MAX.checked_mul(2), None);
+        assert_eq!(
+            Target::from_u64(u64::MAX)
+                .checked_mul(1 << 32)
+                .unwrap()
+                .div_u64(1 << 32),
+            Target::from_u64(u64::MAX)
+        );
+    }
+}
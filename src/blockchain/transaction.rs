@@ -0,0 +1,300 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+use super::chain_error::ChainError;
+use super::{double_sha256, Hash};
+use crate::bytes::{take, take_array, take_u32};
+
+/// The public key of the owner of an output, as Ed25519 encodes it.
+pub type PublicKey = [u8; 32];
+
+/// Identifies an output: the id of the transaction that created it and its position in
+/// the outputs of that transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub txid: Hash,
+    pub index: u32,
+}
+
+impl OutPoint {
+    /// Creates the out point of the output at `index` of the transaction `txid`.
+    pub fn new(txid: Hash, index: u32) -> Self {
+        OutPoint { txid, index }
+    }
+}
+
+/// Spends an output, proving it with a signature of its owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxInput {
+    pub previous: OutPoint,
+    /// Ed25519 signature of `Transaction::signing_hash` by the owner of the output.
+    pub signature: [u8; 64],
+}
+
+/// An amount that only the holder of the private key of `owner` can spend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxOutput {
+    /// The amount, in the smallest unit of the currency.
+    pub value: u64,
+    pub owner: PublicKey,
+}
+
+impl TxOutput {
+    /// Creates an output of `value` owned by `owner`.
+    pub fn new(value: u64, owner: &VerifyingKey) -> Self {
+        TxOutput {
+            value,
+            owner: owner.to_bytes(),
+        }
+    }
+}
+
+/// A transfer that spends outputs of earlier transactions and creates new ones.
+///
+/// A transaction without inputs is a coinbase: the first transaction of a block, which
+/// creates the reward of the block's producer out of nothing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    pub inputs: Vec<TxInput>,
+    pub outputs: Vec<TxOutput>,
+    /// Free bytes, such as a payment reference. A coinbase puts the height of its block
+    /// here, so that two coinbases paying the same producer don't have the same id.
+    pub data: Vec<u8>,
+}
+
+impl Transaction {
+    /// Creates a transaction spending `previous` into `outputs`. Its inputs must be
+    /// signed with `sign` or `sign_input` before it is valid.
+    pub fn new(previous: Vec<OutPoint>, outputs: Vec<TxOutput>) -> Self {
+        let inputs = previous
+            .into_iter()
+            .map(|previous| TxInput {
+                previous,
+                signature: [0; 64],
+            })
+            .collect();
+        Transaction {
+            inputs,
+            outputs,
+            data: vec![],
+        }
+    }
+
+    /// Creates the coinbase of the block at `height`, paying `outputs`.
+    pub fn coinbase(height: usize, outputs: Vec<TxOutput>) -> Self {
+        Transaction {
+            inputs: vec![],
+            outputs,
+            data: (height as u64).to_le_bytes().to_vec(),
+        }
+    }
+
+    /// Returns `true` if the transaction has no inputs.
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// Returns the id of the transaction: the double SHA-256 of its serialized form.
+    pub fn id(&self) -> Hash {
+        double_sha256(&self.to_bytes())
+    }
+
+    /// Returns the sum of the outputs, or `None` if it overflows.
+    pub fn output_value(&self) -> Option<u64> {
+        self.outputs
+            .iter()
+            .try_fold(0u64, |total, output| total.checked_add(output.value))
+    }
+
+    /// Returns what every input signs: the double SHA-256 of the transaction serialized
+    /// without its signatures. A signature covers every input and output, so none of
+    /// them can be changed once it is signed.
+    pub fn signing_hash(&self) -> Hash {
+        double_sha256(&self.serialize(false))
+    }
+
+    /// Signs the input at `index` with `key`, which must own the output it spends.
+    ///
+    /// # Panics
+    /// If there is no input at `index`.
+    pub fn sign_input(&mut self, index: usize, key: &SigningKey) {
+        let signature = key.sign(&self.signing_hash());
+        self.inputs[index].signature = signature.to_bytes();
+    }
+
+    /// Signs every input with `key`, for a transaction spending outputs of a single owner.
+    pub fn sign(&mut self, key: &SigningKey) {
+        let signature = key.sign(&self.signing_hash()).to_bytes();
+        for input in self.inputs.iter_mut() {
+            input.signature = signature;
+        }
+    }
+
+    /// Returns `true` if the input at `index` is signed by `owner`. Signatures are
+    /// checked strictly, so a valid signature can't be altered into another valid one.
+    ///
+    /// # Parameters
+    /// - `signing_hash`: the `signing_hash` of the transaction, computed once by the
+    ///   caller for all of its inputs since it covers the whole transaction
+    pub fn verify_input(&self, index: usize, owner: &PublicKey, signing_hash: &Hash) -> bool {
+        let (Some(input), Ok(key)) = (self.inputs.get(index), VerifyingKey::from_bytes(owner))
+        else {
+            return false;
+        };
+        key.verify_strict(signing_hash, &Signature::from_bytes(&input.signature))
+            .is_ok()
+    }
+
+    /// Serializes the transaction: the number of inputs, then each input as the id and
+    /// index of the output it spends and its signature, the number of outputs, then each
+    /// output as its value and owner, and finally the length of the data followed by the
+    /// data. Counts, indices and values are little-endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.serialize(true)
+    }
+
+    /// Deserializes a transaction written by `to_bytes`.
+    ///
+    /// # Returns
+    /// `ChainError::MalformedBlock` if `bytes` is truncated or has trailing bytes.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, ChainError> {
+        let transaction = Transaction::read(&mut bytes)
+            .ok_or_else(|| ChainError::MalformedBlock("truncated transaction".to_string()))?;
+        if !bytes.is_empty() {
+            return Err(ChainError::MalformedBlock(format!(
+                "{} trailing bytes after the transaction",
+                bytes.len()
+            )));
+        }
+        Ok(transaction)
+    }
+
+    /// Removes a transaction written by `to_bytes` from the front of `bytes`, or returns
+    /// `None` if it is truncated.
+    pub(super) fn read(bytes: &mut &[u8]) -> Option<Self> {
+        // Counts aren't trusted to reserve memory: a truncated list fails on its own
+        let mut inputs = vec![];
+        for _ in 0..take_u32(bytes)? {
+            let previous = OutPoint::new(take_array(bytes)?, take_u32(bytes)?);
+            let signature = take_array(bytes)?;
+            inputs.push(TxInput {
+                previous,
+                signature,
+            });
+        }
+        let mut outputs = vec![];
+        for _ in 0..take_u32(bytes)? {
+            let value = u64::from_le_bytes(take_array(bytes)?);
+            let owner = take_array(bytes)?;
+            outputs.push(TxOutput { value, owner });
+        }
+        let len = take_u32(bytes)? as usize;
+        let data = take(bytes, len)?.to_vec();
+        Some(Transaction {
+            inputs,
+            outputs,
+            data,
+        })
+    }
+
+    fn serialize(&self, with_signatures: bool) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            12 + self.inputs.len() * 100 + self.outputs.len() * 40 + self.data.len(),
+        );
+        bytes.extend_from_slice(&(self.inputs.len() as u32).to_le_bytes());
+        for input in self.inputs.iter() {
+            bytes.extend_from_slice(&input.previous.txid);
+            bytes.extend_from_slice(&input.previous.index.to_le_bytes());
+            if with_signatures {
+                bytes.extend_from_slice(&input.signature);
+            }
+        }
+        bytes.extend_from_slice(&(self.outputs.len() as u32).to_le_bytes());
+        for output in self.outputs.iter() {
+            bytes.extend_from_slice(&output.value.to_le_bytes());
+            bytes.extend_from_slice(&output.owner);
+        }
+        bytes.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    /// Test that a signature covers the whole transaction and only verifies for its owner
+    #[test]
+    fn signature_covers_transaction() {
+        let alice = key(1);
+        let bob = key(2);
+        let mut transaction = Transaction::new(
+            vec![OutPoint::new([9; 32], 0), OutPoint::new([9; 32], 1)],
+            vec![TxOutput::new(70, &bob.verifying_key())],
+        );
+        let unsigned_id = transaction.id();
+
+        transaction.sign(&alice);
+        let owner = alice.verifying_key().to_bytes();
+        let hash = transaction.signing_hash();
+        assert!(transaction.verify_input(0, &owner, &hash));
+        assert!(transaction.verify_input(1, &owner, &hash));
+        assert!(!transaction.verify_input(0, &bob.verifying_key().to_bytes(), &hash));
+        assert!(!transaction.verify_input(2, &owner, &hash));
+        assert_ne!(transaction.id(), unsigned_id);
+
+        let mut redirected = transaction.clone();
+        redirected.outputs[0].owner = alice.verifying_key().to_bytes();
+        assert!(!redirected.verify_input(0, &owner, &redirected.signing_hash()));
+
+        let mut tampered = transaction.clone();
+        tampered.inputs[1].signature[10] ^= 1;
+        assert!(transaction.verify_input(1, &owner, &hash));
+        assert!(!tampered.verify_input(1, &owner, &hash));
+    }
+
+    /// Test that a transaction survives a round trip through its serialized form
+    #[test]
+    fn bytes_round_trip() {
+        let mut transaction = Transaction::new(
+            vec![OutPoint::new([9; 32], 3)],
+            vec![TxOutput::new(70, &key(2).verifying_key())],
+        );
+        transaction.data = b"invoice 42".to_vec();
+        transaction.sign(&key(1));
+
+        let bytes = transaction.to_bytes();
+        assert_eq!(Transaction::from_bytes(&bytes), Ok(transaction));
+        assert!(Transaction::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Transaction::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(Transaction::from_bytes(&[0xff; 4]).is_err());
+    }
+
+    /// Test that coinbases of different heights have different ids
+    #[test]
+    fn coinbase_ids_differ_by_height() {
+        let output = TxOutput::new(50, &key(1).verifying_key());
+        let first = Transaction::coinbase(1, vec![output]);
+        let second = Transaction::coinbase(2, vec![output]);
+
+        assert!(first.is_coinbase());
+        assert_ne!(first.id(), second.id());
+        assert_eq!(first.output_value(), Some(50));
+
+        let overflowing = Transaction::coinbase(
+            1,
+            vec![
+                output,
+                TxOutput {
+                    value: u64::MAX,
+                    ..output
+                },
+            ],
+        );
+        assert_eq!(overflowing.output_value(), None);
+    }
+}
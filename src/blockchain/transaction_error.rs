@@ -0,0 +1,76 @@
+use std::error::Error;
+use std::fmt;
+
+use super::transaction::OutPoint;
+use crate::merkle::merkle_hash::to_hex;
+
+/// Reasons a transaction can't be applied to the set of unspent outputs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    /// The output doesn't exist, or was already spent.
+    MissingOutput(OutPoint),
+    /// The output is spent twice, by the same transaction or the same block.
+    DoubleSpend(OutPoint),
+    /// The input at this position isn't signed by the owner of the output it spends.
+    InvalidSignature(usize),
+    /// The outputs are worth more than the inputs.
+    InsufficientInputs { inputs: u64, outputs: u64 },
+    /// The sum of the inputs or of the outputs doesn't fit in a `u64`.
+    ValueOverflow,
+    /// The transaction creates an output that is already unspent, which happens when
+    /// the same transaction is included twice.
+    DuplicateOutput(OutPoint),
+    /// The first transaction of a block isn't a coinbase.
+    MissingCoinbase,
+    /// A coinbase appears after the first transaction of a block.
+    MisplacedCoinbase,
+    /// The coinbase pays more than the block reward plus the fees of the block.
+    CoinbaseTooLarge { claimed: u64, allowed: u64 },
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::MissingOutput(out_point) => write!(
+                f,
+                "output {}:{} doesn't exist or is spent",
+                to_hex(&out_point.txid),
+                out_point.index
+            ),
+            TransactionError::DoubleSpend(out_point) => write!(
+                f,
+                "output {}:{} is spent twice",
+                to_hex(&out_point.txid),
+                out_point.index
+            ),
+            TransactionError::InvalidSignature(input) => {
+                write!(f, "input {} isn't signed by the owner of its output", input)
+            }
+            TransactionError::InsufficientInputs { inputs, outputs } => write!(
+                f,
+                "outputs are worth {} but inputs only {}",
+                outputs, inputs
+            ),
+            TransactionError::ValueOverflow => write!(f, "values overflow"),
+            TransactionError::DuplicateOutput(out_point) => write!(
+                f,
+                "output {}:{} already exists",
+                to_hex(&out_point.txid),
+                out_point.index
+            ),
+            TransactionError::MissingCoinbase => {
+                write!(f, "the first transaction isn't a coinbase")
+            }
+            TransactionError::MisplacedCoinbase => {
+                write!(f, "only the first transaction may be a coinbase")
+            }
+            TransactionError::CoinbaseTooLarge { claimed, allowed } => write!(
+                f,
+                "coinbase claims {} but only {} is allowed",
+                claimed, allowed
+            ),
+        }
+    }
+}
+
+impl Error for TransactionError {}
@@ -0,0 +1,454 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::chain_error::ChainError;
+use super::transaction::{OutPoint, PublicKey, Transaction, TxOutput};
+use super::transaction_error::TransactionError;
+
+/// What a block changes in the set of unspent outputs. Applying it removes `spent` and
+/// adds `created`, and undoing it does the opposite.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UtxoChanges {
+    /// The outputs the block spends that existed before it, with their content so they
+    /// can be restored.
+    pub spent: Vec<(OutPoint, TxOutput)>,
+    /// The outputs the block creates that are still unspent after it.
+    pub created: Vec<(OutPoint, TxOutput)>,
+}
+
+/// The set of unspent transaction outputs (UTXOs): every amount that can still be spent,
+/// and who owns it.
+///
+/// # Methods
+/// - `get`: Returns an unspent output.
+/// - `balance`: Returns the total value owned by a public key.
+/// - `validate_transaction`: Checks that a transaction can spend outputs of the set.
+/// - `validate_block`: Checks every transaction of a block, returning what it changes.
+/// - `apply`: Applies the changes of a validated block.
+/// - `revert`: Undoes the changes of the last block applied.
+/// - `apply_block`: Validates and applies a block, or changes nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UtxoSet {
+    outputs: HashMap<OutPoint, TxOutput>,
+}
+
+impl UtxoSet {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        UtxoSet::default()
+    }
+
+    /// Returns the output at `out_point`, if it is unspent.
+    pub fn get(&self, out_point: &OutPoint) -> Option<&TxOutput> {
+        self.outputs.get(out_point)
+    }
+
+    /// Returns the number of unspent outputs.
+    pub fn len(&self) -> usize {
+        self.outputs.len()
+    }
+
+    /// Returns `true` if there are no unspent outputs.
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+
+    /// Returns the unspent outputs owned by `owner`, sorted by out point. In O(n) time,
+    /// with n = number of unspent outputs.
+    pub fn outputs_of(&self, owner: &PublicKey) -> Vec<(OutPoint, TxOutput)> {
+        let mut outputs: Vec<_> = self
+            .outputs
+            .iter()
+            .filter(|(_, output)| output.owner == *owner)
+            .map(|(out_point, output)| (*out_point, *output))
+            .collect();
+        outputs.sort_unstable_by_key(|(out_point, _)| *out_point);
+        outputs
+    }
+
+    /// Returns the total value of the unspent outputs owned by `owner`.
+    pub fn balance(&self, owner: &PublicKey) -> u64 {
+        self.outputs
+            .values()
+            .filter(|output| output.owner == *owner)
+            .map(|output| output.value)
+            .sum()
+    }
+
+    /// Checks that `transaction` only spends unspent outputs, each signed by its owner,
+    /// and doesn't create more value than it spends.
+    ///
+    /// # Returns
+    /// The fee of the transaction, what its inputs are worth beyond its outputs, or the
+    /// reason it is invalid.
+    pub fn validate_transaction(&self, transaction: &Transaction) -> Result<u64, TransactionError> {
+        check_spend(transaction, |out_point| {
+            self.outputs
+                .get(out_point)
+                .copied()
+                .ok_or(TransactionError::MissingOutput(*out_point))
+        })
+    }
+
+    /// Checks the transactions of a block, in order, without changing the set.
+    ///
+    /// The first transaction must be the only coinbase, and may claim at most `reward`
+    /// plus the fees of the other transactions. The other transactions may spend the
+    /// outputs of earlier transactions of the block, but not those of the coinbase.
+    ///
+    /// # Parameters
+    /// - `transactions`: The transactions of the block
+    /// - `reward`: The new value the coinbase may create
+    ///
+    /// # Returns
+    /// The changes the block makes, or `ChainError::InvalidTransaction` with the index of
+    /// the first invalid transaction.
+    pub fn validate_block(
+        &self,
+        transactions: &[Transaction],
+        reward: u64,
+    ) -> Result<UtxoChanges, ChainError> {
+        let invalid = |index, error| ChainError::InvalidTransaction { index, error };
+
+        let coinbase = match transactions.first() {
+            Some(coinbase) if coinbase.is_coinbase() => coinbase,
+            _ => return Err(invalid(0, TransactionError::MissingCoinbase)),
+        };
+
+        let mut spent = vec![];
+        let mut spent_in_block = HashSet::new();
+        let mut created = BTreeMap::new();
+        let mut fees = 0u64;
+
+        for (index, transaction) in transactions.iter().enumerate().skip(1) {
+            let fee = check_spend(transaction, |out_point| {
+                if spent_in_block.contains(out_point) {
+                    return Err(TransactionError::DoubleSpend(*out_point));
+                }
+                created
+                    .get(out_point)
+                    .or_else(|| self.outputs.get(out_point))
+                    .copied()
+                    .ok_or(TransactionError::MissingOutput(*out_point))
+            })
+            .map_err(|error| invalid(index, error))?;
+
+            for input in transaction.inputs.iter() {
+                let previous = input.previous;
+                spent_in_block.insert(previous);
+                // An output created earlier in the block was never part of the set
+                if created.remove(&previous).is_none() {
+                    spent.push((previous, self.outputs[&previous]));
+                }
+            }
+            self.create_outputs(transaction, &mut created)
+                .map_err(|error| invalid(index, error))?;
+            fees = fees
+                .checked_add(fee)
+                .ok_or(invalid(index, TransactionError::ValueOverflow))?;
+        }
+
+        let allowed = reward.saturating_add(fees);
+        let claimed = coinbase
+            .output_value()
+            .ok_or(invalid(0, TransactionError::ValueOverflow))?;
+        if claimed > allowed {
+            return Err(invalid(
+                0,
+                TransactionError::CoinbaseTooLarge { claimed, allowed },
+            ));
+        }
+        self.create_outputs(coinbase, &mut created)
+            .map_err(|error| invalid(0, error))?;
+
+        Ok(UtxoChanges {
+            spent,
+            created: created.into_iter().collect(),
+        })
+    }
+
+    /// Applies the changes of a block returned by `validate_block`.
+    pub fn apply(&mut self, changes: &UtxoChanges) {
+        for (out_point, _) in changes.spent.iter() {
+            self.outputs.remove(out_point);
+        }
+        self.outputs.extend(changes.created.iter().copied());
+    }
+
+    /// Undoes `changes`, which must be those of the last block applied, restoring the
+    /// outputs it spent and removing those it created.
+    pub fn revert(&mut self, changes: &UtxoChanges) {
+        for (out_point, _) in changes.created.iter() {
+            self.outputs.remove(out_point);
+        }
+        self.outputs.extend(changes.spent.iter().copied());
+    }
+
+    /// Validates the transactions of a block and applies them all, or none if one of
+    /// them is invalid.
+    ///
+    /// # Returns
+    /// The changes the block made, or the error of `validate_block`.
+    pub fn apply_block(
+        &mut self,
+        transactions: &[Transaction],
+        reward: u64,
+    ) -> Result<UtxoChanges, ChainError> {
+        let changes = self.validate_block(transactions, reward)?;
+        self.apply(&changes);
+        Ok(changes)
+    }
+
+    /// Adds the outputs of `transaction` to `created`, unless one of them exists already.
+    fn create_outputs(
+        &self,
+        transaction: &Transaction,
+        created: &mut BTreeMap<OutPoint, TxOutput>,
+    ) -> Result<(), TransactionError> {
+        let txid = transaction.id();
+        for (index, output) in transaction.outputs.iter().enumerate() {
+            let out_point = OutPoint::new(txid, index as u32);
+            if self.outputs.contains_key(&out_point) || created.contains_key(&out_point) {
+                return Err(TransactionError::DuplicateOutput(out_point));
+            }
+            created.insert(out_point, *output);
+        }
+        Ok(())
+    }
+}
+
+/// Checks the inputs of a transaction that isn't a coinbase, finding the outputs they
+/// spend with `lookup`, and returns its fee.
+pub(super) fn check_spend(
+    transaction: &Transaction,
+    lookup: impl Fn(&OutPoint) -> Result<TxOutput, TransactionError>,
+) -> Result<u64, TransactionError> {
+    if transaction.is_coinbase() {
+        return Err(TransactionError::MisplacedCoinbase);
+    }
+
+    // Every signature covers the whole transaction, so hash it once for all the inputs
+    let signing_hash = transaction.signing_hash();
+    let mut seen = HashSet::new();
+    let mut inputs = 0u64;
+    for (index, input) in transaction.inputs.iter().enumerate() {
+        if !seen.insert(input.previous) {
+            return Err(TransactionError::DoubleSpend(input.previous));
+        }
+        let output = lookup(&input.previous)?;
+        if !transaction.verify_input(index, &output.owner, &signing_hash) {
+            return Err(TransactionError::InvalidSignature(index));
+        }
+        inputs = inputs
+            .checked_add(output.value)
+            .ok_or(TransactionError::ValueOverflow)?;
+    }
+
+    let outputs = transaction
+        .output_value()
+        .ok_or(TransactionError::ValueOverflow)?;
+    if outputs > inputs {
+        return Err(TransactionError::InsufficientInputs { inputs, outputs });
+    }
+    Ok(inputs - outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    const REWARD: u64 = 50;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn owner(key: &SigningKey) -> PublicKey {
+        key.verifying_key().to_bytes()
+    }
+
+    fn pay(
+        key: &SigningKey,
+        previous: Vec<OutPoint>,
+        outputs: Vec<(u64, &SigningKey)>,
+    ) -> Transaction {
+        let outputs = outputs
+            .into_iter()
+            .map(|(value, to)| TxOutput::new(value, &to.verifying_key()))
+            .collect();
+        let mut transaction = Transaction::new(previous, outputs);
+        transaction.sign(key);
+        transaction
+    }
+
+    /// Returns a set where `alice` owns the 50 of a first coinbase.
+    fn funded(alice: &SigningKey) -> (UtxoSet, OutPoint) {
+        let mut utxos = UtxoSet::new();
+        let coinbase =
+            Transaction::coinbase(0, vec![TxOutput::new(REWARD, &alice.verifying_key())]);
+        utxos
+            .apply_block(std::slice::from_ref(&coinbase), REWARD)
+            .unwrap();
+        (utxos, OutPoint::new(coinbase.id(), 0))
+    }
+
+    /// Test that payments move value between owners and leave the fee to the producer
+    #[test]
+    fn payments_move_value() {
+        let (alice, bob, miner) = (key(1), key(2), key(3));
+        let (mut utxos, funds) = funded(&alice);
+
+        let payment = pay(&alice, vec![funds], vec![(30, &bob), (15, &alice)]);
+        assert_eq!(utxos.validate_transaction(&payment), Ok(5));
+
+        // Bob spends his output in the same block
+        let onward = pay(
+            &bob,
+            vec![OutPoint::new(payment.id(), 0)],
+            vec![(29, &miner)],
+        );
+        let coinbase =
+            Transaction::coinbase(1, vec![TxOutput::new(REWARD + 6, &miner.verifying_key())]);
+        let changes = utxos
+            .apply_block(&[coinbase, payment.clone(), onward], REWARD)
+            .unwrap();
+
+        assert_eq!(
+            changes.spent,
+            vec![(funds, TxOutput::new(REWARD, &alice.verifying_key()))]
+        );
+        assert_eq!(changes.created.len(), 3);
+        assert_eq!(utxos.balance(&owner(&alice)), 15);
+        assert_eq!(utxos.balance(&owner(&bob)), 0);
+        assert_eq!(utxos.balance(&owner(&miner)), REWARD + 6 + 29);
+        assert_eq!(utxos.outputs_of(&owner(&alice)).len(), 1);
+        assert_eq!(utxos.len(), 3);
+    }
+
+    /// Test that an output can't be spent twice, in one transaction, one block or two
+    #[test]
+    fn rejects_double_spends() {
+        let (alice, bob, carol) = (key(1), key(2), key(3));
+        let (mut utxos, funds) = funded(&alice);
+
+        let twice = pay(&alice, vec![funds, funds], vec![(100, &bob)]);
+        assert_eq!(
+            utxos.validate_transaction(&twice),
+            Err(TransactionError::DoubleSpend(funds))
+        );
+
+        let to_bob = pay(&alice, vec![funds], vec![(50, &bob)]);
+        let to_carol = pay(&alice, vec![funds], vec![(50, &carol)]);
+        let coinbase = Transaction::coinbase(1, vec![]);
+        assert_eq!(
+            utxos.validate_block(
+                &[coinbase.clone(), to_bob.clone(), to_carol.clone()],
+                REWARD
+            ),
+            Err(ChainError::InvalidTransaction {
+                index: 2,
+                error: TransactionError::DoubleSpend(funds)
+            })
+        );
+
+        utxos.apply_block(&[coinbase, to_bob], REWARD).unwrap();
+        assert_eq!(
+            utxos.validate_transaction(&to_carol),
+            Err(TransactionError::MissingOutput(funds))
+        );
+    }
+
+    /// Test that only the owner can spend an output, and only up to its value
+    #[test]
+    fn rejects_theft_and_overspending() {
+        let (alice, mallory) = (key(1), key(66));
+        let (utxos, funds) = funded(&alice);
+
+        let theft = pay(&mallory, vec![funds], vec![(50, &mallory)]);
+        assert_eq!(
+            utxos.validate_transaction(&theft),
+            Err(TransactionError::InvalidSignature(0))
+        );
+
+        let mut forged = pay(&alice, vec![funds], vec![(50, &alice)]);
+        forged.outputs[0].owner = owner(&mallory);
+        assert_eq!(
+            utxos.validate_transaction(&forged),
+            Err(TransactionError::InvalidSignature(0))
+        );
+
+        let overspend = pay(&alice, vec![funds], vec![(40, &alice), (11, &mallory)]);
+        assert_eq!(
+            utxos.validate_transaction(&overspend),
+            Err(TransactionError::InsufficientInputs {
+                inputs: 50,
+                outputs: 51
+            })
+        );
+
+        let overflow = pay(&alice, vec![funds], vec![(u64::MAX, &alice), (1, &alice)]);
+        assert_eq!(
+            utxos.validate_transaction(&overflow),
+            Err(TransactionError::ValueOverflow)
+        );
+        assert_eq!(
+            utxos.validate_transaction(&Transaction::coinbase(1, vec![])),
+            Err(TransactionError::MisplacedCoinbase)
+        );
+    }
+
+    /// Test that a block with one invalid transaction changes nothing
+    #[test]
+    fn blocks_apply_atomically() {
+        let (alice, bob) = (key(1), key(2));
+        let (mut utxos, funds) = funded(&alice);
+        let before = utxos.clone();
+
+        let valid = pay(&alice, vec![funds], vec![(50, &bob)]);
+        let invalid = pay(&bob, vec![OutPoint::new([7; 32], 0)], vec![(1, &bob)]);
+        let coinbase = Transaction::coinbase(1, vec![TxOutput::new(REWARD, &bob.verifying_key())]);
+        assert!(utxos
+            .apply_block(&[coinbase.clone(), valid.clone(), invalid], REWARD)
+            .is_err());
+        assert_eq!(utxos, before);
+
+        let greedy =
+            Transaction::coinbase(1, vec![TxOutput::new(REWARD + 1, &bob.verifying_key())]);
+        assert_eq!(
+            utxos.apply_block(&[greedy, valid.clone()], REWARD),
+            Err(ChainError::InvalidTransaction {
+                index: 0,
+                error: TransactionError::CoinbaseTooLarge {
+                    claimed: 51,
+                    allowed: 50
+                }
+            })
+        );
+        assert_eq!(
+            utxos.apply_block(std::slice::from_ref(&valid), REWARD),
+            Err(ChainError::InvalidTransaction {
+                index: 0,
+                error: TransactionError::MissingCoinbase
+            })
+        );
+        assert_eq!(
+            utxos.apply_block(&[coinbase.clone(), coinbase.clone()], REWARD),
+            Err(ChainError::InvalidTransaction {
+                index: 1,
+                error: TransactionError::MisplacedCoinbase
+            })
+        );
+        assert_eq!(utxos, before);
+
+        utxos
+            .apply_block(&[coinbase.clone(), valid], REWARD)
+            .unwrap();
+        assert!(matches!(
+            utxos.apply_block(&[coinbase], REWARD),
+            Err(ChainError::InvalidTransaction {
+                index: 0,
+                error: TransactionError::DuplicateOutput(_)
+            })
+        ));
+    }
+}
@@ -0,0 +1,54 @@
+//! Reading the fields of the wire formats of the crate from the front of a byte slice.
+//!
+//! Each function removes what it reads from the slice, or returns `None`, leaving the
+//! slice unchanged, if there are too few bytes left. Integers are little-endian, as
+//! blocks and DHT messages encode them.
+
+use std::io;
+
+/// Removes the first `len` bytes of `bytes` and returns them, or `None` if there are
+/// fewer.
+pub(crate) fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let (head, tail) = bytes.split_at_checked(len)?;
+    *bytes = tail;
+    Some(head)
+}
+
+/// Removes the first `N` bytes of `bytes` and returns them as an array.
+pub(crate) fn take_array<const N: usize>(bytes: &mut &[u8]) -> Option<[u8; N]> {
+    take(bytes, N).map(|head| head.try_into().expect("took N bytes"))
+}
+
+/// Removes a little-endian `u32` from the front of `bytes`.
+pub(crate) fn take_u32(bytes: &mut &[u8]) -> Option<u32> {
+    take_array(bytes).map(u32::from_le_bytes)
+}
+
+/// Removes a little-endian `u64` from the front of `bytes`.
+pub(crate) fn take_u64(bytes: &mut &[u8]) -> Option<u64> {
+    take_array(bytes).map(u64::from_le_bytes)
+}
+
+/// Returns the error of a malformed message, of kind `InvalidData`.
+pub(crate) fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that fields are removed in order, and a truncated one leaves the bytes as they were
+    #[test]
+    fn takes_fields_in_order() {
+        let data = [1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 7, 8];
+        let mut bytes = &data[..];
+        assert_eq!(take_u32(&mut bytes), Some(1));
+        assert_eq!(take_u64(&mut bytes), Some(2));
+        assert_eq!(take_u32(&mut bytes), None);
+        assert_eq!(take_array(&mut bytes), Some([7]));
+        assert_eq!(take(&mut bytes, 2), None);
+        assert_eq!(take(&mut bytes, 1), Some(&[8][..]));
+        assert!(bytes.is_empty());
+    }
+}
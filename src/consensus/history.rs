@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::fmt;
+
+/// What a history event says about an operation, following Jepsen's conventions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind<I, O> {
+    /// The client started the operation.
+    Invoke(I),
+    /// The operation completed with the given output.
+    Ok(O),
+    /// The operation definitely did not take effect.
+    Fail,
+    /// The outcome is unknown: the operation may or may not have taken effect.
+    Info,
+}
+
+/// One entry in a [`History`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event<I, O> {
+    pub time: u64,
+    pub process: u64,
+    pub kind: EventKind<I, O>,
+}
+
+/// An operation built by pairing an invocation with its completion.
+///
+/// `call` and `ret` are positions in the history, which is all a linearizability
+/// checker needs to know which operations were concurrent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operation<I, O> {
+    pub process: u64,
+    pub input: I,
+    /// The observed output, or `None` if the outcome is unknown.
+    pub output: Option<O>,
+    pub call: usize,
+    /// Position of the completion, or `None` if the operation never completed.
+    pub ret: Option<usize>,
+}
+
+/// A record of every operation clients invoked and how each one ended.
+///
+/// Each process runs one operation at a time. After an `info` completion the process
+/// must not be reused, because its operation may still take effect at any later point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct History<I, O> {
+    events: Vec<Event<I, O>>,
+}
+
+impl<I, O> Default for History<I, O> {
+    fn default() -> Self {
+        History { events: vec![] }
+    }
+}
+
+impl<I: Clone, O: Clone> History<I, O> {
+    /// Creates an empty history.
+    pub fn new() -> Self {
+        History::default()
+    }
+
+    /// Records that `process` invoked an operation.
+    pub fn invoke(&mut self, time: u64, process: u64, input: I) {
+        self.push(time, process, EventKind::Invoke(input));
+    }
+
+    /// Records that the pending operation of `process` completed with `output`.
+    pub fn ok(&mut self, time: u64, process: u64, output: O) {
+        self.push(time, process, EventKind::Ok(output));
+    }
+
+    /// Records that the pending operation of `process` definitely failed.
+    pub fn fail(&mut self, time: u64, process: u64) {
+        self.push(time, process, EventKind::Fail);
+    }
+
+    /// Records that the outcome of the pending operation of `process` is unknown.
+    pub fn info(&mut self, time: u64, process: u64) {
+        self.push(time, process, EventKind::Info);
+    }
+
+    fn push(&mut self, time: u64, process: u64, kind: EventKind<I, O>) {
+        self.events.push(Event {
+            time,
+            process,
+            kind,
+        });
+    }
+
+    /// Returns every event in the order it was recorded.
+    pub fn events(&self) -> &[Event<I, O>] {
+        &self.events
+    }
+
+    /// Pairs invocations with their completions.
+    ///
+    /// Failed operations are left out, since they had no effect. Operations that ended
+    /// with `info`, or never ended at all, are returned with no output and no `ret`.
+    pub fn operations(&self) -> Vec<Operation<I, O>> {
+        let mut operations: Vec<Operation<I, O>> = vec![];
+        let mut pending: HashMap<u64, usize> = HashMap::new();
+        let mut failed = vec![];
+
+        for (position, event) in self.events.iter().enumerate() {
+            match &event.kind {
+                EventKind::Invoke(input) => {
+                    pending.insert(event.process, operations.len());
+                    operations.push(Operation {
+                        process: event.process,
+                        input: input.clone(),
+                        output: None,
+                        call: position,
+                        ret: None,
+                    });
+                }
+                EventKind::Ok(output) => {
+                    if let Some(index) = pending.remove(&event.process) {
+                        operations[index].output = Some(output.clone());
+                        operations[index].ret = Some(position);
+                    }
+                }
+                EventKind::Fail => {
+                    if let Some(index) = pending.remove(&event.process) {
+                        failed.push(index);
+                    }
+                }
+                EventKind::Info => {
+                    pending.remove(&event.process);
+                }
+            }
+        }
+
+        operations
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| !failed.contains(index))
+            .map(|(_, operation)| operation)
+            .collect()
+    }
+}
+
+impl<I: fmt::Debug, O: fmt::Debug> fmt::Display for History<I, O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for event in &self.events {
+            write!(f, "[{:>6}ms] process {:>3} ", event.time, event.process)?;
+            match &event.kind {
+                EventKind::Invoke(input) => writeln!(f, "invoke {:?}", input)?,
+                EventKind::Ok(output) => writeln!(f, "ok     {:?}", output)?,
+                EventKind::Fail => writeln!(f, "fail")?,
+                EventKind::Info => writeln!(f, "info")?,
+            }
+        }
+        Ok(())
+    }
+}
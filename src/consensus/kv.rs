@@ -0,0 +1,473 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::history::{History, Operation};
+use super::linearizability::{self, CheckResult, Model};
+use super::scenario::Scenario;
+use super::simulation::Simulation;
+use super::{ConsensusNode, NodeId};
+use crate::rng::SimRng;
+
+/// An operation on the replicated key-value store.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum KvOp {
+    Put { key: String, value: String },
+    Get { key: String },
+}
+
+impl KvOp {
+    /// Returns the key the operation touches.
+    pub fn key(&self) -> &str {
+        match self {
+            KvOp::Put { key, .. } | KvOp::Get { key } => key,
+        }
+    }
+}
+
+/// The result of a [`KvOp`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvResult {
+    Written,
+    Value(Option<String>),
+}
+
+/// A command replicated through consensus. The id is unique per client operation, so
+/// the client can find its command in the committed log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KvCommand {
+    pub id: u64,
+    pub op: KvOp,
+}
+
+/// The state machine every node applies committed commands to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KvStore {
+    data: BTreeMap<String, String>,
+}
+
+impl KvStore {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        KvStore::default()
+    }
+
+    /// Builds the store a node would have after applying its committed log.
+    pub fn from_log(commands: &[KvCommand]) -> Self {
+        let mut store = KvStore::new();
+        for command in commands {
+            store.apply(&command.op);
+        }
+        store
+    }
+
+    /// Applies an operation and returns its result.
+    pub fn apply(&mut self, op: &KvOp) -> KvResult {
+        match op {
+            KvOp::Put { key, value } => {
+                self.data.insert(key.clone(), value.clone());
+                KvResult::Written
+            }
+            KvOp::Get { key } => KvResult::Value(self.data.get(key).cloned()),
+        }
+    }
+}
+
+/// Sequential model of a single key, used to check each key's history independently.
+/// Linearizability is compositional, so the store is linearizable if every key is.
+pub struct KvModel;
+
+impl Model for KvModel {
+    type State = Option<String>;
+    type Input = KvOp;
+    type Output = KvResult;
+
+    fn init(&self) -> Option<String> {
+        None
+    }
+
+    fn step(&self, state: &Option<String>, input: &KvOp) -> (Option<String>, KvResult) {
+        match input {
+            KvOp::Put { value, .. } => (Some(value.clone()), KvResult::Written),
+            KvOp::Get { .. } => (state.clone(), KvResult::Value(state.clone())),
+        }
+    }
+}
+
+/// Error returned when the history of a key is not linearizable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotLinearizable {
+    pub key: String,
+    /// The longest valid order found before the checker got stuck.
+    pub longest_prefix: Vec<Operation<KvOp, KvResult>>,
+    /// The operations that could not be placed after that prefix.
+    pub remaining: Vec<Operation<KvOp, KvResult>>,
+}
+
+impl fmt::Display for NotLinearizable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "history of key {:?} is not linearizable", self.key)?;
+        writeln!(f, "longest valid order:")?;
+        for operation in &self.longest_prefix {
+            writeln!(f, "  {:?} -> {:?}", operation.input, operation.output)?;
+        }
+        writeln!(f, "could not place:")?;
+        for operation in &self.remaining {
+            writeln!(f, "  {:?} -> {:?}", operation.input, operation.output)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NotLinearizable {}
+
+/// Checks that a key-value history is linearizable, one key at a time.
+pub fn check_history(history: &History<KvOp, KvResult>) -> Result<(), NotLinearizable> {
+    let mut by_key: BTreeMap<String, Vec<Operation<KvOp, KvResult>>> = BTreeMap::new();
+    for operation in history.operations() {
+        by_key
+            .entry(operation.input.key().to_string())
+            .or_default()
+            .push(operation);
+    }
+
+    for (key, operations) in by_key {
+        if let CheckResult::NotLinearizable(prefix) = linearizability::check(&KvModel, &operations)
+        {
+            let longest_prefix = prefix.iter().map(|&i| operations[i].clone()).collect();
+            let remaining = (0..operations.len())
+                .filter(|i| !prefix.contains(i))
+                .map(|i| operations[i].clone())
+                .collect();
+            return Err(NotLinearizable {
+                key,
+                longest_prefix,
+                remaining,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// How clients read values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadMode {
+    /// Reads are replicated through the log like writes, which is linearizable.
+    Consensus,
+    /// Reads are answered by any running node from its own committed state. This is
+    /// the classic stale-read bug and exists so the checker can be shown to catch it.
+    LocalUnsafe,
+}
+
+#[derive(Debug, Clone)]
+enum ClientState {
+    Idle {
+        next_at: u64,
+    },
+    Waiting {
+        node: NodeId,
+        id: u64,
+        deadline: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Client {
+    process: u64,
+    state: ClientState,
+}
+
+/// Random key-value clients that run against a cluster while a [`Scenario`] injects
+/// faults, recording everything they observe in a [`History`].
+#[derive(Debug, Clone)]
+pub struct KvWorkload {
+    clients: u64,
+    keys: u64,
+    timeout: u64,
+    read_mode: ReadMode,
+    seed: u64,
+}
+
+impl KvWorkload {
+    /// Creates a workload with the given number of concurrent clients, working on two
+    /// keys and giving up on an operation after one simulated second.
+    pub fn new(clients: u64) -> Self {
+        KvWorkload {
+            clients,
+            keys: 2,
+            timeout: 1_000,
+            read_mode: ReadMode::Consensus,
+            seed: 0,
+        }
+    }
+
+    /// Sets how many distinct keys the clients use.
+    pub fn keys(mut self, keys: u64) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Sets how long a client waits for an operation before recording it as `info`.
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how reads are served.
+    pub fn read_mode(mut self, read_mode: ReadMode) -> Self {
+        self.read_mode = read_mode;
+        self
+    }
+
+    /// Sets the seed the clients draw their operations from.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Runs the clients against the scenario's cluster and returns what they observed.
+    /// Commands from the scenario's `propose` events are ignored.
+    pub fn run<N: ConsensusNode<Command = KvCommand>>(
+        &self,
+        scenario: &Scenario,
+    ) -> History<KvOp, KvResult> {
+        let mut history = History::new();
+        let mut rng = SimRng::new(self.seed);
+        let mut next_id = 0;
+        let mut clients: Vec<Client> = (0..self.clients)
+            .map(|process| Client {
+                process,
+                state: ClientState::Idle { next_at: 0 },
+            })
+            .collect();
+        let mut next_process = self.clients;
+
+        scenario.replay::<N>(|simulation, _| {
+            for client in clients.iter_mut() {
+                self.advance_client(
+                    client,
+                    simulation,
+                    &mut history,
+                    &mut rng,
+                    &mut next_id,
+                    &mut next_process,
+                );
+            }
+        });
+        history
+    }
+
+    fn advance_client<N: ConsensusNode<Command = KvCommand>>(
+        &self,
+        client: &mut Client,
+        simulation: &mut Simulation<N>,
+        history: &mut History<KvOp, KvResult>,
+        rng: &mut SimRng,
+        next_id: &mut u64,
+        next_process: &mut u64,
+    ) {
+        let now = simulation.now();
+        match client.state {
+            ClientState::Waiting { node, id, deadline } => {
+                let result = simulation
+                    .node(node)
+                    .filter(|_| simulation.is_up(node))
+                    .and_then(|node| {
+                        let committed = node.committed();
+                        let position = committed.iter().position(|command| command.id == id)?;
+                        let mut store = KvStore::from_log(&committed[..position]);
+                        Some(store.apply(&committed[position].op))
+                    });
+
+                if let Some(result) = result {
+                    history.ok(now, client.process, result);
+                    client.state = ClientState::Idle {
+                        next_at: now + rng.range(0, 50),
+                    };
+                } else if now >= deadline {
+                    // The command may still commit later, so this process is done
+                    history.info(now, client.process);
+                    client.process = *next_process;
+                    *next_process += 1;
+                    client.state = ClientState::Idle { next_at: now };
+                }
+            }
+            ClientState::Idle { next_at } if now >= next_at => {
+                let key = format!("k{}", rng.range(0, self.keys.saturating_sub(1)));
+                let id = *next_id;
+                *next_id += 1;
+                let op = if rng.chance(0.5) {
+                    KvOp::Put {
+                        key,
+                        value: id.to_string(),
+                    }
+                } else {
+                    KvOp::Get { key }
+                };
+
+                history.invoke(now, client.process, op.clone());
+
+                if let (ReadMode::LocalUnsafe, KvOp::Get { .. }) = (self.read_mode, &op) {
+                    let running: Vec<NodeId> = simulation
+                        .node_ids()
+                        .into_iter()
+                        .filter(|&id| simulation.is_up(id))
+                        .collect();
+                    if running.is_empty() {
+                        // No node can answer, and a read that isn't answered has no effect
+                        history.fail(now, client.process);
+                        client.state = ClientState::Idle {
+                            next_at: now + rng.range(10, 50),
+                        };
+                        return;
+                    }
+                    let node = running[rng.range(0, running.len() as u64 - 1) as usize];
+                    let committed = simulation.node(node).map_or(&[][..], |n| n.committed());
+                    let result = KvStore::from_log(committed).apply(&op);
+                    history.ok(now, client.process, result);
+                    client.state = ClientState::Idle {
+                        next_at: now + rng.range(0, 50),
+                    };
+                    return;
+                }
+
+                let submitted = simulation.leaders().first().copied().and_then(|leader| {
+                    simulation
+                        .propose_to(leader, KvCommand { id, op })
+                        .ok()
+                        .map(|_| leader)
+                });
+                client.state = match submitted {
+                    Some(node) => ClientState::Waiting {
+                        node,
+                        id,
+                        deadline: now + self.timeout,
+                    },
+                    None => {
+                        // No leader took the command, so it can never take effect
+                        history.fail(now, client.process);
+                        ClientState::Idle {
+                            next_at: now + rng.range(10, 50),
+                        }
+                    }
+                };
+            }
+            ClientState::Idle { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::history::EventKind;
+    use crate::consensus::paxos::PaxosNode;
+    use crate::consensus::raft::RaftNode;
+
+    fn faulty_scenario(seed: u64) -> Scenario {
+        Scenario::new("kv under faults")
+            .nodes(5)
+            .seed(seed)
+            .message_loss(0, 0.05)
+            .partition(1_000, &[&[1, 2], &[3, 4, 5]])
+            .heal(2_000)
+            .crash(2_500, 3)
+            .crash(2_500, 4)
+            .restart(3_200, 3)
+            .restart(3_200, 4)
+            .partition(3_600, &[&[1, 3], &[2, 4, 5]])
+            .heal(4_200)
+    }
+
+    fn completed_ops(history: &History<KvOp, KvResult>) -> usize {
+        history
+            .events()
+            .iter()
+            .filter(|event| matches!(event.kind, EventKind::Ok(_)))
+            .count()
+    }
+
+    /// Test that reads and writes through Raft stay linearizable while faults are injected
+    #[test]
+    fn raft_history_is_linearizable() {
+        for seed in 0..3 {
+            let history = KvWorkload::new(4)
+                .seed(seed)
+                .run::<RaftNode<KvCommand>>(&faulty_scenario(seed));
+            assert!(completed_ops(&history) > 50);
+            if let Err(error) = check_history(&history) {
+                panic!("{}\n{}", error, history);
+            }
+        }
+    }
+
+    /// Test that reads and writes through Paxos stay linearizable while faults are injected
+    #[test]
+    fn paxos_history_is_linearizable() {
+        for seed in 0..3 {
+            let history = KvWorkload::new(4)
+                .seed(seed)
+                .run::<PaxosNode<KvCommand>>(&faulty_scenario(seed));
+            assert!(completed_ops(&history) > 50);
+            if let Err(error) = check_history(&history) {
+                panic!("{}\n{}", error, history);
+            }
+        }
+    }
+
+    /// Test that the checker catches stale reads served from a node's local state
+    #[test]
+    fn local_reads_are_caught() {
+        let history = KvWorkload::new(4)
+            .read_mode(ReadMode::LocalUnsafe)
+            .run::<RaftNode<KvCommand>>(&faulty_scenario(0));
+        assert!(check_history(&history).is_err());
+    }
+
+    /// Test that local reads fail rather than panic while every node is down
+    #[test]
+    fn local_reads_fail_with_every_node_down() {
+        let mut scenario = Scenario::new("every node down").nodes(3);
+        for node in 1..=3 {
+            scenario = scenario.crash(500, node).restart(1_500, node);
+        }
+        let history = KvWorkload::new(2)
+            .read_mode(ReadMode::LocalUnsafe)
+            .run::<RaftNode<KvCommand>>(&scenario);
+        let mut invoked = BTreeMap::new();
+        let mut failed_reads = 0;
+        for event in history.events() {
+            match &event.kind {
+                EventKind::Invoke(op) => {
+                    invoked.insert(event.process, op.clone());
+                }
+                EventKind::Fail => {
+                    if let Some(KvOp::Get { .. }) = invoked.get(&event.process) {
+                        assert!(event.time >= 500 && event.time < 1_500);
+                        failed_reads += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        assert!(failed_reads > 0);
+    }
+
+    /// Test that the store applies writes and reads in log order
+    #[test]
+    fn store_applies_log_in_order() {
+        let put = |id: u64, value: &str| KvCommand {
+            id,
+            op: KvOp::Put {
+                key: "x".to_string(),
+                value: value.to_string(),
+            },
+        };
+        let mut store = KvStore::from_log(&[put(0, "a"), put(1, "b")]);
+        assert_eq!(
+            store.apply(&KvOp::Get {
+                key: "x".to_string()
+            }),
+            KvResult::Value(Some("b".to_string()))
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashSet;
+use std::hash::Hash;
+
+use super::history::Operation;
+
+/// A sequential specification the operations of a history are checked against.
+pub trait Model {
+    type State: Clone + Eq + Hash;
+    type Input;
+    type Output: PartialEq;
+
+    /// Returns the state before any operation.
+    fn init(&self) -> Self::State;
+
+    /// Applies an operation to a state, returning the new state and the output a
+    /// correct implementation would produce.
+    fn step(&self, state: &Self::State, input: &Self::Input) -> (Self::State, Self::Output);
+}
+
+/// Outcome of a linearizability check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckResult {
+    /// The history is linearizable. Contains one valid order, as indices of the
+    /// operations that were checked. Operations with unknown outcome may be missing
+    /// from it, meaning they never took effect.
+    Linearizable(Vec<usize>),
+    /// No valid order exists. Contains the longest order that could be built before
+    /// getting stuck, which points at the operations to look at.
+    NotLinearizable(Vec<usize>),
+}
+
+impl CheckResult {
+    /// Returns `true` if the history is linearizable.
+    pub fn is_linearizable(&self) -> bool {
+        matches!(self, CheckResult::Linearizable(_))
+    }
+}
+
+/// Checks whether a set of operations is linearizable with respect to a model.
+///
+/// This is the Wing & Gong search with Lowe's memoization, as used by Knossos: it
+/// repeatedly picks an operation that could have taken effect next (one invoked before
+/// every remaining operation returned), applies it to the model and backtracks when an
+/// output doesn't match. Visited `(linearized set, state)` pairs are cached, so equal
+/// configurations reached in a different order are only explored once.
+pub fn check<M: Model>(model: &M, operations: &[Operation<M::Input, M::Output>]) -> CheckResult {
+    let mut search = Search {
+        model,
+        operations,
+        linearized: vec![false; operations.len()],
+        remaining_completed: operations.iter().filter(|op| op.ret.is_some()).count(),
+        order: vec![],
+        longest: vec![],
+        visited: HashSet::new(),
+    };
+
+    if search.run(model.init()) {
+        CheckResult::Linearizable(search.order)
+    } else {
+        CheckResult::NotLinearizable(search.longest)
+    }
+}
+
+struct Search<'a, M: Model> {
+    model: &'a M,
+    operations: &'a [Operation<M::Input, M::Output>],
+    linearized: Vec<bool>,
+    remaining_completed: usize,
+    order: Vec<usize>,
+    longest: Vec<usize>,
+    visited: HashSet<(Vec<bool>, M::State)>,
+}
+
+impl<M: Model> Search<'_, M> {
+    fn run(&mut self, state: M::State) -> bool {
+        if self.remaining_completed == 0 {
+            return true;
+        }
+        if !self
+            .visited
+            .insert((self.linearized.clone(), state.clone()))
+        {
+            return false;
+        }
+        if self.order.len() > self.longest.len() {
+            self.longest = self.order.clone();
+        }
+
+        // Nothing can be linearized after the first pending operation returned
+        let earliest_return = self
+            .operations
+            .iter()
+            .zip(&self.linearized)
+            .filter(|(_, &done)| !done)
+            .filter_map(|(op, _)| op.ret)
+            .min()
+            .unwrap_or(usize::MAX);
+
+        for index in 0..self.operations.len() {
+            let operation = &self.operations[index];
+            if self.linearized[index] || operation.call > earliest_return {
+                continue;
+            }
+
+            let (next_state, output) = self.model.step(&state, &operation.input);
+            if operation
+                .output
+                .as_ref()
+                .is_some_and(|expected| *expected != output)
+            {
+                continue;
+            }
+
+            self.linearized[index] = true;
+            self.order.push(index);
+            if operation.ret.is_some() {
+                self.remaining_completed -= 1;
+            }
+
+            if self.run(next_state) {
+                return true;
+            }
+
+            if operation.ret.is_some() {
+                self.remaining_completed += 1;
+            }
+            self.order.pop();
+            self.linearized[index] = false;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::history::History;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum RegisterOp {
+        Write(u64),
+        Read,
+    }
+
+    struct Register;
+
+    impl Model for Register {
+        type State = u64;
+        type Input = RegisterOp;
+        type Output = u64;
+
+        fn init(&self) -> u64 {
+            0
+        }
+
+        fn step(&self, state: &u64, input: &RegisterOp) -> (u64, u64) {
+            match input {
+                RegisterOp::Write(value) => (*value, *value),
+                RegisterOp::Read => (*state, *state),
+            }
+        }
+    }
+
+    fn is_linearizable(history: &History<RegisterOp, u64>) -> bool {
+        check(&Register, &history.operations()).is_linearizable()
+    }
+
+    /// Test that a read concurrent with a write may see either value
+    #[test]
+    fn concurrent_read_sees_old_or_new_value() {
+        for seen in [0, 1] {
+            let mut history = History::new();
+            history.invoke(0, 1, RegisterOp::Write(1));
+            history.invoke(1, 2, RegisterOp::Read);
+            history.ok(2, 2, seen);
+            history.ok(3, 1, 1);
+            assert!(is_linearizable(&history));
+        }
+    }
+
+    /// Test that a read that starts after a write finished can't return the old value
+    #[test]
+    fn stale_read_is_not_linearizable() {
+        let mut history = History::new();
+        history.invoke(0, 1, RegisterOp::Write(1));
+        history.ok(1, 1, 1);
+        history.invoke(2, 2, RegisterOp::Read);
+        history.ok(3, 2, 0);
+
+        assert_eq!(
+            check(&Register, &history.operations()),
+            CheckResult::NotLinearizable(vec![0])
+        );
+    }
+
+    /// Test that a write with unknown outcome may or may not have taken effect
+    #[test]
+    fn indeterminate_write_may_take_effect_later() {
+        for seen in [0, 2] {
+            let mut history = History::new();
+            history.invoke(0, 1, RegisterOp::Write(2));
+            history.info(1, 1);
+            history.invoke(2, 2, RegisterOp::Read);
+            history.ok(3, 2, seen);
+            assert!(is_linearizable(&history));
+        }
+    }
+
+    /// Test that failed operations are ignored by the checker
+    #[test]
+    fn failed_write_never_takes_effect() {
+        let mut history = History::new();
+        history.invoke(0, 1, RegisterOp::Write(3));
+        history.fail(1, 1);
+        history.invoke(2, 2, RegisterOp::Read);
+        history.ok(3, 2, 3);
+        assert!(!is_linearizable(&history));
+    }
+}
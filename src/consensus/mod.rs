@@ -0,0 +1,110 @@
+//! Raft and Paxos implementations that can be tested under the same conditions.
+//!
+//! Both algorithms are written as deterministic state machines: they never touch the
+//! network or the system clock themselves. Instead they return the messages they want
+//! to send, and the [`simulation`] module decides when (and whether) those messages are
+//! delivered. This lets [`scenario`] replay the same failures against either algorithm.
+//!
+//! On top of that, [`kv`] runs clients of a replicated key-value store during a scenario
+//! and records a [`history`] of what they observed, which [`linearizability`] checks.
+
+pub mod history;
+pub mod kv;
+pub mod linearizability;
+pub mod paxos;
+pub mod raft;
+pub mod scenario;
+pub mod simulation;
+
+use std::fmt;
+
+/// Identifier of a node in the cluster.
+pub type NodeId = u64;
+
+/// A message together with its sender and receiver.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope<M> {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub message: M,
+}
+
+impl<M> Envelope<M> {
+    /// Creates a new envelope for a message going from `from` to `to`.
+    pub fn new(from: NodeId, to: NodeId, message: M) -> Self {
+        Envelope { from, to, message }
+    }
+}
+
+/// Error returned when a command is proposed to a node that cannot accept it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProposeError {
+    /// The node is not the leader. Contains the leader it knows about, if any.
+    NotLeader(Option<NodeId>),
+}
+
+impl fmt::Display for ProposeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProposeError::NotLeader(Some(leader)) => {
+                write!(f, "not the leader, try node {}", leader)
+            }
+            ProposeError::NotLeader(None) => write!(f, "not the leader, no leader known"),
+        }
+    }
+}
+
+impl std::error::Error for ProposeError {}
+
+/// Common interface for a consensus algorithm that replicates a log of commands.
+///
+/// Time is passed in explicitly as milliseconds of the node's local clock, so a
+/// simulator can skew, pause or fast-forward it.
+pub trait ConsensusNode {
+    /// The commands that are replicated.
+    type Command: Clone + fmt::Debug + PartialEq;
+    /// The messages exchanged between nodes.
+    type Message: Clone + fmt::Debug;
+
+    /// Name of the algorithm, used in reports.
+    const ALGORITHM: &'static str;
+
+    /// Creates a node that is part of a cluster with the given peers.
+    ///
+    /// # Parameters
+    /// - `node_id`: The id of this node
+    /// - `peers`: The ids of every other node in the cluster
+    /// - `seed`: Seed for any randomness the algorithm needs, such as election timeouts
+    fn new_node(node_id: NodeId, peers: Vec<NodeId>, seed: u64) -> Self;
+
+    /// Returns the id of this node.
+    fn node_id(&self) -> NodeId;
+
+    /// Advances the node's timers to `now` and returns the messages it wants to send.
+    fn tick(&mut self, now: u64) -> Vec<Envelope<Self::Message>>;
+
+    /// Handles a message from another node and returns the messages sent in response.
+    fn handle_message(
+        &mut self,
+        now: u64,
+        from: NodeId,
+        message: Self::Message,
+    ) -> Vec<Envelope<Self::Message>>;
+
+    /// Asks the node to replicate a command. Only the leader accepts proposals.
+    fn propose(
+        &mut self,
+        now: u64,
+        command: Self::Command,
+    ) -> Result<Vec<Envelope<Self::Message>>, ProposeError>;
+
+    /// Returns `true` if the node currently believes it is the leader.
+    fn is_leader(&self) -> bool;
+
+    /// Returns the commands this node knows to be committed, in log order.
+    fn committed(&self) -> &[Self::Command];
+
+    /// Simulates a crash followed by a restart: volatile state is lost, while the
+    /// state the algorithm persists to stable storage is kept.
+    fn restart(&mut self, now: u64);
+}
@@ -0,0 +1,666 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use super::{ConsensusNode, Envelope, NodeId, ProposeError};
+use crate::rng::SimRng;
+
+/// Lower bound of the randomized timeout before a node tries to become the leader, in milliseconds.
+pub const ELECTION_TIMEOUT_MIN: u64 = 150;
+/// Upper bound of the randomized timeout before a node tries to become the leader, in milliseconds.
+pub const ELECTION_TIMEOUT_MAX: u64 = 300;
+/// How often a leader sends heartbeats and retransmits pending proposals, in milliseconds.
+pub const HEARTBEAT_INTERVAL: u64 = 50;
+
+/// A proposal number. Ballots are totally ordered and unique per node, because ties
+/// in `round` are broken by `node_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Ballot {
+    pub round: u64,
+    pub node_id: NodeId,
+}
+
+// Define the Prepare message (phase 1a) for every slot from `from_slot` on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prepare {
+    pub ballot: Ballot,
+    pub from_slot: u64,
+}
+
+// Define the Promise message (phase 1b) with the values the acceptor already accepted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Promise<C> {
+    pub ballot: Ballot,
+    pub accepted: Vec<(u64, Ballot, Option<C>)>,
+}
+
+// Define the Accept message (phase 2a). A `None` value is a no-op used to fill gaps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accept<C> {
+    pub ballot: Ballot,
+    pub slot: u64,
+    pub value: Option<C>,
+}
+
+// Define the Ack message (phase 2b)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ack {
+    pub ballot: Ballot,
+    pub slot: u64,
+}
+
+// Define the Learn message, sent once a value is chosen for a slot
+#[derive(Debug, Clone, PartialEq)]
+pub struct Learn<C> {
+    pub slot: u64,
+    pub value: Option<C>,
+}
+
+// Define the Paxos message enum
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaxosMessage<C> {
+    Prepare(Prepare),
+    Promise(Promise<C>),
+    Accept(Accept<C>),
+    Ack(Ack),
+    Learn(Learn<C>),
+    /// Rejects a Prepare or Accept, carrying the higher ballot the acceptor promised.
+    Nack(Ballot),
+    /// Sent by the leader so followers know it is alive and how far it has decided.
+    Heartbeat {
+        ballot: Ballot,
+        decided_upto: u64,
+    },
+    /// Reply to a heartbeat, so the leader knows it can still reach a majority.
+    HeartbeatAck {
+        ballot: Ballot,
+    },
+    /// Asks the leader for every decision from `from_slot` on.
+    CatchUp {
+        from_slot: u64,
+    },
+}
+
+// Define the possible roles of a Paxos node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaxosRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone)]
+struct Proposal<C> {
+    value: Option<C>,
+    acks: BTreeSet<NodeId>,
+}
+
+/// A Multi-Paxos node that acts as proposer, acceptor and learner.
+///
+/// A stable leader runs phase 1 once for all future slots and then only phase 2 per
+/// command. The acceptor state (`promised`, `accepted`) and the decided values are
+/// persistent and survive [`ConsensusNode::restart`].
+#[derive(Debug, Clone)]
+pub struct PaxosNode<C> {
+    node_id: NodeId,
+    peers: Vec<NodeId>,
+    role: PaxosRole,
+    ballot: Ballot,
+    promised: Ballot,
+    accepted: BTreeMap<u64, (Ballot, Option<C>)>,
+    chosen: BTreeMap<u64, Option<C>>,
+    committed: Vec<C>,
+    decided_upto: u64,
+    leader_id: Option<NodeId>,
+    promises: BTreeMap<NodeId, Vec<(u64, Ballot, Option<C>)>>,
+    proposals: BTreeMap<u64, Proposal<C>>,
+    last_contact: BTreeMap<NodeId, u64>,
+    next_slot: u64,
+    election_deadline: u64,
+    last_heartbeat: u64,
+    rng: SimRng,
+}
+
+impl<C: Clone + fmt::Debug + PartialEq> PaxosNode<C> {
+    /// Returns the current role of the node.
+    pub fn role(&self) -> PaxosRole {
+        self.role
+    }
+
+    /// Returns the highest ballot this node has promised.
+    pub fn promised(&self) -> Ballot {
+        self.promised
+    }
+
+    /// Returns the value chosen for a slot, if this node has learned it.
+    /// The outer `Option` is `None` if the slot is undecided; the inner one is `None` for a no-op.
+    pub fn chosen(&self, slot: u64) -> Option<&Option<C>> {
+        self.chosen.get(&slot)
+    }
+
+    fn majority(&self) -> usize {
+        let cluster_size = self.peers.len() + 1;
+        cluster_size / 2 + 1
+    }
+
+    fn reset_election_deadline(&mut self, now: u64) {
+        self.election_deadline = now + self.rng.range(ELECTION_TIMEOUT_MIN, ELECTION_TIMEOUT_MAX);
+    }
+
+    fn broadcast(&self, message: PaxosMessage<C>) -> Vec<Envelope<PaxosMessage<C>>> {
+        self.peers
+            .iter()
+            .map(|&peer| Envelope::new(self.node_id, peer, message.clone()))
+            .collect()
+    }
+
+    fn step_down(&mut self, ballot: Ballot) {
+        if ballot > self.promised {
+            self.promised = ballot;
+        }
+        self.role = PaxosRole::Follower;
+        self.promises.clear();
+        self.proposals.clear();
+    }
+
+    // Start phase 1 with a ballot higher than any seen so far
+    fn start_election(&mut self, now: u64) -> Vec<Envelope<PaxosMessage<C>>> {
+        self.role = PaxosRole::Candidate;
+        self.leader_id = None;
+        self.ballot = Ballot {
+            round: self.promised.round.max(self.ballot.round) + 1,
+            node_id: self.node_id,
+        };
+        self.promised = self.ballot;
+        self.promises.clear();
+        self.proposals.clear();
+        self.reset_election_deadline(now);
+
+        let own_accepted = self.accepted_from(self.decided_upto);
+        self.promises.insert(self.node_id, own_accepted);
+        if self.promises.len() >= self.majority() {
+            return self.become_leader(now);
+        }
+
+        self.broadcast(PaxosMessage::Prepare(Prepare {
+            ballot: self.ballot,
+            from_slot: self.decided_upto,
+        }))
+    }
+
+    fn accepted_from(&self, from_slot: u64) -> Vec<(u64, Ballot, Option<C>)> {
+        self.accepted
+            .range(from_slot..)
+            .map(|(&slot, (ballot, value))| (slot, *ballot, value.clone()))
+            .collect()
+    }
+
+    // A majority promised: re-propose whatever they may have chosen, fill gaps with no-ops
+    fn become_leader(&mut self, now: u64) -> Vec<Envelope<PaxosMessage<C>>> {
+        self.role = PaxosRole::Leader;
+        self.leader_id = Some(self.node_id);
+        self.last_contact = self.peers.iter().map(|&peer| (peer, now)).collect();
+
+        let mut highest: BTreeMap<u64, (Ballot, Option<C>)> = BTreeMap::new();
+        for (slot, ballot, value) in self.promises.values().flatten() {
+            match highest.get(slot) {
+                Some((existing, _)) if existing >= ballot => {}
+                _ => {
+                    highest.insert(*slot, (*ballot, value.clone()));
+                }
+            }
+        }
+        self.promises.clear();
+
+        let last_known = highest
+            .keys()
+            .chain(self.chosen.keys())
+            .max()
+            .map_or(self.decided_upto, |&slot| slot + 1);
+        self.next_slot = last_known.max(self.decided_upto);
+
+        let mut messages = vec![];
+        for slot in self.decided_upto..self.next_slot {
+            if self.chosen.contains_key(&slot) {
+                continue;
+            }
+            let value = highest.remove(&slot).and_then(|(_, value)| value);
+            messages.extend(self.start_proposal(slot, value));
+        }
+        messages.extend(self.send_heartbeat(now));
+        messages
+    }
+
+    fn start_proposal(&mut self, slot: u64, value: Option<C>) -> Vec<Envelope<PaxosMessage<C>>> {
+        let mut acks = BTreeSet::new();
+        if self.ballot >= self.promised {
+            self.accepted.insert(slot, (self.ballot, value.clone()));
+            acks.insert(self.node_id);
+        }
+        self.proposals.insert(
+            slot,
+            Proposal {
+                value: value.clone(),
+                acks,
+            },
+        );
+
+        let mut messages = self.broadcast(PaxosMessage::Accept(Accept {
+            ballot: self.ballot,
+            slot,
+            value,
+        }));
+        messages.extend(self.check_chosen(slot));
+        messages
+    }
+
+    fn check_chosen(&mut self, slot: u64) -> Vec<Envelope<PaxosMessage<C>>> {
+        let is_chosen = self
+            .proposals
+            .get(&slot)
+            .is_some_and(|proposal| proposal.acks.len() >= self.majority());
+        if !is_chosen {
+            return vec![];
+        }
+        let Some(proposal) = self.proposals.remove(&slot) else {
+            return vec![];
+        };
+        self.record_chosen(slot, proposal.value.clone());
+        self.broadcast(PaxosMessage::Learn(Learn {
+            slot,
+            value: proposal.value,
+        }))
+    }
+
+    fn record_chosen(&mut self, slot: u64, value: Option<C>) {
+        self.chosen.entry(slot).or_insert(value);
+        self.apply_chosen();
+    }
+
+    // Move every contiguous decided slot into the committed log
+    fn apply_chosen(&mut self) {
+        while let Some(value) = self.chosen.get(&self.decided_upto) {
+            if let Some(command) = value {
+                self.committed.push(command.clone());
+            }
+            self.decided_upto += 1;
+        }
+    }
+
+    // A leader that hasn't heard from a majority for a whole election timeout is probably
+    // partitioned away, so it steps down instead of accepting commands it can't decide
+    fn has_quorum(&self, now: u64) -> bool {
+        let reachable = self
+            .last_contact
+            .values()
+            .filter(|&&contact| contact + ELECTION_TIMEOUT_MAX >= now)
+            .count();
+        reachable + 1 >= self.majority()
+    }
+
+    fn send_heartbeat(&mut self, now: u64) -> Vec<Envelope<PaxosMessage<C>>> {
+        self.last_heartbeat = now;
+        let mut messages = self.broadcast(PaxosMessage::Heartbeat {
+            ballot: self.ballot,
+            decided_upto: self.decided_upto,
+        });
+        // Retransmit anything not chosen yet in case it was lost
+        for (&slot, proposal) in &self.proposals {
+            messages.extend(self.broadcast(PaxosMessage::Accept(Accept {
+                ballot: self.ballot,
+                slot,
+                value: proposal.value.clone(),
+            })));
+        }
+        messages
+    }
+
+    // Handle a Prepare message
+    fn handle_prepare(
+        &mut self,
+        now: u64,
+        from: NodeId,
+        prepare: Prepare,
+    ) -> Vec<Envelope<PaxosMessage<C>>> {
+        if prepare.ballot < self.promised {
+            return vec![Envelope::new(
+                self.node_id,
+                from,
+                PaxosMessage::Nack(self.promised),
+            )];
+        }
+        if prepare.ballot > self.ballot && self.role != PaxosRole::Follower {
+            self.step_down(prepare.ballot);
+        }
+        self.promised = prepare.ballot;
+        self.leader_id = None;
+        self.reset_election_deadline(now);
+
+        let promise = Promise {
+            ballot: prepare.ballot,
+            accepted: self.accepted_from(prepare.from_slot),
+        };
+        vec![Envelope::new(
+            self.node_id,
+            from,
+            PaxosMessage::Promise(promise),
+        )]
+    }
+
+    // Handle a Promise message
+    fn handle_promise(
+        &mut self,
+        now: u64,
+        from: NodeId,
+        promise: Promise<C>,
+    ) -> Vec<Envelope<PaxosMessage<C>>> {
+        if self.role != PaxosRole::Candidate || promise.ballot != self.ballot {
+            return vec![];
+        }
+        self.promises.insert(from, promise.accepted);
+        if self.promises.len() >= self.majority() {
+            return self.become_leader(now);
+        }
+        vec![]
+    }
+
+    // Handle an Accept message
+    fn handle_accept(
+        &mut self,
+        now: u64,
+        from: NodeId,
+        accept: Accept<C>,
+    ) -> Vec<Envelope<PaxosMessage<C>>> {
+        if accept.ballot < self.promised {
+            return vec![Envelope::new(
+                self.node_id,
+                from,
+                PaxosMessage::Nack(self.promised),
+            )];
+        }
+        if accept.ballot > self.ballot && self.role != PaxosRole::Follower {
+            self.step_down(accept.ballot);
+        }
+        self.promised = accept.ballot;
+        self.leader_id = Some(accept.ballot.node_id);
+        self.reset_election_deadline(now);
+        self.accepted
+            .insert(accept.slot, (accept.ballot, accept.value));
+
+        let ack = Ack {
+            ballot: accept.ballot,
+            slot: accept.slot,
+        };
+        vec![Envelope::new(self.node_id, from, PaxosMessage::Ack(ack))]
+    }
+
+    // Handle an Ack message
+    fn handle_ack(&mut self, now: u64, from: NodeId, ack: Ack) -> Vec<Envelope<PaxosMessage<C>>> {
+        if self.role != PaxosRole::Leader || ack.ballot != self.ballot {
+            return vec![];
+        }
+        self.last_contact.insert(from, now);
+        match self.proposals.get_mut(&ack.slot) {
+            Some(proposal) => {
+                proposal.acks.insert(from);
+            }
+            None => return vec![],
+        }
+        self.check_chosen(ack.slot)
+    }
+
+    // Handle a Nack message: someone promised a higher ballot, so stop leading
+    fn handle_nack(&mut self, now: u64, ballot: Ballot) -> Vec<Envelope<PaxosMessage<C>>> {
+        if ballot > self.ballot && self.role != PaxosRole::Follower {
+            self.step_down(ballot);
+            self.leader_id = None;
+            self.reset_election_deadline(now);
+        }
+        vec![]
+    }
+
+    // Handle a Heartbeat message
+    fn handle_heartbeat(
+        &mut self,
+        now: u64,
+        from: NodeId,
+        ballot: Ballot,
+        decided_upto: u64,
+    ) -> Vec<Envelope<PaxosMessage<C>>> {
+        if ballot < self.promised {
+            return vec![Envelope::new(
+                self.node_id,
+                from,
+                PaxosMessage::Nack(self.promised),
+            )];
+        }
+        if ballot > self.ballot && self.role != PaxosRole::Follower {
+            self.step_down(ballot);
+        }
+        self.promised = ballot;
+        self.leader_id = Some(ballot.node_id);
+        self.reset_election_deadline(now);
+
+        let mut replies = vec![Envelope::new(
+            self.node_id,
+            from,
+            PaxosMessage::HeartbeatAck { ballot },
+        )];
+        if self.decided_upto < decided_upto {
+            let catch_up = PaxosMessage::CatchUp {
+                from_slot: self.decided_upto,
+            };
+            replies.push(Envelope::new(self.node_id, from, catch_up));
+        }
+        replies
+    }
+
+    // Handle a CatchUp message by resending every decision the node is missing
+    fn handle_catch_up(&self, from: NodeId, from_slot: u64) -> Vec<Envelope<PaxosMessage<C>>> {
+        self.chosen
+            .range(from_slot..)
+            .map(|(&slot, value)| {
+                let learn = Learn {
+                    slot,
+                    value: value.clone(),
+                };
+                Envelope::new(self.node_id, from, PaxosMessage::Learn(learn))
+            })
+            .collect()
+    }
+}
+
+impl<C: Clone + fmt::Debug + PartialEq> ConsensusNode for PaxosNode<C> {
+    type Command = C;
+    type Message = PaxosMessage<C>;
+
+    const ALGORITHM: &'static str = "paxos";
+
+    fn new_node(node_id: NodeId, peers: Vec<NodeId>, seed: u64) -> Self {
+        let mut node = PaxosNode {
+            node_id,
+            peers,
+            role: PaxosRole::Follower,
+            ballot: Ballot::default(),
+            promised: Ballot::default(),
+            accepted: BTreeMap::new(),
+            chosen: BTreeMap::new(),
+            committed: vec![],
+            decided_upto: 0,
+            leader_id: None,
+            promises: BTreeMap::new(),
+            proposals: BTreeMap::new(),
+            last_contact: BTreeMap::new(),
+            next_slot: 0,
+            election_deadline: 0,
+            last_heartbeat: 0,
+            rng: SimRng::new(seed ^ node_id.wrapping_mul(0x2545_f491_4f6c_dd1d)),
+        };
+        node.reset_election_deadline(0);
+        node
+    }
+
+    fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    fn tick(&mut self, now: u64) -> Vec<Envelope<PaxosMessage<C>>> {
+        match self.role {
+            PaxosRole::Leader if !self.has_quorum(now) => {
+                self.step_down(self.ballot);
+                self.leader_id = None;
+                self.reset_election_deadline(now);
+                vec![]
+            }
+            PaxosRole::Leader if now >= self.last_heartbeat + HEARTBEAT_INTERVAL => {
+                self.send_heartbeat(now)
+            }
+            PaxosRole::Leader => vec![],
+            PaxosRole::Follower | PaxosRole::Candidate if now >= self.election_deadline => {
+                self.start_election(now)
+            }
+            PaxosRole::Follower | PaxosRole::Candidate => vec![],
+        }
+    }
+
+    fn handle_message(
+        &mut self,
+        now: u64,
+        from: NodeId,
+        message: PaxosMessage<C>,
+    ) -> Vec<Envelope<PaxosMessage<C>>> {
+        match message {
+            PaxosMessage::Prepare(prepare) => self.handle_prepare(now, from, prepare),
+            PaxosMessage::Promise(promise) => self.handle_promise(now, from, promise),
+            PaxosMessage::Accept(accept) => self.handle_accept(now, from, accept),
+            PaxosMessage::Ack(ack) => self.handle_ack(now, from, ack),
+            PaxosMessage::Learn(learn) => {
+                self.record_chosen(learn.slot, learn.value);
+                vec![]
+            }
+            PaxosMessage::Nack(ballot) => self.handle_nack(now, ballot),
+            PaxosMessage::Heartbeat {
+                ballot,
+                decided_upto,
+            } => self.handle_heartbeat(now, from, ballot, decided_upto),
+            PaxosMessage::HeartbeatAck { ballot } => {
+                if self.role == PaxosRole::Leader && ballot == self.ballot {
+                    self.last_contact.insert(from, now);
+                }
+                vec![]
+            }
+            PaxosMessage::CatchUp { from_slot } => self.handle_catch_up(from, from_slot),
+        }
+    }
+
+    fn propose(
+        &mut self,
+        _now: u64,
+        command: C,
+    ) -> Result<Vec<Envelope<PaxosMessage<C>>>, ProposeError> {
+        if self.role != PaxosRole::Leader {
+            return Err(ProposeError::NotLeader(self.leader_id));
+        }
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        Ok(self.start_proposal(slot, Some(command)))
+    }
+
+    fn is_leader(&self) -> bool {
+        self.role == PaxosRole::Leader
+    }
+
+    fn committed(&self) -> &[C] {
+        &self.committed
+    }
+
+    fn restart(&mut self, now: u64) {
+        self.role = PaxosRole::Follower;
+        self.leader_id = None;
+        self.promises.clear();
+        self.proposals.clear();
+        self.last_heartbeat = now;
+        self.reset_election_deadline(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Delivers every message until the cluster goes quiet
+    fn deliver_all(
+        nodes: &mut [PaxosNode<String>],
+        mut messages: Vec<Envelope<PaxosMessage<String>>>,
+    ) {
+        while let Some(envelope) = messages.pop() {
+            let node = nodes.iter_mut().find(|n| n.node_id == envelope.to).unwrap();
+            messages.extend(node.handle_message(0, envelope.from, envelope.message));
+        }
+    }
+
+    fn cluster() -> Vec<PaxosNode<String>> {
+        (1..=3)
+            .map(|id| PaxosNode::new_node(id, (1..=3).filter(|&p| p != id).collect(), 5))
+            .collect()
+    }
+
+    /// Test that a proposer with a majority of promises becomes leader and gets a value chosen
+    #[test]
+    fn leader_gets_value_chosen_by_majority() {
+        let mut nodes = cluster();
+        let messages = nodes[0].start_election(0);
+        deliver_all(&mut nodes, messages);
+        assert!(nodes[0].is_leader());
+
+        let messages = nodes[0].propose(0, "example_value".to_string()).unwrap();
+        deliver_all(&mut nodes, messages);
+        for node in &nodes {
+            assert_eq!(node.committed(), ["example_value".to_string()]);
+        }
+    }
+
+    /// Test that an acceptor rejects a Prepare with a lower ballot than it promised
+    #[test]
+    fn lower_ballot_is_rejected() {
+        let mut node = PaxosNode::<String>::new_node(2, vec![1, 3], 5);
+        node.promised = Ballot {
+            round: 4,
+            node_id: 3,
+        };
+
+        let prepare = Prepare {
+            ballot: Ballot {
+                round: 2,
+                node_id: 1,
+            },
+            from_slot: 0,
+        };
+        let replies = node.handle_message(0, 1, PaxosMessage::Prepare(prepare));
+        assert_eq!(replies[0].message, PaxosMessage::Nack(node.promised()));
+    }
+
+    /// Test that a new leader re-proposes a value a previous leader got accepted
+    #[test]
+    fn new_leader_keeps_previously_accepted_value() {
+        let mut nodes = cluster();
+        let old_ballot = Ballot {
+            round: 1,
+            node_id: 1,
+        };
+        nodes[1]
+            .accepted
+            .insert(0, (old_ballot, Some("first".to_string())));
+        nodes[1].promised = old_ballot;
+
+        let messages = nodes[2].start_election(0);
+        deliver_all(&mut nodes, messages);
+        assert!(nodes[2].is_leader());
+
+        let messages = nodes[2].propose(0, "second".to_string()).unwrap();
+        deliver_all(&mut nodes, messages);
+        assert_eq!(
+            nodes[2].committed(),
+            ["first".to_string(), "second".to_string()]
+        );
+    }
+}
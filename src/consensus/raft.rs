@@ -0,0 +1,638 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use super::{ConsensusNode, Envelope, NodeId, ProposeError};
+use crate::rng::SimRng;
+
+/// Lower bound of the randomized election timeout, in milliseconds.
+pub const ELECTION_TIMEOUT_MIN: u64 = 150;
+/// Upper bound of the randomized election timeout, in milliseconds.
+pub const ELECTION_TIMEOUT_MAX: u64 = 300;
+/// How often a leader sends heartbeats, in milliseconds.
+pub const HEARTBEAT_INTERVAL: u64 = 50;
+
+// Define the possible states of a Raft node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaftState {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// Define a log entry. A `None` command is the no-op a new leader appends to commit
+// entries left over from previous terms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry<C> {
+    pub term: u64,
+    pub command: Option<C>,
+}
+
+// Define the RequestVote message
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestVote {
+    pub term: u64,
+    pub candidate_id: NodeId,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+// Define the Vote message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vote {
+    pub term: u64,
+    pub vote_granted: bool,
+}
+
+// Define the AppendEntries message, which doubles as the heartbeat
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppendEntries<C> {
+    pub term: u64,
+    pub leader_id: NodeId,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<LogEntry<C>>,
+    pub leader_commit: u64,
+}
+
+// Define the reply to an AppendEntries message
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppendEntriesResponse {
+    pub term: u64,
+    pub success: bool,
+    /// On success, the index of the last entry known to match the leader's log.
+    /// On failure, the index of the follower's last entry, so the leader can skip back.
+    pub match_index: u64,
+}
+
+// Define the Raft message enum
+#[derive(Debug, Clone, PartialEq)]
+pub enum RaftMessage<C> {
+    RequestVote(RequestVote),
+    Vote(Vote),
+    AppendEntries(AppendEntries<C>),
+    AppendEntriesResponse(AppendEntriesResponse),
+}
+
+/// A Raft node.
+///
+/// `current_term`, `voted_for` and `log` are the persistent state and survive
+/// [`ConsensusNode::restart`]; everything else is volatile.
+#[derive(Debug, Clone)]
+pub struct RaftNode<C> {
+    node_id: NodeId,
+    peers: Vec<NodeId>,
+    state: RaftState,
+    current_term: u64,
+    voted_for: Option<NodeId>,
+    log: Vec<LogEntry<C>>,
+    commit_index: u64,
+    committed: Vec<C>,
+    leader_id: Option<NodeId>,
+    votes_received: BTreeSet<NodeId>,
+    next_index: BTreeMap<NodeId, u64>,
+    match_index: BTreeMap<NodeId, u64>,
+    last_contact: BTreeMap<NodeId, u64>,
+    election_deadline: u64,
+    last_heartbeat: u64,
+    rng: SimRng,
+}
+
+impl<C: Clone + fmt::Debug + PartialEq> RaftNode<C> {
+    /// Returns the current state of the node.
+    pub fn state(&self) -> RaftState {
+        self.state
+    }
+
+    /// Returns the current term of the node.
+    pub fn current_term(&self) -> u64 {
+        self.current_term
+    }
+
+    /// Returns the full log, including entries that are not committed yet.
+    pub fn log(&self) -> &[LogEntry<C>] {
+        &self.log
+    }
+
+    /// Returns the index of the highest log entry known to be committed.
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    fn last_log_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn last_log_term(&self) -> u64 {
+        self.log.last().map_or(0, |entry| entry.term)
+    }
+
+    /// Returns the term of the entry at `index` (1-based), or 0 for index 0.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == 0 {
+            return Some(0);
+        }
+        self.log.get(index as usize - 1).map(|entry| entry.term)
+    }
+
+    fn majority(&self) -> usize {
+        let cluster_size = self.peers.len() + 1;
+        cluster_size / 2 + 1
+    }
+
+    fn reset_election_deadline(&mut self, now: u64) {
+        self.election_deadline = now + self.rng.range(ELECTION_TIMEOUT_MIN, ELECTION_TIMEOUT_MAX);
+    }
+
+    // Move to a newer term as a follower
+    fn step_down(&mut self, term: u64) {
+        if term > self.current_term {
+            self.current_term = term;
+            self.voted_for = None;
+        }
+        self.state = RaftState::Follower;
+        self.votes_received.clear();
+    }
+
+    // Start a new election
+    fn start_election(&mut self, now: u64) -> Vec<Envelope<RaftMessage<C>>> {
+        self.state = RaftState::Candidate;
+        self.current_term += 1;
+        self.voted_for = Some(self.node_id);
+        self.leader_id = None;
+        self.votes_received.clear();
+        self.votes_received.insert(self.node_id);
+        self.reset_election_deadline(now);
+
+        if self.votes_received.len() >= self.majority() {
+            return self.become_leader(now);
+        }
+
+        let request_vote = RequestVote {
+            term: self.current_term,
+            candidate_id: self.node_id,
+            last_log_index: self.last_log_index(),
+            last_log_term: self.last_log_term(),
+        };
+        self.peers
+            .iter()
+            .map(|&peer| {
+                Envelope::new(
+                    self.node_id,
+                    peer,
+                    RaftMessage::RequestVote(request_vote.clone()),
+                )
+            })
+            .collect()
+    }
+
+    fn become_leader(&mut self, now: u64) -> Vec<Envelope<RaftMessage<C>>> {
+        self.state = RaftState::Leader;
+        self.leader_id = Some(self.node_id);
+        self.next_index.clear();
+        self.match_index.clear();
+        self.last_contact.clear();
+        for &peer in &self.peers {
+            self.next_index.insert(peer, self.last_log_index() + 1);
+            self.match_index.insert(peer, 0);
+            self.last_contact.insert(peer, now);
+        }
+        // A no-op in the new term lets the leader commit entries from earlier terms
+        self.log.push(LogEntry {
+            term: self.current_term,
+            command: None,
+        });
+        self.advance_commit_index();
+        self.broadcast_append_entries(now)
+    }
+
+    // Replicate the log to follower nodes; with nothing new this is a heartbeat
+    fn broadcast_append_entries(&mut self, now: u64) -> Vec<Envelope<RaftMessage<C>>> {
+        self.last_heartbeat = now;
+        let peers = self.peers.clone();
+        peers
+            .into_iter()
+            .filter_map(|peer| self.append_entries_for(peer))
+            .collect()
+    }
+
+    fn append_entries_for(&self, peer: NodeId) -> Option<Envelope<RaftMessage<C>>> {
+        let next_index = *self.next_index.get(&peer)?;
+        let prev_log_index = next_index - 1;
+        let append_entries = AppendEntries {
+            term: self.current_term,
+            leader_id: self.node_id,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index).unwrap_or(0),
+            entries: self.log[prev_log_index as usize..].to_vec(),
+            leader_commit: self.commit_index,
+        };
+        Some(Envelope::new(
+            self.node_id,
+            peer,
+            RaftMessage::AppendEntries(append_entries),
+        ))
+    }
+
+    // A leader that hasn't heard from a majority for a whole election timeout is probably
+    // partitioned away, so it steps down instead of accepting commands it can't commit
+    fn has_quorum(&self, now: u64) -> bool {
+        let reachable = self
+            .last_contact
+            .values()
+            .filter(|&&contact| contact + ELECTION_TIMEOUT_MAX >= now)
+            .count();
+        reachable + 1 >= self.majority()
+    }
+
+    // Commit the highest index of the current term that a majority has replicated
+    fn advance_commit_index(&mut self) {
+        for index in (self.commit_index + 1..=self.last_log_index()).rev() {
+            if self.term_at(index) != Some(self.current_term) {
+                break;
+            }
+            let replicated = 1 + self
+                .match_index
+                .values()
+                .filter(|&&matched| matched >= index)
+                .count();
+            if replicated >= self.majority() {
+                self.set_commit_index(index);
+                break;
+            }
+        }
+    }
+
+    fn set_commit_index(&mut self, index: u64) {
+        let index = index.min(self.last_log_index());
+        if index <= self.commit_index {
+            return;
+        }
+        for entry in &self.log[self.commit_index as usize..index as usize] {
+            if let Some(command) = &entry.command {
+                self.committed.push(command.clone());
+            }
+        }
+        self.commit_index = index;
+    }
+
+    // Handle a RequestVote message
+    fn handle_request_vote(
+        &mut self,
+        now: u64,
+        from: NodeId,
+        request_vote: RequestVote,
+    ) -> Vec<Envelope<RaftMessage<C>>> {
+        if request_vote.term > self.current_term {
+            self.step_down(request_vote.term);
+        }
+
+        let log_is_up_to_date = request_vote.last_log_term > self.last_log_term()
+            || (request_vote.last_log_term == self.last_log_term()
+                && request_vote.last_log_index >= self.last_log_index());
+        let can_vote = self.voted_for.is_none() || self.voted_for == Some(from);
+        let vote_granted = request_vote.term == self.current_term && can_vote && log_is_up_to_date;
+
+        if vote_granted {
+            self.voted_for = Some(from);
+            self.reset_election_deadline(now);
+        }
+
+        let vote = Vote {
+            term: self.current_term,
+            vote_granted,
+        };
+        vec![Envelope::new(self.node_id, from, RaftMessage::Vote(vote))]
+    }
+
+    // Handle a Vote message
+    fn handle_vote(&mut self, now: u64, from: NodeId, vote: Vote) -> Vec<Envelope<RaftMessage<C>>> {
+        if vote.term > self.current_term {
+            self.step_down(vote.term);
+            return vec![];
+        }
+        if self.state != RaftState::Candidate
+            || vote.term != self.current_term
+            || !vote.vote_granted
+        {
+            return vec![];
+        }
+
+        self.votes_received.insert(from);
+        if self.votes_received.len() >= self.majority() {
+            return self.become_leader(now);
+        }
+        vec![]
+    }
+
+    // Handle an AppendEntries message
+    fn handle_append_entries(
+        &mut self,
+        now: u64,
+        from: NodeId,
+        append_entries: AppendEntries<C>,
+    ) -> Vec<Envelope<RaftMessage<C>>> {
+        if append_entries.term < self.current_term {
+            // Reject the request if the term is outdated
+            return vec![self.append_response(from, false, self.last_log_index())];
+        }
+
+        self.step_down(append_entries.term);
+        self.leader_id = Some(append_entries.leader_id);
+        self.reset_election_deadline(now);
+
+        if self.term_at(append_entries.prev_log_index) != Some(append_entries.prev_log_term) {
+            // Our log doesn't contain the entry the new ones follow from
+            let hint = self
+                .last_log_index()
+                .min(append_entries.prev_log_index.saturating_sub(1));
+            return vec![self.append_response(from, false, hint)];
+        }
+
+        let mut index = append_entries.prev_log_index;
+        for entry in append_entries.entries {
+            index += 1;
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    // Conflicting entry: drop it and everything after it
+                    self.log.truncate(index as usize - 1);
+                    self.log.push(entry);
+                }
+                None => self.log.push(entry),
+            }
+        }
+
+        self.set_commit_index(append_entries.leader_commit.min(index));
+        vec![self.append_response(from, true, index)]
+    }
+
+    fn append_response(
+        &self,
+        to: NodeId,
+        success: bool,
+        match_index: u64,
+    ) -> Envelope<RaftMessage<C>> {
+        let response = AppendEntriesResponse {
+            term: self.current_term,
+            success,
+            match_index,
+        };
+        Envelope::new(
+            self.node_id,
+            to,
+            RaftMessage::AppendEntriesResponse(response),
+        )
+    }
+
+    // Handle an AppendEntriesResponse message
+    fn handle_append_entries_response(
+        &mut self,
+        now: u64,
+        from: NodeId,
+        response: AppendEntriesResponse,
+    ) -> Vec<Envelope<RaftMessage<C>>> {
+        if response.term > self.current_term {
+            self.step_down(response.term);
+            self.leader_id = None;
+            return vec![];
+        }
+        if self.state != RaftState::Leader || response.term != self.current_term {
+            return vec![];
+        }
+        self.last_contact.insert(from, now);
+
+        if response.success {
+            let matched = self.match_index.entry(from).or_insert(0);
+            *matched = (*matched).max(response.match_index);
+            let matched = *matched;
+            self.next_index.insert(from, matched + 1);
+            self.advance_commit_index();
+            vec![]
+        } else {
+            let next_index = self.next_index.get(&from).copied().unwrap_or(1);
+            let next_index = (next_index - 1).min(response.match_index + 1).max(1);
+            self.next_index.insert(from, next_index);
+            self.append_entries_for(from).into_iter().collect()
+        }
+    }
+}
+
+impl<C: Clone + fmt::Debug + PartialEq> ConsensusNode for RaftNode<C> {
+    type Command = C;
+    type Message = RaftMessage<C>;
+
+    const ALGORITHM: &'static str = "raft";
+
+    fn new_node(node_id: NodeId, peers: Vec<NodeId>, seed: u64) -> Self {
+        let mut node = RaftNode {
+            node_id,
+            peers,
+            state: RaftState::Follower,
+            current_term: 0,
+            voted_for: None,
+            log: vec![],
+            commit_index: 0,
+            committed: vec![],
+            leader_id: None,
+            votes_received: BTreeSet::new(),
+            next_index: BTreeMap::new(),
+            match_index: BTreeMap::new(),
+            last_contact: BTreeMap::new(),
+            election_deadline: 0,
+            last_heartbeat: 0,
+            rng: SimRng::new(seed ^ node_id.wrapping_mul(0x2545_f491_4f6c_dd1d)),
+        };
+        node.reset_election_deadline(0);
+        node
+    }
+
+    fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    fn tick(&mut self, now: u64) -> Vec<Envelope<RaftMessage<C>>> {
+        match self.state {
+            RaftState::Leader if !self.has_quorum(now) => {
+                self.step_down(self.current_term);
+                self.leader_id = None;
+                self.reset_election_deadline(now);
+                vec![]
+            }
+            RaftState::Leader if now >= self.last_heartbeat + HEARTBEAT_INTERVAL => {
+                self.broadcast_append_entries(now)
+            }
+            RaftState::Leader => vec![],
+            RaftState::Follower | RaftState::Candidate if now >= self.election_deadline => {
+                self.start_election(now)
+            }
+            RaftState::Follower | RaftState::Candidate => vec![],
+        }
+    }
+
+    fn handle_message(
+        &mut self,
+        now: u64,
+        from: NodeId,
+        message: RaftMessage<C>,
+    ) -> Vec<Envelope<RaftMessage<C>>> {
+        match message {
+            RaftMessage::RequestVote(request_vote) => {
+                self.handle_request_vote(now, from, request_vote)
+            }
+            RaftMessage::Vote(vote) => self.handle_vote(now, from, vote),
+            RaftMessage::AppendEntries(append_entries) => {
+                self.handle_append_entries(now, from, append_entries)
+            }
+            RaftMessage::AppendEntriesResponse(response) => {
+                self.handle_append_entries_response(now, from, response)
+            }
+        }
+    }
+
+    fn propose(
+        &mut self,
+        now: u64,
+        command: C,
+    ) -> Result<Vec<Envelope<RaftMessage<C>>>, ProposeError> {
+        if self.state != RaftState::Leader {
+            return Err(ProposeError::NotLeader(self.leader_id));
+        }
+        self.log.push(LogEntry {
+            term: self.current_term,
+            command: Some(command),
+        });
+        self.advance_commit_index();
+        Ok(self.broadcast_append_entries(now))
+    }
+
+    fn is_leader(&self) -> bool {
+        self.state == RaftState::Leader
+    }
+
+    fn committed(&self) -> &[C] {
+        &self.committed
+    }
+
+    fn restart(&mut self, now: u64) {
+        self.state = RaftState::Follower;
+        self.commit_index = 0;
+        self.committed.clear();
+        self.leader_id = None;
+        self.votes_received.clear();
+        self.next_index.clear();
+        self.match_index.clear();
+        self.last_contact.clear();
+        self.last_heartbeat = now;
+        self.reset_election_deadline(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Delivers every message until the cluster goes quiet
+    fn deliver_all(
+        nodes: &mut [RaftNode<String>],
+        mut messages: Vec<Envelope<RaftMessage<String>>>,
+    ) {
+        while let Some(envelope) = messages.pop() {
+            let node = nodes.iter_mut().find(|n| n.node_id == envelope.to).unwrap();
+            messages.extend(node.handle_message(0, envelope.from, envelope.message));
+        }
+    }
+
+    /// Test that a single node cluster elects itself and commits immediately
+    #[test]
+    fn single_node_commits_without_peers() {
+        let mut node = RaftNode::<String>::new_node(1, vec![], 7);
+        node.tick(ELECTION_TIMEOUT_MAX);
+        assert!(node.is_leader());
+
+        node.propose(ELECTION_TIMEOUT_MAX, "set x".to_string())
+            .unwrap();
+        assert_eq!(node.committed(), ["set x".to_string()]);
+    }
+
+    /// Test that a candidate with an outdated log does not get a vote
+    #[test]
+    fn vote_is_refused_to_candidate_with_stale_log() {
+        let mut node = RaftNode::<String>::new_node(1, vec![2], 7);
+        node.current_term = 2;
+        node.log.push(LogEntry {
+            term: 2,
+            command: None,
+        });
+
+        let request_vote = RequestVote {
+            term: 3,
+            candidate_id: 2,
+            last_log_index: 5,
+            last_log_term: 1,
+        };
+        let replies = node.handle_message(0, 2, RaftMessage::RequestVote(request_vote));
+        assert_eq!(node.current_term(), 3);
+        assert_eq!(
+            replies[0].message,
+            RaftMessage::Vote(Vote {
+                term: 3,
+                vote_granted: false
+            })
+        );
+    }
+
+    /// Test that a three node cluster elects a leader and replicates a command
+    #[test]
+    fn three_nodes_elect_leader_and_replicate() {
+        let mut nodes: Vec<RaftNode<String>> = (1..=3)
+            .map(|id| RaftNode::new_node(id, (1..=3).filter(|&p| p != id).collect(), 11))
+            .collect();
+
+        let messages = nodes[0].start_election(0);
+        deliver_all(&mut nodes, messages);
+        assert!(nodes[0].is_leader());
+
+        let messages = nodes[0].propose(0, "set x".to_string()).unwrap();
+        deliver_all(&mut nodes, messages);
+        assert_eq!(nodes[0].committed(), ["set x".to_string()]);
+
+        // Followers learn the commit index with the next heartbeat
+        let messages = nodes[0].broadcast_append_entries(HEARTBEAT_INTERVAL);
+        deliver_all(&mut nodes, messages);
+        for node in &nodes {
+            assert_eq!(node.committed(), ["set x".to_string()]);
+        }
+    }
+
+    /// Test that a follower drops entries that conflict with the leader's log
+    #[test]
+    fn conflicting_entries_are_truncated() {
+        let mut follower = RaftNode::<String>::new_node(2, vec![1], 3);
+        follower.log = vec![
+            LogEntry {
+                term: 1,
+                command: Some("a".to_string()),
+            },
+            LogEntry {
+                term: 1,
+                command: Some("stale".to_string()),
+            },
+        ];
+
+        let append_entries = AppendEntries {
+            term: 2,
+            leader_id: 1,
+            prev_log_index: 1,
+            prev_log_term: 1,
+            entries: vec![LogEntry {
+                term: 2,
+                command: Some("b".to_string()),
+            }],
+            leader_commit: 2,
+        };
+        follower.handle_message(0, 1, RaftMessage::AppendEntries(append_entries));
+
+        assert_eq!(follower.log().len(), 2);
+        assert_eq!(follower.committed(), ["a".to_string(), "b".to_string()]);
+    }
+}
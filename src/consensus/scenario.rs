@@ -0,0 +1,480 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use super::simulation::{NetworkConfig, NetworkStats, Simulation, TraceEvent, TraceKind};
+use super::{ConsensusNode, NodeId};
+
+/// A fault or client action scheduled at a point in simulated time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScenarioEvent {
+    Crash(NodeId),
+    Restart(NodeId),
+    Partition(Vec<Vec<NodeId>>),
+    Heal,
+    MessageLoss(f64),
+    ClockRate { node: NodeId, percent: u64 },
+    Propose(String),
+}
+
+/// A condition that must hold when the scenario finishes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expectation {
+    /// At least one running node is the leader.
+    Leader,
+    /// Every running node has committed at least this many commands.
+    Committed(usize),
+    /// Every proposed command is committed on every running node.
+    AllProposalsCommitted,
+}
+
+/// A reproducible failure scenario that can be replayed against any [`ConsensusNode`].
+///
+/// Besides the expectations given by the user, every run checks after each step that
+/// all nodes agree on the committed log (no node commits a different command at the
+/// same position) and that only proposed commands get committed.
+///
+/// # Example
+/// ```
+/// use training_llms::consensus::raft::RaftNode;
+/// use training_llms::consensus::scenario::Scenario;
+///
+/// let report = Scenario::new("leader crash")
+///     .nodes(3)
+///     .propose(500, "set x = 1")
+///     .crash(1_000, 1)
+///     .propose(1_500, "set x = 2")
+///     .restart(2_000, 1)
+///     .expect_all_proposals_committed()
+///     .run::<RaftNode<String>>();
+/// assert!(report.passed, "{}", report);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    name: String,
+    nodes: u64,
+    seed: u64,
+    duration: u64,
+    network: NetworkConfig,
+    events: Vec<(u64, ScenarioEvent)>,
+    expectations: Vec<Expectation>,
+}
+
+impl Scenario {
+    /// Creates an empty scenario with 3 nodes, seed 0, lasting 5 simulated seconds.
+    pub fn new(name: &str) -> Self {
+        Scenario {
+            name: name.to_string(),
+            nodes: 3,
+            seed: 0,
+            duration: 5_000,
+            network: NetworkConfig::default(),
+            events: vec![],
+            expectations: vec![],
+        }
+    }
+
+    /// Sets the number of nodes, which get ids `1..=count`.
+    pub fn nodes(mut self, count: u64) -> Self {
+        self.nodes = count;
+        self
+    }
+
+    /// Sets the seed every random choice is derived from.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Sets how long the scenario runs, in simulated milliseconds.
+    pub fn duration(mut self, duration: u64) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Sets latency and tick settings of the simulated network.
+    pub fn network(mut self, network: NetworkConfig) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Schedules an arbitrary event at time `at`.
+    pub fn at(mut self, at: u64, event: ScenarioEvent) -> Self {
+        self.events.push((at, event));
+        self
+    }
+
+    /// Crashes a node at time `at`.
+    pub fn crash(self, at: u64, node: NodeId) -> Self {
+        self.at(at, ScenarioEvent::Crash(node))
+    }
+
+    /// Restarts a crashed node at time `at`.
+    pub fn restart(self, at: u64, node: NodeId) -> Self {
+        self.at(at, ScenarioEvent::Restart(node))
+    }
+
+    /// Splits the network into groups at time `at`.
+    pub fn partition(self, at: u64, groups: &[&[NodeId]]) -> Self {
+        let groups = groups.iter().map(|group| group.to_vec()).collect();
+        self.at(at, ScenarioEvent::Partition(groups))
+    }
+
+    /// Removes any partition at time `at`.
+    pub fn heal(self, at: u64) -> Self {
+        self.at(at, ScenarioEvent::Heal)
+    }
+
+    /// Drops each message with the given probability from time `at` on.
+    pub fn message_loss(self, at: u64, probability: f64) -> Self {
+        self.at(at, ScenarioEvent::MessageLoss(probability))
+    }
+
+    /// Makes a node's clock run at `percent`% speed from time `at` on.
+    pub fn clock_rate(self, at: u64, node: NodeId, percent: u64) -> Self {
+        self.at(at, ScenarioEvent::ClockRate { node, percent })
+    }
+
+    /// Submits a command at time `at`. If there is no leader yet, it is retried every
+    /// step until some leader accepts it.
+    pub fn propose(self, at: u64, command: &str) -> Self {
+        self.at(at, ScenarioEvent::Propose(command.to_string()))
+    }
+
+    /// Expects a leader at the end of the run.
+    pub fn expect_leader(mut self) -> Self {
+        self.expectations.push(Expectation::Leader);
+        self
+    }
+
+    /// Expects every running node to have committed at least `count` commands.
+    pub fn expect_committed(mut self, count: usize) -> Self {
+        self.expectations.push(Expectation::Committed(count));
+        self
+    }
+
+    /// Expects every proposed command to be committed on every running node.
+    pub fn expect_all_proposals_committed(mut self) -> Self {
+        self.expectations.push(Expectation::AllProposalsCommitted);
+        self
+    }
+
+    /// Replays the scenario against a consensus implementation.
+    pub fn run<N: ConsensusNode<Command = String>>(&self) -> ScenarioReport {
+        let mut checker = InvariantChecker::default();
+        let mut pending: Vec<String> = vec![];
+
+        let mut simulation = self.replay::<N>(|simulation, proposed| {
+            // Check what the previous step did before doing anything new
+            checker.check(simulation);
+            pending.extend(proposed);
+            pending.retain(|command| match simulation.propose(command.clone()) {
+                Ok(_) => {
+                    checker.proposed.insert(command.clone());
+                    false
+                }
+                Err(_) => true,
+            });
+        });
+        checker.check(&mut simulation);
+
+        let mut failures = checker.violations.clone();
+        for command in &pending {
+            failures.push(format!(
+                "command {:?} was never accepted by a leader",
+                command
+            ));
+        }
+        for expectation in &self.expectations {
+            if let Err(failure) = check_expectation(&simulation, &checker, expectation) {
+                failures.push(failure);
+            }
+        }
+
+        ScenarioReport {
+            scenario: self.name.clone(),
+            algorithm: N::ALGORITHM,
+            passed: failures.is_empty(),
+            failures,
+            trace: simulation.trace().to_vec(),
+            stats: simulation.stats().clone(),
+        }
+    }
+
+    /// Creates the simulation for this scenario and applies its faults at their scheduled
+    /// times. `before_step` runs before every step and receives the commands whose
+    /// `propose` events are due, so callers decide how to submit them.
+    pub(crate) fn replay<N: ConsensusNode>(
+        &self,
+        mut before_step: impl FnMut(&mut Simulation<N>, Vec<String>),
+    ) -> Simulation<N> {
+        let mut simulation = Simulation::<N>::new(self.nodes, self.seed, self.network.clone());
+        let mut events = self.events.clone();
+        events.sort_by_key(|(at, _)| *at);
+        let mut events = events.into_iter().peekable();
+
+        while simulation.now() < self.duration {
+            let mut proposed = vec![];
+            while let Some((_, event)) = events.next_if(|(at, _)| *at <= simulation.now()) {
+                match event {
+                    ScenarioEvent::Crash(node) => simulation.crash(node),
+                    ScenarioEvent::Restart(node) => simulation.restart(node),
+                    ScenarioEvent::Partition(groups) => simulation.partition(groups),
+                    ScenarioEvent::Heal => simulation.heal(),
+                    ScenarioEvent::MessageLoss(probability) => {
+                        simulation.set_message_loss(probability)
+                    }
+                    ScenarioEvent::ClockRate { node, percent } => {
+                        simulation.set_clock_rate(node, percent)
+                    }
+                    ScenarioEvent::Propose(command) => proposed.push(command),
+                }
+            }
+
+            before_step(&mut simulation, proposed);
+            simulation.step();
+        }
+        simulation
+    }
+}
+
+// Safety checks that run after every step
+#[derive(Default)]
+struct InvariantChecker {
+    proposed: BTreeSet<String>,
+    longest_committed: Vec<String>,
+    violations: Vec<String>,
+}
+
+impl InvariantChecker {
+    fn check<N: ConsensusNode<Command = String>>(&mut self, simulation: &mut Simulation<N>) {
+        for id in simulation.node_ids() {
+            let committed = match simulation.node(id) {
+                Some(node) => node.committed().to_vec(),
+                None => continue,
+            };
+
+            let shared = committed.len().min(self.longest_committed.len());
+            if let Some(index) = (0..shared).find(|&i| committed[i] != self.longest_committed[i]) {
+                self.violate(
+                    simulation,
+                    format!(
+                        "agreement: node {} committed {:?} at #{} but {:?} was committed there before",
+                        id, committed[index], index, self.longest_committed[index]
+                    ),
+                );
+            } else if committed.len() > self.longest_committed.len() {
+                self.longest_committed = committed.clone();
+            }
+
+            if let Some(command) = committed.iter().find(|c| !self.proposed.contains(*c)) {
+                self.violate(
+                    simulation,
+                    format!(
+                        "validity: node {} committed {:?}, which was never proposed",
+                        id, command
+                    ),
+                );
+            }
+        }
+    }
+
+    fn violate<N: ConsensusNode>(&mut self, simulation: &mut Simulation<N>, description: String) {
+        if !self.violations.contains(&description) {
+            simulation.record(TraceKind::Violation(description.clone()));
+            self.violations.push(description);
+        }
+    }
+}
+
+fn check_expectation<N: ConsensusNode<Command = String>>(
+    simulation: &Simulation<N>,
+    checker: &InvariantChecker,
+    expectation: &Expectation,
+) -> Result<(), String> {
+    let running: Vec<NodeId> = simulation
+        .node_ids()
+        .into_iter()
+        .filter(|&id| simulation.is_up(id))
+        .collect();
+
+    match expectation {
+        Expectation::Leader if simulation.leaders().is_empty() => {
+            Err("expected a leader at the end of the run, found none".to_string())
+        }
+        Expectation::Leader => Ok(()),
+        Expectation::Committed(count) => {
+            for id in running {
+                let committed = simulation.node(id).map_or(0, |node| node.committed().len());
+                if committed < *count {
+                    return Err(format!(
+                        "expected node {} to commit at least {} commands, it committed {}",
+                        id, count, committed
+                    ));
+                }
+            }
+            Ok(())
+        }
+        Expectation::AllProposalsCommitted => {
+            for id in running {
+                let Some(node) = simulation.node(id) else {
+                    continue;
+                };
+                if let Some(missing) = checker
+                    .proposed
+                    .iter()
+                    .find(|command| !node.committed().contains(command))
+                {
+                    return Err(format!(
+                        "expected node {} to commit {:?}, but it did not",
+                        id, missing
+                    ));
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The outcome of running a [`Scenario`] against one algorithm.
+#[derive(Debug, Clone)]
+pub struct ScenarioReport {
+    pub scenario: String,
+    pub algorithm: &'static str,
+    pub passed: bool,
+    pub failures: Vec<String>,
+    pub trace: Vec<TraceEvent>,
+    pub stats: NetworkStats,
+}
+
+impl fmt::Display for ScenarioReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let outcome = if self.passed { "PASSED" } else { "FAILED" };
+        writeln!(
+            f,
+            "Scenario '{}' on {}: {}",
+            self.scenario, self.algorithm, outcome
+        )?;
+        for failure in &self.failures {
+            writeln!(f, "- {}", failure)?;
+        }
+        writeln!(
+            f,
+            "Messages: {} sent, {} delivered, {} dropped",
+            self.stats.sent, self.stats.delivered, self.stats.dropped
+        )?;
+        writeln!(f, "Trace:")?;
+        for event in &self.trace {
+            writeln!(f, "  {}", event)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::paxos::PaxosNode;
+    use crate::consensus::raft::RaftNode;
+
+    fn assert_passes_on_both(scenario: &Scenario) {
+        let raft = scenario.run::<RaftNode<String>>();
+        assert!(raft.passed, "{}", raft);
+        let paxos = scenario.run::<PaxosNode<String>>();
+        assert!(paxos.passed, "{}", paxos);
+    }
+
+    /// Test that the cluster keeps committing after the leader crashes and comes back
+    #[test]
+    fn leader_crash_and_restart() {
+        let scenario = Scenario::new("leader crash")
+            .nodes(5)
+            .propose(600, "a")
+            .crash(1_000, 1)
+            .crash(1_000, 2)
+            .propose(1_200, "b")
+            .restart(2_500, 1)
+            .restart(2_500, 2)
+            .propose(3_000, "c")
+            .expect_leader()
+            .expect_all_proposals_committed();
+
+        // Whichever node leads first, crashing two of five still leaves a majority
+        for seed in 0..5 {
+            assert_passes_on_both(&scenario.clone().seed(seed));
+        }
+    }
+
+    /// Test that a minority partition cannot commit and catches up after healing
+    #[test]
+    fn partition_and_heal() {
+        let scenario = Scenario::new("partition")
+            .nodes(5)
+            .propose(500, "before")
+            .partition(1_000, &[&[1, 2], &[3, 4, 5]])
+            .propose(1_800, "during")
+            .heal(3_000)
+            .propose(3_500, "after")
+            .duration(6_000)
+            .expect_leader()
+            .expect_all_proposals_committed();
+
+        assert_passes_on_both(&scenario);
+    }
+
+    /// Test that commands still get committed when a fifth of the messages are lost
+    #[test]
+    fn lossy_network() {
+        let scenario = Scenario::new("message loss")
+            .message_loss(0, 0.2)
+            .propose(1_000, "a")
+            .propose(1_500, "b")
+            .message_loss(4_000, 0.0)
+            .duration(8_000)
+            .expect_committed(2);
+
+        assert_passes_on_both(&scenario);
+    }
+
+    /// Test that a node with a fast clock does not break agreement
+    #[test]
+    fn clock_skew() {
+        let scenario = Scenario::new("clock skew")
+            .clock_rate(0, 3, 300)
+            .clock_rate(0, 1, 50)
+            .propose(1_000, "a")
+            .propose(2_000, "b")
+            .expect_all_proposals_committed();
+
+        assert_passes_on_both(&scenario);
+    }
+
+    /// Test that replaying a scenario with the same seed produces the same trace
+    #[test]
+    fn replay_is_deterministic() {
+        let scenario = Scenario::new("replay")
+            .seed(42)
+            .message_loss(0, 0.1)
+            .propose(700, "a")
+            .crash(1_500, 2)
+            .restart(2_500, 2);
+
+        let first = scenario.run::<RaftNode<String>>();
+        let second = scenario.run::<RaftNode<String>>();
+        assert_eq!(first.trace, second.trace);
+        assert_eq!(first.stats, second.stats);
+    }
+
+    /// Test that unmet expectations make the report fail
+    #[test]
+    fn isolated_nodes_fail_expectations() {
+        let report = Scenario::new("no quorum")
+            .partition(0, &[&[1], &[2], &[3]])
+            .propose(100, "a")
+            .duration(2_000)
+            .expect_leader()
+            .run::<RaftNode<String>>();
+
+        assert!(!report.passed);
+        assert_eq!(report.failures.len(), 2);
+    }
+}
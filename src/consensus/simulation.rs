@@ -0,0 +1,413 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use super::{ConsensusNode, Envelope, NodeId, ProposeError};
+use crate::rng::SimRng;
+
+/// Settings of the simulated network.
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    /// Minimum delivery latency of a message, in milliseconds.
+    pub min_latency: u64,
+    /// Maximum delivery latency of a message, in milliseconds.
+    pub max_latency: u64,
+    /// How much simulated time passes between two steps, in milliseconds.
+    pub tick_interval: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            min_latency: 1,
+            max_latency: 10,
+            tick_interval: 5,
+        }
+    }
+}
+
+/// Counters of what happened to the messages sent during a simulation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    pub sent: u64,
+    pub delivered: u64,
+    pub dropped: u64,
+}
+
+/// Something that happened during a simulation, recorded in the trace.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceKind {
+    Crashed(NodeId),
+    Restarted(NodeId),
+    Partitioned(Vec<Vec<NodeId>>),
+    Healed,
+    MessageLoss(f64),
+    ClockRate {
+        node: NodeId,
+        percent: u64,
+    },
+    Proposed {
+        node: NodeId,
+        command: String,
+    },
+    LeaderElected(NodeId),
+    LeaderLost(NodeId),
+    Committed {
+        node: NodeId,
+        index: usize,
+        command: String,
+    },
+    Violation(String),
+}
+
+/// A trace entry: what happened and at which simulated time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEvent {
+    pub time: u64,
+    pub kind: TraceKind,
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{:>6}ms] ", self.time)?;
+        match &self.kind {
+            TraceKind::Crashed(node) => write!(f, "node {} crashed", node),
+            TraceKind::Restarted(node) => write!(f, "node {} restarted", node),
+            TraceKind::Partitioned(groups) => write!(f, "network partitioned into {:?}", groups),
+            TraceKind::Healed => write!(f, "network healed"),
+            TraceKind::MessageLoss(probability) => {
+                write!(f, "message loss set to {:.0}%", probability * 100.0)
+            }
+            TraceKind::ClockRate { node, percent } => {
+                write!(f, "clock of node {} runs at {}% speed", node, percent)
+            }
+            TraceKind::Proposed { node, command } => {
+                write!(f, "command {} proposed to node {}", command, node)
+            }
+            TraceKind::LeaderElected(node) => write!(f, "node {} became leader", node),
+            TraceKind::LeaderLost(node) => write!(f, "node {} is no longer leader", node),
+            TraceKind::Committed {
+                node,
+                index,
+                command,
+            } => write!(f, "node {} committed #{}: {}", node, index, command),
+            TraceKind::Violation(description) => write!(f, "VIOLATION: {}", description),
+        }
+    }
+}
+
+// A node's local clock, which may run faster or slower than simulated time
+#[derive(Debug, Clone)]
+struct Clock {
+    rate_percent: u64,
+    since_sim: u64,
+    since_local: u64,
+}
+
+impl Clock {
+    fn local_time(&self, now: u64) -> u64 {
+        self.since_local + (now - self.since_sim) * self.rate_percent / 100
+    }
+}
+
+struct SimNode<N> {
+    node: N,
+    up: bool,
+    clock: Clock,
+    was_leader: bool,
+    committed_len: usize,
+}
+
+/// A deterministic, single-threaded network of consensus nodes.
+///
+/// Every source of nondeterminism (latency, message loss, election timeouts) comes
+/// from seeded [`SimRng`]s, so running the same steps with the same seed always
+/// produces the same trace.
+pub struct Simulation<N: ConsensusNode> {
+    now: u64,
+    config: NetworkConfig,
+    nodes: BTreeMap<NodeId, SimNode<N>>,
+    in_flight: BTreeMap<(u64, u64), Envelope<N::Message>>,
+    next_sequence: u64,
+    partitions: Option<Vec<BTreeSet<NodeId>>>,
+    message_loss: f64,
+    rng: SimRng,
+    stats: NetworkStats,
+    trace: Vec<TraceEvent>,
+}
+
+impl<N: ConsensusNode> Simulation<N> {
+    /// Creates a simulation with nodes `1..=node_count`.
+    pub fn new(node_count: u64, seed: u64, config: NetworkConfig) -> Self {
+        let ids: Vec<NodeId> = (1..=node_count).collect();
+        let nodes = ids
+            .iter()
+            .map(|&id| {
+                let peers = ids.iter().copied().filter(|&peer| peer != id).collect();
+                let node = SimNode {
+                    node: N::new_node(id, peers, seed),
+                    up: true,
+                    clock: Clock {
+                        rate_percent: 100,
+                        since_sim: 0,
+                        since_local: 0,
+                    },
+                    was_leader: false,
+                    committed_len: 0,
+                };
+                (id, node)
+            })
+            .collect();
+
+        Simulation {
+            now: 0,
+            config,
+            nodes,
+            in_flight: BTreeMap::new(),
+            next_sequence: 0,
+            partitions: None,
+            message_loss: 0.0,
+            rng: SimRng::new(seed),
+            stats: NetworkStats::default(),
+            trace: vec![],
+        }
+    }
+
+    /// Returns the current simulated time, in milliseconds.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Returns the ids of every node in the cluster.
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        self.nodes.keys().copied().collect()
+    }
+
+    /// Returns a node by id.
+    pub fn node(&self, id: NodeId) -> Option<&N> {
+        self.nodes.get(&id).map(|sim_node| &sim_node.node)
+    }
+
+    /// Returns `true` if the node exists and has not crashed.
+    pub fn is_up(&self, id: NodeId) -> bool {
+        self.nodes.get(&id).is_some_and(|sim_node| sim_node.up)
+    }
+
+    /// Returns the running nodes that believe they are the leader.
+    pub fn leaders(&self) -> Vec<NodeId> {
+        self.nodes
+            .iter()
+            .filter(|(_, sim_node)| sim_node.up && sim_node.node.is_leader())
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    /// Returns the message counters so far.
+    pub fn stats(&self) -> &NetworkStats {
+        &self.stats
+    }
+
+    /// Returns every event recorded so far.
+    pub fn trace(&self) -> &[TraceEvent] {
+        &self.trace
+    }
+
+    /// Appends an event to the trace at the current time.
+    pub fn record(&mut self, kind: TraceKind) {
+        self.trace.push(TraceEvent {
+            time: self.now,
+            kind,
+        });
+    }
+
+    /// Stops a node. It keeps its state but ignores messages and timers until restarted.
+    pub fn crash(&mut self, id: NodeId) {
+        if let Some(sim_node) = self.nodes.get_mut(&id) {
+            sim_node.up = false;
+            self.record(TraceKind::Crashed(id));
+        }
+    }
+
+    /// Restarts a crashed node, which loses its volatile state.
+    pub fn restart(&mut self, id: NodeId) {
+        let now = self.now;
+        if let Some(sim_node) = self.nodes.get_mut(&id) {
+            sim_node.up = true;
+            let local = sim_node.clock.local_time(now);
+            sim_node.node.restart(local);
+            sim_node.committed_len = sim_node.node.committed().len();
+            self.record(TraceKind::Restarted(id));
+        }
+    }
+
+    /// Splits the network: only nodes in the same group can talk to each other.
+    /// Nodes that are not listed in any group are isolated.
+    pub fn partition(&mut self, groups: Vec<Vec<NodeId>>) {
+        self.partitions = Some(
+            groups
+                .iter()
+                .map(|group| group.iter().copied().collect())
+                .collect(),
+        );
+        self.record(TraceKind::Partitioned(groups));
+    }
+
+    /// Removes any partition.
+    pub fn heal(&mut self) {
+        self.partitions = None;
+        self.record(TraceKind::Healed);
+    }
+
+    /// Sets the probability that any message is lost.
+    pub fn set_message_loss(&mut self, probability: f64) {
+        self.message_loss = probability;
+        self.record(TraceKind::MessageLoss(probability));
+    }
+
+    /// Makes a node's clock run at `percent`% of the speed of simulated time from now on.
+    pub fn set_clock_rate(&mut self, id: NodeId, percent: u64) {
+        let now = self.now;
+        if let Some(sim_node) = self.nodes.get_mut(&id) {
+            sim_node.clock = Clock {
+                rate_percent: percent,
+                since_sim: now,
+                since_local: sim_node.clock.local_time(now),
+            };
+            self.record(TraceKind::ClockRate { node: id, percent });
+        }
+    }
+
+    /// Proposes a command to a specific node.
+    pub fn propose_to(&mut self, id: NodeId, command: N::Command) -> Result<(), ProposeError> {
+        let now = self.now;
+        let sim_node = match self.nodes.get_mut(&id) {
+            Some(sim_node) if sim_node.up => sim_node,
+            _ => return Err(ProposeError::NotLeader(None)),
+        };
+        let description = format!("{:?}", command);
+        let local = sim_node.clock.local_time(now);
+        let messages = sim_node.node.propose(local, command)?;
+        self.record(TraceKind::Proposed {
+            node: id,
+            command: description,
+        });
+        self.dispatch(messages);
+        Ok(())
+    }
+
+    /// Proposes a command to whichever running node believes it is the leader.
+    ///
+    /// # Returns
+    /// The id of the node that accepted the proposal.
+    pub fn propose(&mut self, command: N::Command) -> Result<NodeId, ProposeError> {
+        let leader = match self.leaders().first() {
+            Some(&leader) => leader,
+            None => return Err(ProposeError::NotLeader(None)),
+        };
+        self.propose_to(leader, command)?;
+        Ok(leader)
+    }
+
+    fn connected(&self, from: NodeId, to: NodeId) -> bool {
+        match &self.partitions {
+            None => true,
+            Some(groups) => groups
+                .iter()
+                .any(|group| group.contains(&from) && group.contains(&to)),
+        }
+    }
+
+    fn dispatch(&mut self, messages: Vec<Envelope<N::Message>>) {
+        for envelope in messages {
+            self.stats.sent += 1;
+            if !self.connected(envelope.from, envelope.to) || self.rng.chance(self.message_loss) {
+                self.stats.dropped += 1;
+                continue;
+            }
+            let latency = self
+                .rng
+                .range(self.config.min_latency, self.config.max_latency);
+            self.in_flight
+                .insert((self.now + latency, self.next_sequence), envelope);
+            self.next_sequence += 1;
+        }
+    }
+
+    /// Advances simulated time by one tick: delivers due messages, then fires timers.
+    pub fn step(&mut self) {
+        self.now += self.config.tick_interval;
+
+        while let Some(entry) = self.in_flight.first_entry() {
+            if entry.key().0 > self.now {
+                break;
+            }
+            let envelope = entry.remove();
+            let reachable = self.connected(envelope.from, envelope.to);
+            let now = self.now;
+            let replies = match self.nodes.get_mut(&envelope.to) {
+                Some(sim_node) if sim_node.up && reachable => {
+                    let local = sim_node.clock.local_time(now);
+                    sim_node
+                        .node
+                        .handle_message(local, envelope.from, envelope.message)
+                }
+                _ => {
+                    self.stats.dropped += 1;
+                    continue;
+                }
+            };
+            self.stats.delivered += 1;
+            self.dispatch(replies);
+        }
+
+        let ids = self.node_ids();
+        for id in ids {
+            let now = self.now;
+            let messages = match self.nodes.get_mut(&id) {
+                Some(sim_node) if sim_node.up => {
+                    let local = sim_node.clock.local_time(now);
+                    sim_node.node.tick(local)
+                }
+                _ => continue,
+            };
+            self.dispatch(messages);
+        }
+
+        self.observe();
+    }
+
+    /// Runs steps until simulated time reaches `time`.
+    pub fn run_until(&mut self, time: u64) {
+        while self.now + self.config.tick_interval <= time {
+            self.step();
+        }
+    }
+
+    // Record leadership changes and newly committed commands in the trace
+    fn observe(&mut self) {
+        let mut events = vec![];
+        for (&id, sim_node) in self.nodes.iter_mut() {
+            let is_leader = sim_node.up && sim_node.node.is_leader();
+            if is_leader != sim_node.was_leader {
+                events.push(if is_leader {
+                    TraceKind::LeaderElected(id)
+                } else {
+                    TraceKind::LeaderLost(id)
+                });
+                sim_node.was_leader = is_leader;
+            }
+
+            let committed = sim_node.node.committed();
+            for (index, command) in committed.iter().enumerate().skip(sim_node.committed_len) {
+                events.push(TraceKind::Committed {
+                    node: id,
+                    index,
+                    command: format!("{:?}", command),
+                });
+            }
+            sim_node.committed_len = committed.len();
+        }
+        for kind in events {
+            self.record(kind);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::BTreeMap;
+
+use super::message::Request;
+use super::node_id::{Distance, NodeId};
+use super::routing_table::Contact;
+
+/// Number of requests a lookup keeps in flight.
+pub const ALPHA: usize = 3;
+
+/// What a lookup looks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupKind {
+    /// The nodes closest to the target.
+    FindNode,
+    /// The value stored under the target, or the nodes closest to it if none has it.
+    FindValue,
+}
+
+/// Where a candidate of a lookup stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    NotQueried,
+    Waiting,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    contact: Contact,
+    state: State,
+}
+
+/// An iterative lookup of the nodes closest to a target, or of the value stored under
+/// it.
+///
+/// The lookup starts from the closest contacts the node knows, and asks the closest
+/// ones it hasn't asked yet for the contacts they know closest to the target, keeping
+/// `alpha` requests in flight. Each answer brings candidates closer to the target,
+/// about halving the distance, so a lookup among n nodes takes O(log n) steps. It is
+/// done once the `k` closest candidates that didn't fail have all answered, or a value
+/// was found.
+///
+/// The lookup doesn't send anything itself: `next_contact` returns who to ask, and the caller
+/// reports answers with `on_nodes` or `on_value`, and timeouts with `on_failure`.
+///
+/// # Methods
+/// - `new`: Starts a lookup from known contacts.
+/// - `next_contact`: Returns the next contact to ask, if a request may be sent.
+/// - `on_nodes`: Records the contacts a candidate answered with.
+/// - `on_value`: Records the value a candidate answered with.
+/// - `on_failure`: Records that a candidate didn't answer.
+/// - `is_finished`: Returns `true` once the lookup converged.
+/// - `closest`: Returns the closest candidates that answered.
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    target: NodeId,
+    kind: LookupKind,
+    alpha: usize,
+    k: usize,
+    candidates: BTreeMap<Distance, Candidate>,
+    value: Option<Vec<u8>>,
+    /// The version of the value.
+    version: u64,
+    /// The contact that answered with the value.
+    found_at: Option<Contact>,
+    queried: usize,
+    in_flight: usize,
+}
+
+impl Lookup {
+    /// Starts a lookup of `target` from the `seeds` contacts.
+    ///
+    /// # Parameters
+    /// - `target`: The id to find the closest nodes to, or the key of the value
+    /// - `kind`: Whether to look for nodes or a value
+    /// - `seeds`: The contacts to start from, typically the closest in the routing table
+    /// - `alpha`: The number of requests in flight
+    /// - `k`: The number of closest nodes to converge on
+    pub fn new(
+        target: NodeId,
+        kind: LookupKind,
+        seeds: impl IntoIterator<Item = Contact>,
+        alpha: usize,
+        k: usize,
+    ) -> Self {
+        let mut lookup = Lookup {
+            target,
+            kind,
+            alpha,
+            k,
+            candidates: BTreeMap::new(),
+            value: None,
+            version: 0,
+            found_at: None,
+            queried: 0,
+            in_flight: 0,
+        };
+        lookup.add_candidates(seeds);
+        lookup
+    }
+
+    /// Returns the id the lookup converges on.
+    pub fn target(&self) -> NodeId {
+        self.target
+    }
+
+    /// Returns what the lookup looks for.
+    pub fn kind(&self) -> LookupKind {
+        self.kind
+    }
+
+    /// Returns the request to send to candidates.
+    pub fn request(&self) -> Request {
+        match self.kind {
+            LookupKind::FindNode => Request::FindNode(self.target),
+            LookupKind::FindValue => Request::FindValue(self.target),
+        }
+    }
+
+    /// Returns the number of candidates asked so far.
+    pub fn queried(&self) -> usize {
+        self.queried
+    }
+
+    /// Returns the number of requests in flight.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    /// Returns the next candidate to ask, the closest not asked yet among the `k`
+    /// closest that didn't fail, and marks it as waiting for its answer.
+    ///
+    /// # Returns
+    /// The candidate, or `None` if `alpha` requests are in flight, the lookup is
+    /// finished, or no candidate among the `k` closest is left to ask.
+    pub fn next_contact(&mut self) -> Option<Contact> {
+        if self.value.is_some() || self.in_flight >= self.alpha {
+            return None;
+        }
+        let k = self.k;
+        let candidate = self
+            .candidates
+            .values_mut()
+            .filter(|candidate| candidate.state != State::Failed)
+            .take(k)
+            .find(|candidate| candidate.state == State::NotQueried)?;
+        candidate.state = State::Waiting;
+        self.queried += 1;
+        self.in_flight += 1;
+        Some(candidate.contact)
+    }
+
+    /// Records that the candidate `from` answered with the `contacts` it knows closest to
+    /// the target, which become candidates. Answers of nodes that weren't asked are
+    /// ignored.
+    pub fn on_nodes(&mut self, from: &NodeId, contacts: impl IntoIterator<Item = Contact>) {
+        if self.set_state(from, State::Succeeded) {
+            self.add_candidates(contacts);
+        }
+    }
+
+    /// Records that the candidate `from` answered with the value and its version, which
+    /// finishes the lookup.
+    pub fn on_value(&mut self, from: &NodeId, value: Vec<u8>, version: u64) {
+        let Some(candidate) = self.candidates.get(&from.distance(&self.target)) else {
+            return;
+        };
+        if candidate.state == State::Waiting && self.value.is_none() {
+            self.found_at = Some(candidate.contact);
+            self.value = Some(value);
+            self.version = version;
+            self.set_state(from, State::Succeeded);
+        }
+    }
+
+    /// Records that the candidate `from` didn't answer in time. It is left out of the
+    /// result, and the next candidate takes its place.
+    pub fn on_failure(&mut self, from: &NodeId) {
+        self.set_state(from, State::Failed);
+    }
+
+    /// Returns `true` once a value was found, or the `k` closest candidates that didn't
+    /// fail all answered.
+    pub fn is_finished(&self) -> bool {
+        self.value.is_some()
+            || self
+                .candidates
+                .values()
+                .filter(|candidate| candidate.state != State::Failed)
+                .take(self.k)
+                .all(|candidate| candidate.state == State::Succeeded)
+    }
+
+    /// Returns the value found, if any.
+    pub fn value(&self) -> Option<&[u8]> {
+        self.value.as_deref()
+    }
+
+    /// Returns the version of the value found, if any.
+    pub fn version(&self) -> Option<u64> {
+        self.value.as_ref().map(|_| self.version)
+    }
+
+    /// Returns the contact that answered with the value, if any.
+    pub fn found_at(&self) -> Option<Contact> {
+        self.found_at
+    }
+
+    /// Returns up to `k` candidates that answered, the closest to the target first. Once
+    /// a node lookup is finished, these are the `k` closest nodes that are up.
+    pub fn closest(&self) -> Vec<Contact> {
+        self.candidates
+            .values()
+            .filter(|candidate| candidate.state == State::Succeeded)
+            .take(self.k)
+            .map(|candidate| candidate.contact)
+            .collect()
+    }
+
+    fn add_candidates(&mut self, contacts: impl IntoIterator<Item = Contact>) {
+        for contact in contacts {
+            self.candidates
+                .entry(contact.id.distance(&self.target))
+                .or_insert(Candidate {
+                    contact,
+                    state: State::NotQueried,
+                });
+        }
+    }
+
+    /// Moves the candidate `id` from waiting to `state`.
+    ///
+    /// # Returns
+    /// `true` if the candidate was waiting.
+    fn set_state(&mut self, id: &NodeId, state: State) -> bool {
+        match self.candidates.get_mut(&id.distance(&self.target)) {
+            Some(candidate) if candidate.state == State::Waiting => {
+                candidate.state = state;
+                self.in_flight -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn contact(last: u8) -> Contact {
+        let mut bytes = [0; 32];
+        bytes[31] = last;
+        Contact::new(
+            NodeId::new(bytes),
+            SocketAddr::from(([127, 0, 0, 1], last as u16)),
+        )
+    }
+
+    fn lasts(contacts: &[Contact]) -> Vec<u8> {
+        contacts
+            .iter()
+            .map(|contact| contact.id.as_bytes()[31])
+            .collect()
+    }
+
+    /// Test that at most alpha requests are in flight, to the closest candidates first
+    #[test]
+    fn keeps_alpha_requests_in_flight() {
+        let target = contact(0).id;
+        let seeds = [64, 32, 16, 8, 4].map(contact);
+        let mut lookup = Lookup::new(target, LookupKind::FindNode, seeds, ALPHA, 3);
+
+        let asked: Vec<Contact> = std::iter::from_fn(|| lookup.next_contact()).collect();
+        assert_eq!(lasts(&asked), [4, 8, 16]);
+        assert_eq!(lookup.in_flight(), 3);
+        assert!(!lookup.is_finished());
+
+        lookup.on_nodes(&contact(4).id, [2, 3].map(contact));
+        assert_eq!(lookup.next_contact(), Some(contact(2)));
+        assert_eq!(lookup.next_contact(), None);
+        // Unsolicited answers don't count
+        lookup.on_nodes(&contact(64).id, [1].map(contact));
+        lookup.on_nodes(&contact(2).id, [1].map(contact));
+        lookup.on_nodes(&contact(8).id, []);
+        lookup.on_nodes(&contact(16).id, []);
+        assert_eq!(lookup.next_contact(), Some(contact(1)));
+        assert_eq!(lookup.next_contact(), Some(contact(3)));
+        assert_eq!(lookup.next_contact(), None);
+        lookup.on_nodes(&contact(1).id, []);
+        lookup.on_nodes(&contact(3).id, []);
+
+        assert!(lookup.is_finished());
+        assert_eq!(lasts(&lookup.closest()), [1, 2, 3]);
+        assert_eq!(lookup.queried(), 6);
+    }
+
+    /// Test that candidates that time out are replaced by the next closest ones
+    #[test]
+    fn skips_failed_candidates() {
+        let target = contact(0).id;
+        let seeds = [1, 2, 3, 4, 5].map(contact);
+        let mut lookup = Lookup::new(target, LookupKind::FindNode, seeds, 2, 3);
+
+        assert_eq!(lookup.next_contact(), Some(contact(1)));
+        assert_eq!(lookup.next_contact(), Some(contact(2)));
+        lookup.on_failure(&contact(1).id);
+        lookup.on_failure(&contact(2).id);
+        assert_eq!(lookup.next_contact(), Some(contact(3)));
+        assert_eq!(lookup.next_contact(), Some(contact(4)));
+        lookup.on_nodes(&contact(3).id, []);
+        lookup.on_nodes(&contact(4).id, []);
+        assert!(!lookup.is_finished());
+        assert_eq!(lookup.next_contact(), Some(contact(5)));
+        lookup.on_nodes(&contact(5).id, []);
+
+        assert!(lookup.is_finished());
+        assert_eq!(lasts(&lookup.closest()), [3, 4, 5]);
+        let mut empty = Lookup::new(target, LookupKind::FindNode, [], ALPHA, 3);
+        assert!(empty.is_finished());
+        assert_eq!(empty.next_contact(), None);
+    }
+
+    /// Test that a value lookup stops at the first value
+    #[test]
+    fn stops_at_value() {
+        let target = contact(0).id;
+        let seeds = [1, 2, 3, 4].map(contact);
+        let mut lookup = Lookup::new(target, LookupKind::FindValue, seeds, ALPHA, 3);
+        assert_eq!(lookup.request(), Request::FindValue(target));
+
+        while lookup.next_contact().is_some() {}
+        lookup.on_nodes(&contact(1).id, []);
+        lookup.on_value(&contact(4).id, b"ignored".to_vec(), 1);
+        lookup.on_value(&contact(3).id, b"value".to_vec(), 2);
+        lookup.on_value(&contact(2).id, b"other".to_vec(), 3);
+
+        assert!(lookup.is_finished());
+        assert_eq!(lookup.next_contact(), None);
+        assert_eq!(lookup.value(), Some(&b"value"[..]));
+        assert_eq!(lookup.version(), Some(2));
+        assert_eq!(lookup.found_at(), Some(contact(3)));
+        assert_eq!(lasts(&lookup.closest()), [1, 3]);
+    }
+}
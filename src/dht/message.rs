@@ -0,0 +1,399 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use super::node_id::NodeId;
+use super::routing_table::Contact;
+use crate::bytes::{invalid, take, take_array, take_u64};
+
+/// Largest value nodes store, in bytes, so that every message, including a response of
+/// `K` IPv6 contacts, fits in a datagram of `MAX_DATAGRAM_SIZE` bytes.
+pub const MAX_VALUE_SIZE: usize = 1024;
+
+/// Largest message on the wire, in bytes: the minimum MTU of IPv6, so that datagrams are
+/// never fragmented.
+pub const MAX_DATAGRAM_SIZE: usize = 1280;
+
+const PING: u8 = 0;
+const STORE: u8 = 1;
+const FIND_NODE: u8 = 2;
+const FIND_VALUE: u8 = 3;
+const PONG: u8 = 0x80;
+const STORED: u8 = 0x81;
+const NODES: u8 = 0x82;
+const VALUE: u8 = 0x83;
+
+const IPV4: u8 = 4;
+const IPV6: u8 = 6;
+
+/// What a node asks another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Checks that the node is up.
+    Ping,
+    /// Asks for the contacts the node knows closest to an id.
+    FindNode(NodeId),
+    /// Asks for the value stored under a key, or the contacts closest to it if the node
+    /// doesn't have it.
+    FindValue(NodeId),
+    /// Asks the node to store a value under a key, for `ttl` milliseconds, unless it
+    /// has a newer version of it.
+    Store {
+        key: NodeId,
+        value: Vec<u8>,
+        version: u64,
+        ttl: u64,
+    },
+}
+
+/// What a node answers to a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// Answers a ping.
+    Pong,
+    /// The contacts closest to the id of a `FindNode` or `FindValue`.
+    Nodes(Vec<Contact>),
+    /// The value of a `FindValue`, and its version.
+    Value { value: Vec<u8>, version: u64 },
+    /// Confirms a `Store`.
+    Stored,
+}
+
+/// A message between two nodes. Every message carries the id of its sender, so that the
+/// receiver learns about it, and the id of the request, which its response repeats.
+///
+/// On the wire, a message is a datagram: a tag byte, the request id as a little-endian
+/// `u64`, the sender id, then the fields of the request or response. Values are prefixed
+/// by their length as a `u16`, contact lists by their count as a `u8`, and addresses by
+/// their family, 4 or 6.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Request {
+        id: u64,
+        sender: NodeId,
+        request: Request,
+    },
+    Response {
+        id: u64,
+        sender: NodeId,
+        response: Response,
+    },
+}
+
+impl Message {
+    /// Returns the id of the node that sent the message.
+    pub fn sender(&self) -> NodeId {
+        match self {
+            Message::Request { sender, .. } | Message::Response { sender, .. } => *sender,
+        }
+    }
+
+    /// Serializes the message into a datagram.
+    ///
+    /// # Panics
+    /// If a value is larger than `MAX_VALUE_SIZE`, or a response has more than 255
+    /// contacts.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (tag, id, sender) = match self {
+            Message::Request {
+                id,
+                sender,
+                request,
+            } => {
+                let tag = match request {
+                    Request::Ping => PING,
+                    Request::Store { .. } => STORE,
+                    Request::FindNode(_) => FIND_NODE,
+                    Request::FindValue(_) => FIND_VALUE,
+                };
+                (tag, id, sender)
+            }
+            Message::Response {
+                id,
+                sender,
+                response,
+            } => {
+                let tag = match response {
+                    Response::Pong => PONG,
+                    Response::Stored => STORED,
+                    Response::Nodes(_) => NODES,
+                    Response::Value { .. } => VALUE,
+                };
+                (tag, id, sender)
+            }
+        };
+        let mut bytes = vec![tag];
+        bytes.extend_from_slice(&id.to_le_bytes());
+        bytes.extend_from_slice(sender.as_bytes());
+
+        match self {
+            Message::Request { request, .. } => match request {
+                Request::Ping => {}
+                Request::Store {
+                    key,
+                    value,
+                    version,
+                    ttl,
+                } => {
+                    bytes.extend_from_slice(key.as_bytes());
+                    bytes.extend_from_slice(&version.to_le_bytes());
+                    bytes.extend_from_slice(&ttl.to_le_bytes());
+                    put_value(&mut bytes, value);
+                }
+                Request::FindNode(target) | Request::FindValue(target) => {
+                    bytes.extend_from_slice(target.as_bytes())
+                }
+            },
+            Message::Response { response, .. } => match response {
+                Response::Pong | Response::Stored => {}
+                Response::Nodes(contacts) => {
+                    let count = u8::try_from(contacts.len()).expect("at most 255 contacts");
+                    bytes.push(count);
+                    for contact in contacts {
+                        bytes.extend_from_slice(contact.id.as_bytes());
+                        put_addr(&mut bytes, &contact.addr);
+                    }
+                }
+                Response::Value { value, version } => {
+                    bytes.extend_from_slice(&version.to_le_bytes());
+                    put_value(&mut bytes, value);
+                }
+            },
+        }
+        bytes
+    }
+
+    /// Deserializes a datagram written by `to_bytes`.
+    ///
+    /// # Returns
+    /// An error of kind `InvalidData` if the tag or an address family is unknown, a
+    /// value is larger than `MAX_VALUE_SIZE`, or the datagram is truncated or has
+    /// trailing bytes.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let (&tag, mut bytes) = bytes
+            .split_first()
+            .ok_or_else(|| invalid("empty message".to_string()))?;
+        let bytes = &mut bytes;
+        let truncated = || invalid("truncated message".to_string());
+        let id = take_u64(bytes).ok_or_else(truncated)?;
+        let sender = take_id(bytes).ok_or_else(truncated)?;
+
+        let request = |request| Message::Request {
+            id,
+            sender,
+            request,
+        };
+        let response = |response| Message::Response {
+            id,
+            sender,
+            response,
+        };
+        let message = match tag {
+            PING => request(Request::Ping),
+            STORE => {
+                let key = take_id(bytes).ok_or_else(truncated)?;
+                let version = take_u64(bytes).ok_or_else(truncated)?;
+                let ttl = take_u64(bytes).ok_or_else(truncated)?;
+                let value = take_value(bytes)?.ok_or_else(truncated)?;
+                request(Request::Store {
+                    key,
+                    value,
+                    version,
+                    ttl,
+                })
+            }
+            FIND_NODE => request(Request::FindNode(take_id(bytes).ok_or_else(truncated)?)),
+            FIND_VALUE => request(Request::FindValue(take_id(bytes).ok_or_else(truncated)?)),
+            PONG => response(Response::Pong),
+            STORED => response(Response::Stored),
+            NODES => {
+                let count = take(bytes, 1).ok_or_else(truncated)?[0];
+                let mut contacts = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let id = take_id(bytes).ok_or_else(truncated)?;
+                    let addr = take_addr(bytes)?.ok_or_else(truncated)?;
+                    contacts.push(Contact::new(id, addr));
+                }
+                response(Response::Nodes(contacts))
+            }
+            VALUE => {
+                let version = take_u64(bytes).ok_or_else(truncated)?;
+                let value = take_value(bytes)?.ok_or_else(truncated)?;
+                response(Response::Value { value, version })
+            }
+            tag => return Err(invalid(format!("unknown message tag {}", tag))),
+        };
+        if !bytes.is_empty() {
+            return Err(invalid(format!(
+                "{} trailing bytes after the message",
+                bytes.len()
+            )));
+        }
+        Ok(message)
+    }
+}
+
+/// Appends `value`, prefixed by its length.
+fn put_value(bytes: &mut Vec<u8>, value: &[u8]) {
+    assert!(
+        value.len() <= MAX_VALUE_SIZE,
+        "value of {} bytes is larger than {}",
+        value.len(),
+        MAX_VALUE_SIZE
+    );
+    bytes.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(value);
+}
+
+/// Appends `addr`, prefixed by its family.
+fn put_addr(bytes: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            bytes.push(IPV4);
+            bytes.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            bytes.push(IPV6);
+            bytes.extend_from_slice(&ip.octets());
+        }
+    }
+    bytes.extend_from_slice(&addr.port().to_le_bytes());
+}
+
+fn take_id(bytes: &mut &[u8]) -> Option<NodeId> {
+    take_array(bytes).map(NodeId::new)
+}
+
+/// Removes a value, prefixed by its length, from the front of `bytes`.
+///
+/// # Returns
+/// The value, `None` if it is truncated, or an error if it is larger than
+/// `MAX_VALUE_SIZE`.
+fn take_value(bytes: &mut &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let Some(len) = take_array(bytes).map(u16::from_le_bytes) else {
+        return Ok(None);
+    };
+    if len as usize > MAX_VALUE_SIZE {
+        return Err(invalid(format!(
+            "value of {} bytes is larger than {}",
+            len, MAX_VALUE_SIZE
+        )));
+    }
+    Ok(take(bytes, len as usize).map(<[u8]>::to_vec))
+}
+
+/// Removes an address, prefixed by its family, from the front of `bytes`.
+///
+/// # Returns
+/// The address, `None` if it is truncated, or an error if the family is unknown.
+fn take_addr(bytes: &mut &[u8]) -> io::Result<Option<SocketAddr>> {
+    let Some(family) = take(bytes, 1).map(|family| family[0]) else {
+        return Ok(None);
+    };
+    let ip = match family {
+        IPV4 => take_array(bytes).map(|octets: [u8; 4]| IpAddr::V4(Ipv4Addr::from(octets))),
+        IPV6 => take_array(bytes).map(|octets: [u8; 16]| IpAddr::V6(Ipv6Addr::from(octets))),
+        family => return Err(invalid(format!("unknown address family {}", family))),
+    };
+    let port = take_array(bytes).map(u16::from_le_bytes);
+    Ok(ip.zip(port).map(|(ip, port)| SocketAddr::new(ip, port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::routing_table::K;
+
+    fn contact(last: u8, addr: &str) -> Contact {
+        let mut bytes = [0; 32];
+        bytes[31] = last;
+        Contact::new(NodeId::new(bytes), addr.parse().unwrap())
+    }
+
+    fn messages() -> Vec<Message> {
+        let sender = NodeId::from_key(b"sender");
+        let key = NodeId::from_key(b"lobby");
+        let requests = [
+            Request::Ping,
+            Request::Store {
+                key,
+                value: vec![7; MAX_VALUE_SIZE],
+                version: 1_700_000_000_000,
+                ttl: 3_600_000,
+            },
+            Request::FindNode(key),
+            Request::FindValue(key),
+        ];
+        let contacts = vec![
+            contact(1, "127.0.0.1:4001"),
+            contact(2, "[2001:db8::1]:4002"),
+        ];
+        let responses = [
+            Response::Pong,
+            Response::Stored,
+            Response::Nodes(contacts),
+            Response::Nodes(vec![]),
+            Response::Value {
+                value: b"eu-west".to_vec(),
+                version: 3,
+            },
+        ];
+        let requests = requests.into_iter().map(|request| Message::Request {
+            id: 42,
+            sender,
+            request,
+        });
+        let responses = responses.into_iter().map(|response| Message::Response {
+            id: u64::MAX,
+            sender,
+            response,
+        });
+        requests.chain(responses).collect()
+    }
+
+    /// Test that every kind of message survives a round trip through a datagram
+    #[test]
+    fn round_trip() {
+        for message in messages() {
+            let bytes = message.to_bytes();
+            assert!(bytes.len() <= MAX_DATAGRAM_SIZE);
+            assert_eq!(Message::from_bytes(&bytes).unwrap(), message);
+        }
+        let contacts = (0..K as u8)
+            .map(|last| contact(last, "[2001:db8::1]:4000"))
+            .collect();
+        let nodes = Message::Response {
+            id: 1,
+            sender: NodeId::from_key(b"sender"),
+            response: Response::Nodes(contacts),
+        };
+        assert!(nodes.to_bytes().len() <= MAX_DATAGRAM_SIZE);
+    }
+
+    /// Test that malformed datagrams and oversized values are rejected
+    #[test]
+    fn rejects_malformed() {
+        for message in messages() {
+            let bytes = message.to_bytes();
+            for bytes in [
+                &bytes[..bytes.len() - 1],
+                &[bytes.as_slice(), &[0]].concat(),
+            ] {
+                let error = Message::from_bytes(bytes).unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            }
+        }
+
+        let mut unknown_tag = messages()[0].to_bytes();
+        unknown_tag[0] = 9;
+        let mut oversized = messages()[1].to_bytes();
+        let len = 1 + 8 + 32 + 32 + 8;
+        oversized[len..len + 2].copy_from_slice(&(MAX_VALUE_SIZE as u16 + 1).to_le_bytes());
+        oversized.push(7);
+        let mut unknown_family = messages()[6].to_bytes();
+        unknown_family[1 + 8 + 32 + 1 + 32] = 5;
+        for bytes in [vec![], unknown_tag, oversized, unknown_family] {
+            let error = Message::from_bytes(&bytes).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+//! A Kademlia distributed hash table, for the use case of
+//! `issues/ditributed-hash-tables.rs`: peers of a game share key-value pairs without a
+//! central server, each storing the keys closest to its id.
+//!
+//! Nodes and keys share the 256-bit id space of [`node_id`], where the distance between
+//! two ids is their XOR. Each node knows a few nodes at each range of distances from it
+//! through the k-buckets of its [`routing_table`], and finds the nodes closest to any id
+//! with an iterative [`lookup`] that asks ever closer nodes.
+//!
+//! A value is stored on the k nodes closest to its key, in their [`storage`]. Those
+//! replicate it to the nodes closest to the key every so often, so that it survives
+//! nodes leaving and reaches the nodes joining, and its publisher publishes it again
+//! before it expires. Lookups of a value cache it on their way. Publishers version
+//! their values, and nodes keep the highest version of a key, so that an update isn't
+//! undone by a node replicating the value it replaces.
+//!
+//! A [`node`] is a deterministic state machine: it never touches the network or the
+//! clock, and returns the [`message`]s it wants to send. The [`simulation`] module
+//! delivers them between thousands of nodes in one process, jumping from one event to
+//! the next, and the [`udp`] module between processes, one datagram per message.
+
+pub mod lookup;
+pub mod message;
+pub mod node;
+pub mod node_id;
+pub mod routing_table;
+pub mod simulation;
+pub mod storage;
+pub mod udp;
+
+pub use lookup::{Lookup, LookupKind, ALPHA};
+pub use message::{Message, Request, Response, MAX_DATAGRAM_SIZE, MAX_VALUE_SIZE};
+pub use node::{DhtConfig, DhtNode, LookupId, Outgoing};
+pub use node_id::{Distance, NodeId, ID_BITS};
+pub use routing_table::{Contact, RoutingTable, Update, K, REFRESH_INTERVAL};
+pub use simulation::{Network, NetworkConfig, NetworkStats};
+pub use storage::{Record, Storage};
+pub use udp::{UdpNode, UdpStats};
@@ -0,0 +1,911 @@
+use std::collections::HashMap;
+use std::mem;
+use std::net::SocketAddr;
+
+use super::lookup::{Lookup, LookupKind, ALPHA};
+use super::message::{Message, Request, Response};
+use super::node_id::NodeId;
+use super::routing_table::{Contact, RoutingTable, Update, K, REFRESH_INTERVAL};
+use super::storage::Storage;
+use crate::rng::SimRng;
+
+/// Identifier of a lookup started by a node.
+pub type LookupId = u64;
+
+const HOUR: u64 = 60 * 60 * 1000;
+
+/// Settings of a DHT node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DhtConfig {
+    /// Number of contacts per bucket, and of nodes a lookup converges on.
+    pub k: usize,
+    /// Number of requests a lookup keeps in flight.
+    pub alpha: usize,
+    /// How long to wait for a response before giving up on a node, in milliseconds.
+    pub request_timeout: u64,
+    /// How long a value lives unless its publisher publishes it again, in milliseconds.
+    pub record_ttl: u64,
+    /// How often the publisher of a value publishes it again, in milliseconds.
+    pub republish_interval: u64,
+    /// How often the nodes storing a value store it again on the nodes closest to its
+    /// key, which may have changed, in milliseconds.
+    pub replicate_interval: u64,
+    /// How long a copy of a value cached on the path of a lookup lives, in milliseconds.
+    pub cache_ttl: u64,
+    /// Number of values a node stores for other nodes, beyond which values under new
+    /// keys are refused, unless they take the place of cached copies.
+    pub max_records: usize,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        DhtConfig {
+            k: K,
+            alpha: ALPHA,
+            request_timeout: 500,
+            record_ttl: 36 * HOUR,
+            republish_interval: 24 * HOUR,
+            replicate_interval: HOUR,
+            cache_ttl: HOUR,
+            max_records: 10_000,
+        }
+    }
+}
+
+/// A message a node wants sent, and where to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outgoing {
+    pub to: SocketAddr,
+    pub message: Message,
+}
+
+/// What a request waits for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Purpose {
+    /// An answer for a lookup.
+    Lookup(LookupId),
+    /// A sign of life from the least recently seen contact of a full bucket.
+    Ping,
+    /// A confirmation that a value was stored.
+    Store,
+    /// A sign of life from a node known by its address alone, which tells its id.
+    Introduce,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingRequest {
+    /// The node asked, whose id is this node's own until it answers an introduction.
+    to: Contact,
+    deadline: u64,
+    purpose: Purpose,
+}
+
+/// What a lookup does once it is finished, besides refreshing the bucket of its target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Then {
+    Nothing,
+    /// Stores the value on the closest nodes found, for `ttl` milliseconds.
+    Store {
+        value: Vec<u8>,
+        version: u64,
+        ttl: u64,
+    },
+    /// Caches the value found on the closest node that didn't have it.
+    Cache,
+}
+
+#[derive(Debug, Clone)]
+struct RunningLookup {
+    lookup: Lookup,
+    then: Then,
+    /// Whether to keep the lookup until the caller takes it, rather than dropping it.
+    keep: bool,
+}
+
+/// A node of the DHT, as a state machine that never touches the network or the clock.
+///
+/// The node is given the messages it receives with `handle`, and the time with `tick`,
+/// and returns the messages it wants to send. A transport delivers them, which can be a
+/// simulated network or UDP sockets. Time is in milliseconds of the node's clock.
+///
+/// # Methods
+/// - `new`: Creates a node that knows no other node.
+/// - `introduce`: Pings a node known by its address alone.
+/// - `bootstrap`: Joins the network through known nodes.
+/// - `find_node`: Starts a lookup of the nodes closest to an id.
+/// - `find_value`: Starts a lookup of the value of a key.
+/// - `put`: Publishes a value on the nodes closest to its key.
+/// - `get`: Looks up a value, and caches it on the way.
+/// - `take_finished`: Returns a lookup once it is finished.
+/// - `handle`: Handles a message from another node.
+/// - `tick`: Times out requests, refreshes stale buckets, and replicates values.
+/// - `next_timer`: Returns when `tick` has something to do.
+#[derive(Debug, Clone)]
+pub struct DhtNode {
+    contact: Contact,
+    config: DhtConfig,
+    table: RoutingTable,
+    storage: Storage,
+    lookups: HashMap<LookupId, RunningLookup>,
+    next_lookup: LookupId,
+    requests: HashMap<u64, PendingRequest>,
+    next_refresh: u64,
+    rng: SimRng,
+}
+
+impl DhtNode {
+    /// Creates a node reachable as `contact`, which knows no other node.
+    ///
+    /// # Parameters
+    /// - `contact`: The id and address of the node
+    /// - `config`: The settings of the node
+    /// - `seed`: Seed for request ids and refresh targets
+    /// - `now`: The current time
+    pub fn new(contact: Contact, config: DhtConfig, seed: u64, now: u64) -> Self {
+        DhtNode {
+            contact,
+            config,
+            table: RoutingTable::new(contact.id, config.k),
+            storage: Storage::with_max_records(config.max_records),
+            lookups: HashMap::new(),
+            next_lookup: 0,
+            requests: HashMap::new(),
+            next_refresh: now + REFRESH_INTERVAL,
+            rng: SimRng::new(seed),
+        }
+    }
+
+    /// Returns the id and address of the node.
+    pub fn contact(&self) -> Contact {
+        self.contact
+    }
+
+    /// Returns the id of the node.
+    pub fn id(&self) -> NodeId {
+        self.contact.id
+    }
+
+    /// Returns the routing table of the node.
+    pub fn table(&self) -> &RoutingTable {
+        &self.table
+    }
+
+    /// Returns `true` if the node waits for responses.
+    pub fn has_pending_requests(&self) -> bool {
+        !self.requests.is_empty()
+    }
+
+    /// Returns the values stored on this node.
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// Returns the values stored on this node, to store some without the network.
+    pub fn storage_mut(&mut self) -> &mut Storage {
+        &mut self.storage
+    }
+
+    /// Pings the node at `addr`, whose id isn't known yet, such as a seed node to
+    /// bootstrap from. Once it answers, it is in the routing table.
+    ///
+    /// # Returns
+    /// The ping to send.
+    pub fn introduce(&mut self, now: u64, addr: SocketAddr) -> Outgoing {
+        let to = Contact::new(self.id(), addr);
+        self.send(now, to, Request::Ping, Purpose::Introduce)
+    }
+
+    /// Joins the network through the `seeds` nodes: looks up the id of this node, which
+    /// fills its closest buckets and makes it known to the nodes closest to it.
+    ///
+    /// # Returns
+    /// The id of the lookup, and the requests to send.
+    pub fn bootstrap(&mut self, now: u64, seeds: &[Contact]) -> (LookupId, Vec<Outgoing>) {
+        for seed in seeds {
+            self.table.update(*seed);
+        }
+        self.find_node(now, self.id())
+    }
+
+    /// Starts a lookup of the nodes closest to `target`.
+    ///
+    /// # Returns
+    /// The id of the lookup, and the requests to send.
+    pub fn find_node(&mut self, now: u64, target: NodeId) -> (LookupId, Vec<Outgoing>) {
+        self.start_lookup(now, target, LookupKind::FindNode, Then::Nothing, true)
+    }
+
+    /// Starts a lookup of the value stored under `key`.
+    ///
+    /// # Returns
+    /// The id of the lookup, and the requests to send.
+    pub fn find_value(&mut self, now: u64, key: NodeId) -> (LookupId, Vec<Outgoing>) {
+        self.start_lookup(now, key, LookupKind::FindValue, Then::Nothing, true)
+    }
+
+    /// Publishes `value` under the hash of `key`: stores it on this node, and on the `k`
+    /// nodes closest to the key once a lookup finds them. This node publishes it again
+    /// every `republish_interval`, so that it never expires while this node is up.
+    ///
+    /// # Parameters
+    /// - `now`: The time of the node's clock
+    /// - `key`: The key, whose hash the value is stored under
+    /// - `value`: The value
+    /// - `version`: The version of the value, which nodes storing a value of a higher
+    ///   version under the key ignore, such as the time of publication in milliseconds
+    ///   since the Unix epoch
+    ///
+    /// # Returns
+    /// The id of the lookup of the closest nodes, and the requests to send.
+    pub fn put(
+        &mut self,
+        now: u64,
+        key: &[u8],
+        value: Vec<u8>,
+        version: u64,
+    ) -> (LookupId, Vec<Outgoing>) {
+        let key = NodeId::from_key(key);
+        let ttl = self.config.record_ttl;
+        self.storage.publish(
+            key,
+            value.clone(),
+            version,
+            now + ttl,
+            now + self.config.republish_interval,
+        );
+        let then = Then::Store {
+            value,
+            version,
+            ttl,
+        };
+        self.start_lookup(now, key, LookupKind::FindNode, then, true)
+    }
+
+    /// Looks up the value stored under the hash of `key`. Once found, the value is cached
+    /// on the closest node the lookup asked that didn't have it, which is on the path of
+    /// later lookups of the same key from nearby nodes.
+    ///
+    /// # Returns
+    /// The id of the lookup, and the requests to send.
+    pub fn get(&mut self, now: u64, key: &[u8]) -> (LookupId, Vec<Outgoing>) {
+        let key = NodeId::from_key(key);
+        let Some(record) = self
+            .storage
+            .record(&key)
+            .filter(|record| record.expires > now)
+        else {
+            return self.start_lookup(now, key, LookupKind::FindValue, Then::Cache, true);
+        };
+        // The lookup asks this node alone, which answers right away
+        let mut lookup = Lookup::new(key, LookupKind::FindValue, [self.contact], 1, self.config.k);
+        lookup.next_contact();
+        lookup.on_value(&self.id(), record.value.clone(), record.version);
+        let id = self.next_lookup;
+        self.next_lookup += 1;
+        let running = RunningLookup {
+            lookup,
+            then: Then::Nothing,
+            keep: true,
+        };
+        self.lookups.insert(id, running);
+        (id, vec![])
+    }
+
+    /// Returns the lookup `id` if it is finished, and forgets it.
+    pub fn take_finished(&mut self, id: LookupId) -> Option<Lookup> {
+        if !self.lookups.get(&id)?.lookup.is_finished() {
+            return None;
+        }
+        self.lookups.remove(&id).map(|running| running.lookup)
+    }
+
+    /// Handles `message`, received from `from`.
+    ///
+    /// Requests are answered, and make their sender known to the routing table.
+    /// Responses are only accepted from the node a request was sent to, with the id of
+    /// that request.
+    ///
+    /// # Returns
+    /// The messages to send in reaction.
+    pub fn handle(&mut self, now: u64, from: SocketAddr, message: Message) -> Vec<Outgoing> {
+        let sender = Contact::new(message.sender(), from);
+        if sender.id == self.id() {
+            return vec![];
+        }
+        match message {
+            Message::Request { id, request, .. } => {
+                let mut outgoing = self.see(now, sender);
+                let response = self.answer(now, &sender.id, request);
+                outgoing.push(Outgoing {
+                    to: from,
+                    message: Message::Response {
+                        id,
+                        sender: self.id(),
+                        response,
+                    },
+                });
+                outgoing
+            }
+            Message::Response { id, response, .. } => {
+                match self.requests.get(&id) {
+                    Some(pending) if pending.to == sender => {}
+                    Some(pending)
+                        if pending.purpose == Purpose::Introduce && pending.to.addr == from => {}
+                    _ => return vec![],
+                }
+                let pending = self.requests.remove(&id).expect("request is pending");
+                let mut outgoing = self.see(now, sender);
+                if let Purpose::Lookup(lookup_id) = pending.purpose {
+                    if let Some(running) = self.lookups.get_mut(&lookup_id) {
+                        match response {
+                            Response::Nodes(contacts) => {
+                                let local = self.contact.id;
+                                let contacts =
+                                    contacts.into_iter().filter(|contact| contact.id != local);
+                                running.lookup.on_nodes(&sender.id, contacts)
+                            }
+                            Response::Value { value, version } => {
+                                running.lookup.on_value(&sender.id, value, version)
+                            }
+                            Response::Pong | Response::Stored => {
+                                running.lookup.on_failure(&sender.id)
+                            }
+                        }
+                        outgoing.extend(self.query(now, lookup_id));
+                    }
+                }
+                outgoing
+            }
+        }
+    }
+
+    /// Gives up on the requests that timed out at `now`, removing the nodes they were
+    /// sent to from the routing table, starts lookups to refresh stale buckets, drops
+    /// expired values, and replicates and republishes the values that are due.
+    ///
+    /// # Returns
+    /// The messages to send.
+    pub fn tick(&mut self, now: u64) -> Vec<Outgoing> {
+        let mut expired: Vec<(u64, PendingRequest)> = self
+            .requests
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(id, pending)| (*id, *pending))
+            .collect();
+        // Sorted, so that the order of the messages doesn't depend on the hash map
+        expired.sort_by_key(|(id, _)| *id);
+
+        let mut outgoing = vec![];
+        for (id, pending) in expired {
+            self.requests.remove(&id);
+            if pending.purpose != Purpose::Introduce {
+                self.table.remove(&pending.to.id);
+            }
+            if let Purpose::Lookup(lookup_id) = pending.purpose {
+                if let Some(running) = self.lookups.get_mut(&lookup_id) {
+                    running.lookup.on_failure(&pending.to.id);
+                    outgoing.extend(self.query(now, lookup_id));
+                }
+            }
+        }
+
+        if now >= self.next_refresh {
+            self.next_refresh = now + REFRESH_INTERVAL;
+            for target in self.table.refresh_targets(now, &mut self.rng) {
+                let (_, requests) =
+                    self.start_lookup(now, target, LookupKind::FindNode, Then::Nothing, false);
+                outgoing.extend(requests);
+            }
+        }
+
+        self.storage.expire(now);
+        let (ttl, interval) = (self.config.record_ttl, self.config.republish_interval);
+        for (key, value, version) in self.storage.due_republications(now, ttl, interval) {
+            let then = Then::Store {
+                value,
+                version,
+                ttl,
+            };
+            outgoing.extend(
+                self.start_lookup(now, key, LookupKind::FindNode, then, false)
+                    .1,
+            );
+        }
+        let interval = self.config.replicate_interval;
+        for (key, value, version, expires) in self.storage.due_replications(now, interval) {
+            // Closer nodes joined, which the closest nodes keep the value on from now on
+            if !self.is_among_closest(&key) {
+                self.storage.stop_replicating(&key);
+                continue;
+            }
+            let then = Then::Store {
+                value,
+                version,
+                ttl: expires - now,
+            };
+            outgoing.extend(
+                self.start_lookup(now, key, LookupKind::FindNode, then, false)
+                    .1,
+            );
+        }
+        outgoing
+    }
+
+    /// Returns the time of the next request timeout, bucket refresh, or value
+    /// expiration, replication or republication, when `tick` should be called.
+    pub fn next_timer(&self) -> u64 {
+        let deadline = self.requests.values().map(|pending| pending.deadline).min();
+        [Some(self.next_refresh), deadline, self.storage.next_due()]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(self.next_refresh)
+    }
+
+    /// Returns the answer to `request` from `requester`.
+    fn answer(&mut self, now: u64, requester: &NodeId, request: Request) -> Response {
+        match request {
+            Request::Ping => Response::Pong,
+            Request::FindNode(target) => Response::Nodes(self.closest(&target, requester)),
+            Request::FindValue(key) => {
+                match self
+                    .storage
+                    .record(&key)
+                    .filter(|record| record.expires > now)
+                {
+                    Some(record) => Response::Value {
+                        value: record.value.clone(),
+                        version: record.version,
+                    },
+                    None => Response::Nodes(self.closest(&key, requester)),
+                }
+            }
+            Request::Store {
+                key,
+                value,
+                version,
+                ttl,
+            } => {
+                // Only the nodes closest to the key replicate it, others merely cache it.
+                // The node that stored it replicates it again first, and the others
+                // wait a random extra delay, so that it reaches them before they all
+                // replicate it at once
+                let interval = self.config.replicate_interval;
+                let replicate_at = self
+                    .is_among_closest(&key)
+                    .then(|| now + interval + self.rng.range(0, interval));
+                let expires = now + ttl.min(self.config.record_ttl);
+                // A value refused for lack of room is acknowledged all the same, as the
+                // answer only tells the sender that this node is up
+                let _ = self
+                    .storage
+                    .insert(key, value, version, expires, replicate_at);
+                Response::Stored
+            }
+        }
+    }
+
+    /// Returns `true` if this node is among the `k` closest to `key` it knows of.
+    fn is_among_closest(&self, key: &NodeId) -> bool {
+        let closest = self.table.closest(key, self.config.k);
+        closest.len() < self.config.k
+            || self.id().distance(key) < closest[closest.len() - 1].id.distance(key)
+    }
+
+    /// Returns the `k` contacts closest to `target`, other than `requester`, which
+    /// knows itself.
+    fn closest(&self, target: &NodeId, requester: &NodeId) -> Vec<Contact> {
+        let mut closest = self.table.closest(target, self.config.k + 1);
+        closest.retain(|contact| contact.id != *requester);
+        closest.truncate(self.config.k);
+        closest
+    }
+
+    /// Records that `contact` is up, pinging the least recently seen contact of its
+    /// bucket if it is full.
+    fn see(&mut self, now: u64, contact: Contact) -> Vec<Outgoing> {
+        match self.table.update(contact) {
+            Update::PingOldest(oldest) => {
+                vec![self.send(now, oldest, Request::Ping, Purpose::Ping)]
+            }
+            Update::Inserted | Update::Refreshed | Update::Pending | Update::Ignored => vec![],
+        }
+    }
+
+    fn start_lookup(
+        &mut self,
+        now: u64,
+        target: NodeId,
+        kind: LookupKind,
+        then: Then,
+        keep: bool,
+    ) -> (LookupId, Vec<Outgoing>) {
+        let seeds = self.table.closest(&target, self.config.k);
+        let lookup = Lookup::new(target, kind, seeds, self.config.alpha, self.config.k);
+        let id = self.next_lookup;
+        self.next_lookup += 1;
+        self.lookups
+            .insert(id, RunningLookup { lookup, then, keep });
+        (id, self.query(now, id))
+    }
+
+    /// Sends the requests lookup `id` may send, and wraps it up if it is finished.
+    fn query(&mut self, now: u64, id: LookupId) -> Vec<Outgoing> {
+        let Some(running) = self.lookups.get_mut(&id) else {
+            return vec![];
+        };
+        let request = running.lookup.request();
+        let contacts: Vec<Contact> = std::iter::from_fn(|| running.lookup.next_contact()).collect();
+
+        let mut stores = vec![];
+        if running.lookup.is_finished() {
+            let key = running.lookup.target();
+            self.table.mark_refreshed(&key, now);
+            match mem::replace(&mut running.then, Then::Nothing) {
+                Then::Nothing => {}
+                Then::Store {
+                    value,
+                    version,
+                    ttl,
+                } => {
+                    for contact in running.lookup.closest() {
+                        let store = Request::Store {
+                            key,
+                            value: value.clone(),
+                            version,
+                            ttl,
+                        };
+                        stores.push((contact, store));
+                    }
+                }
+                Then::Cache => {
+                    let lookup = &running.lookup;
+                    if let (Some(value), Some(version), Some(found_at)) =
+                        (lookup.value(), lookup.version(), lookup.found_at())
+                    {
+                        let closest = lookup
+                            .closest()
+                            .into_iter()
+                            .find(|contact| *contact != found_at);
+                        if let Some(contact) = closest {
+                            let store = Request::Store {
+                                key,
+                                value: value.to_vec(),
+                                version,
+                                ttl: self.config.cache_ttl,
+                            };
+                            stores.push((contact, store));
+                        }
+                    }
+                }
+            }
+            if !running.keep {
+                self.lookups.remove(&id);
+            }
+        }
+
+        let mut outgoing: Vec<Outgoing> = contacts
+            .into_iter()
+            .map(|contact| self.send(now, contact, request.clone(), Purpose::Lookup(id)))
+            .collect();
+        for (contact, request) in stores {
+            outgoing.push(self.send(now, contact, request, Purpose::Store));
+        }
+        outgoing
+    }
+
+    /// Returns `request` to send to `to`, and waits for its response.
+    fn send(&mut self, now: u64, to: Contact, request: Request, purpose: Purpose) -> Outgoing {
+        let mut id = self.rng.next_u64();
+        while self.requests.contains_key(&id) {
+            id = self.rng.next_u64();
+        }
+        self.requests.insert(
+            id,
+            PendingRequest {
+                to,
+                deadline: now + self.config.request_timeout,
+                purpose,
+            },
+        );
+        Outgoing {
+            to: to.addr,
+            message: Message::Request {
+                id,
+                sender: self.id(),
+                request,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::message::MAX_VALUE_SIZE;
+
+    fn contact(last: u8) -> Contact {
+        let mut bytes = [0; 32];
+        bytes[31] = last;
+        Contact::new(
+            NodeId::new(bytes),
+            SocketAddr::from(([127, 0, 0, 1], 4000 + last as u16)),
+        )
+    }
+
+    fn request(from: u8, id: u64, request: Request) -> Message {
+        Message::Request {
+            id,
+            sender: contact(from).id,
+            request,
+        }
+    }
+
+    fn response(from: u8, id: u64, response: Response) -> Message {
+        Message::Response {
+            id,
+            sender: contact(from).id,
+            response,
+        }
+    }
+
+    /// Returns the id and request of a message the node sent.
+    fn sent_request(outgoing: &Outgoing) -> (u64, Request) {
+        match &outgoing.message {
+            Message::Request { id, request, .. } => (*id, request.clone()),
+            message => panic!("{:?} isn't a request", message),
+        }
+    }
+
+    /// Test that requests are answered, and teach the node about their senders
+    #[test]
+    fn answers_requests() {
+        let mut node = DhtNode::new(contact(0), DhtConfig::default(), 1, 0);
+        let key = NodeId::from_key(b"lobby");
+        node.storage_mut()
+            .insert(key, b"eu-west".to_vec(), 1, 1000, None);
+
+        let outgoing = node.handle(0, contact(1).addr, request(1, 7, Request::Ping));
+        assert_eq!(
+            outgoing,
+            [Outgoing {
+                to: contact(1).addr,
+                message: response(0, 7, Response::Pong)
+            }]
+        );
+        assert_eq!(node.table().get(&contact(1).id), Some(&contact(1)));
+
+        node.handle(0, contact(2).addr, request(2, 8, Request::Ping));
+        node.handle(0, contact(3).addr, request(3, 9, Request::Ping));
+        let outgoing = node.handle(
+            0,
+            contact(2).addr,
+            request(2, 10, Request::FindNode(contact(1).id)),
+        );
+        assert_eq!(
+            outgoing[0].message,
+            response(0, 10, Response::Nodes(vec![contact(1), contact(3)]))
+        );
+        let outgoing = node.handle(0, contact(2).addr, request(2, 11, Request::FindValue(key)));
+        assert_eq!(
+            outgoing[0].message,
+            response(
+                0,
+                11,
+                Response::Value {
+                    value: b"eu-west".to_vec(),
+                    version: 1
+                }
+            )
+        );
+        let outgoing = node.handle(
+            0,
+            contact(2).addr,
+            request(2, 12, Request::FindValue(contact(3).id)),
+        );
+        assert_eq!(
+            outgoing[0].message,
+            response(0, 12, Response::Nodes(vec![contact(3), contact(1)]))
+        );
+    }
+
+    /// Test that a full bucket pings its oldest contact, and evicts it on timeout
+    #[test]
+    fn pings_before_evicting() {
+        let config = DhtConfig {
+            k: 2,
+            ..DhtConfig::default()
+        };
+        let mut node = DhtNode::new(contact(0), config, 1, 0);
+        node.handle(0, contact(4).addr, request(4, 1, Request::Ping));
+        node.handle(0, contact(5).addr, request(5, 2, Request::Ping));
+
+        let outgoing = node.handle(10, contact(6).addr, request(6, 3, Request::Ping));
+        assert_eq!(outgoing.len(), 2);
+        assert_eq!(outgoing[0].to, contact(4).addr);
+        let (ping, _) = sent_request(&outgoing[0]);
+        // Answers from the wrong node or to unknown requests are ignored
+        assert!(node
+            .handle(20, contact(5).addr, response(5, ping, Response::Pong))
+            .is_empty());
+        assert!(node
+            .handle(20, contact(4).addr, response(4, ping + 1, Response::Pong))
+            .is_empty());
+        assert!(node.has_pending_requests());
+        node.handle(20, contact(4).addr, response(4, ping, Response::Pong));
+        assert!(!node.has_pending_requests());
+        assert_eq!(node.table().get(&contact(6).id), None);
+
+        let outgoing = node.handle(30, contact(7).addr, request(7, 4, Request::Ping));
+        assert_eq!(outgoing[0].to, contact(5).addr);
+        assert!(node.tick(529).is_empty());
+        assert_eq!(node.table().get(&contact(5).id), Some(&contact(5)));
+        node.tick(530);
+        assert_eq!(node.table().get(&contact(5).id), None);
+        assert_eq!(node.table().get(&contact(7).id), Some(&contact(7)));
+    }
+
+    /// Test that a lookup goes on when a node times out, and finishes with the others
+    #[test]
+    fn looks_up_through_responses() {
+        let mut node = DhtNode::new(contact(0), DhtConfig::default(), 1, 0);
+        let (lookup, outgoing) = node.bootstrap(0, &[contact(8), contact(16)]);
+        assert_eq!(outgoing.len(), 2);
+        assert!(node.take_finished(lookup).is_none());
+
+        let (first, request) = sent_request(&outgoing[0]);
+        assert_eq!(request, Request::FindNode(contact(0).id));
+        let (to_first, to_second) = if outgoing[0].to == contact(8).addr {
+            (8, 16)
+        } else {
+            (16, 8)
+        };
+
+        let nodes = Response::Nodes(vec![contact(0), contact(1)]);
+        let outgoing = node.handle(10, contact(to_first).addr, response(to_first, first, nodes));
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].to, contact(1).addr);
+        let (third, _) = sent_request(&outgoing[0]);
+        node.handle(
+            20,
+            contact(1).addr,
+            response(1, third, Response::Nodes(vec![])),
+        );
+        assert!(node.take_finished(lookup).is_none());
+
+        // The other seed never answers
+        node.tick(500);
+        let lookup = node.take_finished(lookup).unwrap();
+        let closest: Vec<NodeId> = lookup.closest().iter().map(|contact| contact.id).collect();
+        assert_eq!(closest, [contact(1).id, contact(to_first).id]);
+        assert_eq!(node.table().get(&contact(to_second).id), None);
+        assert_eq!(node.table().len(), 2);
+    }
+
+    /// Test that stored values are capped to the record lifetime, and that the closest
+    /// nodes replicate them while others merely cache them
+    #[test]
+    fn stores_values() {
+        let config = DhtConfig {
+            k: 2,
+            record_ttl: 1000,
+            replicate_interval: 100,
+            ..DhtConfig::default()
+        };
+        let mut node = DhtNode::new(contact(0), config, 1, 0);
+        node.handle(0, contact(1).addr, request(1, 1, Request::Ping));
+        node.handle(0, contact(2).addr, request(2, 2, Request::Ping));
+
+        // Node 0 is closer to contact(128) than nodes 1 and 2, but not to contact(3)
+        let (near, far) = (contact(128).id, contact(3).id);
+        for (id, key) in [(3, near), (4, far)] {
+            let store = Request::Store {
+                key,
+                value: b"v".to_vec(),
+                version: 1,
+                ttl: u64::MAX,
+            };
+            let outgoing = node.handle(10, contact(2).addr, request(2, id, store));
+            assert_eq!(outgoing[0].message, response(0, id, Response::Stored));
+        }
+        let record = node.storage().record(&near).unwrap();
+        let replicate_at = record.replicate_at.unwrap();
+        assert_eq!(record.expires, 1010);
+        assert!((110..210).contains(&replicate_at));
+        assert_eq!(node.storage().record(&far).unwrap().replicate_at, None);
+        assert_eq!(node.next_timer(), replicate_at);
+
+        // Replicating looks up the closest nodes, then stores the value on them
+        let outgoing = node.tick(replicate_at);
+        assert_eq!(outgoing.len(), 2);
+        for sent in &outgoing {
+            let (id, request) = sent_request(sent);
+            assert_eq!(request, Request::FindNode(near));
+            let from = if sent.to == contact(1).addr { 1 } else { 2 };
+            let nodes = Response::Nodes(vec![]);
+            let stores = node.handle(replicate_at, sent.to, response(from, id, nodes));
+            for store in stores {
+                let (_, request) = sent_request(&store);
+                assert_eq!(
+                    request,
+                    Request::Store {
+                        key: near,
+                        value: b"v".to_vec(),
+                        version: 1,
+                        ttl: 1010 - replicate_at
+                    }
+                );
+            }
+        }
+        assert_eq!(node.next_timer(), replicate_at + 100);
+        node.tick(1010);
+        assert!(node.storage().is_empty());
+    }
+
+    /// Test that a node stores at most `max_records` values for other nodes
+    #[test]
+    fn bounds_stored_values() {
+        let config = DhtConfig {
+            max_records: 2,
+            ..DhtConfig::default()
+        };
+        let mut node = DhtNode::new(contact(0), config, 1, 0);
+        for id in 1..=3 {
+            let store = Request::Store {
+                key: contact(id).id,
+                value: vec![0; MAX_VALUE_SIZE],
+                version: 1,
+                ttl: u64::MAX,
+            };
+            let outgoing = node.handle(0, contact(1).addr, request(1, id as u64, store));
+            assert_eq!(
+                outgoing[0].message,
+                response(0, id as u64, Response::Stored)
+            );
+        }
+        assert_eq!(node.storage().len(), 2);
+        assert!(node.storage().record(&contact(3).id).is_none());
+
+        // The values this node publishes are stored all the same
+        node.put(0, b"lobby", b"eu-west".to_vec(), 1);
+        assert_eq!(node.storage().len(), 3);
+    }
+
+    /// Test that a node answers a get of a value it stores without asking others
+    #[test]
+    fn gets_local_values() {
+        let mut node = DhtNode::new(contact(0), DhtConfig::default(), 1, 0);
+        node.handle(0, contact(1).addr, request(1, 1, Request::Ping));
+        let (put, outgoing) = node.put(0, b"lobby", b"eu-west".to_vec(), 1);
+        assert_eq!(outgoing.len(), 1);
+
+        let (get, outgoing) = node.get(0, b"lobby");
+        assert!(outgoing.is_empty());
+        let lookup = node.take_finished(get).unwrap();
+        assert_eq!(lookup.value(), Some(&b"eu-west"[..]));
+        assert!(node.take_finished(put).is_none());
+    }
+
+    /// Test that a node pinged by address alone is added once it answers from there
+    #[test]
+    fn introduces_by_address() {
+        let mut node = DhtNode::new(contact(0), DhtConfig::default(), 1, 0);
+        let ping = node.introduce(0, contact(1).addr);
+        assert_eq!(ping.to, contact(1).addr);
+        let (id, request) = sent_request(&ping);
+        assert_eq!(request, Request::Ping);
+
+        // An answer from another address doesn't count
+        node.handle(10, contact(2).addr, response(2, id, Response::Pong));
+        assert!(node.table().is_empty());
+        node.handle(10, contact(1).addr, response(1, id, Response::Pong));
+        assert_eq!(node.table().get(&contact(1).id), Some(&contact(1)));
+        assert!(!node.has_pending_requests());
+
+        // A node that never answers is forgotten without touching the table
+        node.introduce(20, contact(3).addr);
+        node.tick(520);
+        assert!(!node.has_pending_requests());
+        assert_eq!(node.table().len(), 1);
+    }
+}
@@ -0,0 +1,202 @@
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::merkle::merkle_hash::to_hex;
+use crate::rng::SimRng;
+
+/// Number of bits of a node id, and of the keys stored in the DHT.
+pub const ID_BITS: usize = 256;
+
+/// Identifier of a node, in the same 256-bit space as the keys it stores.
+///
+/// Ids are compared through their XOR distance, which gives each node a different view
+/// of the same space: the nodes closest to a key, the ones storing it, are the same
+/// from every node, and a lookup halves its distance to the key at each step.
+///
+/// # Methods
+/// - `new`: Creates an id from its bytes.
+/// - `from_key`: Hashes a key into the id space.
+/// - `random`: Draws an id uniformly.
+/// - `random_at_distance`: Draws an id whose distance from this one falls in a bucket.
+/// - `distance`: Returns the XOR distance to another id.
+/// - `bucket_index`: Returns the bucket of the routing table another id falls in.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId([u8; 32]);
+
+impl NodeId {
+    /// Creates the id of the given bytes, the most significant first.
+    pub fn new(bytes: [u8; 32]) -> Self {
+        NodeId(bytes)
+    }
+
+    /// Returns the id a key is stored under: its SHA-256 hash, so that keys spread
+    /// evenly over the nodes whatever they look like.
+    pub fn from_key(key: &[u8]) -> Self {
+        NodeId(Sha256::digest(key).into())
+    }
+
+    /// Returns an id drawn uniformly from `rng`.
+    pub fn random(rng: &mut SimRng) -> Self {
+        let mut bytes = [0; 32];
+        for chunk in bytes.chunks_mut(8) {
+            chunk.copy_from_slice(&rng.next_u64().to_be_bytes());
+        }
+        NodeId(bytes)
+    }
+
+    /// Returns an id drawn uniformly among the ones in bucket `index` of this id, whose
+    /// distance is in `[2^index, 2^(index + 1))`.
+    ///
+    /// # Panics
+    /// If `index` isn't below `ID_BITS`.
+    pub fn random_at_distance(&self, index: usize, rng: &mut SimRng) -> Self {
+        assert!(index < ID_BITS, "bucket {} is out of range", index);
+        let mut distance = NodeId::random(rng).0;
+        // Clears the bits above bit `index` and sets bit `index`, counting from the least
+        // significant one
+        let byte = 31 - index / 8;
+        let bit = index % 8;
+        distance[..byte].fill(0);
+        distance[byte] &= ((1u16 << (bit + 1)) - 1) as u8;
+        distance[byte] |= 1 << bit;
+        NodeId(xor(&self.0, &distance))
+    }
+
+    /// Returns the bytes of the id, the most significant first.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Returns the XOR distance between this id and `other`.
+    pub fn distance(&self, other: &NodeId) -> Distance {
+        Distance(xor(&self.0, &other.0))
+    }
+
+    /// Returns the index of the bucket `other` falls in from this id: the position of
+    /// the highest bit of their distance, so bucket `i` holds the ids at distances in
+    /// `[2^i, 2^(i + 1))`. Half of the id space is in bucket 255.
+    ///
+    /// # Returns
+    /// The index, or `None` if `other` is this id.
+    pub fn bucket_index(&self, other: &NodeId) -> Option<usize> {
+        let zeros = self.distance(other).leading_zeros();
+        (zeros < ID_BITS).then(|| ID_BITS - 1 - zeros)
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NodeId({})", self)
+    }
+}
+
+/// Displays the first 8 bytes in hexadecimal, enough to tell ids apart in logs.
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", &to_hex(&self.0)[..16])
+    }
+}
+
+/// XOR distance between two ids, ordered as a 256-bit unsigned integer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Distance([u8; 32]);
+
+impl Distance {
+    /// Returns the number of leading zero bits, `ID_BITS` for the distance of an id to
+    /// itself.
+    pub fn leading_zeros(&self) -> usize {
+        self.0
+            .iter()
+            .position(|byte| *byte != 0)
+            .map_or(ID_BITS, |index| {
+                index * 8 + self.0[index].leading_zeros() as usize
+            })
+    }
+}
+
+fn xor(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let mut result = *a;
+    for (byte, other) in result.iter_mut().zip(b) {
+        *byte ^= other;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(last: u8) -> NodeId {
+        let mut bytes = [0; 32];
+        bytes[31] = last;
+        NodeId::new(bytes)
+    }
+
+    /// Test that the XOR distance is a metric, ordered as an integer
+    #[test]
+    fn distance_is_a_metric() {
+        let mut rng = SimRng::new(1);
+        for _ in 0..100 {
+            let (a, b, c) = (
+                NodeId::random(&mut rng),
+                NodeId::random(&mut rng),
+                NodeId::random(&mut rng),
+            );
+            assert_eq!(a.distance(&a), id(0).distance(&id(0)));
+            assert_eq!(a.distance(&b), b.distance(&a));
+            assert!(a.distance(&b) > a.distance(&a));
+            // XOR has no carries, so going through b never sets a higher bit
+            let (ab, bc, ac) = (a.distance(&b), b.distance(&c), a.distance(&c));
+            assert!(ac.leading_zeros() >= ab.leading_zeros().min(bc.leading_zeros()));
+        }
+
+        let target = id(0b1000);
+        let mut ids: Vec<NodeId> = (0..16).map(id).collect();
+        ids.sort_by_key(|id| id.distance(&target));
+        let last_bytes: Vec<u8> = ids.iter().map(|id| id.as_bytes()[31]).collect();
+        assert_eq!(
+            last_bytes,
+            [8, 9, 10, 11, 12, 13, 14, 15, 0, 1, 2, 3, 4, 5, 6, 7]
+        );
+
+        let mut far = [0; 32];
+        far[0] = 1;
+        assert!(id(0).distance(&NodeId::new(far)) > id(0).distance(&id(255)));
+    }
+
+    /// Test that ids fall in the bucket of the highest bit of their distance
+    #[test]
+    fn places_ids_in_buckets() {
+        let local = id(0b1010);
+        assert_eq!(local.bucket_index(&local), None);
+        assert_eq!(local.bucket_index(&id(0b1011)), Some(0));
+        assert_eq!(local.bucket_index(&id(0b1000)), Some(1));
+        assert_eq!(local.bucket_index(&id(0b0010)), Some(3));
+        assert_eq!(local.bucket_index(&id(0b1111_0000)), Some(7));
+        let mut far = *local.as_bytes();
+        far[0] ^= 0x80;
+        assert_eq!(local.bucket_index(&NodeId::new(far)), Some(255));
+
+        let mut rng = SimRng::new(2);
+        let random = NodeId::random(&mut rng);
+        for index in 0..ID_BITS {
+            let other = random.random_at_distance(index, &mut rng);
+            assert_eq!(random.bucket_index(&other), Some(index));
+        }
+    }
+
+    /// Test that keys are hashed into the id space
+    #[test]
+    fn hashes_keys() {
+        assert_eq!(
+            NodeId::from_key(b"player:42"),
+            NodeId::from_key(b"player:42")
+        );
+        assert_ne!(
+            NodeId::from_key(b"player:42"),
+            NodeId::from_key(b"player:43")
+        );
+        assert_eq!(NodeId::from_key(b"").to_string(), "e3b0c44298fc1c14");
+    }
+}
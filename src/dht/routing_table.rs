@@ -0,0 +1,379 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use super::node_id::{NodeId, ID_BITS};
+use crate::rng::SimRng;
+
+/// Number of contacts a bucket holds, and of nodes a lookup converges on.
+pub const K: usize = 20;
+
+/// How long a bucket may go without a lookup in its range before it's refreshed, in
+/// milliseconds.
+pub const REFRESH_INTERVAL: u64 = 60 * 60 * 1000;
+
+/// A node as other nodes know it: its id and where to reach it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Contact {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+impl Contact {
+    /// Creates the contact of the node `id` reachable at `addr`.
+    pub fn new(id: NodeId, addr: SocketAddr) -> Self {
+        Contact { id, addr }
+    }
+}
+
+/// What `RoutingTable::update` did with a contact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Update {
+    /// The contact was added to its bucket, which had room.
+    Inserted,
+    /// The contact was known, and is now the most recently seen of its bucket.
+    Refreshed,
+    /// The bucket is full. The contact waits while the given least recently seen
+    /// contact is pinged: it replaces it if the ping fails, and is dropped otherwise.
+    PingOldest(Contact),
+    /// The bucket is full and a ping is already pending, so the contact waits in place
+    /// of the previous one.
+    Pending,
+    /// The contact is the local node.
+    Ignored,
+}
+
+/// A candidate for a full bucket, waiting for the result of a ping.
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    candidate: Contact,
+    pinged: NodeId,
+}
+
+/// The contacts of one range of distances, least recently seen first.
+#[derive(Debug, Clone, Default)]
+struct KBucket {
+    contacts: VecDeque<Contact>,
+    pending: Option<Pending>,
+    /// When a lookup last targeted the range of the bucket.
+    refreshed: u64,
+}
+
+/// The contacts a node knows, in k-buckets by distance from it.
+///
+/// Bucket `i` holds up to `k` contacts at distances in `[2^i, 2^(i + 1))`, so a node
+/// knows many nodes close to it and a few in each farther range, which is enough to
+/// halve the distance to any id at each step of a lookup.
+///
+/// Buckets favor the contacts seen for the longest time, since nodes that have been up
+/// for long tend to stay up. A new contact never evicts a known one directly: when its
+/// bucket is full, `update` asks to ping the least recently seen contact, which is only
+/// replaced if it doesn't answer. This also keeps floods of new ids from flushing the
+/// table.
+///
+/// # Methods
+/// - `new`: Creates an empty table.
+/// - `update`: Records that a contact was seen.
+/// - `remove`: Removes a contact that failed to answer.
+/// - `closest`: Returns the contacts closest to an id.
+/// - `mark_refreshed`: Records a lookup, which refreshes a bucket.
+/// - `refresh_targets`: Returns ids to look up to refresh stale buckets.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    local: NodeId,
+    k: usize,
+    buckets: Vec<KBucket>,
+}
+
+impl RoutingTable {
+    /// Creates the empty table of the node `local`, with buckets of `k` contacts.
+    pub fn new(local: NodeId, k: usize) -> Self {
+        RoutingTable {
+            local,
+            k,
+            buckets: vec![KBucket::default(); ID_BITS],
+        }
+    }
+
+    /// Returns the id of the node the table belongs to.
+    pub fn local(&self) -> NodeId {
+        self.local
+    }
+
+    /// Returns the number of contacts a bucket holds.
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// Returns the number of contacts in the table.
+    pub fn len(&self) -> usize {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.contacts.len())
+            .sum()
+    }
+
+    /// Returns `true` if the table has no contact.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the contact of `id`, if known.
+    pub fn get(&self, id: &NodeId) -> Option<&Contact> {
+        let index = self.local.bucket_index(id)?;
+        self.buckets[index]
+            .contacts
+            .iter()
+            .find(|contact| contact.id == *id)
+    }
+
+    /// Returns the contacts of bucket `index`, least recently seen first.
+    pub fn bucket(&self, index: usize) -> impl Iterator<Item = &Contact> {
+        self.buckets[index].contacts.iter()
+    }
+
+    /// Returns every contact of the table.
+    pub fn contacts(&self) -> impl Iterator<Item = &Contact> {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.contacts.iter())
+    }
+
+    /// Records that `contact` was seen, by a message from it or an answer to one.
+    ///
+    /// A known contact becomes the most recently seen of its bucket, and takes the new
+    /// address if it changed. If it was being pinged to make room for a candidate, the
+    /// candidate is dropped.
+    pub fn update(&mut self, contact: Contact) -> Update {
+        let Some(index) = self.local.bucket_index(&contact.id) else {
+            return Update::Ignored;
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(position) = bucket
+            .contacts
+            .iter()
+            .position(|known| known.id == contact.id)
+        {
+            bucket.contacts.remove(position);
+            bucket.contacts.push_back(contact);
+            if bucket
+                .pending
+                .is_some_and(|pending| pending.pinged == contact.id)
+            {
+                bucket.pending = None;
+            }
+            return Update::Refreshed;
+        }
+
+        if bucket.contacts.len() < self.k {
+            bucket.contacts.push_back(contact);
+            return Update::Inserted;
+        }
+        match &mut bucket.pending {
+            Some(pending) => {
+                pending.candidate = contact;
+                Update::Pending
+            }
+            None => {
+                let oldest = bucket.contacts[0];
+                bucket.pending = Some(Pending {
+                    candidate: contact,
+                    pinged: oldest.id,
+                });
+                Update::PingOldest(oldest)
+            }
+        }
+    }
+
+    /// Removes the contact of `id`, which failed to answer, and puts the candidate
+    /// waiting for room in its bucket in its place.
+    ///
+    /// # Returns
+    /// The removed contact, or `None` if it wasn't in the table.
+    pub fn remove(&mut self, id: &NodeId) -> Option<Contact> {
+        let bucket = &mut self.buckets[self.local.bucket_index(id)?];
+        let position = bucket
+            .contacts
+            .iter()
+            .position(|contact| contact.id == *id)?;
+        let removed = bucket.contacts.remove(position);
+        if let Some(pending) = bucket.pending.take() {
+            bucket.contacts.push_back(pending.candidate);
+        }
+        removed
+    }
+
+    /// Returns up to `count` contacts closest to `target`, the closest first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<Contact> = self.contacts().copied().collect();
+        contacts.sort_by_cached_key(|contact| contact.id.distance(target));
+        contacts.truncate(count);
+        contacts
+    }
+
+    /// Records that a lookup of `target` was done at `now`, which refreshes its bucket.
+    pub fn mark_refreshed(&mut self, target: &NodeId, now: u64) {
+        if let Some(index) = self.local.bucket_index(target) {
+            self.buckets[index].refreshed = now;
+        }
+    }
+
+    /// Returns a random id in each bucket without a lookup for `REFRESH_INTERVAL`, to
+    /// look up so that the bucket learns about nodes in its range. Buckets closer than
+    /// the closest contact are skipped: their ranges are too small to hold any node.
+    pub fn refresh_targets(&self, now: u64, rng: &mut SimRng) -> Vec<NodeId> {
+        let Some(closest) = self
+            .buckets
+            .iter()
+            .position(|bucket| !bucket.contacts.is_empty())
+        else {
+            return vec![];
+        };
+        (closest..ID_BITS)
+            .filter(|index| now.saturating_sub(self.buckets[*index].refreshed) >= REFRESH_INTERVAL)
+            .map(|index| self.local.random_at_distance(index, rng))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(last: u8) -> NodeId {
+        let mut bytes = [0; 32];
+        bytes[31] = last;
+        NodeId::new(bytes)
+    }
+
+    fn contact(last: u8) -> Contact {
+        Contact::new(
+            id(last),
+            SocketAddr::from(([127, 0, 0, 1], 4000 + last as u16)),
+        )
+    }
+
+    fn bucket(table: &RoutingTable, index: usize) -> Vec<u8> {
+        table
+            .bucket(index)
+            .map(|contact| contact.id.as_bytes()[31])
+            .collect()
+    }
+
+    /// Test that contacts go in the bucket of their distance, and the local node in none
+    #[test]
+    fn places_contacts_in_buckets() {
+        let mut table = RoutingTable::new(id(0), K);
+        assert_eq!(table.update(contact(0)), Update::Ignored);
+        for last in 1..=20 {
+            assert_eq!(table.update(contact(last)), Update::Inserted);
+        }
+
+        assert_eq!(table.len(), 20);
+        assert_eq!(bucket(&table, 0), [1]);
+        assert_eq!(bucket(&table, 1), [2, 3]);
+        assert_eq!(bucket(&table, 2), [4, 5, 6, 7]);
+        assert_eq!(bucket(&table, 4), [16, 17, 18, 19, 20]);
+        assert_eq!(table.get(&id(5)), Some(&contact(5)));
+        assert_eq!(table.get(&id(21)), None);
+
+        let moved = Contact::new(id(5), SocketAddr::from(([10, 0, 0, 5], 5)));
+        assert_eq!(table.update(moved), Update::Refreshed);
+        assert_eq!(bucket(&table, 2), [4, 6, 7, 5]);
+        assert_eq!(table.get(&id(5)), Some(&moved));
+    }
+
+    /// Test that full buckets keep their contacts unless they fail to answer a ping
+    #[test]
+    fn pings_before_evicting() {
+        let mut table = RoutingTable::new(id(0), 3);
+        for last in 16..19 {
+            table.update(contact(last));
+        }
+
+        assert_eq!(table.update(contact(19)), Update::PingOldest(contact(16)));
+        assert_eq!(table.update(contact(20)), Update::Pending);
+        assert_eq!(bucket(&table, 4), [16, 17, 18]);
+        // The oldest contact answers, so the candidate is dropped
+        assert_eq!(table.update(contact(16)), Update::Refreshed);
+        assert_eq!(bucket(&table, 4), [17, 18, 16]);
+        assert_eq!(table.remove(&id(18)), Some(contact(18)));
+        assert_eq!(bucket(&table, 4), [17, 16]);
+
+        table.update(contact(18));
+        assert_eq!(table.update(contact(21)), Update::PingOldest(contact(17)));
+        // The oldest contact doesn't answer, so the candidate takes its place
+        assert_eq!(table.remove(&id(17)), Some(contact(17)));
+        assert_eq!(bucket(&table, 4), [16, 18, 21]);
+        assert_eq!(table.remove(&id(17)), None);
+        assert_eq!(table.update(contact(22)), Update::PingOldest(contact(16)));
+    }
+
+    /// Test that the closest contacts to an id come sorted by distance
+    #[test]
+    fn returns_closest_contacts() {
+        let mut table = RoutingTable::new(id(0), K);
+        let mut rng = SimRng::new(3);
+        for last in 1..=255 {
+            table.update(contact(last));
+        }
+        for _ in 0..200 {
+            let id = NodeId::random(&mut rng);
+            let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+            table.update(Contact::new(id, addr));
+        }
+
+        let closest = table.closest(&id(0b1001), 4);
+        let lasts: Vec<u8> = closest
+            .iter()
+            .map(|contact| contact.id.as_bytes()[31])
+            .collect();
+        assert_eq!(lasts, [9, 8, 11, 10]);
+
+        let target = NodeId::random(&mut rng);
+        let closest = table.closest(&target, K);
+        assert_eq!(closest.len(), K);
+        assert!(closest
+            .windows(2)
+            .all(|pair| pair[0].id.distance(&target) < pair[1].id.distance(&target)));
+        let farthest = closest[K - 1].id.distance(&target);
+        assert_eq!(
+            table
+                .contacts()
+                .filter(|contact| contact.id.distance(&target) <= farthest)
+                .count(),
+            K
+        );
+    }
+
+    /// Test that buckets without lookups for an hour are refreshed with an id in range
+    #[test]
+    fn refreshes_stale_buckets() {
+        let mut table = RoutingTable::new(id(0), K);
+        let mut rng = SimRng::new(4);
+        assert!(table.refresh_targets(REFRESH_INTERVAL, &mut rng).is_empty());
+        table.update(contact(4));
+
+        let targets = table.refresh_targets(REFRESH_INTERVAL, &mut rng);
+        let indexes: Vec<usize> = targets
+            .iter()
+            .map(|target| id(0).bucket_index(target).unwrap())
+            .collect();
+        assert_eq!(indexes, (2..ID_BITS).collect::<Vec<_>>());
+
+        for target in targets.iter() {
+            table.mark_refreshed(target, REFRESH_INTERVAL);
+        }
+        let mut far = [0; 32];
+        far[0] = 0x80;
+        table.mark_refreshed(&NodeId::new(far), 2 * REFRESH_INTERVAL);
+        assert!(table
+            .refresh_targets(2 * REFRESH_INTERVAL - 1, &mut rng)
+            .is_empty());
+        let targets = table.refresh_targets(2 * REFRESH_INTERVAL, &mut rng);
+        assert_eq!(targets.len(), ID_BITS - 3);
+        assert!(targets
+            .iter()
+            .all(|target| id(0).bucket_index(target) != Some(255)));
+    }
+}
@@ -0,0 +1,590 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
+
+use super::lookup::Lookup;
+use super::node::{DhtConfig, DhtNode, LookupId, Outgoing};
+use super::node_id::NodeId;
+use super::routing_table::Contact;
+use crate::rng::SimRng;
+
+/// Settings of the simulated network.
+#[derive(Debug, Clone, Copy)]
+pub struct NetworkConfig {
+    /// Minimum delivery latency of a message, in milliseconds.
+    pub min_latency: u64,
+    /// Maximum delivery latency of a message, in milliseconds.
+    pub max_latency: u64,
+    /// Settings of every node.
+    pub dht: DhtConfig,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            min_latency: 5,
+            max_latency: 50,
+            dht: DhtConfig::default(),
+        }
+    }
+}
+
+/// Counters of what happened to the messages sent during a simulation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    pub sent: u64,
+    pub delivered: u64,
+    /// Messages to nodes that were down.
+    pub dropped: u64,
+}
+
+struct SimNode {
+    node: DhtNode,
+    up: bool,
+    /// When the node is due for a tick, if up.
+    wakes_at: Option<u64>,
+}
+
+/// A deterministic, single-threaded network of DHT nodes.
+///
+/// Nodes are numbered in the order they joined, and each has a made-up address. A node
+/// joins by bootstrapping from a random node that is up. Messages to nodes that are
+/// down are lost, so their senders time out. The ids, latencies and bootstrap nodes come
+/// from a seeded [`SimRng`], so the same seed always gives the same network.
+///
+/// Time jumps from one event to the next, a message delivery or a node timer, so hours
+/// of replication and republishing are simulated as fast as the messages they send.
+///
+/// # Methods
+/// - `new`: Creates an empty network.
+/// - `with_nodes`: Creates a network and joins nodes one after the other.
+/// - `add_node`: Joins a new node.
+/// - `crash`: Stops a node for good.
+/// - `find_node`: Runs a node lookup from a node.
+/// - `find_value`: Runs a value lookup from a node.
+/// - `put`: Publishes a value from a node.
+/// - `get`: Looks up a value from a node.
+/// - `run_until`: Delivers messages and fires timers until a time.
+/// - `closest_up`: Returns the nodes that are up closest to an id, by brute force.
+pub struct Network {
+    now: u64,
+    config: NetworkConfig,
+    nodes: Vec<SimNode>,
+    indexes: HashMap<SocketAddr, usize>,
+    in_flight: BTreeMap<(u64, u64), (SocketAddr, Outgoing)>,
+    next_sequence: u64,
+    /// The time each node that is up is due for a tick, and its index.
+    timers: BTreeSet<(u64, usize)>,
+    rng: SimRng,
+    stats: NetworkStats,
+}
+
+impl Network {
+    /// Creates a network without nodes.
+    pub fn new(seed: u64, config: NetworkConfig) -> Self {
+        Network {
+            now: 0,
+            config,
+            nodes: vec![],
+            indexes: HashMap::new(),
+            in_flight: BTreeMap::new(),
+            next_sequence: 0,
+            timers: BTreeSet::new(),
+            rng: SimRng::new(seed),
+            stats: NetworkStats::default(),
+        }
+    }
+
+    /// Creates a network, and joins `count` nodes to it one after the other.
+    pub fn with_nodes(count: usize, seed: u64, config: NetworkConfig) -> Self {
+        let mut network = Network::new(seed, config);
+        for _ in 0..count {
+            network.add_node();
+        }
+        network
+    }
+
+    /// Returns the current simulated time, in milliseconds.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Returns the number of nodes, up or down.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns `true` if no node ever joined.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns the node `index`.
+    pub fn node(&self, index: usize) -> &DhtNode {
+        &self.nodes[index].node
+    }
+
+    /// Changes the node `index` with `change`, such as to store values without the
+    /// network.
+    pub fn update_node(&mut self, index: usize, change: impl FnOnce(&mut DhtNode)) {
+        change(&mut self.nodes[index].node);
+        self.schedule(index);
+    }
+
+    /// Returns `true` if the node `index` is up.
+    pub fn is_up(&self, index: usize) -> bool {
+        self.nodes[index].up
+    }
+
+    /// Returns the index of the node with the id `id`.
+    pub fn index_of(&self, id: &NodeId) -> Option<usize> {
+        (0..self.nodes.len()).find(|index| self.nodes[*index].node.id() == *id)
+    }
+
+    /// Returns the indexes of the nodes that are up.
+    pub fn up_nodes(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|index| self.nodes[*index].up)
+            .collect()
+    }
+
+    /// Returns the message counters so far.
+    pub fn stats(&self) -> NetworkStats {
+        self.stats
+    }
+
+    /// Joins a new node with a random id, bootstrapping from a random node that is up,
+    /// and runs until it bootstrapped.
+    ///
+    /// # Returns
+    /// The index of the node.
+    pub fn add_node(&mut self) -> usize {
+        let index = self.nodes.len();
+        let addr = SocketAddr::from((
+            [10, (index >> 16) as u8, (index >> 8) as u8, index as u8],
+            4000,
+        ));
+        let contact = Contact::new(NodeId::random(&mut self.rng), addr);
+        let node = DhtNode::new(contact, self.config.dht, self.rng.next_u64(), self.now);
+        let up = self.up_nodes();
+        self.nodes.push(SimNode {
+            node,
+            up: true,
+            wakes_at: None,
+        });
+        self.indexes.insert(addr, index);
+        self.schedule(index);
+
+        if !up.is_empty() {
+            let seed = up[self.rng.range(0, up.len() as u64 - 1) as usize];
+            let seed = self.nodes[seed].node.contact();
+            let (lookup, outgoing) = self.nodes[index].node.bootstrap(self.now, &[seed]);
+            self.dispatch(index, outgoing);
+            self.run_lookup(index, lookup);
+        }
+        index
+    }
+
+    /// Stops the node `index` for good: messages to it are lost from now on.
+    pub fn crash(&mut self, index: usize) {
+        self.nodes[index].up = false;
+        self.schedule(index);
+    }
+
+    /// Looks up the nodes closest to `target` from the node `index`, and runs until the
+    /// lookup is finished.
+    pub fn find_node(&mut self, index: usize, target: NodeId) -> Lookup {
+        let (lookup, outgoing) = self.nodes[index].node.find_node(self.now, target);
+        self.dispatch(index, outgoing);
+        self.run_lookup(index, lookup)
+    }
+
+    /// Looks up the value of `key` from the node `index`, and runs until the lookup is
+    /// finished.
+    pub fn find_value(&mut self, index: usize, key: NodeId) -> Lookup {
+        let (lookup, outgoing) = self.nodes[index].node.find_value(self.now, key);
+        self.dispatch(index, outgoing);
+        self.run_lookup(index, lookup)
+    }
+
+    /// Publishes `value` under `key` from the node `index`, versioned by the time of the
+    /// network, and runs until the lookup of the closest nodes is finished. They store
+    /// the value once the requests to store it arrive, a latency later.
+    pub fn put(&mut self, index: usize, key: &[u8], value: Vec<u8>) -> Lookup {
+        let now = self.now;
+        let (lookup, outgoing) = self.nodes[index].node.put(now, key, value, now);
+        self.dispatch(index, outgoing);
+        self.run_lookup(index, lookup)
+    }
+
+    /// Looks up the value of `key` from the node `index`, and runs until the lookup is
+    /// finished.
+    pub fn get(&mut self, index: usize, key: &[u8]) -> Lookup {
+        let (lookup, outgoing) = self.nodes[index].node.get(self.now, key);
+        self.dispatch(index, outgoing);
+        self.run_lookup(index, lookup)
+    }
+
+    /// Returns the number of nodes that are up and store a value under `key`, expired or
+    /// not.
+    pub fn holders(&self, key: &NodeId) -> usize {
+        self.nodes
+            .iter()
+            .filter(|sim_node| sim_node.up && sim_node.node.storage().record(key).is_some())
+            .count()
+    }
+
+    /// Returns the ids of the `count` nodes that are up closest to `target`, the closest
+    /// first, by comparing every node.
+    pub fn closest_up(&self, target: &NodeId, count: usize) -> Vec<NodeId> {
+        let mut ids: Vec<NodeId> = self
+            .nodes
+            .iter()
+            .filter(|sim_node| sim_node.up)
+            .map(|sim_node| sim_node.node.id())
+            .collect();
+        ids.sort_by_key(|id| id.distance(target));
+        ids.truncate(count);
+        ids
+    }
+
+    /// Returns the time of the next event: a message delivery or a node timer.
+    pub fn next_event(&self) -> Option<u64> {
+        let delivery = self.in_flight.keys().next().map(|(time, _)| *time);
+        let timer = self.timers.first().map(|(time, _)| *time);
+        delivery.into_iter().chain(timer).min()
+    }
+
+    /// Advances simulated time to the next event, then delivers the messages and fires
+    /// the timers due by then.
+    ///
+    /// # Returns
+    /// `false` if there was no event left.
+    pub fn step(&mut self) -> bool {
+        let Some(time) = self.next_event() else {
+            return false;
+        };
+        self.now = self.now.max(time);
+
+        while let Some(entry) = self.in_flight.first_entry() {
+            if entry.key().0 > self.now {
+                break;
+            }
+            let (from, outgoing) = entry.remove();
+            let to = match self.indexes.get(&outgoing.to) {
+                Some(to) if self.nodes[*to].up => *to,
+                _ => {
+                    self.stats.dropped += 1;
+                    continue;
+                }
+            };
+            self.stats.delivered += 1;
+            let replies = self.nodes[to].node.handle(self.now, from, outgoing.message);
+            self.dispatch(to, replies);
+        }
+
+        while let Some(&(time, index)) = self.timers.first() {
+            if time > self.now {
+                break;
+            }
+            let outgoing = self.nodes[index].node.tick(self.now);
+            self.dispatch(index, outgoing);
+        }
+        true
+    }
+
+    /// Runs the events due until `time`, and moves simulated time to it.
+    pub fn run_until(&mut self, time: u64) {
+        while self.next_event().is_some_and(|next| next <= time) {
+            self.step();
+        }
+        self.now = self.now.max(time);
+    }
+
+    /// Runs steps until lookup `id` of node `index` is finished, and returns it.
+    ///
+    /// # Panics
+    /// If the lookup takes more than a minute, which means requests never time out.
+    fn run_lookup(&mut self, index: usize, id: LookupId) -> Lookup {
+        let deadline = self.now + 60_000;
+        loop {
+            if let Some(lookup) = self.nodes[index].node.take_finished(id) {
+                return lookup;
+            }
+            assert!(
+                self.now < deadline && self.step(),
+                "lookup {} of node {} never finished",
+                id,
+                index
+            );
+        }
+    }
+
+    /// Sends the `outgoing` messages of node `from`, with random latencies, and
+    /// reschedules its timer.
+    fn dispatch(&mut self, from: usize, outgoing: Vec<Outgoing>) {
+        self.schedule(from);
+        let addr = self.nodes[from].node.contact().addr;
+        for message in outgoing {
+            self.stats.sent += 1;
+            let latency = self
+                .rng
+                .range(self.config.min_latency, self.config.max_latency);
+            self.in_flight
+                .insert((self.now + latency, self.next_sequence), (addr, message));
+            self.next_sequence += 1;
+        }
+    }
+
+    /// Schedules the next tick of the node `index`, if it is up.
+    fn schedule(&mut self, index: usize) {
+        let sim_node = &mut self.nodes[index];
+        if let Some(time) = sim_node.wakes_at.take() {
+            self.timers.remove(&(time, index));
+        }
+        if sim_node.up {
+            let time = sim_node.node.next_timer();
+            sim_node.wakes_at = Some(time);
+            self.timers.insert((time, index));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::routing_table::K;
+
+    fn ids(contacts: &[Contact]) -> Vec<NodeId> {
+        contacts.iter().map(|contact| contact.id).collect()
+    }
+
+    /// Test that lookups among thousands of nodes find the k closest in a few hops
+    #[test]
+    fn lookups_find_closest_nodes() {
+        let mut network = Network::with_nodes(2000, 1, NetworkConfig::default());
+        let mut rng = SimRng::new(2);
+        let mut queried = 0;
+        for _ in 0..20 {
+            let target = NodeId::random(&mut rng);
+            let from = rng.range(0, 1999) as usize;
+            let start = network.now();
+            let lookup = network.find_node(from, target);
+
+            assert_eq!(ids(&lookup.closest()), network.closest_up(&target, K));
+            assert!(network.now() - start < 1000);
+            queried += lookup.queried();
+        }
+        // Far fewer than the 2000 nodes are asked
+        assert!(queried / 20 < 60, "{} nodes asked per lookup", queried / 20);
+        assert_eq!(network.stats().dropped, 0);
+    }
+
+    /// Test that lookups time out on crashed nodes, and still find the closest nodes up
+    #[test]
+    fn lookups_survive_crashes() {
+        let mut network = Network::with_nodes(1000, 3, NetworkConfig::default());
+        let mut rng = SimRng::new(4);
+        for index in 0..1000 {
+            if rng.chance(0.3) {
+                network.crash(index);
+            }
+        }
+
+        let up: Vec<usize> = (0..1000).filter(|index| network.is_up(*index)).collect();
+        let mut missed = 0;
+        for _ in 0..20 {
+            let target = NodeId::random(&mut rng);
+            let from = up[rng.range(0, up.len() as u64 - 1) as usize];
+            let lookup = network.find_node(from, target);
+
+            let closest = network.closest_up(&target, K);
+            let found = ids(&lookup.closest());
+            assert_eq!(found.len(), K);
+            assert_eq!(found[..K / 2], closest[..K / 2]);
+            missed += closest.iter().filter(|id| !found.contains(id)).count();
+        }
+        // Nodes only return the live contacts among their k closest, so the farthest of
+        // the k closest nodes are sometimes missed, but not more than 5% of them
+        assert!(missed <= K, "missed {} of the closest nodes", missed);
+        assert!(network.stats().dropped > 0);
+    }
+
+    /// Test that value lookups stop at a node storing the value
+    #[test]
+    fn finds_values() {
+        let mut network = Network::with_nodes(500, 5, NetworkConfig::default());
+        let key = NodeId::from_key(b"match:7");
+        let holders = network.closest_up(&key, 3);
+        for index in 0..network.len() {
+            if holders.contains(&network.node(index).id()) {
+                network.update_node(index, |node| {
+                    node.storage_mut()
+                        .insert(key, b"map=desert".to_vec(), 0, u64::MAX, None);
+                });
+            }
+        }
+
+        for from in [0, 100, 499] {
+            let lookup = network.find_value(from, key);
+            assert_eq!(lookup.value(), Some(&b"map=desert"[..]));
+            assert!(holders.contains(&lookup.found_at().unwrap().id));
+        }
+        let missing = NodeId::from_key(b"match:8");
+        let lookup = network.find_value(0, missing);
+        assert_eq!(lookup.value(), None);
+        assert_eq!(ids(&lookup.closest()), network.closest_up(&missing, K));
+    }
+
+    /// Test that a put stores a value on the k closest nodes, and that a get caches it on
+    /// the path of the lookup
+    #[test]
+    fn puts_and_gets_values() {
+        let mut network = Network::with_nodes(300, 6, NetworkConfig::default());
+        let key = NodeId::from_key(b"player:1");
+        let lookup = network.put(0, b"player:1", b"elo=1500".to_vec());
+        assert_eq!(ids(&lookup.closest()), network.closest_up(&key, K));
+        network.run_until(network.now() + 1000);
+
+        let closest = network.closest_up(&key, K);
+        for index in network.up_nodes() {
+            let record = network.node(index).storage().record(&key);
+            if closest.contains(&network.node(index).id()) {
+                assert!(record.unwrap().replicate_at.is_some());
+            } else {
+                // Besides the closest nodes, only the publisher stores the value
+                assert!(record.is_none() || index == 0);
+            }
+        }
+        let lookup = network.get(150, b"player:1");
+        assert_eq!(lookup.value(), Some(&b"elo=1500"[..]));
+
+        // A value held by the farthest of the closest nodes alone is cached on the way
+        let other = NodeId::from_key(b"player:2");
+        let holder = network.closest_up(&other, K)[K - 1];
+        let index = network.index_of(&holder).unwrap();
+        network.update_node(index, |node| {
+            node.storage_mut()
+                .insert(other, b"elo=900".to_vec(), 0, u64::MAX, None);
+        });
+        let lookup = network.get(299, b"player:2");
+        assert_eq!(lookup.value(), Some(&b"elo=900"[..]));
+        network.run_until(network.now() + 1000);
+
+        assert_eq!(network.holders(&other), 2);
+        let cached = network
+            .up_nodes()
+            .into_iter()
+            .find(|index| {
+                let node = network.node(*index);
+                node.id() != holder && node.storage().record(&other).is_some()
+            })
+            .unwrap();
+        // The closest node that lacked the value got it, and now replicates it as well
+        assert!(network
+            .node(cached)
+            .storage()
+            .record(&other)
+            .unwrap()
+            .replicate_at
+            .is_some());
+        assert_eq!(network.closest_up(&other, K)[0], network.node(cached).id());
+    }
+
+    /// Test that values survive nodes leaving and joining, as the nodes storing them
+    /// replicate them to the nodes now closest to their keys
+    #[test]
+    fn values_survive_churn() {
+        let config = NetworkConfig {
+            dht: DhtConfig {
+                replicate_interval: 10_000,
+                ..DhtConfig::default()
+            },
+            ..NetworkConfig::default()
+        };
+        let mut network = Network::with_nodes(100, 7, config);
+        let mut rng = SimRng::new(8);
+        let keys: Vec<String> = (0..10).map(|index| format!("room:{}", index)).collect();
+        for key in &keys {
+            let from = rng.range(0, 99) as usize;
+            network.put(from, key.as_bytes(), key.as_bytes().to_vec());
+        }
+
+        for _ in 0..10 {
+            for index in network.up_nodes() {
+                if rng.chance(0.1) {
+                    network.crash(index);
+                }
+            }
+            for _ in 0..10 {
+                network.add_node();
+            }
+            network.run_until(network.now() + 10_000);
+        }
+
+        let up = network.up_nodes();
+        // Most of the nodes that stored the values at first are gone
+        assert!(up.iter().filter(|index| **index < 100).count() < 50);
+        for key in &keys {
+            let from = up[rng.range(0, up.len() as u64 - 1) as usize];
+            let lookup = network.get(from, key.as_bytes());
+            assert_eq!(lookup.value(), Some(key.as_bytes()));
+            let holders = network.holders(&NodeId::from_key(key.as_bytes()));
+            assert!(holders >= K / 2, "{} nodes store {}", holders, key);
+        }
+    }
+
+    /// Test that a newer value replaces an older one on every node but the publisher of
+    /// the older one, however often the holders of the older one replicate it
+    #[test]
+    fn newer_values_win() {
+        let config = NetworkConfig {
+            dht: DhtConfig {
+                replicate_interval: 5_000,
+                ..DhtConfig::default()
+            },
+            ..NetworkConfig::default()
+        };
+        let mut network = Network::with_nodes(100, 10, config);
+        let key = NodeId::from_key(b"lobby");
+        network.put(10, b"lobby", b"old".to_vec());
+        network.run_until(network.now() + 1000);
+        network.put(20, b"lobby", b"new".to_vec());
+        network.run_until(network.now() + 30_000);
+
+        for index in network.up_nodes() {
+            if let Some(record) = network.node(index).storage().record(&key) {
+                let expected = if index == 10 {
+                    &b"old"[..]
+                } else {
+                    &b"new"[..]
+                };
+                assert_eq!(record.value, expected, "value of node {}", index);
+            }
+        }
+        assert!(network.holders(&key) > K);
+        assert_eq!(network.get(30, b"lobby").value(), Some(&b"new"[..]));
+    }
+
+    /// Test that values expire once their publisher stops republishing them
+    #[test]
+    fn values_expire_unless_republished() {
+        let config = NetworkConfig {
+            dht: DhtConfig {
+                record_ttl: 30_000,
+                republish_interval: 10_000,
+                replicate_interval: 5_000,
+                ..DhtConfig::default()
+            },
+            ..NetworkConfig::default()
+        };
+        let mut network = Network::with_nodes(100, 9, config);
+        network.put(10, b"kept", b"1".to_vec());
+        network.put(20, b"dropped", b"2".to_vec());
+        network.crash(20);
+        network.run_until(network.now() + 60_000);
+
+        assert_eq!(network.get(30, b"kept").value(), Some(&b"1"[..]));
+        assert_eq!(network.holders(&NodeId::from_key(b"dropped")), 0);
+        assert_eq!(network.get(30, b"dropped").value(), None);
+    }
+}
@@ -0,0 +1,410 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use super::node_id::NodeId;
+
+/// A value stored on a node, and when to act on it. Times are in milliseconds of the
+/// node's clock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub value: Vec<u8>,
+    /// The version its publisher gave the value, higher than those of the values it
+    /// published under the same key before.
+    pub version: u64,
+    /// When the value is dropped, unless stored again before.
+    pub expires: u64,
+    /// When to store the value again to the nodes closest to its key, or `None` for a
+    /// copy cached by a node that isn't among them.
+    pub replicate_at: Option<u64>,
+    /// When to publish the value again with a new lifetime, if this node published it.
+    pub republish_at: Option<u64>,
+}
+
+/// The values a node stores, by key.
+///
+/// Values are kept in key order, so that a node replicates them in the same order on
+/// every run. Of the values stored under a key, a node keeps the one of the highest
+/// version, so that a holder replicating a stale copy can't undo a newer publication.
+///
+/// The number of values is bounded, so that other nodes can't exhaust the memory of
+/// the node by storing values under ever new keys. Once full, a value under a new key
+/// takes the place of the cached copy expiring first, or is refused if there is none.
+///
+/// # Methods
+/// - `new`: Creates an empty storage without limit.
+/// - `with_max_records`: Creates an empty storage holding a limited number of values.
+/// - `get`: Returns a value that didn't expire.
+/// - `insert`: Stores a value received from another node.
+/// - `publish`: Stores a value published by this node.
+/// - `stop_replicating`: Turns a value into a cached copy.
+/// - `expire`: Drops the expired values.
+/// - `due_replications`: Returns the values to replicate.
+/// - `due_republications`: Returns the values to republish.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    records: BTreeMap<NodeId, Record>,
+    max_records: usize,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage::new()
+    }
+}
+
+impl Storage {
+    /// Creates an empty storage without limit.
+    pub fn new() -> Self {
+        Storage::with_max_records(usize::MAX)
+    }
+
+    /// Creates an empty storage holding at most `max_records` values received from other
+    /// nodes.
+    pub fn with_max_records(max_records: usize) -> Self {
+        Storage {
+            records: BTreeMap::new(),
+            max_records,
+        }
+    }
+
+    /// Returns the number of values stored, including expired ones not dropped yet.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Returns `true` if no value is stored.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Returns the record of `key`, expired or not.
+    pub fn record(&self, key: &NodeId) -> Option<&Record> {
+        self.records.get(key)
+    }
+
+    /// Returns the value of `key`, unless it expired at `now`.
+    pub fn get(&self, key: &NodeId, now: u64) -> Option<&[u8]> {
+        self.records
+            .get(key)
+            .filter(|record| record.expires > now)
+            .map(|record| record.value.as_slice())
+    }
+
+    /// Stores `value` under `key`, received from another node, until `expires`.
+    ///
+    /// A value replaces the one stored under the same key if it has a higher version, or
+    /// the same version and a higher value, so that the nodes agree on it whatever order
+    /// the values arrive in. A value this node publishes is never replaced. The same
+    /// value stored again lives until the later of both expirations. Its replication is
+    /// postponed to `replicate_at`, since the node that stored it just replicated it.
+    ///
+    /// A value under a new key is refused once the storage is full, unless a cached copy
+    /// can be dropped to make room.
+    ///
+    /// # Parameters
+    /// - `key`: The key of the value
+    /// - `value`: The value
+    /// - `version`: The version its publisher gave the value
+    /// - `expires`: When the value expires
+    /// - `replicate_at`: When to replicate the value, or `None` for a cached copy
+    ///
+    /// # Returns
+    /// `false` if the value is refused for lack of room.
+    pub fn insert(
+        &mut self,
+        key: NodeId,
+        value: Vec<u8>,
+        version: u64,
+        expires: u64,
+        replicate_at: Option<u64>,
+    ) -> bool {
+        let Some(record) = self.records.get_mut(&key) else {
+            if !self.make_room() {
+                return false;
+            }
+            self.records.insert(
+                key,
+                Record {
+                    value,
+                    version,
+                    expires,
+                    replicate_at,
+                    republish_at: None,
+                },
+            );
+            return true;
+        };
+        if record.republish_at.is_some() {
+            return true;
+        }
+        match (version, &value).cmp(&(record.version, &record.value)) {
+            Ordering::Less => {}
+            Ordering::Equal => {
+                record.expires = record.expires.max(expires);
+                record.replicate_at = replicate_at.or(record.replicate_at);
+            }
+            Ordering::Greater => {
+                record.value = value;
+                record.version = version;
+                record.expires = expires;
+                record.replicate_at = replicate_at.or(record.replicate_at);
+            }
+        }
+        true
+    }
+
+    /// Stores `value` under `key`, published by this node with `version`, which
+    /// publishes it again at `republish_at`. The value is stored even if the storage is
+    /// full, as the node chooses what it publishes, once a cached copy is dropped if
+    /// there is one.
+    pub fn publish(
+        &mut self,
+        key: NodeId,
+        value: Vec<u8>,
+        version: u64,
+        expires: u64,
+        republish_at: u64,
+    ) {
+        if !self.records.contains_key(&key) {
+            self.make_room();
+        }
+        self.records.insert(
+            key,
+            Record {
+                value,
+                version,
+                expires,
+                replicate_at: Some(republish_at),
+                republish_at: Some(republish_at),
+            },
+        );
+    }
+
+    /// Stops replicating the value of `key`, which is kept as a cached copy until it
+    /// expires.
+    pub fn stop_replicating(&mut self, key: &NodeId) {
+        if let Some(record) = self.records.get_mut(key) {
+            record.replicate_at = None;
+        }
+    }
+
+    /// Drops the values that expired at `now`.
+    ///
+    /// # Returns
+    /// The number of values dropped.
+    pub fn expire(&mut self, now: u64) -> usize {
+        let before = self.records.len();
+        self.records.retain(|_, record| record.expires > now);
+        before - self.records.len()
+    }
+
+    /// Returns the values due for replication at `now`, with their version and
+    /// expiration, and schedules their next replication `interval` later.
+    pub fn due_replications(
+        &mut self,
+        now: u64,
+        interval: u64,
+    ) -> Vec<(NodeId, Vec<u8>, u64, u64)> {
+        self.records
+            .iter_mut()
+            .filter(|(_, record)| {
+                record.expires > now && record.replicate_at.is_some_and(|at| at <= now)
+            })
+            .map(|(key, record)| {
+                record.replicate_at = Some(now + interval);
+                (*key, record.value.clone(), record.version, record.expires)
+            })
+            .collect()
+    }
+
+    /// Returns the values this node published that are due for republication at `now`,
+    /// with their version, which now expire `ttl` later and are republished again
+    /// `interval` later.
+    pub fn due_republications(
+        &mut self,
+        now: u64,
+        ttl: u64,
+        interval: u64,
+    ) -> Vec<(NodeId, Vec<u8>, u64)> {
+        self.records
+            .iter_mut()
+            .filter(|(_, record)| record.republish_at.is_some_and(|at| at <= now))
+            .map(|(key, record)| {
+                record.expires = now + ttl;
+                record.republish_at = Some(now + interval);
+                record.replicate_at = Some(now + interval);
+                (*key, record.value.clone(), record.version)
+            })
+            .collect()
+    }
+
+    /// Makes room for a value under a new key, dropping the cached copy expiring first
+    /// if the storage is full.
+    ///
+    /// # Returns
+    /// `false` if the storage is still full.
+    fn make_room(&mut self) -> bool {
+        if self.records.len() < self.max_records {
+            return true;
+        }
+        let cached = self
+            .records
+            .iter()
+            .filter(|(_, record)| record.replicate_at.is_none() && record.republish_at.is_none())
+            .min_by_key(|(_, record)| record.expires)
+            .map(|(key, _)| *key);
+        match cached {
+            Some(key) => {
+                self.records.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the next time a value expires, or is due for replication or
+    /// republication.
+    pub fn next_due(&self) -> Option<u64> {
+        self.records
+            .values()
+            .flat_map(|record| {
+                [
+                    Some(record.expires),
+                    record.replicate_at,
+                    record.republish_at,
+                ]
+            })
+            .flatten()
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that values expire, and live until the later expiration when stored again
+    #[test]
+    fn expires_values() {
+        let mut storage = Storage::new();
+        let key = NodeId::from_key(b"score");
+        storage.insert(key, b"10".to_vec(), 1, 100, None);
+        assert_eq!(storage.get(&key, 99), Some(&b"10"[..]));
+        assert_eq!(storage.get(&key, 100), None);
+
+        storage.insert(key, b"10".to_vec(), 1, 50, Some(20));
+        assert_eq!(storage.get(&key, 99), Some(&b"10"[..]));
+        assert_eq!(storage.record(&key).unwrap().replicate_at, Some(20));
+        assert_eq!(storage.next_due(), Some(20));
+        assert_eq!(storage.expire(99), 0);
+        assert_eq!(storage.expire(100), 1);
+        assert!(storage.is_empty());
+        assert_eq!(storage.next_due(), None);
+    }
+
+    /// Test that stored values are replicated periodically, and cached copies never
+    #[test]
+    fn schedules_replication() {
+        let mut storage = Storage::new();
+        let (stored, cached) = (NodeId::from_key(b"stored"), NodeId::from_key(b"cached"));
+        storage.insert(stored, b"a".to_vec(), 1, 1000, Some(100));
+        storage.insert(cached, b"b".to_vec(), 1, 1000, None);
+
+        assert!(storage.due_replications(99, 100).is_empty());
+        assert_eq!(
+            storage.due_replications(100, 100),
+            [(stored, b"a".to_vec(), 1, 1000)]
+        );
+        assert!(storage.due_replications(150, 100).is_empty());
+        // Another node replicated it in the meantime
+        storage.insert(stored, b"a".to_vec(), 1, 1000, Some(250));
+        assert!(storage.due_replications(200, 100).is_empty());
+        assert_eq!(storage.due_replications(250, 100).len(), 1);
+        assert!(storage.due_replications(1000, 100).is_empty());
+        storage.stop_replicating(&stored);
+        assert_eq!(storage.record(&stored).unwrap().replicate_at, None);
+    }
+
+    /// Test that a value only replaces one of a lower version, or of the same version and a lower value
+    #[test]
+    fn keeps_newest_versions() {
+        let mut storage = Storage::new();
+        let key = NodeId::from_key(b"score");
+        storage.insert(key, b"new".to_vec(), 2, 100, Some(50));
+        // A holder of a stale copy replicates it
+        storage.insert(key, b"old".to_vec(), 1, 500, Some(20));
+        let record = storage.record(&key).unwrap();
+        assert_eq!((&record.value[..], record.version), (&b"new"[..], 2));
+        assert_eq!((record.expires, record.replicate_at), (100, Some(50)));
+
+        storage.insert(key, b"newer".to_vec(), 3, 80, None);
+        let record = storage.record(&key).unwrap();
+        assert_eq!((&record.value[..], record.version), (&b"newer"[..], 3));
+        assert_eq!((record.expires, record.replicate_at), (80, Some(50)));
+
+        // Values of the same version win by value, whatever order they arrive in
+        let mut other = storage.clone();
+        storage.insert(key, b"tie-a".to_vec(), 4, 80, None);
+        storage.insert(key, b"tie-b".to_vec(), 4, 80, None);
+        other.insert(key, b"tie-b".to_vec(), 4, 80, None);
+        other.insert(key, b"tie-a".to_vec(), 4, 80, None);
+        assert_eq!(storage.get(&key, 0), Some(&b"tie-b"[..]));
+        assert_eq!(other.get(&key, 0), Some(&b"tie-b"[..]));
+    }
+
+    /// Test that a value this node publishes is never replaced by a stored one
+    #[test]
+    fn keeps_published_values() {
+        let mut storage = Storage::new();
+        let key = NodeId::from_key(b"profile");
+        storage.publish(key, b"mine".to_vec(), 1, 300, 200);
+        for version in [0, 1, 2] {
+            storage.insert(key, b"theirs".to_vec(), version, 1000, Some(10));
+        }
+        let record = storage.record(&key).unwrap();
+        assert_eq!((&record.value[..], record.version), (&b"mine"[..], 1));
+        assert_eq!((record.expires, record.replicate_at), (300, Some(200)));
+    }
+
+    /// Test that a full storage drops cached copies for new keys, and refuses them
+    /// once only replicated values are left
+    #[test]
+    fn bounds_values() {
+        let mut storage = Storage::with_max_records(3);
+        let keys: Vec<NodeId> = (0..6u8).map(|index| NodeId::from_key(&[index])).collect();
+        assert!(storage.insert(keys[0], b"a".to_vec(), 1, 200, None));
+        assert!(storage.insert(keys[1], b"b".to_vec(), 1, 100, None));
+        assert!(storage.insert(keys[2], b"c".to_vec(), 1, 100, Some(50)));
+
+        assert!(storage.insert(keys[3], b"d".to_vec(), 1, 100, Some(50)));
+        assert!(storage.record(&keys[1]).is_none());
+        assert!(storage.insert(keys[4], b"e".to_vec(), 1, 100, Some(50)));
+        assert!(storage.record(&keys[0]).is_none());
+        assert!(!storage.insert(keys[5], b"f".to_vec(), 1, 100, None));
+        assert_eq!(storage.len(), 3);
+
+        // Known keys are still updated, and published values always stored
+        assert!(storage.insert(keys[2], b"c2".to_vec(), 2, 100, Some(50)));
+        assert_eq!(storage.get(&keys[2], 0), Some(&b"c2"[..]));
+        storage.publish(keys[5], b"f".to_vec(), 1, 100, 50);
+        assert_eq!(storage.len(), 4);
+    }
+
+    /// Test that published values are republished with a new lifetime
+    #[test]
+    fn republishes_own_values() {
+        let mut storage = Storage::new();
+        let key = NodeId::from_key(b"profile");
+        storage.publish(key, b"v1".to_vec(), 7, 300, 200);
+        assert_eq!(storage.next_due(), Some(200));
+        assert!(storage.due_republications(199, 300, 200).is_empty());
+
+        assert_eq!(
+            storage.due_republications(200, 300, 200),
+            [(key, b"v1".to_vec(), 7)]
+        );
+        let record = storage.record(&key).unwrap();
+        assert_eq!((record.expires, record.republish_at), (500, Some(400)));
+        assert_eq!(storage.get(&key, 450), Some(&b"v1"[..]));
+    }
+}
@@ -0,0 +1,402 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::lookup::Lookup;
+use super::message::{Message, MAX_DATAGRAM_SIZE, MAX_VALUE_SIZE};
+use super::node::{DhtConfig, DhtNode, LookupId, Outgoing};
+use super::node_id::NodeId;
+use super::routing_table::Contact;
+
+/// Longest the socket thread blocks on the socket, so that it notices the timers that
+/// requests made meanwhile started.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Counters of the datagrams a node exchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UdpStats {
+    /// Datagrams sent.
+    pub sent: usize,
+    /// Datagrams received and decoded.
+    pub received: usize,
+    /// Datagrams received that weren't valid messages.
+    pub malformed: usize,
+}
+
+struct State {
+    node: DhtNode,
+    stats: UdpStats,
+}
+
+/// What the socket thread and the handle of a node share.
+struct Shared {
+    state: Mutex<State>,
+    /// Notified whenever the node handled a message or a timer, which may have finished
+    /// a lookup.
+    changed: Condvar,
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    started: Instant,
+    shutdown: AtomicBool,
+}
+
+/// A DHT node that exchanges its messages with other processes over UDP.
+///
+/// The [`DhtNode`] state machine runs behind a mutex: a thread receives datagrams,
+/// decodes them with `Message::from_bytes` and hands them to the node, and ticks it when
+/// a timer is due. Each message is one datagram, and a response is matched to its
+/// request by the request id it repeats, which the node checks against the address and
+/// id it sent the request to. The clock of the node is the time since it started.
+///
+/// The methods that look something up block the caller until the lookup finished, which
+/// takes at most a few request timeouts.
+///
+/// # Methods
+/// - `start`: Starts a node listening on a UDP address.
+/// - `bootstrap`: Joins the network through a seed node.
+/// - `find_node`: Looks up the nodes closest to an id.
+/// - `put`: Publishes a value.
+/// - `get`: Looks up a value.
+/// - `with_node`: Reads the state of the node.
+/// - `stats`: Returns the datagrams the node exchanged.
+pub struct UdpNode {
+    shared: Arc<Shared>,
+    contact: Contact,
+    config: DhtConfig,
+}
+
+impl UdpNode {
+    /// Starts a node with the id `id`, listening on `addr`.
+    ///
+    /// # Parameters
+    /// - `addr`: The address to listen on, with port 0 for any free port
+    /// - `id`: The id of the node
+    /// - `config`: The settings of the node
+    /// - `seed`: Seed for request ids and refresh targets
+    ///
+    /// # Returns
+    /// The node, or the error of binding the socket.
+    pub fn start(
+        addr: impl ToSocketAddrs,
+        id: NodeId,
+        config: DhtConfig,
+        seed: u64,
+    ) -> io::Result<UdpNode> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let local_addr = socket.local_addr()?;
+        let contact = Contact::new(id, local_addr);
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                node: DhtNode::new(contact, config, seed, 0),
+                stats: UdpStats::default(),
+            }),
+            changed: Condvar::new(),
+            socket,
+            local_addr,
+            started: Instant::now(),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let receiving = Arc::clone(&shared);
+        thread::spawn(move || receiving.run());
+        Ok(UdpNode {
+            shared,
+            contact,
+            config,
+        })
+    }
+
+    /// Returns the id and address of the node.
+    pub fn contact(&self) -> Contact {
+        self.contact
+    }
+
+    /// Returns the address the node listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.local_addr
+    }
+
+    /// Joins the network through the node at `seed`: pings it to learn its id, then
+    /// looks up the id of this node.
+    ///
+    /// # Returns
+    /// The finished lookup, an error of kind `TimedOut` if the seed didn't answer, or the
+    /// error of sending the ping.
+    pub fn bootstrap(&self, seed: impl ToSocketAddrs) -> io::Result<Lookup> {
+        let seed = seed
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no seed address"))?;
+        let mut state = self.shared.lock();
+        let ping = state.node.introduce(self.shared.now(), seed);
+        self.shared.send(&mut state, vec![ping])?;
+
+        let knows_seed = |state: &State| {
+            state
+                .node
+                .table()
+                .contacts()
+                .any(|contact| contact.addr == seed)
+        };
+        // With some slack for the socket thread to notice the answer
+        let timeout = Duration::from_millis(self.config.request_timeout) + POLL_INTERVAL * 2;
+        let deadline = Instant::now() + timeout;
+        while !knows_seed(&state) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("seed {} didn't answer", seed),
+                ));
+            }
+            state = self.shared.wait(state, left);
+        }
+
+        let (id, outgoing) = state.node.bootstrap(self.shared.now(), &[]);
+        self.shared.send(&mut state, outgoing)?;
+        Ok(self.shared.wait_for(state, id))
+    }
+
+    /// Looks up the nodes closest to `target`.
+    ///
+    /// # Returns
+    /// The finished lookup, or the error of sending the requests.
+    pub fn find_node(&self, target: NodeId) -> io::Result<Lookup> {
+        self.run(|node, now| node.find_node(now, target))
+    }
+
+    /// Publishes `value` under the hash of `key` on the nodes closest to it, as
+    /// `DhtNode::put` does, versioned by the time in milliseconds since the Unix epoch.
+    ///
+    /// # Returns
+    /// The finished lookup of the closest nodes, which are sent the value, an error of
+    /// kind `InvalidInput` if the value is larger than `MAX_VALUE_SIZE`, or the error of
+    /// sending the requests.
+    pub fn put(&self, key: &[u8], value: Vec<u8>) -> io::Result<Lookup> {
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "value of {} bytes is larger than {}",
+                    value.len(),
+                    MAX_VALUE_SIZE
+                ),
+            ));
+        }
+        let version = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        self.run(|node, now| node.put(now, key, value, version))
+    }
+
+    /// Looks up the value stored under the hash of `key`.
+    ///
+    /// # Returns
+    /// The value, `None` if no node has it, or the error of sending the requests.
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let lookup = self.run(|node, now| node.get(now, key))?;
+        Ok(lookup.value().map(<[u8]>::to_vec))
+    }
+
+    /// Calls `f` with the state machine of the node, such as to read its routing table.
+    /// Messages received meanwhile wait for `f` to return.
+    pub fn with_node<R>(&self, f: impl FnOnce(&DhtNode) -> R) -> R {
+        f(&self.shared.lock().node)
+    }
+
+    /// Returns the datagrams the node exchanged so far.
+    pub fn stats(&self) -> UdpStats {
+        self.shared.lock().stats
+    }
+
+    /// Starts a lookup with `start`, sends its requests, and waits for it to finish.
+    fn run(
+        &self,
+        start: impl FnOnce(&mut DhtNode, u64) -> (LookupId, Vec<Outgoing>),
+    ) -> io::Result<Lookup> {
+        let mut state = self.shared.lock();
+        let (id, outgoing) = start(&mut state.node, self.shared.now());
+        self.shared.send(&mut state, outgoing)?;
+        Ok(self.shared.wait_for(state, id))
+    }
+}
+
+impl Drop for UdpNode {
+    /// Stops the socket thread, which notices within `POLL_INTERVAL`.
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("a thread of the node panicked")
+    }
+
+    /// Returns the time of the node's clock: the milliseconds since it started.
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    /// Waits until the node changes, or `timeout` passes.
+    fn wait<'a>(&self, state: MutexGuard<'a, State>, timeout: Duration) -> MutexGuard<'a, State> {
+        self.changed
+            .wait_timeout(state, timeout)
+            .expect("a thread of the node panicked")
+            .0
+    }
+
+    /// Waits until the lookup `id` finished, and returns it.
+    fn wait_for(&self, mut state: MutexGuard<'_, State>, id: LookupId) -> Lookup {
+        loop {
+            if let Some(lookup) = state.node.take_finished(id) {
+                return lookup;
+            }
+            state = self.wait(state, POLL_INTERVAL);
+        }
+    }
+
+    /// Sends the `outgoing` messages, one datagram each.
+    ///
+    /// # Returns
+    /// The first error of sending, after trying every message. Requests that weren't
+    /// sent time out like lost ones.
+    fn send(&self, state: &mut State, outgoing: Vec<Outgoing>) -> io::Result<()> {
+        let mut result = Ok(());
+        for outgoing in outgoing {
+            match self
+                .socket
+                .send_to(&outgoing.message.to_bytes(), outgoing.to)
+            {
+                Ok(_) => state.stats.sent += 1,
+                Err(error) => result = result.and(Err(error)),
+            }
+        }
+        result
+    }
+
+    /// Receives datagrams and fires the timers of the node until it shuts down.
+    fn run(&self) {
+        // Room for one byte more than the largest message, to tell oversized ones apart
+        let mut buffer = [0; MAX_DATAGRAM_SIZE + 1];
+        while !self.shutdown.load(Ordering::Relaxed) {
+            let received = match self.socket.recv_from(&mut buffer) {
+                Ok((len, from)) => Some((len, from)),
+                // Timeouts, and errors such as ICMP port unreachable reported by
+                // some platforms on the next receive
+                Err(_) => None,
+            };
+
+            let mut state = self.lock();
+            let now = self.now();
+            if let Some((len, from)) = received {
+                let message = if len > MAX_DATAGRAM_SIZE {
+                    Err(io::Error::from(io::ErrorKind::InvalidData))
+                } else {
+                    Message::from_bytes(&buffer[..len])
+                };
+                match message {
+                    Ok(message) => {
+                        state.stats.received += 1;
+                        let outgoing = state.node.handle(now, from, message);
+                        let _ = self.send(&mut state, outgoing);
+                    }
+                    Err(_) => state.stats.malformed += 1,
+                }
+            }
+            if state.node.next_timer() <= now {
+                let outgoing = state.node.tick(now);
+                let _ = self.send(&mut state, outgoing);
+            }
+            drop(state);
+            self.changed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::routing_table::K;
+
+    fn start(index: u64) -> UdpNode {
+        let id = NodeId::from_key(&index.to_le_bytes());
+        UdpNode::start("127.0.0.1:0", id, DhtConfig::default(), index).unwrap()
+    }
+
+    /// Test that nodes bootstrap off a seed node and find each other over UDP
+    #[test]
+    fn bootstraps_off_seed() {
+        let seed = start(0);
+        let nodes: Vec<UdpNode> = (1..30).map(start).collect();
+        for node in nodes.iter() {
+            node.bootstrap(seed.local_addr()).unwrap();
+        }
+
+        let target = NodeId::from_key(b"target");
+        let mut expected: Vec<Contact> = nodes
+            .iter()
+            .chain([&seed])
+            .map(UdpNode::contact)
+            .filter(|contact| *contact != nodes[0].contact())
+            .collect();
+        expected.sort_by_key(|contact| contact.id.distance(&target));
+        expected.truncate(K);
+        assert_eq!(nodes[0].find_node(target).unwrap().closest(), expected);
+        assert_eq!(seed.stats().malformed, 0);
+        assert!(seed.with_node(|node| node.table().len()) >= K);
+    }
+
+    /// Test that values put by a node can be got by another over UDP
+    #[test]
+    fn puts_and_gets_values() {
+        let seed = start(100);
+        let nodes: Vec<UdpNode> = (101..110).map(start).collect();
+        for node in nodes.iter() {
+            node.bootstrap(seed.local_addr()).unwrap();
+        }
+
+        let lookup = nodes[0].put(b"lobby", b"eu-west".to_vec()).unwrap();
+        assert_eq!(lookup.closest().len(), 9);
+        let key = NodeId::from_key(b"lobby");
+        let stored = |node: &UdpNode| node.with_node(|node| node.storage().get(&key, 0).is_some());
+        // The stores are sent once the lookup finished, so they may still be on their way
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !nodes.iter().chain([&seed]).all(stored) {
+            assert!(
+                Instant::now() < deadline,
+                "the value wasn't stored everywhere"
+            );
+            thread::sleep(Duration::from_millis(5));
+        }
+        for node in nodes.iter().skip(1) {
+            assert_eq!(node.get(b"lobby").unwrap(), Some(b"eu-west".to_vec()));
+        }
+        assert_eq!(nodes[1].get(b"missing").unwrap(), None);
+
+        let error = nodes[0].put(b"big", vec![0; MAX_VALUE_SIZE + 1]);
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    /// Test that bootstrapping off an address where no node answers times out
+    #[test]
+    fn bootstrap_times_out() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let node = start(200);
+        let error = node.bootstrap(silent.local_addr().unwrap()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(node.with_node(|node| node.table().is_empty()));
+
+        // Garbage is counted, and doesn't stop the node
+        silent.send_to(b"garbage", node.local_addr()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while node.stats().malformed == 0 {
+            assert!(Instant::now() < deadline, "the garbage never arrived");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
@@ -0,0 +1,8 @@
+//! Working versions of the implementations discussed in the `issues` directory.
+
+pub mod blockchain;
+mod bytes;
+pub mod consensus;
+pub mod dht;
+pub mod merkle;
+pub mod rng;
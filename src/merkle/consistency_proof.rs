@@ -0,0 +1,202 @@
+use super::hasher::Hasher;
+use super::merkle_hash::{to_hex, MerkleHash};
+use super::merkle_tree_error::MerkleTreeError;
+use super::reader::Reader;
+
+/// A proof that the tree with `old_size` leaves is a prefix of the tree with `new_size`
+/// leaves, as defined in RFC 6962: nothing was removed or changed, only appended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsistencyProof {
+    old_size: usize,
+    new_size: usize,
+    path: Vec<MerkleHash>,
+}
+
+impl ConsistencyProof {
+    /// Creates a proof between two tree sizes from its subtree hashes, in RFC 6962 order.
+    pub fn new_from(old_size: usize, new_size: usize, path: Vec<MerkleHash>) -> Self {
+        ConsistencyProof {
+            old_size,
+            new_size,
+            path,
+        }
+    }
+
+    /// Returns the number of leaves of the older tree.
+    pub fn old_size(&self) -> usize {
+        self.old_size
+    }
+
+    /// Returns the number of leaves of the newer tree.
+    pub fn new_size(&self) -> usize {
+        self.new_size
+    }
+
+    /// Returns the subtree hashes that make up the proof.
+    pub fn path(&self) -> &[MerkleHash] {
+        &self.path
+    }
+
+    /// Verifies that the tree with root `old_root` is a prefix of the tree with root
+    /// `new_root`, following the algorithm in section 2.1.4.2 of RFC 9162. In O(log n)
+    /// time, with n = number of leaves of the newer tree.
+    ///
+    /// # Parameters
+    /// - `old_root`: The root of the tree with `old_size` leaves
+    /// - `new_root`: The root of the tree with `new_size` leaves
+    pub fn verify<H: Hasher>(&self, old_root: &MerkleHash, new_root: &MerkleHash) -> bool {
+        if self.old_size == 0 || self.old_size > self.new_size {
+            return false;
+        }
+        if self.old_size == self.new_size {
+            return self.path.is_empty() && old_root == new_root;
+        }
+
+        // When the old tree is a complete subtree, its root is the first node of the path
+        let mut path = self.path.iter();
+        let first = if self.old_size.is_power_of_two() {
+            *old_root
+        } else {
+            match path.next() {
+                Some(first) => *first,
+                None => return false,
+            }
+        };
+
+        let mut old_node = self.old_size - 1;
+        let mut new_node = self.new_size - 1;
+        while old_node & 1 == 1 {
+            old_node >>= 1;
+            new_node >>= 1;
+        }
+
+        let mut old_hash = first;
+        let mut new_hash = first;
+        for hash in path {
+            if new_node == 0 {
+                return false;
+            }
+
+            if old_node & 1 == 1 || old_node == new_node {
+                old_hash = H::combine(hash, &old_hash);
+                new_hash = H::combine(hash, &new_hash);
+                while old_node & 1 == 0 && old_node != 0 {
+                    old_node >>= 1;
+                    new_node >>= 1;
+                }
+            } else {
+                new_hash = H::combine(&new_hash, hash);
+            }
+
+            old_node >>= 1;
+            new_node >>= 1;
+        }
+
+        new_node == 0 && old_hash == *old_root && new_hash == *new_root
+    }
+
+    /// Serializes the proof so it can be sent to another node.
+    ///
+    /// The format is both sizes as big-endian `u64`s, the number of hashes as a
+    /// big-endian `u32`, then the 32-byte hashes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(20 + self.path.len() * 32);
+        bytes.extend_from_slice(&(self.old_size as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.new_size as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.path.len() as u32).to_be_bytes());
+        for hash in self.path.iter() {
+            bytes.extend_from_slice(hash);
+        }
+        bytes
+    }
+
+    /// Deserializes a proof written by `to_bytes`.
+    ///
+    /// # Returns
+    /// `MerkleTreeError::InvalidProof` if the bytes are truncated or have trailing data.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MerkleTreeError> {
+        let mut reader = Reader::new(bytes);
+
+        let old_size = reader.u64()? as usize;
+        let new_size = reader.u64()? as usize;
+        let length = reader.u32()? as usize;
+        let mut path = Vec::with_capacity(length.min(bytes.len() / 32));
+        for _ in 0..length {
+            path.push(reader.hash()?);
+        }
+
+        reader.finish()?;
+
+        Ok(ConsistencyProof {
+            old_size,
+            new_size,
+            path,
+        })
+    }
+
+    /// Prints the proof.
+    pub fn print(&self) {
+        println!(
+            "CONSISTENCY PROOF FROM {} TO {} LEAVES:",
+            self.old_size, self.new_size
+        );
+        for hash in self.path.iter() {
+            println!("- {}", to_hex(hash));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::hasher::Sha3Hasher;
+    use crate::merkle::merkle_tree::MerkleTree;
+
+    fn tree() -> MerkleTree {
+        let data: Vec<String> = (0..21).map(|i| format!("entry {}", i)).collect();
+        MerkleTree::new_from_data(&data).unwrap()
+    }
+
+    /// Test that a proof fails against the wrong roots or once tampered with
+    #[test]
+    fn tampered_proof_fails() {
+        let tree = tree();
+        let old_root = tree.root_at(13).unwrap();
+        let new_root = tree.root_at(21).unwrap();
+        let proof = tree.consistency_proof(13, 21).unwrap();
+        assert!(proof.verify::<Sha3Hasher>(&old_root, &new_root));
+
+        assert!(!proof.verify::<Sha3Hasher>(&new_root, &new_root));
+        assert!(!proof.verify::<Sha3Hasher>(&old_root, &old_root));
+
+        for index in 0..proof.path().len() {
+            let mut path = proof.path().to_vec();
+            path[index][0] ^= 1;
+            let tampered = ConsistencyProof::new_from(13, 21, path);
+            assert!(!tampered.verify::<Sha3Hasher>(&old_root, &new_root));
+        }
+
+        let mut path = proof.path().to_vec();
+        path.pop();
+        let truncated = ConsistencyProof::new_from(13, 21, path);
+        assert!(!truncated.verify::<Sha3Hasher>(&old_root, &new_root));
+
+        let wrong_sizes = ConsistencyProof::new_from(12, 21, proof.path().to_vec());
+        assert!(!wrong_sizes.verify::<Sha3Hasher>(&old_root, &new_root));
+    }
+
+    /// Test that a proof survives a round trip through its serialized form
+    #[test]
+    fn serialization_round_trip() {
+        let tree = tree();
+        let proof = tree.consistency_proof(5, 21).unwrap();
+
+        let bytes = proof.to_bytes();
+        assert_eq!(bytes.len(), 20 + proof.path().len() * 32);
+        assert_eq!(ConsistencyProof::from_bytes(&bytes).unwrap(), proof);
+
+        assert!(ConsistencyProof::from_bytes(&bytes[..10]).is_err());
+        assert!(ConsistencyProof::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(ConsistencyProof::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+    }
+}
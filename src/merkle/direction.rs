@@ -0,0 +1,8 @@
+/// Side on which a sibling hash sits when recomputing a root from a proof.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The sibling is the left child, so it goes first when combining.
+    Left,
+    /// The sibling is the right child, so it goes second when combining.
+    Right,
+}
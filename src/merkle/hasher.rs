@@ -0,0 +1,142 @@
+use sha2::Sha256;
+use sha3::{Digest, Sha3_256};
+
+use super::merkle_hash::MerkleHash;
+
+/// Prefix of the data hashed to get a leaf, as in RFC 6962.
+pub const LEAF_PREFIX: u8 = 0x00;
+
+/// Prefix of the data hashed to get an internal node, as in RFC 6962.
+pub const NODE_PREFIX: u8 = 0x01;
+
+/// Hash function used to build a Merkle Tree.
+///
+/// Implementors only need to provide `digest`. Leaves and internal nodes are hashed
+/// with different prefixes, so a leaf can never be passed off as an internal node
+/// (or the other way around) to forge a proof.
+pub trait Hasher {
+    /// Returns the 256-bit digest of `data`.
+    fn digest(data: &[u8]) -> MerkleHash;
+
+    /// Returns the leaf hash of a data item: the digest of `0x00 || data`.
+    fn hash_leaf(data: &[u8]) -> MerkleHash {
+        let mut prefixed = Vec::with_capacity(data.len() + 1);
+        prefixed.push(LEAF_PREFIX);
+        prefixed.extend_from_slice(data);
+        Self::digest(&prefixed)
+    }
+
+    /// Returns the parent of two nodes: the digest of `0x01 || left || right`.
+    fn combine(left: &MerkleHash, right: &MerkleHash) -> MerkleHash {
+        let mut combined = [0; 65];
+        combined[0] = NODE_PREFIX;
+        combined[1..33].copy_from_slice(left);
+        combined[33..].copy_from_slice(right);
+        Self::digest(&combined)
+    }
+}
+
+/// SHA-256, as used by Bitcoin and Certificate Transparency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sha256Hasher;
+
+impl Hasher for Sha256Hasher {
+    fn digest(data: &[u8]) -> MerkleHash {
+        Sha256::digest(data).into()
+    }
+}
+
+/// SHA3-256, the default hash of the tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sha3Hasher;
+
+impl Hasher for Sha3Hasher {
+    fn digest(data: &[u8]) -> MerkleHash {
+        Sha3_256::digest(data).into()
+    }
+}
+
+/// BLAKE3, the fastest of the three in software.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Blake3Hasher;
+
+impl Hasher for Blake3Hasher {
+    fn digest(data: &[u8]) -> MerkleHash {
+        blake3::hash(data).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::merkle_hash::to_hex;
+
+    /// Test SHA-256 against the FIPS 180-2 test vectors
+    #[test]
+    fn sha256_known_answers() {
+        assert_eq!(
+            to_hex(&Sha256Hasher::digest(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            to_hex(&Sha256Hasher::digest(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    /// Test SHA3-256 against the FIPS 202 test vectors
+    #[test]
+    fn sha3_known_answers() {
+        assert_eq!(
+            to_hex(&Sha3Hasher::digest(b"")),
+            "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a"
+        );
+        assert_eq!(
+            to_hex(&Sha3Hasher::digest(b"abc")),
+            "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532"
+        );
+    }
+
+    /// Test BLAKE3 against the vectors from the reference implementation
+    #[test]
+    fn blake3_known_answers() {
+        assert_eq!(
+            to_hex(&Blake3Hasher::digest(b"")),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(
+            to_hex(&Blake3Hasher::digest(b"abc")),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+
+    /// Test that combining hashes the prefixed concatenation of both children
+    #[test]
+    fn combine_hashes_prefixed_concatenation() {
+        let left = Sha256Hasher::digest(b"left");
+        let right = Sha256Hasher::digest(b"right");
+        let concatenated = [&[NODE_PREFIX][..], &left, &right].concat();
+        assert_eq!(
+            Sha256Hasher::combine(&left, &right),
+            Sha256Hasher::digest(&concatenated)
+        );
+        assert_ne!(
+            Sha256Hasher::combine(&left, &right),
+            Sha256Hasher::combine(&right, &left)
+        );
+    }
+
+    /// Test that a leaf and a node built from the same bytes hash differently
+    #[test]
+    fn leaves_and_nodes_are_domain_separated() {
+        let left = Sha3Hasher::digest(b"left");
+        let right = Sha3Hasher::digest(b"right");
+        let concatenated = [left, right].concat();
+
+        assert_ne!(
+            Sha3Hasher::hash_leaf(&concatenated),
+            Sha3Hasher::combine(&left, &right)
+        );
+        assert_ne!(Sha3Hasher::hash_leaf(b"abc"), Sha3Hasher::digest(b"abc"));
+    }
+}
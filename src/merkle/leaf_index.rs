@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use super::merkle_hash::MerkleHash;
+
+/// The indices at which each leaf hash appears in a tree.
+///
+/// Most hashes appear once, so the first index of each hash is kept on its own, and
+/// only the hashes that appear more than once get a list of their other indices.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct LeafIndex {
+    /// The first index of each hash.
+    first: HashMap<MerkleHash, usize>,
+    /// The indices after the first of the hashes that appear more than once, in
+    /// increasing order.
+    duplicates: HashMap<MerkleHash, Vec<usize>>,
+}
+
+impl LeafIndex {
+    /// Creates an index with room for `capacity` hashes.
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        LeafIndex {
+            first: HashMap::with_capacity(capacity),
+            duplicates: HashMap::new(),
+        }
+    }
+
+    /// Returns the first index of `leaf`, in O(1) time.
+    pub(crate) fn first(&self, leaf: &MerkleHash) -> Option<usize> {
+        self.first.get(leaf).copied()
+    }
+
+    /// Returns every index of `leaf`, in increasing order.
+    pub(crate) fn all(&self, leaf: &MerkleHash) -> Vec<usize> {
+        let others = self.duplicates.get(leaf).map_or(&[][..], Vec::as_slice);
+        self.first(leaf)
+            .into_iter()
+            .chain(others.iter().copied())
+            .collect()
+    }
+
+    /// Records that `leaf` is at `index`.
+    pub(crate) fn insert(&mut self, leaf: MerkleHash, index: usize) {
+        let first = *self.first.entry(leaf).or_insert(index);
+        if index == first {
+            return;
+        }
+        // The lower index becomes the first, the other one a duplicate
+        let (first, duplicate) = (first.min(index), first.max(index));
+        self.first.insert(leaf, first);
+        let duplicates = self.duplicates.entry(leaf).or_default();
+        if let Err(position) = duplicates.binary_search(&duplicate) {
+            duplicates.insert(position, duplicate);
+        }
+    }
+
+    /// Records that `leaf` is no longer at `index`.
+    pub(crate) fn remove(&mut self, leaf: &MerkleHash, index: usize) {
+        let Some(duplicates) = self.duplicates.get_mut(leaf) else {
+            if self.first(leaf) == Some(index) {
+                self.first.remove(leaf);
+            }
+            return;
+        };
+        if self.first.get(leaf) == Some(&index) {
+            self.first.insert(*leaf, duplicates.remove(0));
+        } else if let Ok(position) = duplicates.binary_search(&index) {
+            duplicates.remove(position);
+        }
+        if duplicates.is_empty() {
+            self.duplicates.remove(leaf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that duplicates are tracked apart, and the lowest index stays first
+    #[test]
+    fn tracks_duplicates() {
+        let (a, b) = ([1; 32], [2; 32]);
+        let mut index = LeafIndex::with_capacity(4);
+        index.insert(a, 5);
+        index.insert(b, 1);
+        assert!(index.duplicates.is_empty());
+
+        index.insert(a, 2);
+        index.insert(a, 9);
+        index.insert(a, 9);
+        assert_eq!(index.first(&a), Some(2));
+        assert_eq!(index.all(&a), [2, 5, 9]);
+
+        index.remove(&a, 2);
+        assert_eq!(index.all(&a), [5, 9]);
+        index.remove(&a, 9);
+        assert_eq!(index.all(&a), [5]);
+        assert!(index.duplicates.is_empty());
+        index.remove(&a, 7);
+        index.remove(&a, 5);
+        assert_eq!(index.first(&a), None);
+        assert!(index.all(&a).is_empty());
+        assert_eq!(index.all(&b), [1]);
+    }
+}
@@ -0,0 +1,22 @@
+/// A node of the Merkle Tree: a fixed-size 256-bit digest.
+pub type MerkleHash = [u8; 32];
+
+/// Converts a hash to a lowercase hexadecimal string.
+pub fn to_hex(hash: &MerkleHash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parses a 64 character hexadecimal string into a hash.
+///
+/// # Returns
+/// `None` if the string has the wrong length or contains non-hexadecimal characters.
+pub fn from_hex(hex: &str) -> Option<MerkleHash> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(hash)
+}
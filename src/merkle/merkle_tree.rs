@@ -0,0 +1,1387 @@
+use std::marker::PhantomData;
+use std::sync::OnceLock;
+
+use crossbeam::queue::SegQueue;
+use crossbeam::thread;
+
+use super::consistency_proof::ConsistencyProof;
+use super::direction::Direction;
+use super::hasher::{Hasher, Sha3Hasher};
+use super::leaf_index::LeafIndex;
+use super::merkle_hash::{to_hex, MerkleHash};
+use super::merkle_tree_error::MerkleTreeError;
+use super::multi_proof::MultiProof;
+use super::proof_of_inclusion::ProofOfInclusion;
+use super::storage::{MemoryStorage, Storage};
+
+/// Leaf that takes the place of a removed hash, so the other leaves keep their index.
+pub const TOMBSTONE: MerkleHash = [0; 32];
+
+/// Number of parents each task computes when a level is built in parallel.
+const PARALLEL_CHUNK: usize = 4096;
+
+/// A Merkle Tree implementation
+///
+/// Nodes are hashed with the `H` hash function, SHA3-256 by default, and kept in the `S`
+/// storage, in memory by default. A tree kept in a `FileStorage` is reopened with `open`.
+///
+/// Looking a leaf up by its hash goes through an index of every leaf, which takes about
+/// 50 bytes of memory per leaf. It is only built on the first lookup by hash, reading
+/// every leaf, so a large tree that serves proofs by index never pays for it.
+///
+/// # Methods
+/// - `new_from_hashes`: Creates a new MerkleTree from a list of hashes.
+/// - `new_from_data`: Creates a new MerkleTree from a list of data items, hashing each one.
+/// - `new_from_hashes_parallel`: Creates a new MerkleTree from a list of hashes, on several threads.
+/// - `new_in`: Creates a new MerkleTree from a list of hashes, in the given storage.
+/// - `open`: Opens a MerkleTree that was built in the given storage.
+/// - `root`: Returns the root of the Merkle Tree, which is the Merkle Root.
+/// - `verify`: Verifies that a given hash is contained in the Merkle Tree.
+/// - `index_of`: Returns the index of a given hash in the bottom level of the tree.
+/// - `proof_of_inclusion`: Returns a proof of inclusion for a given hash in the Merkle Tree.
+/// - `multi_proof`: Returns a single proof of inclusion for several hashes in the Merkle Tree.
+/// - `consistency_proof`: Returns a proof that an older version of the tree is a prefix of a newer one.
+/// - `add_hash`: Adds a hash to the Merkle Tree, updating the tree structure.
+/// - `update_hash`: Replaces the leaf at an index, updating the tree structure.
+/// - `remove_hash`: Removes a hash from the Merkle Tree, updating the tree structure.
+#[derive(Debug, Clone)]
+pub struct MerkleTree<H: Hasher = Sha3Hasher, S: Storage = MemoryStorage> {
+    storage: S,
+    /// Every index at which each leaf hash appears, once a lookup by hash needed it.
+    /// Tombstones are not indexed.
+    indices: OnceLock<LeafIndex>,
+    hasher: PhantomData<H>,
+}
+
+impl<H: Hasher> MerkleTree<H> {
+    /// Creates a new MerkleTree from a list of hashes.
+    ///
+    /// The same hash may appear more than once, `indices_of` returns every index it is at.
+    ///
+    /// # Parameters
+    /// - `hashes`: The leaves of the tree, in order
+    ///
+    /// # Returns
+    /// The tree, or `MerkleTreeError::EmptyTree` if `hashes` is empty.
+    pub fn new_from_hashes(hashes: Vec<MerkleHash>) -> Result<Self, MerkleTreeError> {
+        MerkleTree::new_in(MemoryStorage::new(), hashes)
+    }
+
+    /// Creates a new MerkleTree from a list of hashes, computing each level on `threads`
+    /// threads. The tree is the same as the one `new_from_hashes` builds.
+    ///
+    /// # Parameters
+    /// - `hashes`: The leaves of the tree, in order
+    /// - `threads`: Number of threads to hash with, usually `std::thread::available_parallelism`
+    ///
+    /// # Returns
+    /// The tree, or `MerkleTreeError::EmptyTree` if `hashes` is empty.
+    pub fn new_from_hashes_parallel(
+        hashes: Vec<MerkleHash>,
+        threads: usize,
+    ) -> Result<Self, MerkleTreeError> {
+        MerkleTree::new_in_parallel(MemoryStorage::new(), hashes, threads)
+    }
+
+    /// Creates a new MerkleTree whose leaves are the hashes of the given data items.
+    ///
+    /// # Parameters
+    /// - `data`: Objects that can be converted to byte slices
+    pub fn new_from_data<T: AsRef<[u8]>>(data: &[T]) -> Result<Self, MerkleTreeError> {
+        MerkleTree::new_from_hashes(data.iter().map(MerkleTree::<H>::get_hash_of).collect())
+    }
+}
+
+impl<H: Hasher, S: Storage> MerkleTree<H, S> {
+    /// Creates a new MerkleTree from a list of hashes, replacing whatever `storage` held.
+    ///
+    /// # Parameters
+    /// - `storage`: Where the nodes are kept
+    /// - `hashes`: The leaves of the tree, in order
+    ///
+    /// # Returns
+    /// The tree, `MerkleTreeError::EmptyTree` if `hashes` is empty, or
+    /// `MerkleTreeError::Storage` if the nodes can't be written.
+    pub fn new_in(storage: S, hashes: Vec<MerkleHash>) -> Result<Self, MerkleTreeError> {
+        MerkleTree::new_in_parallel(storage, hashes, 1)
+    }
+
+    /// Creates a new MerkleTree from a list of hashes, replacing whatever `storage` held,
+    /// and computing each level on `threads` threads.
+    ///
+    /// # Parameters
+    /// - `storage`: Where the nodes are kept
+    /// - `hashes`: The leaves of the tree, in order
+    /// - `threads`: Number of threads to hash with
+    ///
+    /// # Returns
+    /// The tree, `MerkleTreeError::EmptyTree` if `hashes` is empty, or
+    /// `MerkleTreeError::Storage` if the nodes can't be written.
+    pub fn new_in_parallel(
+        storage: S,
+        hashes: Vec<MerkleHash>,
+        threads: usize,
+    ) -> Result<Self, MerkleTreeError> {
+        let mut tree = MerkleTree {
+            storage,
+            indices: OnceLock::new(),
+            hasher: PhantomData,
+        };
+        tree.rebuild(hashes, threads)?;
+        Ok(tree)
+    }
+
+    /// Opens a MerkleTree that was built in `storage`, with the same hash function.
+    ///
+    /// Only the sizes of the levels are checked, so opening takes O(log n) time whatever
+    /// the size of the tree. The leaves are read and indexed on the first lookup by hash.
+    ///
+    /// # Returns
+    /// `MerkleTreeError::EmptyTree` if the storage is empty, or
+    /// `MerkleTreeError::Storage` if its levels don't have the sizes of a Merkle Tree.
+    pub fn open(storage: S) -> Result<Self, MerkleTreeError> {
+        let level_count = storage.level_count();
+        if level_count == 0 {
+            return Err(MerkleTreeError::EmptyTree);
+        }
+        for level in 1..level_count {
+            let expected = storage.level(level - 1).len().div_ceil(2);
+            if storage.level(level).len() != expected || storage.level(level - 1).len() == 1 {
+                return Err(MerkleTreeError::Storage(format!(
+                    "Level {} has {} nodes instead of {}",
+                    level,
+                    storage.level(level).len(),
+                    expected
+                )));
+            }
+        }
+        if storage.level(level_count - 1).len() != 1 {
+            return Err(MerkleTreeError::Storage(
+                "The top level has more than one node".to_string(),
+            ));
+        }
+
+        Ok(MerkleTree {
+            storage,
+            indices: OnceLock::new(),
+            hasher: PhantomData,
+        })
+    }
+
+    /// Returns the storage the nodes are kept in.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Makes every change to the tree durable, for storage that is persistent.
+    pub fn flush(&mut self) -> Result<(), MerkleTreeError> {
+        self.storage.flush()
+    }
+
+    /// Replaces every node with a tree built from `hashes`.
+    ///
+    /// When a level has an odd number of nodes, the last one is promoted to the next level
+    /// as it is. Combining it with a copy of itself would give `[a, b, c]` and
+    /// `[a, b, c, c]` the same root (CVE-2012-2459). Promoting gives the same root as the
+    /// RFC 6962 definition, which splits `n` leaves at the largest power of two below `n`.
+    ///
+    /// Each level is computed on `threads` threads, but written to the storage on the
+    /// calling one. The index of the leaves is dropped, to be built again when needed.
+    fn rebuild(&mut self, hashes: Vec<MerkleHash>, threads: usize) -> Result<(), MerkleTreeError> {
+        if hashes.is_empty() {
+            return Err(MerkleTreeError::EmptyTree);
+        }
+
+        self.storage.clear()?;
+        self.indices = OnceLock::new();
+
+        let mut level = 0;
+        let mut hashes = hashes;
+        loop {
+            for (index, hash) in hashes.iter().enumerate() {
+                self.storage.set(level, index, *hash)?;
+            }
+            if hashes.len() == 1 {
+                break;
+            }
+
+            hashes = parent_level::<H>(&hashes, threads);
+            level += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns the root of the Merkle Tree, which is the Merkle Root.
+    ///
+    /// Every constructor refuses to build a tree without leaves, and `compact` refuses to
+    /// remove the last one, so there is always a root.
+    pub fn root(&self) -> &MerkleHash {
+        &self.storage.level(self.storage.level_count() - 1)[0]
+    }
+
+    /// Returns the leaves of the tree, in order.
+    pub fn leaves(&self) -> &[MerkleHash] {
+        self.storage.level(0)
+    }
+
+    /// Iterates over the levels of the tree, from the leaves up to the root.
+    fn levels(&self) -> impl Iterator<Item = &[MerkleHash]> {
+        (0..self.storage.level_count()).map(|level| self.storage.level(level))
+    }
+
+    /// Verifies that a given hash is contained in the Merkle Tree, in O(log n) time, with n = number of leaf hashes.
+    ///
+    /// # Parameters
+    /// - `leaf`: The hash to verify
+    /// - `index`: The index of the hash in the bottom level of the tree
+    pub fn verify_with_index(&self, leaf: &MerkleHash, index: u32) -> bool {
+        match self.proof_of_inclusion_with_index(leaf, index) {
+            Ok(proof) => proof.verify::<H>(self.root()),
+            Err(_) => false,
+        }
+    }
+
+    /// Verifies that a given hash is contained in the Merkle Tree, in O(log n) time, with n = number of leaf hashes.
+    ///
+    /// # Parameters
+    /// - `leaf`: The hash to verify
+    pub fn verify(&self, leaf: &MerkleHash) -> bool {
+        let hash_index = match self.index_of(leaf) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        self.verify_with_index(leaf, hash_index as u32)
+    }
+
+    /// Returns the first index of a given hash in the bottom level of the tree, in O(1) time.
+    ///
+    /// # Parameters
+    /// - `leaf`: The hash to look for
+    pub fn index_of(&self, leaf: &MerkleHash) -> Option<usize> {
+        self.indices().first(leaf)
+    }
+
+    /// Returns every index of a given hash in the bottom level of the tree, in increasing
+    /// order. Empty if the hash is not part of the tree.
+    ///
+    /// # Parameters
+    /// - `leaf`: The hash to look for
+    pub fn indices_of(&self, leaf: &MerkleHash) -> Vec<usize> {
+        self.indices().all(leaf)
+    }
+
+    /// Returns the leaf hash of the given data, which is prefixed with `0x00` so it can't
+    /// collide with an internal node.
+    ///
+    /// # Parameters
+    /// - `data`: An object that can be converted to a byte slice
+    pub fn get_hash_of(data: &impl AsRef<[u8]>) -> MerkleHash {
+        H::hash_leaf(data.as_ref())
+    }
+
+    /// Returns a proof of inclusion for a given hash in the Merkle Tree. The proof generated contains the hashes of the siblings of the nodes in the path from the leaf to the root, and their directions. In O(log n) time, with n = number of leaf hashes.
+    ///
+    /// # Parameters
+    /// - `leaf`: The hash to generate the proof for
+    /// - `index`: The index of the hash in the bottom level of the tree
+    ///
+    /// # Returns
+    /// The proof of inclusion, `MerkleTreeError::IndexOutOfRange` if there is no leaf at
+    /// `index`, or `MerkleTreeError::LeafNotFound` if the leaf at `index` is another hash.
+    pub fn proof_of_inclusion_with_index(
+        &self,
+        leaf: &MerkleHash,
+        mut index: u32,
+    ) -> Result<ProofOfInclusion, MerkleTreeError> {
+        let len = self.leaves().len();
+        match self.leaves().get(index as usize) {
+            None => {
+                return Err(MerkleTreeError::IndexOutOfRange {
+                    index: index as usize,
+                    len,
+                })
+            }
+            Some(found) if found != leaf || *leaf == TOMBSTONE => {
+                return Err(MerkleTreeError::LeafNotFound(*leaf))
+            }
+            Some(_) => {}
+        }
+
+        let mut proof = vec![];
+
+        for level in self.levels() {
+            if level.len() == 1 {
+                break;
+            }
+
+            if index.is_multiple_of(2) {
+                // A last node without a sibling is promoted, so there is nothing to combine
+                if index + 1 < level.len() as u32 {
+                    proof.push((level[(index + 1) as usize], Direction::Right));
+                }
+            } else {
+                proof.push((level[(index - 1) as usize], Direction::Left));
+            }
+
+            index /= 2;
+        }
+
+        Ok(ProofOfInclusion::new_from(*leaf, proof))
+    }
+
+    /// Returns a proof of inclusion for a given hash in the Merkle Tree. The proof generated contains the hashes of the siblings of the nodes in the path from the leaf to the root, and their directions. In O(log n) time, with n = number of leaf hashes.
+    ///
+    /// If the hash appears more than once, the proof is for its first index.
+    ///
+    /// # Parameters
+    /// - `leaf`: The hash to generate the proof for
+    ///
+    /// # Returns
+    /// The proof of inclusion, or `MerkleTreeError::LeafNotFound` if the hash is not part
+    /// of the tree.
+    pub fn proof_of_inclusion(
+        &self,
+        leaf: &MerkleHash,
+    ) -> Result<ProofOfInclusion, MerkleTreeError> {
+        let hash_index = self
+            .index_of(leaf)
+            .ok_or(MerkleTreeError::LeafNotFound(*leaf))?;
+
+        self.proof_of_inclusion_with_index(leaf, hash_index as u32)
+    }
+
+    /// Adds a hash to the Merkle Tree, updating the tree structure. Use `append_hash` to
+    /// add a hash that may already be part of the tree.
+    ///
+    /// # Parameters
+    /// - `hash`: The hash to add to the tree
+    ///
+    /// # Returns
+    /// The index of the new leaf, `MerkleTreeError::HashAlreadyExists` with the index of
+    /// the existing leaf if the hash is already part of the tree, or
+    /// `MerkleTreeError::InvalidHash` if it is `TOMBSTONE`.
+    pub fn add_hash(&mut self, hash: MerkleHash) -> Result<usize, MerkleTreeError> {
+        if hash == TOMBSTONE {
+            return Err(MerkleTreeError::InvalidHash(hash));
+        }
+        if let Some(index) = self.index_of(&hash) {
+            return Err(MerkleTreeError::HashAlreadyExists(index));
+        }
+
+        self.append_hash(hash)
+    }
+
+    /// Appends a hash as the last leaf of the tree, in O(log n) time, with n = number of leaf hashes.
+    ///
+    /// Only the nodes on the path from the new leaf to the root change, so every other
+    /// node is kept as it is. The hash is appended even if it is already part of the tree.
+    ///
+    /// # Parameters
+    /// - `hash`: The hash to append
+    ///
+    /// # Returns
+    /// The index of the new leaf, or `MerkleTreeError::Storage` if it can't be written.
+    pub fn append_hash(&mut self, hash: MerkleHash) -> Result<usize, MerkleTreeError> {
+        let index = self.leaves().len();
+        self.storage.set(0, index, hash)?;
+        self.index_leaf(index);
+        self.update_path(index)?;
+        Ok(index)
+    }
+
+    /// Replaces the leaf at `index` with another hash, in O(log n) time, with n = number of leaf hashes.
+    ///
+    /// # Parameters
+    /// - `index`: The index of the leaf in the bottom level of the tree
+    /// - `hash`: The new hash of the leaf
+    ///
+    /// # Returns
+    /// `MerkleTreeError::IndexOutOfRange` if there is no leaf at `index`, or
+    /// `MerkleTreeError::Storage` if it can't be written.
+    pub fn update_hash(&mut self, index: usize, hash: MerkleHash) -> Result<(), MerkleTreeError> {
+        if index >= self.leaves().len() {
+            return Err(MerkleTreeError::IndexOutOfRange {
+                index,
+                len: self.leaves().len(),
+            });
+        }
+
+        self.unindex_leaf(index);
+        self.storage.set(0, index, hash)?;
+        self.index_leaf(index);
+        self.update_path(index)
+    }
+
+    /// Removes a hash from the Merkle Tree, updating the tree structure.
+    ///
+    /// The leaf is replaced with `TOMBSTONE` rather than taken out, so every other leaf
+    /// keeps its index and only the path to the root is recomputed. Use `compact` to
+    /// drop the tombstones.
+    ///
+    /// If the hash appears more than once, only its first index is removed. Use
+    /// `indices_of` and `update_hash` with `TOMBSTONE` to remove another one.
+    ///
+    /// # Parameters
+    /// - `hash`: The hash to remove from the tree
+    ///
+    /// # Returns
+    /// `MerkleTreeError::LeafNotFound` if the hash is not part of the tree.
+    pub fn remove_hash(&mut self, hash: &MerkleHash) -> Result<(), MerkleTreeError> {
+        let index = self
+            .index_of(hash)
+            .ok_or(MerkleTreeError::LeafNotFound(*hash))?;
+
+        self.update_hash(index, TOMBSTONE)
+    }
+
+    /// Rebuilds the tree without the leaves that were removed. This changes the index of
+    /// every leaf after the first tombstone, so proofs must be requested again.
+    ///
+    /// # Returns
+    /// `MerkleTreeError::EmptyTree` if every leaf was removed, in which case the tree
+    /// is left untouched.
+    pub fn compact(&mut self) -> Result<(), MerkleTreeError> {
+        let leaves = self
+            .leaves()
+            .iter()
+            .filter(|leaf| **leaf != TOMBSTONE)
+            .copied()
+            .collect();
+        self.rebuild(leaves, 1)
+    }
+
+    /// Returns the index of the leaves by hash, building it on the first call, in O(n)
+    /// time.
+    fn indices(&self) -> &LeafIndex {
+        self.indices.get_or_init(|| {
+            let leaves = self.leaves();
+            let mut indices = LeafIndex::with_capacity(leaves.len());
+            for (index, leaf) in leaves.iter().enumerate() {
+                if *leaf != TOMBSTONE {
+                    indices.insert(*leaf, index);
+                }
+            }
+            indices
+        })
+    }
+
+    /// Adds the leaf at `index` to the index of its hash, if the index is built.
+    fn index_leaf(&mut self, index: usize) {
+        let leaf = self.leaves()[index];
+        if leaf == TOMBSTONE {
+            return;
+        }
+        if let Some(indices) = self.indices.get_mut() {
+            indices.insert(leaf, index);
+        }
+    }
+
+    /// Removes the leaf at `index` from the index of its hash, if the index is built.
+    fn unindex_leaf(&mut self, index: usize) {
+        let leaf = self.leaves()[index];
+        if let Some(indices) = self.indices.get_mut() {
+            indices.remove(&leaf, index);
+        }
+    }
+
+    /// Recomputes the ancestors of the leaf at `index`, adding the nodes and levels an
+    /// append creates.
+    fn update_path(&mut self, mut index: usize) -> Result<(), MerkleTreeError> {
+        let mut level = 0;
+
+        while self.storage.level(level).len() > 1 {
+            let nodes = self.storage.level(level);
+            let parent_index = index / 2;
+            let left = nodes[parent_index * 2];
+            let parent = match nodes.get(parent_index * 2 + 1) {
+                Some(right) => H::combine(&left, right),
+                None => left,
+            };
+
+            self.storage.set(level + 1, parent_index, parent)?;
+
+            index = parent_index;
+            level += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns a single proof of inclusion for several leaves, in which the sibling hashes
+    /// shared by their paths appear only once, and the hashes that can be computed from
+    /// the proven leaves not at all.
+    ///
+    /// # Parameters
+    /// - `indices`: The indices of the leaves to prove, in any order
+    ///
+    /// # Returns
+    /// `MerkleTreeError::NoLeavesToProve` if `indices` is empty,
+    /// `MerkleTreeError::IndexOutOfRange` if an index has no leaf, or
+    /// `MerkleTreeError::LeafNotFound` if one of the leaves was removed.
+    pub fn multi_proof(&self, indices: &[usize]) -> Result<MultiProof, MerkleTreeError> {
+        let mut positions = indices.to_vec();
+        positions.sort_unstable();
+        positions.dedup();
+
+        match positions.last() {
+            None => return Err(MerkleTreeError::NoLeavesToProve),
+            Some(&last) if last >= self.leaves().len() => {
+                return Err(MerkleTreeError::IndexOutOfRange {
+                    index: last,
+                    len: self.leaves().len(),
+                })
+            }
+            Some(_) => {}
+        }
+
+        let leaves: Vec<(usize, MerkleHash)> = positions
+            .iter()
+            .map(|&index| (index, self.leaves()[index]))
+            .collect();
+        if leaves.iter().any(|(_, leaf)| *leaf == TOMBSTONE) {
+            return Err(MerkleTreeError::LeafNotFound(TOMBSTONE));
+        }
+
+        let mut hashes = vec![];
+        for level in self.levels() {
+            if level.len() == 1 {
+                break;
+            }
+
+            let mut parents = Vec::with_capacity(positions.len());
+            let mut i = 0;
+            while i < positions.len() {
+                let position = positions[i];
+                if !position.is_multiple_of(2) {
+                    hashes.push(level[position - 1]);
+                } else if position + 1 < level.len() {
+                    // The right sibling is only needed if it isn't proven as well
+                    if positions.get(i + 1) == Some(&(position + 1)) {
+                        i += 1;
+                    } else {
+                        hashes.push(level[position + 1]);
+                    }
+                }
+                parents.push(position / 2);
+                i += 1;
+            }
+            positions = parents;
+        }
+
+        Ok(MultiProof::new_from(self.leaves().len(), leaves, hashes))
+    }
+
+    /// Returns the root the tree had when it had `size` leaves, in O(log n) time, with n = number of leaf hashes.
+    ///
+    /// Older roots are recomputed from the current nodes, which only gives the historical
+    /// root while the tree is used append-only: updates and removals rewrite the past.
+    ///
+    /// # Returns
+    /// `MerkleTreeError::SizeOutOfRange` if `size` is 0 or larger than the tree.
+    pub fn root_at(&self, size: usize) -> Result<MerkleHash, MerkleTreeError> {
+        self.check_size(size)?;
+        Ok(self.range_hash(0, size))
+    }
+
+    /// Returns an RFC 6962 consistency proof that the tree with `old_size` leaves is a
+    /// prefix of the tree with `new_size` leaves. In O(log n) time, with n = number of leaf hashes.
+    ///
+    /// # Parameters
+    /// - `old_size`: The number of leaves of the older tree
+    /// - `new_size`: The number of leaves of the newer tree, at most the current number
+    ///
+    /// # Returns
+    /// `MerkleTreeError::SizeOutOfRange` if a size is 0, larger than the tree, or
+    /// `old_size` is larger than `new_size`.
+    pub fn consistency_proof(
+        &self,
+        old_size: usize,
+        new_size: usize,
+    ) -> Result<ConsistencyProof, MerkleTreeError> {
+        self.check_size(new_size)?;
+        if old_size == 0 || old_size > new_size {
+            return Err(MerkleTreeError::SizeOutOfRange {
+                size: old_size,
+                len: new_size,
+            });
+        }
+
+        let mut path = vec![];
+        self.subproof(old_size, 0, new_size, true, &mut path);
+        Ok(ConsistencyProof::new_from(old_size, new_size, path))
+    }
+
+    /// Checks that a tree with `size` leaves is part of this tree's history.
+    fn check_size(&self, size: usize) -> Result<(), MerkleTreeError> {
+        if size == 0 || size > self.leaves().len() {
+            return Err(MerkleTreeError::SizeOutOfRange {
+                size,
+                len: self.leaves().len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the root of the subtree over the leaves `start..end`, where `start` is a
+    /// multiple of the largest power of two below `end - start`, as in RFC 6962.
+    fn range_hash(&self, start: usize, end: usize) -> MerkleHash {
+        let width = end - start;
+        if width.is_power_of_two() {
+            let level = width.trailing_zeros() as usize;
+            return self.storage.level(level)[start >> level];
+        }
+
+        let split = start + width.next_power_of_two() / 2;
+        H::combine(&self.range_hash(start, split), &self.range_hash(split, end))
+    }
+
+    /// The `SUBPROOF` function of RFC 6962, over the leaves `start..end`.
+    ///
+    /// `complete` is true while the old tree's root is a node of the subtree being
+    /// visited, in which case the verifier already knows it.
+    fn subproof(
+        &self,
+        old_size: usize,
+        start: usize,
+        end: usize,
+        complete: bool,
+        path: &mut Vec<MerkleHash>,
+    ) {
+        let width = end - start;
+        if old_size == width {
+            if !complete {
+                path.push(self.range_hash(start, end));
+            }
+            return;
+        }
+
+        let split = width.next_power_of_two() / 2;
+        if old_size <= split {
+            self.subproof(old_size, start, start + split, complete, path);
+            path.push(self.range_hash(start + split, end));
+        } else {
+            self.subproof(old_size - split, start + split, end, false, path);
+            path.push(self.range_hash(start, start + split));
+        }
+    }
+
+    /// Prints the Merkle Tree structure.
+    pub fn print(&self) {
+        let level_count = self.storage.level_count();
+        for i in (0..level_count).rev() {
+            println!("LEVEL {}:", level_count - i - 1);
+            for hash in self.storage.level(i).iter() {
+                println!("- {}", to_hex(hash));
+            }
+        }
+    }
+}
+
+/// Returns the level above `nodes`, computed on `threads` threads.
+///
+/// The level is cut into tasks of `PARALLEL_CHUNK` parents that idle threads take from a
+/// shared queue, so a thread that falls behind doesn't hold the others back. Each task
+/// writes its own slice of the result, which is the same whatever the number of threads.
+fn parent_level<H: Hasher>(nodes: &[MerkleHash], threads: usize) -> Vec<MerkleHash> {
+    let parent = |pair: &[MerkleHash]| match pair {
+        [left, right] => H::combine(left, right),
+        [last] => *last,
+        _ => unreachable!("chunks(2) yields one or two hashes"),
+    };
+
+    if threads <= 1 || nodes.len() <= 2 * PARALLEL_CHUNK {
+        return nodes.chunks(2).map(parent).collect();
+    }
+
+    let mut parents = vec![[0; 32]; nodes.len().div_ceil(2)];
+    let tasks = SegQueue::new();
+    for task in parents
+        .chunks_mut(PARALLEL_CHUNK)
+        .zip(nodes.chunks(2 * PARALLEL_CHUNK))
+    {
+        tasks.push(task);
+    }
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|_| {
+                while let Some((output, input)) = tasks.pop() {
+                    for (slot, pair) in output.iter_mut().zip(input.chunks(2)) {
+                        *slot = parent(pair);
+                    }
+                }
+            });
+        }
+    })
+    .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+    drop(tasks);
+
+    parents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::hasher::{Blake3Hasher, Sha256Hasher};
+    use crate::merkle::storage::FileStorage;
+
+    fn something(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("something0{}", i)).collect()
+    }
+
+    /// Test that every level halves the number of nodes, rounding up
+    #[test]
+    fn build_simple_tree() {
+        let data = vec![[1; 32], [2; 32], [3; 32], [4; 32]];
+
+        let tree = MerkleTree::<Sha3Hasher>::new_from_hashes(data).unwrap();
+
+        assert_eq!(tree.storage.level_count(), 3);
+        assert_eq!(tree.storage.level(0).len(), 4);
+        assert_eq!(tree.storage.level(1).len(), 2);
+        assert_eq!(tree.storage.level(2).len(), 1);
+    }
+
+    /// Test that building a tree with no leaves fails instead of panicking
+    #[test]
+    fn build_empty_tree_fails() {
+        let result = MerkleTree::<Sha3Hasher>::new_from_hashes(vec![]);
+        assert!(matches!(result, Err(MerkleTreeError::EmptyTree)));
+    }
+
+    /// Test the roots of small RFC 6962 trees against values computed independently with Python's hashlib
+    #[test]
+    fn root_known_answers() {
+        let four = ["a", "b", "c", "d"];
+        let three = ["a", "b", "c"];
+        let five = ["a", "b", "c", "d", "e"];
+
+        let sha256_four = MerkleTree::<Sha256Hasher>::new_from_data(&four).unwrap();
+        let sha256_three = MerkleTree::<Sha256Hasher>::new_from_data(&three).unwrap();
+        assert_eq!(
+            to_hex(sha256_four.root()),
+            "33376a3bd63e9993708a84ddfe6c28ae58b83505dd1fed711bd924ec5a6239f0"
+        );
+        assert_eq!(
+            to_hex(sha256_three.root()),
+            "36642e73c2540ab121e3a6bf9545b0a24982cd830eb13d3cd19de3ce6c021ec1"
+        );
+
+        let sha3_four = MerkleTree::<Sha3Hasher>::new_from_data(&four).unwrap();
+        let sha3_three = MerkleTree::<Sha3Hasher>::new_from_data(&three).unwrap();
+        assert_eq!(
+            to_hex(sha3_four.root()),
+            "8129e2860f2dff051735954d6be24aa6cb62a060f36497d07b811b8da6d99abb"
+        );
+        assert_eq!(
+            to_hex(sha3_three.root()),
+            "3eaea59d209d4f38ef1fec603f66e86df85d5d8af007985389422debfeaf2e30"
+        );
+
+        let sha256_five = MerkleTree::<Sha256Hasher>::new_from_data(&five).unwrap();
+        let sha3_five = MerkleTree::<Sha3Hasher>::new_from_data(&five).unwrap();
+        assert_eq!(
+            to_hex(sha256_five.root()),
+            "fe14a5426fbd70c0fa73f52342afed0da0bd23c4838662ccf6b88a3070ead97b"
+        );
+        assert_eq!(
+            to_hex(sha3_five.root()),
+            "04b3459e5304b52b7f4c3b194457faec34d9fc0168dcf838f3cd0f4a9a65321f"
+        );
+    }
+
+    /// Test the roots of the first eight trees of the Certificate Transparency test vectors
+    #[test]
+    fn certificate_transparency_roots() {
+        let inputs: Vec<&[u8]> = vec![
+            b"",
+            b"\x00",
+            b"\x10",
+            b"\x20\x21",
+            b"\x30\x31",
+            b"\x40\x41\x42\x43",
+            b"\x50\x51\x52\x53\x54\x55\x56\x57",
+            b"\x60\x61\x62\x63\x64\x65\x66\x67\x68\x69\x6a\x6b\x6c\x6d\x6e\x6f",
+        ];
+        let roots = [
+            "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+            "fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125",
+            "aeb6bcfe274b70a14fb067a5e5578264db0fa9b51af5e0ba159158f329e06e77",
+            "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+            "4e3bbb1f7b478dcfe71fb631631519a3bca12c9aefca1612bfce4c13a86264d4",
+            "76e67dadbcdf1e10e1b74ddc608abd2f98dfb16fbce75277b5232a127f2087ef",
+            "ddb89be403809e325750d3d263cd78929c2942b7942a34b77e122c9594a74c8c",
+            "5dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328",
+        ];
+
+        for (size, root) in roots.iter().enumerate() {
+            let tree = MerkleTree::<Sha256Hasher>::new_from_data(&inputs[..=size]).unwrap();
+            assert_eq!(to_hex(tree.root()), *root, "tree of {} leaves", size + 1);
+        }
+    }
+
+    /// Test that two adjacent leaves can't be passed off as a single leaf.
+    ///
+    /// Without domain separation, the data `leaf0 || leaf1` hashes to the parent of the
+    /// first two leaves, so a one-level-shorter proof for it recomputes the root.
+    #[test]
+    fn concatenated_leaves_do_not_verify_as_a_leaf() {
+        let tree = MerkleTree::<Sha256Hasher>::new_from_data(&["a", "b", "c", "d"]).unwrap();
+        let forged = [tree.storage.level(0)[0], tree.storage.level(0)[1]].concat();
+        let sibling = tree.storage.level(1)[1];
+
+        // The forgery against a tree that hashes leaves and nodes the same way
+        let unprefixed = |left: &MerkleHash, right: &MerkleHash| {
+            Sha256Hasher::digest(&[left.as_slice(), right.as_slice()].concat())
+        };
+        let unprefixed_parent = unprefixed(&tree.storage.level(0)[0], &tree.storage.level(0)[1]);
+        assert_eq!(Sha256Hasher::digest(&forged), unprefixed_parent);
+
+        // The same forgery against the prefixed tree
+        let forged_leaf = MerkleTree::<Sha256Hasher>::get_hash_of(&forged);
+        assert_ne!(forged_leaf, tree.storage.level(1)[0]);
+        assert_ne!(&Sha256Hasher::combine(&forged_leaf, &sibling), tree.root());
+        assert!(!tree.verify(&forged_leaf));
+    }
+
+    /// Test that duplicating the last leaf changes the root (CVE-2012-2459)
+    #[test]
+    fn duplicated_last_leaf_changes_root() {
+        let three: MerkleTree = MerkleTree::new_from_data(&["a", "b", "c"]).unwrap();
+        let four: MerkleTree = MerkleTree::new_from_data(&["a", "b", "c", "c"]).unwrap();
+        assert_ne!(three.root(), four.root());
+
+        let five: MerkleTree = MerkleTree::new_from_data(&something(5)).unwrap();
+        let mut six_data = something(5);
+        six_data.push(six_data[4].clone());
+        let six: MerkleTree = MerkleTree::new_from_data(&six_data).unwrap();
+        assert_ne!(five.root(), six.root());
+    }
+
+    /// Test that a promoted node adds no sibling to the proof
+    #[test]
+    fn promoted_node_has_shorter_proof() {
+        let tree: MerkleTree = MerkleTree::new_from_data(&something(5)).unwrap();
+
+        let last = tree.proof_of_inclusion(&tree.leaves()[4]).unwrap();
+        assert_eq!(last.path().len(), 1);
+        assert_eq!(last.path()[0], (tree.storage.level(2)[0], Direction::Left));
+
+        let first = tree.proof_of_inclusion(&tree.leaves()[0]).unwrap();
+        assert_eq!(first.path().len(), 3);
+    }
+
+    /// Test that the root depends on the hash function
+    #[test]
+    fn hashers_give_different_roots() {
+        let data = something(5);
+        let sha256 = MerkleTree::<Sha256Hasher>::new_from_data(&data).unwrap();
+        let sha3 = MerkleTree::<Sha3Hasher>::new_from_data(&data).unwrap();
+        let blake3 = MerkleTree::<Blake3Hasher>::new_from_data(&data).unwrap();
+
+        assert_ne!(sha256.root(), sha3.root());
+        assert_ne!(sha3.root(), blake3.root());
+        assert_ne!(sha256.root(), blake3.root());
+    }
+
+    /// Test that the last leaf of an odd level is verified
+    #[test]
+    fn verify_inclusion_in_simple_tree_from_strings() {
+        let tree: MerkleTree = MerkleTree::new_from_data(&something(5)).unwrap();
+
+        let hash = MerkleTree::<Sha3Hasher>::get_hash_of(&"something04");
+
+        assert!(tree.verify_with_index(&hash, 4));
+    }
+
+    /// Test that every leaf of a bigger tree is verified
+    #[test]
+    fn verify_inclusion_in_big_tree_from_strings() {
+        let data = something(32);
+        let tree = MerkleTree::<Blake3Hasher>::new_from_data(&data).unwrap();
+
+        for (index, item) in data.iter().enumerate() {
+            let hash = MerkleTree::<Blake3Hasher>::get_hash_of(item);
+            assert!(tree.verify_with_index(&hash, index as u32));
+        }
+        assert!(!tree.verify(&MerkleTree::<Blake3Hasher>::get_hash_of(&"not in the tree")));
+    }
+
+    /// Test that a proof has one sibling per level below the root
+    #[test]
+    fn proof_of_inclusion_in_big_tree_from_strings() {
+        let tree: MerkleTree = MerkleTree::new_from_data(&something(32)).unwrap();
+
+        let hash = MerkleTree::<Sha3Hasher>::get_hash_of(&"something017");
+        let proof = tree.proof_of_inclusion(&hash).unwrap();
+
+        assert_eq!(proof.leaf(), &hash);
+        assert_eq!(proof.path().len(), 5);
+    }
+
+    /// Test that no proof is produced for a hash outside the tree
+    #[test]
+    #[should_panic]
+    fn proof_of_inclusion_fails() {
+        let tree: MerkleTree = MerkleTree::new_from_data(&something(32)).unwrap();
+
+        let hash = MerkleTree::<Sha3Hasher>::get_hash_of(&"not in the tree");
+
+        let _proof = tree.proof_of_inclusion(&hash).unwrap();
+    }
+
+    /// Test that an added hash can be verified and that duplicates are rejected
+    #[test]
+    fn add_to_tree() {
+        let mut tree: MerkleTree = MerkleTree::new_from_data(&something(17)).unwrap();
+
+        let new_data = MerkleTree::<Sha3Hasher>::get_hash_of(&"something099");
+        tree.add_hash(new_data).unwrap();
+
+        assert!(tree.verify(&new_data));
+        assert_eq!(tree.leaves().len(), 18);
+        assert!(matches!(
+            tree.add_hash(new_data),
+            Err(MerkleTreeError::HashAlreadyExists(_))
+        ));
+    }
+
+    /// Test that removing a hash keeps the index of every other leaf
+    #[test]
+    fn remove_from_tree() {
+        let data = something(6);
+        let mut tree: MerkleTree = MerkleTree::new_from_data(&data).unwrap();
+        let removed = MerkleTree::<Sha3Hasher>::get_hash_of(&data[2]);
+
+        tree.remove_hash(&removed).unwrap();
+
+        let mut leaves = tree.leaves().to_vec();
+        assert_eq!(leaves.len(), 6);
+        assert!(!tree.verify(&removed));
+        assert!(!tree.verify(&TOMBSTONE));
+        for (index, item) in data.iter().enumerate().filter(|(index, _)| *index != 2) {
+            let hash = MerkleTree::<Sha3Hasher>::get_hash_of(item);
+            assert!(tree.verify_with_index(&hash, index as u32));
+        }
+        assert!(tree.remove_hash(&removed).is_err());
+        assert!(tree.remove_hash(&TOMBSTONE).is_err());
+
+        leaves.remove(2);
+        let expected: MerkleTree = MerkleTree::new_from_hashes(leaves).unwrap();
+        tree.compact().unwrap();
+        assert_eq!(tree.root(), expected.root());
+    }
+
+    /// Test that appending and updating leaves one by one gives the same tree as
+    /// building it from scratch, at every size
+    #[test]
+    fn incremental_updates_match_rebuild() {
+        let data = something(70);
+        let mut tree: MerkleTree = MerkleTree::new_from_data(&data[..1]).unwrap();
+
+        for size in 2..=data.len() {
+            tree.append_hash(MerkleTree::<Sha3Hasher>::get_hash_of(&data[size - 1]))
+                .unwrap();
+            let expected: MerkleTree = MerkleTree::new_from_data(&data[..size]).unwrap();
+            assert_eq!(tree.storage, expected.storage, "tree of {} leaves", size);
+        }
+
+        let mut updated = data.clone();
+        for index in [0, 33, 64, 69] {
+            updated[index] = format!("updated {}", index);
+            tree.update_hash(
+                index,
+                MerkleTree::<Sha3Hasher>::get_hash_of(&updated[index]),
+            )
+            .unwrap();
+            let expected: MerkleTree = MerkleTree::new_from_data(&updated).unwrap();
+            assert_eq!(tree.storage, expected.storage);
+        }
+
+        assert_eq!(
+            tree.update_hash(70, [1; 32]),
+            Err(MerkleTreeError::IndexOutOfRange { index: 70, len: 70 })
+        );
+    }
+
+    /// Test that the last tombstone can be compacted only while a leaf is left
+    #[test]
+    fn compact_keeps_last_leaf() {
+        let mut tree: MerkleTree = MerkleTree::new_from_data(&something(1)).unwrap();
+        let leaf = tree.leaves()[0];
+
+        tree.remove_hash(&leaf).unwrap();
+
+        assert_eq!(tree.root(), &TOMBSTONE);
+        assert!(matches!(tree.compact(), Err(MerkleTreeError::EmptyTree)));
+        assert_eq!(tree.leaves(), &[TOMBSTONE]);
+    }
+
+    /// Test that historical roots match trees built from the same prefix
+    #[test]
+    fn root_at_matches_prefix_trees() {
+        let data = something(40);
+        let tree: MerkleTree = MerkleTree::new_from_data(&data).unwrap();
+
+        for size in 1..=data.len() {
+            let prefix: MerkleTree = MerkleTree::new_from_data(&data[..size]).unwrap();
+            assert_eq!(&tree.root_at(size).unwrap(), prefix.root());
+        }
+        assert!(tree.root_at(0).is_err());
+        assert!(tree.root_at(41).is_err());
+    }
+
+    /// Test the consistency proofs of the Certificate Transparency test vectors
+    #[test]
+    fn certificate_transparency_consistency_proofs() {
+        let inputs: Vec<&[u8]> = vec![
+            b"",
+            b"\x00",
+            b"\x10",
+            b"\x20\x21",
+            b"\x30\x31",
+            b"\x40\x41\x42\x43",
+            b"\x50\x51\x52\x53\x54\x55\x56\x57",
+            b"\x60\x61\x62\x63\x64\x65\x66\x67\x68\x69\x6a\x6b\x6c\x6d\x6e\x6f",
+        ];
+        let tree = MerkleTree::<Sha256Hasher>::new_from_data(&inputs).unwrap();
+
+        let vectors: [(usize, usize, &[&str]); 4] = [
+            (1, 1, &[]),
+            (
+                1,
+                8,
+                &[
+                    "96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7",
+                    "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                    "6b47aaf29ee3c2af9af889bc1fb9254dabd31177f16232dd6aab035ca39bf6e4",
+                ],
+            ),
+            (
+                6,
+                8,
+                &[
+                    "0ebc5d3437fbe2db158b9f126a1d118e308181031d0a949f8dededebc558ef6a",
+                    "ca854ea128ed050b41b35ffc1b87b8eb2bde461e9e3b5596ece6b9d5975a0ae0",
+                    "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+                ],
+            ),
+            (
+                2,
+                5,
+                &[
+                    "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                    "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+                ],
+            ),
+        ];
+
+        for (old_size, new_size, expected) in vectors {
+            let proof = tree.consistency_proof(old_size, new_size).unwrap();
+            let path: Vec<String> = proof.path().iter().map(to_hex).collect();
+            assert_eq!(path, expected, "proof from {} to {}", old_size, new_size);
+
+            let old_root = tree.root_at(old_size).unwrap();
+            let new_root = tree.root_at(new_size).unwrap();
+            assert!(proof.verify::<Sha256Hasher>(&old_root, &new_root));
+        }
+    }
+
+    /// Test that a proof between every pair of sizes verifies, and that it doesn't once
+    /// an old leaf is rewritten
+    #[test]
+    fn consistency_proofs_between_all_sizes() {
+        let data = something(33);
+        let tree: MerkleTree = MerkleTree::new_from_data(&data).unwrap();
+
+        for new_size in 1..=data.len() {
+            let new_root = tree.root_at(new_size).unwrap();
+            for old_size in 1..=new_size {
+                let old_root = tree.root_at(old_size).unwrap();
+                let proof = tree.consistency_proof(old_size, new_size).unwrap();
+                assert!(
+                    proof.verify::<Sha3Hasher>(&old_root, &new_root),
+                    "proof from {} to {}",
+                    old_size,
+                    new_size
+                );
+            }
+        }
+
+        let old_root = tree.root_at(10).unwrap();
+        let mut rewritten = tree.clone();
+        rewritten.update_hash(3, [9; 32]).unwrap();
+        let proof = rewritten.consistency_proof(10, 33).unwrap();
+        assert!(!proof.verify::<Sha3Hasher>(&old_root, rewritten.root()));
+
+        assert!(tree.consistency_proof(0, 5).is_err());
+        assert!(tree.consistency_proof(6, 5).is_err());
+        assert!(tree.consistency_proof(5, 34).is_err());
+    }
+
+    /// Checks that the index of every leaf hash matches a linear scan of the leaves.
+    fn assert_indices_in_sync(tree: &MerkleTree) {
+        let mut expected = LeafIndex::default();
+        for (index, leaf) in tree.leaves().iter().enumerate() {
+            if *leaf != TOMBSTONE {
+                expected.insert(*leaf, index);
+            }
+        }
+        assert_eq!(tree.indices(), &expected);
+    }
+
+    /// Test that lookups by hash find the leaf without scanning, through every kind of update
+    #[test]
+    fn index_lookup_stays_in_sync() {
+        let data = something(20);
+        let mut tree: MerkleTree = MerkleTree::new_from_data(&data).unwrap();
+        assert_indices_in_sync(&tree);
+
+        for (index, item) in data.iter().enumerate() {
+            let hash = MerkleTree::<Sha3Hasher>::get_hash_of(item);
+            assert_eq!(tree.index_of(&hash), Some(index));
+        }
+
+        let added = MerkleTree::<Sha3Hasher>::get_hash_of(&"added");
+        assert_eq!(tree.add_hash(added), Ok(20));
+        tree.update_hash(3, MerkleTree::<Sha3Hasher>::get_hash_of(&"updated"))
+            .unwrap();
+        tree.remove_hash(&tree.leaves()[7].clone()).unwrap();
+        assert_indices_in_sync(&tree);
+
+        let old = MerkleTree::<Sha3Hasher>::get_hash_of(&data[3]);
+        assert_eq!(tree.index_of(&old), None);
+        assert!(!tree.verify(&old));
+        assert_eq!(tree.index_of(&added), Some(20));
+
+        tree.compact().unwrap();
+        assert_indices_in_sync(&tree);
+        assert_eq!(tree.index_of(&added), Some(19));
+    }
+
+    /// Test that duplicate leaves are tracked at every index and removed one at a time
+    #[test]
+    fn duplicate_leaves() {
+        let repeated = MerkleTree::<Sha3Hasher>::get_hash_of(&"repeated");
+        let other = MerkleTree::<Sha3Hasher>::get_hash_of(&"other");
+        let mut tree: MerkleTree =
+            MerkleTree::new_from_hashes(vec![repeated, other, repeated]).unwrap();
+
+        assert_eq!(tree.indices_of(&repeated), &[0, 2]);
+        assert_eq!(
+            tree.add_hash(repeated),
+            Err(MerkleTreeError::HashAlreadyExists(0))
+        );
+        assert_eq!(tree.append_hash(repeated), Ok(3));
+        assert_eq!(tree.indices_of(&repeated), &[0, 2, 3]);
+
+        tree.remove_hash(&repeated).unwrap();
+        assert_eq!(tree.indices_of(&repeated), &[2, 3]);
+        assert!(tree.verify(&repeated));
+        assert_eq!(
+            tree.proof_of_inclusion(&repeated).unwrap().leaf(),
+            &repeated
+        );
+
+        tree.update_hash(3, TOMBSTONE).unwrap();
+        tree.remove_hash(&repeated).unwrap();
+        assert!(tree.indices_of(&repeated).is_empty());
+        assert!(!tree.verify(&repeated));
+        assert_indices_in_sync(&tree);
+
+        assert_eq!(
+            tree.add_hash(TOMBSTONE),
+            Err(MerkleTreeError::InvalidHash(TOMBSTONE))
+        );
+    }
+
+    /// Test that a tree kept in files can be reopened and keeps growing where it stopped
+    #[test]
+    fn file_backed_tree_reopens() {
+        let directory = tempfile::tempdir().unwrap();
+        let data = something(100);
+        let hashes: Vec<MerkleHash> = data
+            .iter()
+            .map(MerkleTree::<Sha3Hasher>::get_hash_of)
+            .collect();
+
+        let storage = FileStorage::open(directory.path()).unwrap();
+        let mut tree: MerkleTree<Sha3Hasher, FileStorage> =
+            MerkleTree::new_in(storage, hashes[..60].to_vec()).unwrap();
+        for hash in &hashes[60..80] {
+            tree.append_hash(*hash).unwrap();
+        }
+        tree.flush().unwrap();
+        let root = *tree.root();
+        drop(tree);
+
+        let storage = FileStorage::open(directory.path()).unwrap();
+        let mut tree: MerkleTree<Sha3Hasher, FileStorage> = MerkleTree::open(storage).unwrap();
+        assert_eq!(tree.root(), &root);
+        assert_eq!(tree.index_of(&hashes[42]), Some(42));
+        let proof = tree.proof_of_inclusion(&hashes[42]).unwrap();
+        assert!(proof.verify::<Sha3Hasher>(&root));
+
+        for hash in &hashes[80..] {
+            tree.append_hash(*hash).unwrap();
+        }
+        tree.remove_hash(&hashes[7]).unwrap();
+
+        let mut expected: MerkleTree = MerkleTree::new_from_hashes(hashes).unwrap();
+        expected.update_hash(7, TOMBSTONE).unwrap();
+        assert_eq!(tree.root(), expected.root());
+        assert!(tree.consistency_proof(80, 100).is_ok());
+
+        tree.compact().unwrap();
+        assert_eq!(tree.leaves().len(), 99);
+    }
+
+    /// Test that a reopened tree only indexes its leaves on the first lookup by hash, and
+    /// that changes made before stay visible
+    #[test]
+    fn reopened_tree_indexes_lazily() {
+        let directory = tempfile::tempdir().unwrap();
+        let hashes: Vec<MerkleHash> = something(50)
+            .iter()
+            .map(MerkleTree::<Sha3Hasher>::get_hash_of)
+            .collect();
+        let storage = FileStorage::open(directory.path()).unwrap();
+        let mut tree: MerkleTree<Sha3Hasher, FileStorage> =
+            MerkleTree::new_in(storage, hashes.clone()).unwrap();
+        tree.flush().unwrap();
+        drop(tree);
+
+        let storage = FileStorage::open(directory.path()).unwrap();
+        let mut tree: MerkleTree<Sha3Hasher, FileStorage> = MerkleTree::open(storage).unwrap();
+        assert!(tree.indices.get().is_none());
+        let proof = tree.proof_of_inclusion_with_index(&hashes[3], 3).unwrap();
+        assert!(proof.verify::<Sha3Hasher>(tree.root()));
+        let updated = MerkleTree::<Sha3Hasher>::get_hash_of(&"updated");
+        tree.update_hash(3, updated).unwrap();
+        tree.append_hash(hashes[4]).unwrap();
+        assert!(tree.indices.get().is_none());
+
+        assert_eq!(tree.index_of(&updated), Some(3));
+        assert_eq!(tree.index_of(&hashes[3]), None);
+        assert_eq!(tree.indices_of(&hashes[4]), [4, 50]);
+        assert!(tree.indices.get().is_some());
+        tree.update_hash(50, TOMBSTONE).unwrap();
+        assert_eq!(tree.indices_of(&hashes[4]), [4]);
+    }
+
+    /// Test that storage whose levels don't form a tree is rejected on open
+    #[test]
+    fn open_rejects_inconsistent_storage() {
+        assert!(matches!(
+            MerkleTree::<Sha3Hasher>::open(MemoryStorage::new()),
+            Err(MerkleTreeError::EmptyTree)
+        ));
+
+        let mut storage = MemoryStorage::new();
+        for index in 0..5 {
+            storage.set(0, index, [index as u8 + 1; 32]).unwrap();
+        }
+        storage.set(1, 0, [9; 32]).unwrap();
+        assert!(matches!(
+            MerkleTree::<Sha3Hasher>::open(storage),
+            Err(MerkleTreeError::Storage(_))
+        ));
+
+        let tree: MerkleTree = MerkleTree::new_from_data(&something(5)).unwrap();
+        let reopened = MerkleTree::<Sha3Hasher>::open(tree.storage().clone()).unwrap();
+        assert_eq!(reopened.root(), tree.root());
+    }
+
+    /// Test that building on several threads gives exactly the nodes of the sequential build
+    #[test]
+    fn parallel_build_matches_sequential() {
+        for size in [1, 2, 3, 8191, 8192, 8193, 16385, 50001] {
+            let hashes: Vec<MerkleHash> = (0..size as u32)
+                .map(|i| Sha3Hasher::digest(&i.to_be_bytes()))
+                .collect();
+            let sequential: MerkleTree = MerkleTree::new_from_hashes(hashes.clone()).unwrap();
+
+            for threads in [0, 1, 2, 3, 8] {
+                let parallel: MerkleTree =
+                    MerkleTree::new_from_hashes_parallel(hashes.clone(), threads).unwrap();
+                assert_eq!(parallel.storage(), sequential.storage(), "{} leaves", size);
+                assert_eq!(parallel.index_of(&hashes[size - 1]), Some(size - 1));
+            }
+        }
+    }
+
+    /// Test that every method of a single-leaf tree returns a result instead of panicking
+    #[test]
+    fn single_leaf_tree() {
+        let leaf = MerkleTree::<Sha3Hasher>::get_hash_of(&"only");
+        let mut tree: MerkleTree = MerkleTree::new_from_hashes(vec![leaf]).unwrap();
+
+        assert_eq!(tree.root(), &leaf);
+        assert!(tree.verify(&leaf));
+        assert!(tree.proof_of_inclusion(&leaf).unwrap().path().is_empty());
+        assert!(tree.multi_proof(&[0]).unwrap().verify::<Sha3Hasher>(&leaf));
+        assert_eq!(tree.root_at(1), Ok(leaf));
+        assert!(tree
+            .consistency_proof(1, 1)
+            .unwrap()
+            .verify::<Sha3Hasher>(&leaf, &leaf));
+
+        assert!(!tree.verify_with_index(&leaf, 1));
+        assert!(!tree.verify_with_index(&leaf, u32::MAX));
+        assert_eq!(
+            tree.proof_of_inclusion_with_index(&leaf, 1),
+            Err(MerkleTreeError::IndexOutOfRange { index: 1, len: 1 })
+        );
+        assert_eq!(
+            tree.proof_of_inclusion(&[7; 32]),
+            Err(MerkleTreeError::LeafNotFound([7; 32]))
+        );
+        assert_eq!(
+            tree.proof_of_inclusion_with_index(&[7; 32], 0),
+            Err(MerkleTreeError::LeafNotFound([7; 32]))
+        );
+        assert_eq!(tree.multi_proof(&[]), Err(MerkleTreeError::NoLeavesToProve));
+        assert_eq!(
+            tree.multi_proof(&[1]),
+            Err(MerkleTreeError::IndexOutOfRange { index: 1, len: 1 })
+        );
+        assert_eq!(
+            tree.root_at(2),
+            Err(MerkleTreeError::SizeOutOfRange { size: 2, len: 1 })
+        );
+        assert_eq!(
+            tree.consistency_proof(0, 1),
+            Err(MerkleTreeError::SizeOutOfRange { size: 0, len: 1 })
+        );
+
+        tree.remove_hash(&leaf).unwrap();
+        assert_eq!(tree.root(), &TOMBSTONE);
+        assert_eq!(
+            tree.remove_hash(&leaf),
+            Err(MerkleTreeError::LeafNotFound(leaf))
+        );
+        assert_eq!(
+            tree.multi_proof(&[0]),
+            Err(MerkleTreeError::LeafNotFound(TOMBSTONE))
+        );
+        assert_eq!(tree.compact(), Err(MerkleTreeError::EmptyTree));
+        assert_eq!(tree.leaves(), &[TOMBSTONE]);
+    }
+
+    /// Test that a tree can't be built or opened without leaves
+    #[test]
+    fn empty_tree_is_rejected() {
+        assert_eq!(
+            MerkleTree::<Sha3Hasher>::new_from_data::<&str>(&[]).unwrap_err(),
+            MerkleTreeError::EmptyTree
+        );
+        assert_eq!(
+            MerkleTree::<Sha3Hasher>::new_from_hashes_parallel(vec![], 4).unwrap_err(),
+            MerkleTreeError::EmptyTree
+        );
+        assert_eq!(
+            MerkleTree::<Sha3Hasher>::open(MemoryStorage::new()).unwrap_err(),
+            MerkleTreeError::EmptyTree
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use super::merkle_hash::{to_hex, MerkleHash};
+
+/// Errors returned by the Merkle Tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MerkleTreeError {
+    /// The tree would have no leaves.
+    EmptyTree,
+    /// A proof was requested for no leaves at all.
+    NoLeavesToProve,
+    /// There is no node at `index`, the level having `len` nodes.
+    IndexOutOfRange { index: usize, len: usize },
+    /// A tree of `size` leaves is not part of the history of a tree of `len` leaves,
+    /// because `size` is 0 or larger than `len`.
+    SizeOutOfRange { size: usize, len: usize },
+    /// The hash is not a leaf of the tree, or not at the given index.
+    LeafNotFound(MerkleHash),
+    /// The hash is already a leaf of the tree, at the given index.
+    HashAlreadyExists(usize),
+    /// The hash can't be a leaf of the tree, which is the case of `TOMBSTONE`.
+    InvalidHash(MerkleHash),
+    /// A serialized proof is truncated, has trailing bytes or an invalid field.
+    InvalidProof(String),
+    /// The nodes couldn't be read from or written to storage.
+    Storage(String),
+}
+
+impl fmt::Display for MerkleTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MerkleTreeError::EmptyTree => write!(f, "a Merkle Tree needs at least one leaf"),
+            MerkleTreeError::NoLeavesToProve => write!(f, "no leaves to prove"),
+            MerkleTreeError::IndexOutOfRange { index, len } => {
+                write!(f, "index {} is out of range for {} nodes", index, len)
+            }
+            MerkleTreeError::SizeOutOfRange { size, len } => {
+                write!(f, "size {} must be between 1 and {} leaves", size, len)
+            }
+            MerkleTreeError::LeafNotFound(hash) => {
+                write!(f, "{} is not a leaf of the tree", to_hex(hash))
+            }
+            MerkleTreeError::HashAlreadyExists(index) => {
+                write!(f, "hash is already the leaf at index {}", index)
+            }
+            MerkleTreeError::InvalidHash(hash) => {
+                write!(f, "{} can't be a leaf of the tree", to_hex(hash))
+            }
+            MerkleTreeError::InvalidProof(reason) => write!(f, "invalid proof: {}", reason),
+            MerkleTreeError::Storage(reason) => write!(f, "storage error: {}", reason),
+        }
+    }
+}
+
+impl Error for MerkleTreeError {}
+
+impl From<io::Error> for MerkleTreeError {
+    fn from(error: io::Error) -> Self {
+        MerkleTreeError::Storage(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that errors describe what went wrong and can be boxed as `dyn Error`
+    #[test]
+    fn display() {
+        let error: Box<dyn Error> = Box::new(MerkleTreeError::IndexOutOfRange { index: 7, len: 3 });
+        assert_eq!(error.to_string(), "index 7 is out of range for 3 nodes");
+
+        assert_eq!(
+            MerkleTreeError::LeafNotFound([0xab; 32]).to_string(),
+            format!("{} is not a leaf of the tree", "ab".repeat(32))
+        );
+        assert_eq!(
+            MerkleTreeError::from(io::Error::other("disk full")).to_string(),
+            "storage error: disk full"
+        );
+    }
+}
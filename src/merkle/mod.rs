@@ -0,0 +1,28 @@
+//! A Merkle Tree to store hashes efficiently in a blockchain node, grown out of the
+//! ideal response in `issues/merkle-tree.rs`.
+
+pub mod consistency_proof;
+pub mod direction;
+pub mod hasher;
+mod leaf_index;
+pub mod merkle_hash;
+pub mod merkle_tree;
+pub mod merkle_tree_error;
+pub mod multi_proof;
+pub mod proof_of_inclusion;
+mod reader;
+pub mod sparse_merkle_proof;
+pub mod sparse_merkle_tree;
+pub mod storage;
+
+pub use consistency_proof::ConsistencyProof;
+pub use direction::Direction;
+pub use hasher::{Blake3Hasher, Hasher, Sha256Hasher, Sha3Hasher};
+pub use merkle_hash::MerkleHash;
+pub use merkle_tree::{MerkleTree, TOMBSTONE};
+pub use merkle_tree_error::MerkleTreeError;
+pub use multi_proof::MultiProof;
+pub use proof_of_inclusion::ProofOfInclusion;
+pub use sparse_merkle_proof::SparseMerkleProof;
+pub use sparse_merkle_tree::SparseMerkleTree;
+pub use storage::{FileStorage, MemoryStorage, Storage};
@@ -0,0 +1,282 @@
+use super::hasher::Hasher;
+use super::merkle_hash::{to_hex, MerkleHash};
+use super::merkle_tree_error::MerkleTreeError;
+use super::reader::Reader;
+
+/// A proof that several leaves are part of a Merkle Tree, sharing the sibling hashes
+/// their paths have in common.
+///
+/// Only the hashes the verifier can't compute from the leaves themselves are included,
+/// ordered level by level from the bottom up and by position within each level.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiProof {
+    leaf_count: usize,
+    leaves: Vec<(usize, MerkleHash)>,
+    hashes: Vec<MerkleHash>,
+}
+
+impl MultiProof {
+    /// Creates a proof for the given leaves and their indices, in a tree of `leaf_count`
+    /// leaves, from the sibling hashes in bottom-up order.
+    pub fn new_from(
+        leaf_count: usize,
+        leaves: Vec<(usize, MerkleHash)>,
+        hashes: Vec<MerkleHash>,
+    ) -> Self {
+        MultiProof {
+            leaf_count,
+            leaves,
+            hashes,
+        }
+    }
+
+    /// Returns the number of leaves of the tree the proof is for.
+    pub fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
+
+    /// Returns the proven leaves with their indices, sorted by index.
+    pub fn leaves(&self) -> &[(usize, MerkleHash)] {
+        &self.leaves
+    }
+
+    /// Returns the sibling hashes of the proof.
+    pub fn hashes(&self) -> &[MerkleHash] {
+        &self.hashes
+    }
+
+    /// Recomputes the root of the tree from the leaves and the sibling hashes.
+    ///
+    /// # Returns
+    /// `None` if the leaves are not sorted by index, an index is out of range, or the
+    /// proof has too few or too many hashes.
+    pub fn compute_root<H: Hasher>(&self) -> Option<MerkleHash> {
+        if self.leaves.is_empty()
+            || self.leaves.windows(2).any(|pair| pair[0].0 >= pair[1].0)
+            || self
+                .leaves
+                .iter()
+                .any(|(index, _)| *index >= self.leaf_count)
+        {
+            return None;
+        }
+
+        let mut nodes = self.leaves.clone();
+        let mut hashes = self.hashes.iter();
+        let mut width = self.leaf_count;
+
+        while width > 1 {
+            let mut parents = Vec::with_capacity(nodes.len());
+            let mut i = 0;
+
+            while i < nodes.len() {
+                let (position, hash) = nodes[i];
+                let parent = if position % 2 == 1 {
+                    // A known left sibling would have been handled with it, at `i - 1`
+                    H::combine(hashes.next()?, &hash)
+                } else if position + 1 == width {
+                    // A last node without a sibling is promoted
+                    hash
+                } else if nodes.get(i + 1).is_some_and(|next| next.0 == position + 1) {
+                    i += 1;
+                    H::combine(&hash, &nodes[i].1)
+                } else {
+                    H::combine(&hash, hashes.next()?)
+                };
+
+                parents.push((position / 2, parent));
+                i += 1;
+            }
+
+            nodes = parents;
+            width = width.div_ceil(2);
+        }
+
+        if hashes.next().is_some() {
+            return None;
+        }
+        Some(nodes[0].1)
+    }
+
+    /// Verifies that every leaf of the proof is part of the tree with the given root,
+    /// without needing the tree itself.
+    ///
+    /// # Parameters
+    /// - `root`: The Merkle Root the proof is checked against, obtained from a trusted source
+    pub fn verify<H: Hasher>(&self, root: &MerkleHash) -> bool {
+        self.compute_root::<H>() == Some(*root)
+    }
+
+    /// Serializes the proof so it can be sent to another node.
+    ///
+    /// The format is the number of leaves of the tree as a big-endian `u64`, the number
+    /// of proven leaves as a big-endian `u32`, each proven leaf as its index (big-endian
+    /// `u64`) followed by its 32-byte hash, then the number of sibling hashes as a
+    /// big-endian `u32` followed by the 32-byte hashes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16 + self.leaves.len() * 40 + self.hashes.len() * 32);
+        bytes.extend_from_slice(&(self.leaf_count as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.leaves.len() as u32).to_be_bytes());
+        for (index, leaf) in self.leaves.iter() {
+            bytes.extend_from_slice(&(*index as u64).to_be_bytes());
+            bytes.extend_from_slice(leaf);
+        }
+        bytes.extend_from_slice(&(self.hashes.len() as u32).to_be_bytes());
+        for hash in self.hashes.iter() {
+            bytes.extend_from_slice(hash);
+        }
+        bytes
+    }
+
+    /// Deserializes a proof written by `to_bytes`.
+    ///
+    /// # Returns
+    /// `MerkleTreeError::InvalidProof` if the bytes are truncated or have trailing data.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MerkleTreeError> {
+        let mut reader = Reader::new(bytes);
+
+        let leaf_count = reader.u64()? as usize;
+        let leaf_total = reader.u32()? as usize;
+        let mut leaves = Vec::with_capacity(leaf_total.min(bytes.len() / 40));
+        for _ in 0..leaf_total {
+            let index = reader.u64()? as usize;
+            leaves.push((index, reader.hash()?));
+        }
+        let hash_total = reader.u32()? as usize;
+        let mut hashes = Vec::with_capacity(hash_total.min(bytes.len() / 32));
+        for _ in 0..hash_total {
+            hashes.push(reader.hash()?);
+        }
+
+        reader.finish()?;
+
+        Ok(MultiProof {
+            leaf_count,
+            leaves,
+            hashes,
+        })
+    }
+
+    /// Prints the proof.
+    pub fn print(&self) {
+        println!("MULTI PROOF IN A TREE OF {} LEAVES:", self.leaf_count);
+        for (index, leaf) in self.leaves.iter() {
+            println!("- leaf {} {}", index, to_hex(leaf));
+        }
+        for hash in self.hashes.iter() {
+            println!("- sibling {}", to_hex(hash));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::hasher::{Sha256Hasher, Sha3Hasher};
+    use crate::merkle::merkle_tree::MerkleTree;
+
+    fn tree(size: usize) -> MerkleTree {
+        let data: Vec<String> = (0..size).map(|i| format!("transaction {}", i)).collect();
+        MerkleTree::new_from_data(&data).unwrap()
+    }
+
+    /// Test that every subset of leaves of small trees can be proven at once
+    #[test]
+    fn every_subset_verifies() {
+        for size in 1..=9 {
+            let tree = tree(size);
+            for subset in 1..(1u32 << size) {
+                let indices: Vec<usize> = (0..size).filter(|i| subset & (1 << i) != 0).collect();
+                let proof = tree.multi_proof(&indices).unwrap();
+                assert!(
+                    proof.verify::<Sha3Hasher>(tree.root()),
+                    "leaves {:?} of {}",
+                    indices,
+                    size
+                );
+                if size > 1 {
+                    assert!(!proof.verify::<Sha256Hasher>(tree.root()));
+                }
+            }
+        }
+    }
+
+    /// Test that a batch proof is smaller than the separate proofs it replaces
+    #[test]
+    fn shares_sibling_hashes() {
+        let tree = tree(1000);
+        let indices: Vec<usize> = (192..256).collect();
+
+        let proof = tree.multi_proof(&indices).unwrap();
+        let separate: usize = indices
+            .iter()
+            .map(|&index| {
+                let leaf = tree.leaves()[index];
+                tree.proof_of_inclusion_with_index(&leaf, index as u32)
+                    .unwrap()
+                    .path()
+                    .len()
+            })
+            .sum();
+
+        // 64 aligned leaves form a complete subtree, only the path above it is needed
+        assert_eq!(proof.hashes().len(), 4);
+        assert_eq!(separate, 64 * 10);
+        assert!(proof.verify::<Sha3Hasher>(tree.root()));
+
+        let everything: Vec<usize> = (0..1000).collect();
+        assert!(tree.multi_proof(&everything).unwrap().hashes().is_empty());
+    }
+
+    /// Test that a proof fails once a leaf, an index or a hash is changed
+    #[test]
+    fn tampered_proof_fails() {
+        let tree = tree(37);
+        let proof = tree.multi_proof(&[3, 4, 17, 36]).unwrap();
+        let root = *tree.root();
+        assert!(proof.verify::<Sha3Hasher>(&root));
+
+        let mut leaves = proof.leaves().to_vec();
+        leaves[2].1[0] ^= 1;
+        let changed_leaf = MultiProof::new_from(37, leaves, proof.hashes().to_vec());
+        assert!(!changed_leaf.verify::<Sha3Hasher>(&root));
+
+        let mut leaves = proof.leaves().to_vec();
+        leaves[2].0 = 16;
+        let changed_index = MultiProof::new_from(37, leaves, proof.hashes().to_vec());
+        assert!(!changed_index.verify::<Sha3Hasher>(&root));
+
+        for index in 0..proof.hashes().len() {
+            let mut hashes = proof.hashes().to_vec();
+            hashes[index][0] ^= 1;
+            let changed_hash = MultiProof::new_from(37, proof.leaves().to_vec(), hashes);
+            assert!(!changed_hash.verify::<Sha3Hasher>(&root));
+        }
+
+        let mut hashes = proof.hashes().to_vec();
+        hashes.push([0; 32]);
+        let extra_hash = MultiProof::new_from(37, proof.leaves().to_vec(), hashes);
+        assert!(!extra_hash.verify::<Sha3Hasher>(&root));
+
+        let wrong_size = MultiProof::new_from(36, proof.leaves().to_vec(), proof.hashes().to_vec());
+        assert!(!wrong_size.verify::<Sha3Hasher>(&root));
+
+        assert!(tree.multi_proof(&[]).is_err());
+        assert!(tree.multi_proof(&[5, 37]).is_err());
+    }
+
+    /// Test that a proof survives a round trip through its serialized form
+    #[test]
+    fn serialization_round_trip() {
+        let tree = tree(100);
+        let proof = tree.multi_proof(&[99, 0, 50, 51, 7]).unwrap();
+
+        let bytes = proof.to_bytes();
+        let decoded = MultiProof::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, proof);
+        assert!(decoded.verify::<Sha3Hasher>(tree.root()));
+
+        assert!(MultiProof::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(MultiProof::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use std::slice::Iter;
+
+use super::direction::Direction;
+use super::hasher::Hasher;
+use super::merkle_hash::{to_hex, MerkleHash};
+use super::merkle_tree_error::MerkleTreeError;
+use super::reader::Reader;
+
+/// A proof that a leaf is part of a Merkle Tree: the hashes of the siblings of every
+/// node in the path from the leaf to the root, together with the side they are on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProofOfInclusion {
+    leaf: MerkleHash,
+    path: Vec<(MerkleHash, Direction)>,
+}
+
+impl ProofOfInclusion {
+    /// Creates a proof for `leaf` from its sibling path, ordered from the bottom up.
+    pub fn new_from(leaf: MerkleHash, path: Vec<(MerkleHash, Direction)>) -> Self {
+        ProofOfInclusion { leaf, path }
+    }
+
+    /// Returns the leaf this proof is for.
+    pub fn leaf(&self) -> &MerkleHash {
+        &self.leaf
+    }
+
+    /// Returns the sibling path, ordered from the bottom up.
+    pub fn path(&self) -> &[(MerkleHash, Direction)] {
+        &self.path
+    }
+
+    /// Iterates over the sibling path, from the bottom up.
+    pub fn iter(&self) -> Iter<'_, (MerkleHash, Direction)> {
+        self.path.iter()
+    }
+
+    /// Recomputes the root of the tree from the leaf and the sibling path.
+    pub fn compute_root<H: Hasher>(&self) -> MerkleHash {
+        let mut computed_root = self.leaf;
+
+        for (hash, direction) in self.path.iter() {
+            computed_root = match direction {
+                Direction::Left => H::combine(hash, &computed_root),
+                Direction::Right => H::combine(&computed_root, hash),
+            };
+        }
+
+        computed_root
+    }
+
+    /// Verifies that the leaf is part of the tree with the given root, without needing
+    /// the tree itself. In O(log n) time, with n = number of leaf hashes.
+    ///
+    /// # Parameters
+    /// - `root`: The Merkle Root the proof is checked against, obtained from a trusted source
+    pub fn verify<H: Hasher>(&self, root: &MerkleHash) -> bool {
+        self.compute_root::<H>() == *root
+    }
+
+    /// Serializes the proof so it can be sent to another node.
+    ///
+    /// The format is the 32-byte leaf, the number of siblings as a big-endian `u32`,
+    /// then for each sibling one direction byte (`0` for left, `1` for right) followed
+    /// by its 32-byte hash.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(36 + self.path.len() * 33);
+        bytes.extend_from_slice(&self.leaf);
+        bytes.extend_from_slice(&(self.path.len() as u32).to_be_bytes());
+        for (hash, direction) in self.path.iter() {
+            bytes.push(match direction {
+                Direction::Left => 0,
+                Direction::Right => 1,
+            });
+            bytes.extend_from_slice(hash);
+        }
+        bytes
+    }
+
+    /// Deserializes a proof written by `to_bytes`.
+    ///
+    /// # Returns
+    /// `MerkleTreeError::InvalidProof` if the bytes are truncated, have trailing data or
+    /// contain an unknown direction.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MerkleTreeError> {
+        let mut reader = Reader::new(bytes);
+
+        let leaf = reader.hash()?;
+        let length = reader.u32()? as usize;
+        let mut path = Vec::with_capacity(length.min(bytes.len() / 33));
+        for _ in 0..length {
+            let direction = match reader.u8()? {
+                0 => Direction::Left,
+                1 => Direction::Right,
+                _ => {
+                    return Err(MerkleTreeError::InvalidProof(
+                        "unknown direction in proof".to_string(),
+                    ))
+                }
+            };
+            path.push((reader.hash()?, direction));
+        }
+
+        reader.finish()?;
+
+        Ok(ProofOfInclusion { leaf, path })
+    }
+
+    /// Prints the proof.
+    pub fn print(&self) {
+        println!("PROOF FOR {}:", to_hex(&self.leaf));
+        for (hash, direction) in self.path.iter() {
+            println!("- {:?} {}", direction, to_hex(hash));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::hasher::{Sha256Hasher, Sha3Hasher};
+    use crate::merkle::merkle_tree::MerkleTree;
+
+    fn tree_and_proof() -> (MerkleHash, ProofOfInclusion) {
+        let data: Vec<String> = (0..11).map(|i| format!("transaction {}", i)).collect();
+        let tree: MerkleTree = MerkleTree::new_from_data(&data).unwrap();
+        let proof = tree.proof_of_inclusion(&tree.leaves()[6]).unwrap();
+        (*tree.root(), proof)
+    }
+
+    /// Test that a proof verifies against the root alone, for every leaf
+    #[test]
+    fn verify_against_root() {
+        let data: Vec<String> = (0..11).map(|i| format!("transaction {}", i)).collect();
+        let tree: MerkleTree = MerkleTree::new_from_data(&data).unwrap();
+        let root = *tree.root();
+
+        for leaf in tree.leaves() {
+            let proof = tree.proof_of_inclusion(leaf).unwrap();
+            assert!(proof.verify::<Sha3Hasher>(&root));
+            assert!(!proof.verify::<Sha256Hasher>(&root));
+        }
+    }
+
+    /// Test that a tampered proof or a wrong root fails to verify
+    #[test]
+    fn tampered_proof_fails() {
+        let (root, proof) = tree_and_proof();
+        assert!(!proof.verify::<Sha3Hasher>(&[0; 32]));
+
+        let mut path = proof.path().to_vec();
+        path[1].0[0] ^= 1;
+        let tampered = ProofOfInclusion::new_from(*proof.leaf(), path);
+        assert!(!tampered.verify::<Sha3Hasher>(&root));
+
+        let mut path = proof.path().to_vec();
+        path[0].1 = match path[0].1 {
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        };
+        let flipped = ProofOfInclusion::new_from(*proof.leaf(), path);
+        assert!(!flipped.verify::<Sha3Hasher>(&root));
+
+        let other_leaf = ProofOfInclusion::new_from([7; 32], proof.path().to_vec());
+        assert!(!other_leaf.verify::<Sha3Hasher>(&root));
+    }
+
+    /// Test that a proof survives a round trip through its serialized form
+    #[test]
+    fn serialization_round_trip() {
+        let (root, proof) = tree_and_proof();
+
+        let bytes = proof.to_bytes();
+        assert_eq!(bytes.len(), 36 + proof.path().len() * 33);
+
+        let decoded = ProofOfInclusion::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, proof);
+        assert!(decoded.verify::<Sha3Hasher>(&root));
+    }
+
+    /// Test that malformed bytes are rejected
+    #[test]
+    fn malformed_bytes_are_rejected() {
+        let (_, proof) = tree_and_proof();
+        let bytes = proof.to_bytes();
+
+        let is_invalid = |bytes: &[u8]| {
+            matches!(
+                ProofOfInclusion::from_bytes(bytes),
+                Err(MerkleTreeError::InvalidProof(_))
+            )
+        };
+
+        assert!(is_invalid(&bytes[..20]));
+        assert!(is_invalid(&bytes[..bytes.len() - 1]));
+        assert!(is_invalid(&[bytes.as_slice(), &[0]].concat()));
+
+        let mut bad_direction = bytes.clone();
+        bad_direction[36] = 2;
+        assert!(is_invalid(&bad_direction));
+    }
+}
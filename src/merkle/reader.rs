@@ -0,0 +1,53 @@
+use super::merkle_hash::MerkleHash;
+use super::merkle_tree_error::MerkleTreeError;
+use crate::bytes::take;
+
+/// Reads the fields of a serialized proof in order, with the cursor of
+/// [`crate::bytes`], turning truncation into `MerkleTreeError::InvalidProof`. Integers
+/// are big-endian.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes }
+    }
+
+    pub(crate) fn take<const N: usize>(&mut self) -> Result<[u8; N], MerkleTreeError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.slice(N)?);
+        Ok(array)
+    }
+
+    pub(crate) fn slice(&mut self, length: usize) -> Result<&'a [u8], MerkleTreeError> {
+        take(&mut self.bytes, length)
+            .ok_or_else(|| MerkleTreeError::InvalidProof("proof is truncated".to_string()))
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, MerkleTreeError> {
+        self.take().map(u8::from_be_bytes)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, MerkleTreeError> {
+        self.take().map(u32::from_be_bytes)
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, MerkleTreeError> {
+        self.take().map(u64::from_be_bytes)
+    }
+
+    pub(crate) fn hash(&mut self) -> Result<MerkleHash, MerkleTreeError> {
+        self.take()
+    }
+
+    /// Checks that every byte was read.
+    pub(crate) fn finish(&self) -> Result<(), MerkleTreeError> {
+        if !self.bytes.is_empty() {
+            return Err(MerkleTreeError::InvalidProof(
+                "proof has trailing bytes".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
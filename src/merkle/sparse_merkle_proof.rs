@@ -0,0 +1,273 @@
+use super::hasher::Hasher;
+use super::merkle_hash::{to_hex, MerkleHash};
+use super::merkle_tree_error::MerkleTreeError;
+use super::reader::Reader;
+use super::sparse_merkle_tree::{goes_right, leaf_hash, DEPTH, EMPTY_LEAF};
+
+/// A proof of the value of a key in a Sparse Merkle Tree, or that the key has no value.
+///
+/// The siblings that are empty subtrees are left out: bit `h` of the bitmap tells whether
+/// the sibling at height `h` is in `siblings`, which are ordered from the bottom up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SparseMerkleProof {
+    key: MerkleHash,
+    value: Option<Vec<u8>>,
+    bitmap: [u8; 32],
+    siblings: Vec<MerkleHash>,
+}
+
+impl SparseMerkleProof {
+    /// Creates a proof for `key` holding `value`, or no value if it is `None`.
+    pub fn new_from(
+        key: MerkleHash,
+        value: Option<Vec<u8>>,
+        bitmap: [u8; 32],
+        siblings: Vec<MerkleHash>,
+    ) -> Self {
+        SparseMerkleProof {
+            key,
+            value,
+            bitmap,
+            siblings,
+        }
+    }
+
+    /// Returns the key this proof is for.
+    pub fn key(&self) -> &MerkleHash {
+        &self.key
+    }
+
+    /// Returns the value the proof claims the key has, or `None` for a non-inclusion proof.
+    pub fn value(&self) -> Option<&[u8]> {
+        self.value.as_deref()
+    }
+
+    /// Returns `true` if the proof shows the key has a value, `false` if it shows it has none.
+    pub fn is_inclusion(&self) -> bool {
+        self.value.is_some()
+    }
+
+    /// Returns the siblings that are not empty subtrees, from the bottom up.
+    pub fn siblings(&self) -> &[MerkleHash] {
+        &self.siblings
+    }
+
+    /// Recomputes the root of the tree from the key, its value and the siblings.
+    ///
+    /// # Returns
+    /// `None` if the bitmap doesn't match the number of siblings.
+    pub fn compute_root<H: Hasher>(&self) -> Option<MerkleHash> {
+        let mut current = match &self.value {
+            Some(value) => leaf_hash::<H>(&self.key, value),
+            None => EMPTY_LEAF,
+        };
+        let mut empty_subtree = EMPTY_LEAF;
+        let mut siblings = self.siblings.iter();
+
+        for height in 0..DEPTH {
+            let sibling = if self.bitmap[height / 8] & (1 << (height % 8)) != 0 {
+                *siblings.next()?
+            } else {
+                empty_subtree
+            };
+
+            current = if goes_right(&self.key, height) {
+                H::combine(&sibling, &current)
+            } else {
+                H::combine(&current, &sibling)
+            };
+            empty_subtree = H::combine(&empty_subtree, &empty_subtree);
+        }
+
+        if siblings.next().is_some() {
+            return None;
+        }
+        Some(current)
+    }
+
+    /// Verifies the claimed value of the key, or its absence, against the given root
+    /// without needing the tree itself.
+    ///
+    /// # Parameters
+    /// - `root`: The root of the Sparse Merkle Tree, obtained from a trusted source
+    pub fn verify<H: Hasher>(&self, root: &MerkleHash) -> bool {
+        self.compute_root::<H>() == Some(*root)
+    }
+
+    /// Serializes the proof so it can be sent to another node.
+    ///
+    /// The format is the 32-byte key, a byte set to `1` if a value follows, the length of
+    /// the value as a big-endian `u32` and the value itself, then the 32-byte bitmap and
+    /// one 32-byte hash for each bit set in it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let value_length = self.value.as_ref().map_or(0, |value| 4 + value.len());
+        let mut bytes = Vec::with_capacity(65 + value_length + self.siblings.len() * 32);
+        bytes.extend_from_slice(&self.key);
+        match &self.value {
+            Some(value) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(value);
+            }
+            None => bytes.push(0),
+        }
+        bytes.extend_from_slice(&self.bitmap);
+        for sibling in self.siblings.iter() {
+            bytes.extend_from_slice(sibling);
+        }
+        bytes
+    }
+
+    /// Deserializes a proof written by `to_bytes`.
+    ///
+    /// # Returns
+    /// `MerkleTreeError::InvalidProof` if the bytes are truncated, have trailing data or
+    /// an invalid value marker.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MerkleTreeError> {
+        let mut reader = Reader::new(bytes);
+
+        let key = reader.hash()?;
+        let value = match reader.u8()? {
+            0 => None,
+            1 => {
+                let length = reader.u32()? as usize;
+                Some(reader.slice(length)?.to_vec())
+            }
+            _ => {
+                return Err(MerkleTreeError::InvalidProof(
+                    "invalid value marker in proof".to_string(),
+                ))
+            }
+        };
+        let bitmap: [u8; 32] = reader.take()?;
+        let sibling_count = bitmap.iter().map(|byte| byte.count_ones()).sum::<u32>();
+        let siblings = (0..sibling_count)
+            .map(|_| reader.hash())
+            .collect::<Result<Vec<_>, _>>()?;
+        reader.finish()?;
+
+        Ok(SparseMerkleProof {
+            key,
+            value,
+            bitmap,
+            siblings,
+        })
+    }
+
+    /// Prints the proof.
+    pub fn print(&self) {
+        match &self.value {
+            Some(value) => println!(
+                "PROOF THAT {} HOLDS {} BYTES:",
+                to_hex(&self.key),
+                value.len()
+            ),
+            None => println!("PROOF THAT {} HAS NO VALUE:", to_hex(&self.key)),
+        }
+        for sibling in self.siblings.iter() {
+            println!("- {}", to_hex(sibling));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::hasher::{Sha256Hasher, Sha3Hasher};
+    use crate::merkle::sparse_merkle_tree::SparseMerkleTree;
+
+    fn tree() -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new();
+        for i in 0..50u32 {
+            tree.insert(
+                Sha3Hasher::digest(&i.to_be_bytes()),
+                i.to_be_bytes().to_vec(),
+            );
+        }
+        tree
+    }
+
+    /// Test inclusion proofs for keys with a value
+    #[test]
+    fn inclusion_proofs_verify() {
+        let tree = tree();
+        let root = tree.root();
+
+        for i in 0..50u32 {
+            let proof = tree.prove(&Sha3Hasher::digest(&i.to_be_bytes()));
+            assert!(proof.is_inclusion());
+            assert_eq!(proof.value(), Some(&i.to_be_bytes()[..]));
+            assert!(proof.verify::<Sha3Hasher>(&root));
+            assert!(!proof.verify::<Sha256Hasher>(&root));
+            // Only the top levels of a tree of 50 keys are shared
+            assert!(proof.siblings().len() < 20);
+        }
+    }
+
+    /// Test non-inclusion proofs for keys without a value, including a removed one
+    #[test]
+    fn non_inclusion_proofs_verify() {
+        let mut tree = tree();
+        let removed = Sha3Hasher::digest(&7u32.to_be_bytes());
+        tree.remove(&removed);
+        let root = tree.root();
+
+        for key in [removed, Sha3Hasher::digest(b"never inserted"), [0xff; 32]] {
+            let proof = tree.prove(&key);
+            assert!(!proof.is_inclusion());
+            assert!(proof.verify::<Sha3Hasher>(&root));
+        }
+    }
+
+    /// Test that a proof can't claim a different value, or the absence of a present key
+    #[test]
+    fn forged_proofs_fail() {
+        let tree = tree();
+        let root = tree.root();
+        let key = Sha3Hasher::digest(&3u32.to_be_bytes());
+        let proof = tree.prove(&key);
+        let bitmap = proof.bitmap;
+
+        let wrong_value = SparseMerkleProof::new_from(
+            key,
+            Some(b"other".to_vec()),
+            bitmap,
+            proof.siblings.clone(),
+        );
+        assert!(!wrong_value.verify::<Sha3Hasher>(&root));
+
+        let absent = SparseMerkleProof::new_from(key, None, bitmap, proof.siblings.clone());
+        assert!(!absent.verify::<Sha3Hasher>(&root));
+
+        let other_key = Sha3Hasher::digest(&4u32.to_be_bytes());
+        let moved = SparseMerkleProof::new_from(
+            other_key,
+            proof.value.clone(),
+            bitmap,
+            proof.siblings.clone(),
+        );
+        assert!(!moved.verify::<Sha3Hasher>(&root));
+
+        let mut siblings = proof.siblings.clone();
+        siblings.pop();
+        let truncated = SparseMerkleProof::new_from(key, proof.value.clone(), bitmap, siblings);
+        assert!(!truncated.verify::<Sha3Hasher>(&root));
+    }
+
+    /// Test that proofs survive a round trip through their serialized form
+    #[test]
+    fn serialization_round_trip() {
+        let tree = tree();
+
+        for key in [Sha3Hasher::digest(&9u32.to_be_bytes()), [1; 32]] {
+            let proof = tree.prove(&key);
+            let bytes = proof.to_bytes();
+            let decoded = SparseMerkleProof::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded, proof);
+            assert!(decoded.verify::<Sha3Hasher>(&tree.root()));
+
+            assert!(SparseMerkleProof::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+            assert!(SparseMerkleProof::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        }
+    }
+}
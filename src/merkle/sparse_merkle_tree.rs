@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use super::hasher::{Hasher, Sha3Hasher};
+use super::merkle_hash::MerkleHash;
+use super::sparse_merkle_proof::SparseMerkleProof;
+
+/// Number of levels below the root: one per bit of a key.
+pub const DEPTH: usize = 256;
+
+/// Hash of a leaf with no value.
+pub const EMPTY_LEAF: MerkleHash = [0; 32];
+
+/// A Sparse Merkle Tree: a Merkle Tree with one leaf for every possible 256-bit key, so
+/// it can prove that a key has no value as well as that it has one.
+///
+/// Almost every subtree is empty, and the hash of an empty subtree only depends on its
+/// height, so those hashes are computed once and only the nodes on the path of a key
+/// with a value are stored.
+///
+/// # Methods
+/// - `new`: Creates an empty tree.
+/// - `root`: Returns the root of the tree, which commits to every key and value.
+/// - `get`: Returns the value of a key.
+/// - `insert`: Sets the value of a key, updating the path to the root.
+/// - `remove`: Removes the value of a key, updating the path to the root.
+/// - `prove`: Returns a proof of the value of a key, or that it has none.
+#[derive(Debug, Clone)]
+pub struct SparseMerkleTree<H: Hasher = Sha3Hasher> {
+    nodes: HashMap<(usize, MerkleHash), MerkleHash>,
+    values: HashMap<MerkleHash, Vec<u8>>,
+    defaults: Vec<MerkleHash>,
+    hasher: PhantomData<H>,
+}
+
+impl<H: Hasher> Default for SparseMerkleTree<H> {
+    fn default() -> Self {
+        SparseMerkleTree::new()
+    }
+}
+
+impl<H: Hasher> SparseMerkleTree<H> {
+    /// Creates an empty tree.
+    pub fn new() -> Self {
+        SparseMerkleTree {
+            nodes: HashMap::new(),
+            values: HashMap::new(),
+            defaults: default_hashes::<H>(),
+            hasher: PhantomData,
+        }
+    }
+
+    /// Returns the root of the tree, which commits to every key and value.
+    pub fn root(&self) -> MerkleHash {
+        self.node(DEPTH, &[0; 32])
+    }
+
+    /// Returns the number of keys with a value.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns `true` if no key has a value.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Returns the value of a key, if it has one.
+    pub fn get(&self, key: &MerkleHash) -> Option<&[u8]> {
+        self.values.get(key).map(Vec::as_slice)
+    }
+
+    /// Sets the value of a key, in O(DEPTH) time.
+    ///
+    /// # Parameters
+    /// - `key`: The key, usually the hash of an account address
+    /// - `value`: The value, such as the serialized account state
+    ///
+    /// # Returns
+    /// The previous value of the key, if it had one.
+    pub fn insert(&mut self, key: MerkleHash, value: Vec<u8>) -> Option<Vec<u8>> {
+        self.update_path(&key, leaf_hash::<H>(&key, &value));
+        self.values.insert(key, value)
+    }
+
+    /// Removes the value of a key, in O(DEPTH) time.
+    ///
+    /// # Returns
+    /// The value of the key, if it had one.
+    pub fn remove(&mut self, key: &MerkleHash) -> Option<Vec<u8>> {
+        let value = self.values.remove(key)?;
+        self.update_path(key, EMPTY_LEAF);
+        Some(value)
+    }
+
+    /// Returns a proof of the value of a key, or that it has none.
+    ///
+    /// Only the siblings that are not empty subtrees are included, with a bitmap telling
+    /// the verifier where they go.
+    pub fn prove(&self, key: &MerkleHash) -> SparseMerkleProof {
+        let mut bitmap = [0; 32];
+        let mut siblings = vec![];
+
+        for height in 0..DEPTH {
+            let sibling = self.node(height, &sibling_of(key, height));
+            if sibling != self.defaults[height] {
+                set_bit(&mut bitmap, height);
+                siblings.push(sibling);
+            }
+        }
+
+        SparseMerkleProof::new_from(*key, self.values.get(key).cloned(), bitmap, siblings)
+    }
+
+    /// Returns the node at `height` above the leaves whose subtree contains `key`.
+    fn node(&self, height: usize, key: &MerkleHash) -> MerkleHash {
+        self.nodes
+            .get(&(height, prefix(key, height)))
+            .copied()
+            .unwrap_or(self.defaults[height])
+    }
+
+    /// Sets the leaf of `key` and recomputes its ancestors. Nodes equal to the empty
+    /// subtree of their height are not stored.
+    fn update_path(&mut self, key: &MerkleHash, leaf: MerkleHash) {
+        let mut current = leaf;
+
+        for height in 0..=DEPTH {
+            let node_key = (height, prefix(key, height));
+            if current == self.defaults[height] {
+                self.nodes.remove(&node_key);
+            } else {
+                self.nodes.insert(node_key, current);
+            }
+
+            if height == DEPTH {
+                break;
+            }
+
+            let sibling = self.node(height, &sibling_of(key, height));
+            current = if goes_right(key, height) {
+                H::combine(&sibling, &current)
+            } else {
+                H::combine(&current, &sibling)
+            };
+        }
+    }
+}
+
+/// Returns the hash of a leaf with a value. The key is hashed along with the value so a
+/// proof can't be reused for another key.
+pub fn leaf_hash<H: Hasher>(key: &MerkleHash, value: &[u8]) -> MerkleHash {
+    let mut data = Vec::with_capacity(32 + value.len());
+    data.extend_from_slice(key);
+    data.extend_from_slice(value);
+    H::hash_leaf(&data)
+}
+
+/// Returns the roots of empty subtrees, indexed by height.
+pub fn default_hashes<H: Hasher>() -> Vec<MerkleHash> {
+    let mut defaults = Vec::with_capacity(DEPTH + 1);
+    defaults.push(EMPTY_LEAF);
+    for height in 0..DEPTH {
+        defaults.push(H::combine(&defaults[height], &defaults[height]));
+    }
+    defaults
+}
+
+/// Returns `true` if the node at `height` on the path of `key` is a right child. Keys are
+/// read from their most significant bit, which decides the side just below the root.
+pub(crate) fn goes_right(key: &MerkleHash, height: usize) -> bool {
+    let bit = DEPTH - 1 - height;
+    key[bit / 8] & (0x80 >> (bit % 8)) != 0
+}
+
+/// Returns `key` with the bits below `height` cleared, which identifies the node at
+/// `height` on its path.
+fn prefix(key: &MerkleHash, height: usize) -> MerkleHash {
+    let mut prefix = *key;
+    let first_cleared = DEPTH - height;
+    if first_cleared < DEPTH {
+        prefix[first_cleared / 8] &= !(0xff >> (first_cleared % 8));
+        prefix[first_cleared / 8 + 1..].fill(0);
+    }
+    prefix
+}
+
+/// Returns a key in the subtree of the sibling of the node at `height` on the path of `key`.
+fn sibling_of(key: &MerkleHash, height: usize) -> MerkleHash {
+    let bit = DEPTH - 1 - height;
+    let mut sibling = *key;
+    sibling[bit / 8] ^= 0x80 >> (bit % 8);
+    sibling
+}
+
+fn set_bit(bitmap: &mut [u8; 32], index: usize) {
+    bitmap[index / 8] |= 1 << (index % 8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::hasher::Sha256Hasher;
+
+    fn key(name: &str) -> MerkleHash {
+        Sha3Hasher::digest(name.as_bytes())
+    }
+
+    /// Test that the root of an empty tree is the empty subtree of full height
+    #[test]
+    fn empty_tree_root() {
+        let tree: SparseMerkleTree = SparseMerkleTree::new();
+        assert_eq!(tree.root(), default_hashes::<Sha3Hasher>()[DEPTH]);
+        assert!(tree.is_empty());
+    }
+
+    /// Test that the root only depends on the keys and values, not on the order of updates
+    #[test]
+    fn root_is_independent_of_insertion_order() {
+        let accounts = ["alice", "bob", "carol", "dave", "erin"];
+
+        let mut forward: SparseMerkleTree = SparseMerkleTree::new();
+        for account in accounts {
+            forward.insert(key(account), account.as_bytes().to_vec());
+        }
+
+        let mut backward: SparseMerkleTree = SparseMerkleTree::new();
+        backward.insert(key("mallory"), b"temporary".to_vec());
+        for account in accounts.iter().rev() {
+            backward.insert(key(account), b"old balance".to_vec());
+            backward.insert(key(account), account.as_bytes().to_vec());
+        }
+        backward.remove(&key("mallory"));
+
+        assert_eq!(forward.root(), backward.root());
+        assert_eq!(forward.len(), 5);
+        assert_eq!(forward.get(&key("carol")), Some(&b"carol"[..]));
+    }
+
+    /// Test that removing every key gives back the empty tree, without leftover nodes
+    #[test]
+    fn removing_every_key_empties_tree() {
+        let mut tree: SparseMerkleTree<Sha256Hasher> = SparseMerkleTree::new();
+        let empty_root = tree.root();
+
+        for i in 0..20 {
+            tree.insert(key(&i.to_string()), vec![i as u8]);
+        }
+        assert_ne!(tree.root(), empty_root);
+
+        for i in 0..20 {
+            assert_eq!(tree.remove(&key(&i.to_string())), Some(vec![i as u8]));
+        }
+        assert_eq!(tree.remove(&key("0")), None);
+        assert_eq!(tree.root(), empty_root);
+        assert!(tree.nodes.is_empty());
+    }
+
+    /// Test that keys sharing a long prefix end up in different leaves
+    #[test]
+    fn neighbouring_keys() {
+        let mut tree: SparseMerkleTree = SparseMerkleTree::new();
+        let left = [0; 32];
+        let mut right = [0; 32];
+        right[31] = 1;
+
+        tree.insert(left, b"left".to_vec());
+        let only_left = tree.root();
+        tree.insert(right, b"right".to_vec());
+
+        assert_ne!(tree.root(), only_left);
+        assert_eq!(tree.get(&left), Some(&b"left"[..]));
+        assert_eq!(tree.get(&right), Some(&b"right"[..]));
+        assert!(tree.prove(&left).verify::<Sha3Hasher>(&tree.root()));
+        assert!(tree.prove(&right).verify::<Sha3Hasher>(&tree.root()));
+    }
+}
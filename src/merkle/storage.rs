@@ -0,0 +1,341 @@
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use memmap2::MmapMut;
+
+use super::merkle_hash::MerkleHash;
+use super::merkle_tree_error::MerkleTreeError;
+
+/// Where the nodes of a Merkle Tree are kept, level by level from the leaves up.
+///
+/// Reads can't fail, so implementations must keep every level addressable, either in
+/// memory or memory-mapped. Writes may fail, for instance when a file can't grow.
+pub trait Storage {
+    /// Returns the number of levels, the leaves being level 0.
+    fn level_count(&self) -> usize;
+
+    /// Returns the nodes of a level, from left to right.
+    fn level(&self, level: usize) -> &[MerkleHash];
+
+    /// Sets the node at `index` of `level`.
+    ///
+    /// `index` may be the length of the level to append a node, and `level` may be the
+    /// number of levels to add a level above the others.
+    fn set(&mut self, level: usize, index: usize, hash: MerkleHash) -> Result<(), MerkleTreeError>;
+
+    /// Removes every node.
+    fn clear(&mut self) -> Result<(), MerkleTreeError>;
+
+    /// Makes every write durable. Does nothing for storage that isn't persistent.
+    fn flush(&mut self) -> Result<(), MerkleTreeError> {
+        Ok(())
+    }
+}
+
+fn check_position(
+    level_count: usize,
+    level: usize,
+    level_len: impl FnOnce() -> usize,
+    index: usize,
+) -> Result<(), MerkleTreeError> {
+    if level > level_count {
+        return Err(MerkleTreeError::IndexOutOfRange {
+            index: level,
+            len: level_count,
+        });
+    }
+    let len = if level == level_count { 0 } else { level_len() };
+    if index > len {
+        return Err(MerkleTreeError::IndexOutOfRange { index, len });
+    }
+    Ok(())
+}
+
+/// Keeps every level in memory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryStorage {
+    levels: Vec<Vec<MerkleHash>>,
+}
+
+impl MemoryStorage {
+    /// Creates an empty storage.
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn level_count(&self) -> usize {
+        self.levels.len()
+    }
+
+    fn level(&self, level: usize) -> &[MerkleHash] {
+        &self.levels[level]
+    }
+
+    fn set(&mut self, level: usize, index: usize, hash: MerkleHash) -> Result<(), MerkleTreeError> {
+        check_position(self.levels.len(), level, || self.levels[level].len(), index)?;
+
+        if level == self.levels.len() {
+            self.levels.push(vec![]);
+        }
+        let nodes = &mut self.levels[level];
+        if index == nodes.len() {
+            nodes.push(hash);
+        } else {
+            nodes[index] = hash;
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), MerkleTreeError> {
+        self.levels.clear();
+        Ok(())
+    }
+}
+
+/// Bytes at the start of a level file holding the number of nodes, as a little-endian `u64`.
+const HEADER_SIZE: usize = 8;
+
+/// Number of nodes a new level file has room for.
+const INITIAL_CAPACITY: usize = 64;
+
+/// Keeps each level in its own memory-mapped file, so a tree can be reopened after a
+/// restart and only the pages holding the nodes a proof needs are read from disk.
+///
+/// Level `i` is stored in `level-<i>.bin` in the directory, as a header with the number
+/// of nodes followed by the 32-byte nodes. Files double in size when they are full.
+/// Writes reach the files when the operating system writes the pages back, or when
+/// `flush` is called.
+#[derive(Debug)]
+pub struct FileStorage {
+    directory: PathBuf,
+    files: Vec<LevelFile>,
+    /// Number of levels in use. Files above it were emptied by `clear` and are reused.
+    level_count: usize,
+}
+
+impl FileStorage {
+    /// Opens the storage in `directory`, creating the directory if it doesn't exist.
+    ///
+    /// # Returns
+    /// `MerkleTreeError::Storage` if a file can't be opened or is corrupted.
+    pub fn open(directory: impl AsRef<Path>) -> Result<Self, MerkleTreeError> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+
+        let mut files = vec![];
+        loop {
+            let path = FileStorage::level_path(&directory, files.len());
+            if !path.exists() {
+                break;
+            }
+            files.push(LevelFile::open(&path)?);
+        }
+        let level_count = files.iter().take_while(|file| file.len > 0).count();
+
+        Ok(FileStorage {
+            directory,
+            files,
+            level_count,
+        })
+    }
+
+    /// Returns the directory the level files are in.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn level_path(directory: &Path, level: usize) -> PathBuf {
+        directory.join(format!("level-{}.bin", level))
+    }
+}
+
+impl Storage for FileStorage {
+    fn level_count(&self) -> usize {
+        self.level_count
+    }
+
+    fn level(&self, level: usize) -> &[MerkleHash] {
+        self.files[..self.level_count][level].nodes()
+    }
+
+    fn set(&mut self, level: usize, index: usize, hash: MerkleHash) -> Result<(), MerkleTreeError> {
+        check_position(self.level_count, level, || self.files[level].len, index)?;
+
+        if level == self.level_count {
+            if level == self.files.len() {
+                let path = FileStorage::level_path(&self.directory, level);
+                self.files.push(LevelFile::open(&path)?);
+            }
+            self.level_count += 1;
+        }
+        self.files[level].set(index, hash)
+    }
+
+    fn clear(&mut self) -> Result<(), MerkleTreeError> {
+        for file in self.files.iter_mut() {
+            file.set_len(0);
+        }
+        self.level_count = 0;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), MerkleTreeError> {
+        for file in self.files.iter() {
+            file.map.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// One memory-mapped level file.
+#[derive(Debug)]
+struct LevelFile {
+    file: File,
+    map: MmapMut,
+    len: usize,
+}
+
+impl LevelFile {
+    fn open(path: &Path) -> Result<Self, MerkleTreeError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if file.metadata()?.len() < (HEADER_SIZE + 32) as u64 {
+            file.set_len((HEADER_SIZE + INITIAL_CAPACITY * 32) as u64)?;
+        }
+        let map = LevelFile::map(&file)?;
+
+        let mut header = [0; HEADER_SIZE];
+        header.copy_from_slice(&map[..HEADER_SIZE]);
+        let len = u64::from_le_bytes(header) as usize;
+
+        let level_file = LevelFile { file, map, len: 0 };
+        if len > level_file.capacity() {
+            return Err(MerkleTreeError::Storage(format!(
+                "{} claims {} nodes but only has room for {}",
+                path.display(),
+                len,
+                level_file.capacity()
+            )));
+        }
+        Ok(LevelFile { len, ..level_file })
+    }
+
+    fn map(file: &File) -> Result<MmapMut, MerkleTreeError> {
+        // SAFETY: the file is only modified through this mapping. Another process
+        // changing it would let us read torn nodes, but never out of bounds memory.
+        let map = unsafe { MmapMut::map_mut(file)? };
+        Ok(map)
+    }
+
+    fn capacity(&self) -> usize {
+        (self.map.len() - HEADER_SIZE) / 32
+    }
+
+    fn nodes(&self) -> &[MerkleHash] {
+        self.map[HEADER_SIZE..HEADER_SIZE + self.len * 32]
+            .as_chunks()
+            .0
+    }
+
+    fn set(&mut self, index: usize, hash: MerkleHash) -> Result<(), MerkleTreeError> {
+        if index == self.len {
+            if self.len == self.capacity() {
+                self.grow()?;
+            }
+            self.set_len(self.len + 1);
+        }
+
+        let offset = HEADER_SIZE + index * 32;
+        self.map[offset..offset + 32].copy_from_slice(&hash);
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.len = len;
+        self.map[..HEADER_SIZE].copy_from_slice(&(len as u64).to_le_bytes());
+    }
+
+    fn grow(&mut self) -> Result<(), MerkleTreeError> {
+        let capacity = self.capacity() * 2;
+        self.map.flush()?;
+        self.file.set_len((HEADER_SIZE + capacity * 32) as u64)?;
+        self.map = LevelFile::map(&self.file)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise(storage: &mut impl Storage) {
+        for index in 0..200 {
+            storage.set(0, index, [index as u8; 32]).unwrap();
+        }
+        storage.set(1, 0, [1; 32]).unwrap();
+        storage.set(0, 5, [255; 32]).unwrap();
+
+        assert_eq!(storage.level_count(), 2);
+        assert_eq!(storage.level(0).len(), 200);
+        assert_eq!(storage.level(0)[5], [255; 32]);
+        assert_eq!(storage.level(0)[199], [199; 32]);
+        assert_eq!(storage.level(1), &[[1; 32]]);
+
+        assert!(storage.set(0, 201, [0; 32]).is_err());
+        assert!(storage.set(3, 0, [0; 32]).is_err());
+    }
+
+    /// Test that the in-memory storage appends, overwrites and rejects gaps
+    #[test]
+    fn memory_storage() {
+        let mut storage = MemoryStorage::new();
+        exercise(&mut storage);
+
+        storage.clear().unwrap();
+        assert_eq!(storage.level_count(), 0);
+    }
+
+    /// Test that the file storage grows its files and keeps its nodes across reopening
+    #[test]
+    fn file_storage_reopens() {
+        let directory = tempfile::tempdir().unwrap();
+
+        let mut storage = FileStorage::open(directory.path()).unwrap();
+        exercise(&mut storage);
+        storage.flush().unwrap();
+        drop(storage);
+
+        let mut storage = FileStorage::open(directory.path()).unwrap();
+        assert_eq!(storage.level_count(), 2);
+        assert_eq!(storage.level(0)[5], [255; 32]);
+        assert_eq!(storage.level(0)[199], [199; 32]);
+
+        storage.clear().unwrap();
+        storage.set(0, 0, [7; 32]).unwrap();
+        drop(storage);
+
+        let storage = FileStorage::open(directory.path()).unwrap();
+        assert_eq!(storage.level_count(), 1);
+        assert_eq!(storage.level(0), &[[7; 32]]);
+    }
+
+    /// Test that a level file claiming more nodes than it holds is rejected
+    #[test]
+    fn corrupted_file_is_rejected() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut header = vec![0; HEADER_SIZE + 32 * 4];
+        header[..HEADER_SIZE].copy_from_slice(&100u64.to_le_bytes());
+        fs::write(directory.path().join("level-0.bin"), header).unwrap();
+
+        assert!(matches!(
+            FileStorage::open(directory.path()),
+            Err(MerkleTreeError::Storage(_))
+        ));
+    }
+}
@@ -0,0 +1,75 @@
+/// Increment of the state of `SimRng` for each value drawn.
+const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// A small deterministic pseudo-random number generator (SplitMix64).
+///
+/// Simulations must be replayable from a seed, so everything that needs randomness
+/// (election timeouts, message latency, message loss) draws from one of these instead
+/// of from the operating system.
+#[derive(Debug, Clone)]
+pub struct SimRng {
+    state: u64,
+}
+
+impl SimRng {
+    /// Creates a new generator from a seed. The same seed always yields the same sequence.
+    pub fn new(seed: u64) -> Self {
+        SimRng { state: seed }
+    }
+
+    /// Returns the next pseudo-random `u64`.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GAMMA);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Skips the next `draws` values in O(1) time, so any value of the sequence can be
+    /// drawn without drawing the ones before it.
+    pub fn advance(&mut self, draws: u64) {
+        self.state = self.state.wrapping_add(draws.wrapping_mul(GAMMA));
+    }
+
+    /// Returns a value in the inclusive range `[low, high]`.
+    pub fn range(&mut self, low: u64, high: u64) -> u64 {
+        if high <= low {
+            return low;
+        }
+        match (high - low).checked_add(1) {
+            Some(span) => low + self.next_u64() % span,
+            // The range is every `u64`
+            None => self.next_u64(),
+        }
+    }
+
+    /// Returns `true` with the given probability, clamped to `[0, 1]`.
+    pub fn chance(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        if probability >= 1.0 {
+            return true;
+        }
+        let sample = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        sample < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that values stay in their range, including the range of every `u64`
+    #[test]
+    fn draws_in_range() {
+        let mut rng = SimRng::new(7);
+        assert!((0..1000).all(|_| (10..=20).contains(&rng.range(10, 20))));
+        assert_eq!(rng.range(5, 5), 5);
+        assert_eq!(rng.range(9, 3), 9);
+        let mut again = SimRng::new(7);
+        again.advance(1000);
+        assert_eq!(rng.range(0, u64::MAX), again.next_u64());
+    }
+}